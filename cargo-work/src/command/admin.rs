@@ -1,8 +1,7 @@
 use super::*;
 use crate::options::AdminOptions;
-use color_eyre::eyre::{anyhow, bail, ContextCompat, Result, WrapErr};
-use git2::Repository;
-use std::io::Write;
+use color_eyre::eyre::{anyhow, ContextCompat, Result, WrapErr};
+use std::io::{Read, Write};
 use std::net::SocketAddr;
 use std::path::Path;
 
@@ -27,14 +26,13 @@ pub async fn run(sk: &Path, options: AdminOptions) -> Result<()> {
     } else {
         let trace_id = super::new_trace_id(action);
         super::log_stage(&trace_id, action, "single.start");
-        let result = exec_admin(sk, host, &options.horse, &options.command, &trace_id).await?;
+        let (command, input) = import_input(&options.command)?;
+        let result = exec_admin(sk, host, &options.horse, &command, input, &trace_id).await?;
         print_exec_result(&result)?;
         if !result.success() {
-            bail!(
-                "{}",
-                first_non_empty(&result.stderr, &result.stdout)
-                    .unwrap_or_else(|| "admin 命令执行失败".to_string())
-            );
+            let message = first_non_empty(&result.stderr, &result.stdout)
+                .unwrap_or_else(|| "admin 命令执行失败".to_string());
+            return Err(ExitError::Remote(result.exit_code.unwrap_or(1))).wrap_err(message);
         }
         super::log_stage(&trace_id, action, "single.done");
         Ok(())
@@ -116,6 +114,26 @@ async fn run_interactive(sk: &Path, host: SocketAddr, horse: &HorseOptions) -> R
                 }
                 vec!["keys".to_string(), "delete".to_string(), alg, key]
             }
+            "12" => vec!["repos".to_string(), "list".to_string()],
+            "13" => {
+                let repo = prompt("仓库(ns/name)")?;
+                let user = prompt("所有者用户名")?;
+                vec!["repos".to_string(), "owner".to_string(), repo, user]
+            }
+            "14" => {
+                let repo = prompt("仓库(ns/name)")?;
+                let user = prompt("用户名")?;
+                let level = prompt_default("权限(read/write/admin)", "read")?;
+                vec!["repos".to_string(), "grant".to_string(), repo, user, level]
+            }
+            "15" => {
+                let repo = prompt("仓库(ns/name)")?;
+                let user = prompt("用户名")?;
+                if !confirm(&format!("确认撤销 {user} 对 {repo} 的权限?"))? {
+                    continue;
+                }
+                vec!["repos".to_string(), "revoke".to_string(), repo, user]
+            }
             _ => {
                 eprintln!("无效输入: {choice}");
                 continue;
@@ -124,7 +142,7 @@ async fn run_interactive(sk: &Path, host: SocketAddr, horse: &HorseOptions) -> R
 
         let trace_id = super::new_trace_id("admin");
         super::log_stage(&trace_id, "admin", "interactive.dispatch");
-        match exec_admin(sk, host, horse, &command, &trace_id).await {
+        match exec_admin(sk, host, horse, &command, None, &trace_id).await {
             Ok(result) => {
                 print_exec_result(&result)?;
                 if !result.success() {
@@ -154,6 +172,10 @@ fn print_menu() {
     println!("9) keys enable");
     println!("10) keys disable");
     println!("11) keys delete");
+    println!("12) repos list");
+    println!("13) repos owner");
+    println!("14) repos grant");
+    println!("15) repos revoke");
     println!("0) exit");
 }
 
@@ -214,24 +236,23 @@ fn load_public_key_input(input: &str) -> Result<(String, String, Option<String>)
     }
 }
 
-fn resolve_host(options: &HorseOptions) -> Result<SocketAddr> {
-    if let Ok(host) = std::env::var("HORSED") {
-        return host
-            .parse()
-            .wrap_err_with(|| format!("解析环境变量 HORSED 失败: {host}"));
-    }
-    if let Some(host) = find_host(options) {
-        return Ok(host);
+/// `keys import <user> [file]`: 读取本地的 authorized_keys 文件, 省略文件时读取标准输入
+fn import_input(args: &[String]) -> Result<(Vec<String>, Option<Vec<u8>>)> {
+    if args.len() < 3 || args[0] != "keys" || args[1] != "import" {
+        return Ok((args.to_vec(), None));
     }
 
-    let repo = Repository::discover(".")?;
-    let Some(horsed) = find_remote(&repo, options) else {
-        return Err(anyhow!("找不到 horsed 远程仓库!"));
+    let content = match args.get(3).map(String::as_str) {
+        Some("-") | None => {
+            let mut content = Vec::new();
+            std::io::stdin()
+                .read_to_end(&mut content)
+                .wrap_err("读取标准输入")?;
+            content
+        }
+        Some(path) => std::fs::read(path).wrap_err_with(|| format!("读取 {path}"))?,
     };
-    horsed
-        .url()
-        .and_then(extract_host)
-        .context("获取 horsed 远程仓库 HOST 失败")
+    Ok((args[..3].to_vec(), Some(content)))
 }
 
 async fn exec_admin(
@@ -239,11 +260,12 @@ async fn exec_admin(
     host: SocketAddr,
     horse: &HorseOptions,
     args: &[String],
+    input: Option<Vec<u8>>,
     trace_id: &str,
 ) -> Result<AdminExecResult> {
     let action = "admin";
     super::log_stage(trace_id, action, "connect.start");
-    let mut ssh = HorseClient::connect(sk, horse.key_hash_alg, action, host, None, None).await?;
+    let mut ssh = HorseClient::connect(sk, horse, action, host, None, None).await?;
     let mut channel = ssh.channel_open_session().await?;
     if !trace_id.is_empty() {
         channel.set_env(true, super::TRACE_ID_ENV, trace_id).await?;
@@ -260,6 +282,10 @@ async fn exec_admin(
         .join(" ");
     super::log_stage(trace_id, action, "dispatch.exec");
     channel.exec(true, command).await.wrap_err("exec")?;
+    if let Some(input) = input {
+        channel.data(&input[..]).await.wrap_err("send input")?;
+        channel.eof().await?;
+    }
 
     let mut out = Vec::new();
     let mut err = Vec::new();
@@ -1,6 +1,6 @@
 use super::*;
 use crate::options::CargoKind;
-use color_eyre::eyre::{anyhow, bail, ContextCompat, Result, WrapErr};
+use color_eyre::eyre::{anyhow, ContextCompat, Result, WrapErr};
 use git2::Repository;
 use std::path::Path;
 use tokio::io::AsyncWriteExt;
@@ -53,21 +53,24 @@ pub async fn run(sk: &Path, options: impl CargoKind) -> Result<()> {
     let env = super::ssh::start_proxy(sk, host, options.horse_options()).await?;
     super::log_stage(&trace_id, action, "proxy.ready");
 
-    let diff =
-        super::collect_remote_patch(&repo, options.horse_options().remote.as_deref()).await?;
+    let diff = super::sync::prepare(
+        &repo,
+        options.horse_options(),
+        super::sync::SyncTarget {
+            sk,
+            host,
+            repo_name: &repo_name,
+            branch: &branch,
+            trace_id: &trace_id,
+        },
+    )
+    .await?;
 
     #[cfg(not(feature = "use-system-ssh"))]
     {
         super::log_stage(&trace_id, action, "connect.start");
-        let mut ssh = HorseClient::connect(
-            sk,
-            options.horse_options().key_hash_alg,
-            "cargo",
-            host,
-            None,
-            None,
-        )
-        .await?;
+        let mut ssh =
+            HorseClient::connect(sk, options.horse_options(), "cargo", host, None, None).await?;
         let mut channel = ssh.channel_open_session().await?;
         super::log_stage(&trace_id, action, "channel.open");
         let head_commit = head.peel_to_commit()?;
@@ -94,6 +97,12 @@ pub async fn run(sk: &Path, options: impl CargoKind) -> Result<()> {
         channel
             .set_env(true, "ZIGBUILD", options.use_zigbuild().to_string())
             .await?;
+        if options.horse_options().detach {
+            channel.set_env(true, "DETACH", "true").await?;
+        }
+        for (k, v) in &diff.env {
+            channel.set_env(true, *k, v.as_str()).await?;
+        }
         channel
             .set_env(
                 true,
@@ -106,7 +115,7 @@ pub async fn run(sk: &Path, options: impl CargoKind) -> Result<()> {
         channel.exec(true, options.name()).await.wrap_err("exec")?;
 
         let mut writer = channel.make_writer();
-        writer.write_all(&diff).await.unwrap();
+        writer.write_all(&diff.stdin).await.unwrap();
         writer.shutdown().await?;
 
         let mut stdout = tokio::io::stdout();
@@ -114,7 +123,9 @@ pub async fn run(sk: &Path, options: impl CargoKind) -> Result<()> {
         let mut code = 0_u32;
         let mut got_exit_status = false;
 
-        while let Some(msg) = channel.wait().await {
+        let mut interrupted = false;
+        while let Some(msg) = super::wait_forwarding_interrupt(&mut channel, &mut interrupted).await
+        {
             match msg {
                 ChannelMsg::Data { ref data } => {
                     stdout.write_all(data).await?;
@@ -145,9 +156,7 @@ pub async fn run(sk: &Path, options: impl CargoKind) -> Result<()> {
             ssh.close().await?;
         }
 
-        if got_exit_status && code != 0 {
-            bail!("remote cargo command failed with exit status {code}");
-        }
+        exit::remote_status(got_exit_status.then_some(code))?;
         super::log_stage(&trace_id, action, "done");
     }
 
@@ -161,6 +170,12 @@ pub async fn run(sk: &Path, options: impl CargoKind) -> Result<()> {
         envs.insert("REPO".to_string(), repo_name);
         envs.insert("BRANCH".to_string(), branch);
         envs.insert("ZIGBUILD".to_string(), options.use_zigbuild().to_string());
+        if options.horse_options().detach {
+            envs.insert("DETACH".to_string(), "true".to_string());
+        }
+        for (k, v) in &diff.env {
+            envs.insert(k.to_string(), v.clone());
+        }
         envs.insert(
             "CARGO_OPTIONS".to_string(),
             format!("\'{}\'", serde_json::to_string(options.cargo_options())?),
@@ -196,7 +211,7 @@ pub async fn run(sk: &Path, options: impl CargoKind) -> Result<()> {
         let mut out = tokio::io::stdout();
         let mut err = tokio::io::stderr();
 
-        stdin.write_all(&diff).await?;
+        stdin.write_all(&diff.stdin).await?;
         drop(stdin);
 
         let write_out = tokio::io::copy(&mut stdout, &mut out);
@@ -204,12 +219,7 @@ pub async fn run(sk: &Path, options: impl CargoKind) -> Result<()> {
         futures::future::try_join(write_out, write_err).await?;
 
         let status = ssh.wait().await?;
-        if !status.success() {
-            bail!(
-                "remote cargo command failed with exit status {}",
-                status.code().unwrap_or(128)
-            );
-        }
+        exit::process_status(status)?;
         super::log_stage(&trace_id, action, "done");
     }
 
@@ -28,6 +28,9 @@ pub async fn run(
     sync: CodeSync,
 ) -> Result<()> {
     let action = "cmd";
+    if horse.detach && horse.pty {
+        bail!("--detach 不能与 --pty 同时使用");
+    }
     let trace_id = super::new_trace_id(action);
     super::log_stage(&trace_id, action, "resolve.start");
     let repo = Repository::discover(".")?;
@@ -73,7 +76,20 @@ pub async fn run(
     let env = super::ssh::start_proxy(sk, host, &horse).await?;
     super::log_stage(&trace_id, action, "proxy.ready");
     let patch = if sync == CodeSync::Enabled {
-        Some(super::collect_remote_patch(&repo, horse.remote.as_deref()).await?)
+        Some(
+            super::sync::prepare(
+                &repo,
+                &horse,
+                super::sync::SyncTarget {
+                    sk,
+                    host,
+                    repo_name: &repo_name,
+                    branch: &branch,
+                    trace_id: &trace_id,
+                },
+            )
+            .await?,
+        )
     } else {
         None
     };
@@ -81,8 +97,7 @@ pub async fn run(
     #[cfg(not(feature = "use-system-ssh"))]
     {
         super::log_stage(&trace_id, action, "connect.start");
-        let mut ssh =
-            HorseClient::connect(sk, horse.key_hash_alg, sync.action(), host, None, None).await?;
+        let mut ssh = HorseClient::connect(sk, &horse, sync.action(), host, None, None).await?;
         let mut channel = ssh.channel_open_session().await?;
         super::log_stage(&trace_id, action, "channel.open");
 
@@ -103,6 +118,16 @@ pub async fn run(
             channel.set_env(true, "PTY", "1").await?;
         }
 
+        if horse.detach {
+            channel.set_env(true, "DETACH", "true").await?;
+        }
+
+        if let Some(patch) = &patch {
+            for (k, v) in &patch.env {
+                channel.set_env(true, *k, v.as_str()).await?;
+            }
+        }
+
         if !trace_id.is_empty() {
             channel
                 .set_env(true, super::TRACE_ID_ENV, &trace_id)
@@ -123,7 +148,7 @@ pub async fn run(
 
         if let Some(patch) = patch {
             let mut stdin = channel.make_writer();
-            stdin.write_all(&patch).await?;
+            stdin.write_all(&patch.stdin).await?;
             stdin.shutdown().await?;
             drop(stdin);
         }
@@ -133,7 +158,9 @@ pub async fn run(
         let mut code = 0_u32;
         let mut got_exit_status = false;
 
-        while let Some(msg) = channel.wait().await {
+        let mut interrupted = false;
+        while let Some(msg) = super::wait_forwarding_interrupt(&mut channel, &mut interrupted).await
+        {
             match msg {
                 ChannelMsg::Data { ref data } => {
                     stdout.write_all(data).await?;
@@ -164,9 +191,7 @@ pub async fn run(
             ssh.close().await?;
         }
 
-        if got_exit_status && code != 0 {
-            bail!("remote command failed with exit status {code}");
-        }
+        exit::remote_status(got_exit_status.then_some(code))?;
         super::log_stage(&trace_id, action, "done");
     }
 
@@ -182,6 +207,14 @@ pub async fn run(
         if let Some(shell) = horse.shell {
             envs.insert("SHELL".to_string(), shell);
         }
+        if horse.detach {
+            envs.insert("DETACH".to_string(), "true".to_string());
+        }
+        if let Some(patch) = &patch {
+            for (k, v) in &patch.env {
+                envs.insert(k.to_string(), v.clone());
+            }
+        }
 
         let head_commit = head.peel_to_commit()?;
         let commit = head_commit.id().to_string();
@@ -211,7 +244,7 @@ pub async fn run(
         super::log_stage(&trace_id, action, "connect.start");
         if let Some(patch) = patch {
             let mut stdin = ssh.stdin.take().context("获取远端命令 stdin 失败")?;
-            stdin.write_all(&patch).await?;
+            stdin.write_all(&patch.stdin).await?;
             stdin.shutdown().await?;
         }
         let mut stdout = ssh.stdout.take().unwrap();
@@ -224,12 +257,7 @@ pub async fn run(
         futures::future::try_join(write_out, write_err).await?;
 
         let status = ssh.wait().await?;
-        if !status.success() {
-            bail!(
-                "remote command failed with exit status {}",
-                status.code().unwrap_or(128)
-            );
-        }
+        exit::process_status(status)?;
         super::log_stage(&trace_id, action, "done");
     }
 
@@ -120,8 +120,7 @@ pub async fn run(sk: &Path, options: GetOptions) -> Result<()> {
     #[cfg(not(feature = "use-system-ssh"))]
     let mut channel = {
         super::log_stage(&trace_id, action, "connect.start");
-        let ssh =
-            HorseClient::connect(sk, options.horse.key_hash_alg, "get", host, None, None).await?;
+        let ssh = HorseClient::connect(sk, &options.horse, "get", host, None, None).await?;
         let channel = ssh.channel_open_session().await?;
         if !trace_id.is_empty() {
             channel
@@ -117,7 +117,7 @@ async fn call_health_once(
     req_body: Body,
 ) -> Result<Body> {
     super::log_stage(trace_id, "health", "connect.start");
-    let mut ssh = HorseClient::connect(sk, horse.key_hash_alg, "health", host, None, None).await?;
+    let mut ssh = HorseClient::connect(sk, horse, "health", host, None, None).await?;
     let mut channel = ssh.channel_open_session().await?;
     if !trace_id.is_empty() {
         channel.set_env(true, super::TRACE_ID_ENV, trace_id).await?;
@@ -82,8 +82,8 @@ async fn select_running_job(
     host: SocketAddr,
     trace_id: &str,
 ) -> Result<Option<String>> {
-    let (stdout, exit_code) =
-        exec_job_capture(sk, options, host, trace_id, &["list".to_string()]).await?;
+    let list = ["list", "--status", "running"].map(String::from);
+    let (stdout, exit_code) = exec_job_capture(sk, options, host, trace_id, &list).await?;
     if exit_code.unwrap_or(0) != 0 {
         return Ok(None);
     }
@@ -153,8 +153,7 @@ async fn exec_job_streaming(
     command: &[String],
 ) -> Result<()> {
     super::log_stage(trace_id, "job", "connect.start");
-    let mut ssh =
-        HorseClient::connect(sk, options.horse.key_hash_alg, "job", host, None, None).await?;
+    let mut ssh = HorseClient::connect(sk, &options.horse, "job", host, None, None).await?;
     let mut channel = ssh.channel_open_session().await?;
     if !trace_id.is_empty() {
         channel.set_env(true, super::TRACE_ID_ENV, trace_id).await?;
@@ -198,9 +197,7 @@ async fn exec_job_streaming(
     if !ssh.is_closed() {
         ssh.close().await?;
     }
-    if got_exit_status && code != 0 {
-        return Err(anyhow!("remote job command failed with exit status {code}"));
-    }
+    exit::remote_status(got_exit_status.then_some(code))?;
 
     Ok(())
 }
@@ -212,8 +209,7 @@ async fn exec_job_capture(
     trace_id: &str,
     command: &[String],
 ) -> Result<(Vec<u8>, Option<u32>)> {
-    let mut ssh =
-        HorseClient::connect(sk, options.horse.key_hash_alg, "job", host, None, None).await?;
+    let mut ssh = HorseClient::connect(sk, &options.horse, "job", host, None, None).await?;
     let mut channel = ssh.channel_open_session().await?;
     if !trace_id.is_empty() {
         channel.set_env(true, super::TRACE_ID_ENV, trace_id).await?;
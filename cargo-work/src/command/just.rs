@@ -56,7 +56,18 @@ pub async fn run(sk: &Path, options: JustOptions) -> Result<()> {
     let env = super::ssh::start_proxy(sk, host, &options.horse).await?;
     super::log_stage(&trace_id, action, "proxy.ready");
 
-    let diff = super::collect_remote_patch(&repo, options.horse.remote.as_deref()).await?;
+    let diff = super::sync::prepare(
+        &repo,
+        &options.horse,
+        super::sync::SyncTarget {
+            sk,
+            host,
+            repo_name: &repo_name,
+            branch: &branch,
+            trace_id: &trace_id,
+        },
+    )
+    .await?;
 
     #[cfg(feature = "use-system-ssh")]
     {
@@ -87,6 +98,12 @@ pub async fn run(sk: &Path, options: JustOptions) -> Result<()> {
         if let Some(justfile) = options.file {
             envs.insert("JUSTFILE".to_string(), justfile);
         }
+        if options.horse.detach {
+            envs.insert("DETACH".to_string(), "true".to_string());
+        }
+        for (k, v) in &diff.env {
+            envs.insert(k.to_string(), v.clone());
+        }
 
         let mut cmd =
             super::run_system_ssh(sk, envs, "just", host, [std::ffi::OsString::from(command)]);
@@ -98,7 +115,7 @@ pub async fn run(sk: &Path, options: JustOptions) -> Result<()> {
         let mut ssh = cmd.spawn()?;
         let mut sshin = ssh.stdin.take().unwrap();
 
-        sshin.write_all(&diff).await?;
+        sshin.write_all(&diff.stdin).await?;
         sshin.shutdown().await?;
         drop(sshin);
 
@@ -112,15 +129,15 @@ pub async fn run(sk: &Path, options: JustOptions) -> Result<()> {
 
         futures::future::try_join(write_out, write_err).await?;
 
-        ssh.wait().await?;
+        let status = ssh.wait().await?;
+        exit::process_status(status)?;
         super::log_stage(&trace_id, action, "done");
     }
 
     #[cfg(not(feature = "use-system-ssh"))]
     {
         super::log_stage(&trace_id, action, "connect.start");
-        let mut ssh =
-            HorseClient::connect(sk, options.horse.key_hash_alg, "just", host, None, None).await?;
+        let mut ssh = HorseClient::connect(sk, &options.horse, "just", host, None, None).await?;
         let mut channel = ssh.channel_open_session().await?;
         super::log_stage(&trace_id, action, "channel.open");
         let head_commit = head.peel_to_commit()?;
@@ -146,18 +163,27 @@ pub async fn run(sk: &Path, options: JustOptions) -> Result<()> {
         if let Some(justfile) = options.file {
             channel.set_env(true, "JUSTFILE", justfile).await?;
         }
+        if options.horse.detach {
+            channel.set_env(true, "DETACH", "true").await?;
+        }
+        for (k, v) in &diff.env {
+            channel.set_env(true, *k, v.as_str()).await?;
+        }
         super::log_stage(&trace_id, action, "dispatch.exec");
         channel.exec(true, command.as_bytes()).await?;
 
         let mut stdin = channel.make_writer();
-        stdin.write_all(&diff).await?;
+        stdin.write_all(&diff.stdin).await?;
         stdin.shutdown().await?;
         drop(stdin);
 
         let mut stdout = tokio::io::stdout();
         let mut stderr = tokio::io::stderr();
+        let mut code = None;
 
-        while let Some(msg) = channel.wait().await {
+        let mut interrupted = false;
+        while let Some(msg) = super::wait_forwarding_interrupt(&mut channel, &mut interrupted).await
+        {
             match msg {
                 ChannelMsg::Data { ref data } => {
                     stdout.write_all(data).await?;
@@ -169,12 +195,17 @@ pub async fn run(sk: &Path, options: JustOptions) -> Result<()> {
                 }
                 ChannelMsg::Close => {}
                 ChannelMsg::Eof => {}
-                ChannelMsg::ExitStatus { exit_status } => {}
+                ChannelMsg::ExitStatus { exit_status } => {
+                    code = Some(exit_status);
+                }
                 other => {}
             }
         }
 
-        ssh.close().await?;
+        if !ssh.is_closed() {
+            ssh.close().await?;
+        }
+        exit::remote_status(code)?;
         super::log_stage(&trace_id, action, "done");
     }
 
@@ -0,0 +1,524 @@
+//! 服务端公钥校验
+//!
+//! 信任的公钥记录在 `~/.config/cargo-work/known_hosts`, 同时读取 `~/.ssh/known_hosts`.
+//! 第一次连接时在终端确认指纹 (或者使用 `--accept-new` 直接信任),
+//! 之后公钥发生变化时拒绝连接, 确认服务端更换了公钥后用 `cargo work trust` 更新记录.
+//! 主机已有记录时, 任何不在记录中的公钥都视为变化, 包括算法不同的公钥;
+//! `@revoked` 标记的公钥总是拒绝, `cargo work trust` 也不能信任.
+use russh::keys::ssh_key::PublicKey;
+use russh::keys::HashAlg;
+use std::fmt;
+use std::io::{IsTerminal, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// 遇到未记录的服务端公钥时的处理方式
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HostKeyPolicy {
+    /// 在终端确认指纹, 非交互环境下拒绝连接
+    #[default]
+    Ask,
+    /// 直接信任并记录, 公钥变化时仍然拒绝连接
+    AcceptNew,
+    /// 信任当前公钥并替换已有记录, 用于 `cargo work trust`
+    Replace,
+}
+
+/// 服务端公钥校验失败
+#[derive(Debug, Clone)]
+pub enum HostKeyError {
+    /// 没有记录, 且未被信任
+    Unknown { host: String, fingerprint: String },
+    /// 与记录的公钥不一致
+    Changed {
+        host: String,
+        fingerprint: String,
+        path: PathBuf,
+        line: usize,
+    },
+    /// 公钥已被 `@revoked` 吊销
+    Revoked {
+        host: String,
+        fingerprint: String,
+        path: PathBuf,
+        line: usize,
+    },
+}
+
+impl fmt::Display for HostKeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HostKeyError::Unknown { host, fingerprint } => write!(
+                f,
+                "未知的服务端公钥: {host} {fingerprint}\n确认指纹无误后执行 `cargo work trust` 记录该公钥, 或使用 --accept-new 自动信任新主机"
+            ),
+            HostKeyError::Changed {
+                host,
+                fingerprint,
+                path,
+                line,
+            } => write!(
+                f,
+                "服务端公钥已变化, 可能存在中间人攻击!\n主机: {host}\n当前指纹: {fingerprint}\n原有记录: {}:{line}\n确认服务端更换了公钥后执行 `cargo work trust` 更新记录",
+                path.display()
+            ),
+            HostKeyError::Revoked {
+                host,
+                fingerprint,
+                path,
+                line,
+            } => write!(
+                f,
+                "服务端公钥已被吊销!\n主机: {host}\n当前指纹: {fingerprint}\n吊销记录: {}:{line}",
+                path.display()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for HostKeyError {}
+
+/// 一次连接的公钥校验, 校验失败的原因留给连接方读取
+#[derive(Clone)]
+pub struct HostKeyCheck {
+    host: SocketAddr,
+    policy: HostKeyPolicy,
+    rejected: Arc<Mutex<Option<HostKeyError>>>,
+}
+
+impl HostKeyCheck {
+    pub fn new(host: SocketAddr, policy: HostKeyPolicy) -> Self {
+        Self {
+            host,
+            policy,
+            rejected: Default::default(),
+        }
+    }
+
+    /// 公钥校验失败的原因
+    pub fn rejection(&self) -> Option<HostKeyError> {
+        self.rejected.lock().ok()?.take()
+    }
+
+    pub async fn check(&self, key: &PublicKey) -> bool {
+        let this = self.clone();
+        let key = key.clone();
+        // 需要读写文件和终端, 不阻塞连接所在的运行时
+        let result = tokio::task::spawn_blocking(move || this.verify(&key))
+            .await
+            .unwrap_or_else(|err| Err(std::io::Error::other(err)));
+
+        match result {
+            Ok(Ok(())) => true,
+            Ok(Err(err)) => {
+                if let Ok(mut rejected) = self.rejected.lock() {
+                    rejected.replace(err);
+                }
+                false
+            }
+            Err(err) => {
+                tracing::error!("读取 known_hosts 失败: {err}");
+                false
+            }
+        }
+    }
+
+    fn verify(&self, key: &PublicKey) -> std::io::Result<Result<(), HostKeyError>> {
+        let host = host_pattern(self.host);
+        let fingerprint = fingerprint(key);
+        let trusted = trusted_path()?;
+
+        // 吊销优先于任何信任记录, 其次是任一文件中的信任记录
+        let mut found = Vec::new();
+        for path in [Some(trusted.clone()), ssh_known_hosts()]
+            .into_iter()
+            .flatten()
+        {
+            let result = lookup(&path, &host, key)?;
+            found.push((path, result));
+        }
+        found.sort_by_key(|(_, result)| result.rank());
+
+        match found.into_iter().next() {
+            Some((path, Lookup::Revoked(line))) => {
+                return Ok(Err(HostKeyError::Revoked {
+                    host,
+                    fingerprint,
+                    path,
+                    line,
+                }));
+            }
+            _ if self.policy == HostKeyPolicy::Replace => {
+                forget(&trusted, &host)?;
+                learn(&trusted, &host, key)?;
+                eprintln!("已信任 {host} {fingerprint}");
+                return Ok(Ok(()));
+            }
+            Some((_, Lookup::Trusted)) => return Ok(Ok(())),
+            Some((path, Lookup::Changed(line))) => {
+                return Ok(Err(HostKeyError::Changed {
+                    host,
+                    fingerprint,
+                    path,
+                    line,
+                }));
+            }
+            Some((_, Lookup::Unknown)) | None => {}
+        }
+
+        let accept = match self.policy {
+            HostKeyPolicy::AcceptNew => true,
+            _ => confirm(&host, &fingerprint)?,
+        };
+        if !accept {
+            return Ok(Err(HostKeyError::Unknown { host, fingerprint }));
+        }
+
+        learn(&trusted, &host, key)?;
+        eprintln!("已将 {host} {fingerprint} 加入 {}", trusted.display());
+        Ok(Ok(()))
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Lookup {
+    /// 公钥已被吊销, 吊销记录所在的行号
+    Revoked(usize),
+    Trusted,
+    /// 主机有记录, 但是没有当前的公钥, 第一条记录所在的行号
+    Changed(usize),
+    Unknown,
+}
+
+impl Lookup {
+    /// 多个文件的结果按此顺序取第一个
+    fn rank(&self) -> u8 {
+        match self {
+            Lookup::Revoked(_) => 0,
+            Lookup::Trusted => 1,
+            Lookup::Changed(_) => 2,
+            Lookup::Unknown => 3,
+        }
+    }
+}
+
+/// 在 known_hosts 中查找主机, 支持明文和哈希过的主机名
+fn lookup(path: &Path, host: &str, key: &PublicKey) -> std::io::Result<Lookup> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Lookup::Unknown),
+        Err(err) => return Err(err),
+    };
+
+    let mut trusted = false;
+    let mut changed = None;
+    for (idx, line) in content.lines().enumerate() {
+        let Some(entry) = parse_line(line) else {
+            continue;
+        };
+        if !entry
+            .patterns
+            .split(',')
+            .any(|pattern| host_matches(pattern, host))
+        {
+            continue;
+        }
+        let same = entry.key.key_data() == key.key_data();
+        match entry.marker {
+            Some("@revoked") if same => return Ok(Lookup::Revoked(idx + 1)),
+            // `@cert-authority` 等其他标记不是主机公钥
+            Some(_) => {}
+            None if same => trusted = true,
+            // 算法不同的公钥同样视为变化, 否则中间人换一种算法就能绕过
+            None => {
+                changed.get_or_insert(idx + 1);
+            }
+        }
+    }
+
+    Ok(match (trusted, changed) {
+        (true, _) => Lookup::Trusted,
+        (false, Some(line)) => Lookup::Changed(line),
+        (false, None) => Lookup::Unknown,
+    })
+}
+
+/// known_hosts 中的一行记录
+struct Entry<'a> {
+    /// `@revoked`, `@cert-authority` 等标记
+    marker: Option<&'a str>,
+    patterns: &'a str,
+    key: PublicKey,
+}
+
+/// 解析一行记录, 跳过注释和无法解析的行
+fn parse_line(line: &str) -> Option<Entry<'_>> {
+    let mut line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    let mut marker = None;
+    if line.starts_with('@') {
+        let (name, rest) = line.split_once(char::is_whitespace)?;
+        marker = Some(name);
+        line = rest.trim_start();
+    }
+    let (patterns, key) = line.split_once(char::is_whitespace)?;
+    let key = PublicKey::from_openssh(key.trim()).ok()?;
+    Some(Entry {
+        marker,
+        patterns,
+        key,
+    })
+}
+
+fn host_matches(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix("|1|") {
+        Some(hashed) => hashed_matches(hashed, host),
+        // `@revoked * <key>` 吊销所有主机的公钥
+        None => pattern == "*" || pattern == host,
+    }
+}
+
+/// `|1|<salt>|<hmac-sha1(salt, host)>` 形式的哈希主机名
+fn hashed_matches(hashed: &str, host: &str) -> bool {
+    use base64::Engine as _;
+    use hmac::Mac as _;
+
+    let engine = base64::engine::general_purpose::STANDARD;
+    let Some((salt, hash)) = hashed.split_once('|') else {
+        return false;
+    };
+    let (Ok(salt), Ok(hash)) = (engine.decode(salt), engine.decode(hash)) else {
+        return false;
+    };
+    let Ok(mut mac) = hmac::Hmac::<sha1::Sha1>::new_from_slice(&salt) else {
+        return false;
+    };
+    mac.update(host.as_bytes());
+    mac.verify_slice(&hash).is_ok()
+}
+
+/// known_hosts 中的主机名, 非 22 端口写作 `[host]:port`
+fn host_pattern(host: SocketAddr) -> String {
+    if host.port() == 22 {
+        host.ip().to_string()
+    } else {
+        format!("[{}]:{}", host.ip(), host.port())
+    }
+}
+
+pub fn fingerprint(key: &PublicKey) -> String {
+    format!("{} {}", key.algorithm(), key.fingerprint(HashAlg::Sha256))
+}
+
+fn learn(path: &Path, host: &str, key: &PublicKey) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let key = key.to_openssh().map_err(std::io::Error::other)?;
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    writeln!(file, "{host} {key}")
+}
+
+/// 删除主机在 `cargo-work` 记录中的公钥, 不修改 `~/.ssh/known_hosts`
+fn forget(path: &Path, host: &str) -> std::io::Result<()> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    };
+
+    // 保留 `@revoked` 等标记行
+    let kept = content
+        .lines()
+        .filter(|line| {
+            parse_line(line).is_none_or(|entry| {
+                entry.marker.is_some()
+                    || !entry
+                        .patterns
+                        .split(',')
+                        .any(|pattern| host_matches(pattern, host))
+            })
+        })
+        .map(|line| format!("{line}\n"))
+        .collect::<String>();
+    std::fs::write(path, kept)
+}
+
+/// 在终端确认未知主机的指纹
+fn confirm(host: &str, fingerprint: &str) -> std::io::Result<bool> {
+    if !std::io::stdin().is_terminal() || !std::io::stderr().is_terminal() {
+        return Ok(false);
+    }
+
+    let mut stderr = std::io::stderr();
+    write!(
+        stderr,
+        "无法确认服务端 {host} 的身份.\n公钥指纹: {fingerprint}\n是否信任并继续连接? [y/N] "
+    )?;
+    stderr.flush()?;
+
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes" | "YES"))
+}
+
+fn home_dir() -> std::io::Result<PathBuf> {
+    #[cfg(not(windows))]
+    let home = std::env::var_os("HOME");
+    #[cfg(windows)]
+    let home = std::env::var_os("USERPROFILE");
+
+    home.map(PathBuf::from)
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "找不到用户目录"))
+}
+
+/// `cargo-work` 自己维护的 known_hosts
+pub fn trusted_path() -> std::io::Result<PathBuf> {
+    Ok(home_dir()?.join(".config/cargo-work/known_hosts"))
+}
+
+fn ssh_known_hosts() -> Option<PathBuf> {
+    home_dir().ok().map(|home| home.join(".ssh/known_hosts"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIBmF3wbzz6xOzcx7J1aqv9dCqbIjNi7jwcZmhUCeY9iS";
+    const OTHER: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIOCe4kU5Q0MWGcW76UrgL7rX1sKEN5P9zu3VAU1dgeGM";
+    const ECDSA: &str = "ecdsa-sha2-nistp256 AAAAE2VjZHNhLXNoYTItbmlzdHAyNTYAAAAIbmlzdHAyNTYAAABBBOjCn6MDpOlnqq4SsTIf1dSaVfvcVmv+ipcCnsAKzL+b4iGrXgeN6C8S1aOefdMRPdcucOxWVdbszXSlTh1Fs4o=";
+
+    fn temp_file(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "cargo-work-{name}-{}",
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        ))
+    }
+
+    #[test]
+    fn host_pattern_brackets_custom_ports() {
+        assert_eq!(host_pattern("10.0.0.1:22".parse().unwrap()), "10.0.0.1");
+        assert_eq!(
+            host_pattern("10.0.0.1:2222".parse().unwrap()),
+            "[10.0.0.1]:2222"
+        );
+    }
+
+    #[test]
+    fn lookup_detects_changed_keys() {
+        let path = temp_file("known-hosts");
+        let key = PublicKey::from_openssh(KEY).unwrap();
+        let other = PublicKey::from_openssh(OTHER).unwrap();
+        let host = "[10.0.0.1]:2222";
+
+        assert!(matches!(
+            lookup(&path, host, &key).unwrap(),
+            Lookup::Unknown
+        ));
+
+        learn(&path, "[10.0.0.9]:2222", &other).unwrap();
+        learn(&path, host, &key).unwrap();
+        assert!(matches!(
+            lookup(&path, host, &key).unwrap(),
+            Lookup::Trusted
+        ));
+        assert!(matches!(
+            lookup(&path, host, &other).unwrap(),
+            Lookup::Changed(2)
+        ));
+
+        forget(&path, host).unwrap();
+        assert!(matches!(
+            lookup(&path, host, &key).unwrap(),
+            Lookup::Unknown
+        ));
+        assert!(matches!(
+            lookup(&path, "[10.0.0.9]:2222", &other).unwrap(),
+            Lookup::Trusted
+        ));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn lookup_treats_other_algorithms_as_changed() {
+        let path = temp_file("known-hosts-alg");
+        let key = PublicKey::from_openssh(KEY).unwrap();
+        let ecdsa = PublicKey::from_openssh(ECDSA).unwrap();
+        let host = "[10.0.0.1]:2222";
+
+        learn(&path, host, &key).unwrap();
+        // 记录的是 Ed25519, 服务端出示 ECDSA 公钥
+        assert_eq!(lookup(&path, host, &ecdsa).unwrap(), Lookup::Changed(1));
+
+        // 两种公钥都有记录时都信任
+        learn(&path, host, &ecdsa).unwrap();
+        assert_eq!(lookup(&path, host, &ecdsa).unwrap(), Lookup::Trusted);
+        assert_eq!(lookup(&path, host, &key).unwrap(), Lookup::Trusted);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn lookup_honors_revoked_keys() {
+        let path = temp_file("known-hosts-revoked");
+        let key = PublicKey::from_openssh(KEY).unwrap();
+        let other = PublicKey::from_openssh(OTHER).unwrap();
+        let host = "[10.0.0.1]:2222";
+
+        learn(&path, host, &key).unwrap();
+        let mut content = std::fs::read_to_string(&path).unwrap();
+        content.push_str(&format!("@revoked * {KEY}\n@cert-authority * {OTHER}\n"));
+        std::fs::write(&path, content).unwrap();
+
+        assert_eq!(lookup(&path, host, &key).unwrap(), Lookup::Revoked(2));
+        assert_eq!(
+            lookup(&path, "[10.0.0.9]:2222", &key).unwrap(),
+            Lookup::Revoked(2)
+        );
+        // `@cert-authority` 不是主机公钥
+        assert_eq!(lookup(&path, host, &other).unwrap(), Lookup::Changed(1));
+        assert_eq!(
+            lookup(&path, "[10.0.0.9]:2222", &other).unwrap(),
+            Lookup::Unknown
+        );
+
+        // 更新记录时保留标记行
+        forget(&path, host).unwrap();
+        assert_eq!(lookup(&path, host, &key).unwrap(), Lookup::Revoked(1));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn hashed_hosts_match() {
+        // ssh-keygen -H 生成的 `[10.0.0.1]:2222`
+        use base64::Engine as _;
+        use hmac::Mac as _;
+
+        let salt = b"0123456789abcdefghij";
+        let mut mac = hmac::Hmac::<sha1::Sha1>::new_from_slice(salt).unwrap();
+        mac.update(b"[10.0.0.1]:2222");
+        let engine = base64::engine::general_purpose::STANDARD;
+        let pattern = format!(
+            "|1|{}|{}",
+            engine.encode(salt),
+            engine.encode(mac.finalize().into_bytes())
+        );
+
+        assert!(host_matches(&pattern, "[10.0.0.1]:2222"));
+        assert!(!host_matches(&pattern, "[10.0.0.2]:2222"));
+    }
+}
@@ -29,8 +29,7 @@ pub async fn run(sk: &Path, options: LogsOptions) -> Result<()> {
     super::log_stage(&trace_id, action, "resolve.done");
 
     super::log_stage(&trace_id, action, "connect.start");
-    let mut ssh =
-        HorseClient::connect(sk, options.horse.key_hash_alg, "logs", host, None, None).await?;
+    let mut ssh = HorseClient::connect(sk, &options.horse, "logs", host, None, None).await?;
     let mut channel = ssh.channel_open_session().await?;
     if !trace_id.is_empty() {
         channel
@@ -1,6 +1,7 @@
 #![allow(unused_variables)]
+use crate::exit::{self, ExitError};
 use crate::options::HorseOptions;
-use color_eyre::eyre::{bail, ContextCompat, Result, WrapErr};
+use color_eyre::eyre::{bail, ContextCompat, Report, Result, WrapErr};
 use colored::Colorize;
 use git2::BranchType;
 use git2::Remote;
@@ -19,7 +20,6 @@ use std::sync::Arc;
 use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpSocket;
-use tokio::net::ToSocketAddrs;
 use url::Url;
 
 pub mod admin;
@@ -29,6 +29,7 @@ pub mod get;
 pub mod health;
 pub mod job;
 pub mod just;
+pub mod known_hosts;
 pub mod logs;
 pub mod ping;
 pub mod pull;
@@ -36,27 +37,42 @@ pub mod push;
 pub mod put;
 pub mod scp;
 pub mod ssh;
+pub mod sync;
+pub mod trust;
 pub mod watch;
 
 pub const TRACE_ID_ENV: &str = "HORSE_TRACE_ID";
 pub const DEBUG_ENV: &str = "WH_DEBUG";
+/// 同步方式, `manifest` 表示标准输入为增量同步清单
+pub const SYNC_MODE_ENV: &str = "SYNC_MODE";
+/// 同步的未跟踪文件数量
+pub const SYNC_UNTRACKED_ENV: &str = "SYNC_UNTRACKED";
+/// 增量同步上传的字节数
+pub const SYNC_UPLOADED_ENV: &str = "SYNC_UPLOADED";
+/// 同步的未跟踪文件总大小的默认上限
+pub const UNTRACKED_MAX_BYTES: u64 = 16 * 1024 * 1024;
 static TRACE_SEQ: AtomicU64 = AtomicU64::new(1);
 
 pub struct HorseClient {
     handle: Handle<Client>,
+    /// `-A`: 打开的会话通道都请求 agent 转发
+    forward_agent: bool,
 }
 
 pub struct Client {
     pub forward_host: Option<String>,
     pub forward_port: Option<u32>,
+    pub host_key: known_hosts::HostKeyCheck,
+    /// 是否接受服务端打开的 agent 通道
+    pub forward_agent: bool,
 }
 
 #[async_trait::async_trait]
 impl Handler for Client {
     type Error = russh::Error;
 
-    async fn check_server_key(&mut self, _pk: &PublicKey) -> Result<bool, Self::Error> {
-        Ok(true)
+    async fn check_server_key(&mut self, pk: &PublicKey) -> Result<bool, Self::Error> {
+        Ok(self.host_key.check(pk).await)
     }
 
     async fn auth_banner(
@@ -161,6 +177,35 @@ impl Handler for Client {
         Ok(())
     }
 
+    /// Called when the server opens an agent channel, forward it to the local ssh-agent
+    async fn server_channel_open_agent_forward(
+        &mut self,
+        channel: Channel<Msg>,
+        session: &mut client::Session,
+    ) -> Result<(), Self::Error> {
+        if !self.forward_agent {
+            tracing::warn!("未开启 agent 转发, 拒绝服务端的 agent 通道");
+            channel.close().await?;
+            return Ok(());
+        }
+
+        tokio::spawn(async move {
+            let mut agent = match connect_agent().await {
+                Ok(agent) => agent,
+                Err(err) => {
+                    tracing::warn!("连接本地 ssh-agent 失败: {err}");
+                    channel.close().await?;
+                    return Ok(());
+                }
+            };
+            let mut ch_stream = channel.into_stream();
+            tokio::io::copy_bidirectional(&mut ch_stream, &mut agent).await?;
+            Ok::<_, Self::Error>(())
+        });
+
+        Ok(())
+    }
+
     #[allow(unused_variables)]
     async fn channel_open_confirmation(
         &mut self,
@@ -177,48 +222,114 @@ impl Handler for Client {
     }
 }
 
+/// 连接本地的 ssh-agent: Unix 使用 `SSH_AUTH_SOCK`, Windows 使用 OpenSSH 的命名管道
+#[cfg(unix)]
+async fn connect_agent() -> std::io::Result<tokio::net::UnixStream> {
+    let path = std::env::var_os("SSH_AUTH_SOCK")
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "SSH_AUTH_SOCK 未设置"))?;
+    tokio::net::UnixStream::connect(path).await
+}
+
+#[cfg(windows)]
+async fn connect_agent() -> std::io::Result<tokio::net::windows::named_pipe::NamedPipeClient> {
+    let path = std::env::var("SSH_AUTH_SOCK")
+        .unwrap_or_else(|_| r"\\.\pipe\openssh-ssh-agent".to_string());
+    tokio::net::windows::named_pipe::ClientOptions::new().open(path)
+}
+
+/// 服务端通过 keyboard-interactive 询问邀请码, 兑换成功后当前公钥绑定到账户
+async fn redeem_invite(handle: &mut Handle<Client>, user: String, token: &str) -> Result<bool> {
+    let mut response = handle
+        .authenticate_keyboard_interactive_start(user, None::<String>)
+        .await?;
+    // 服务端可能先发送没有提示的信息请求
+    for _ in 0..3 {
+        match response {
+            client::KeyboardInteractiveAuthResponse::Success => {
+                eprintln!("邀请码已兑换, 当前公钥已绑定到账户");
+                return Ok(true);
+            }
+            client::KeyboardInteractiveAuthResponse::InfoRequest { prompts, .. } => {
+                let answers = prompts.iter().map(|_| token.to_string()).collect();
+                response = handle
+                    .authenticate_keyboard_interactive_respond(answers)
+                    .await?;
+            }
+            _ => break,
+        }
+    }
+    eprintln!("邀请码无效、已过期或已被使用");
+    Ok(false)
+}
+
 impl HorseClient {
     #[allow(unused)]
-    async fn connect<P: AsRef<Path>, A: ToSocketAddrs>(
+    async fn connect<P: AsRef<Path>>(
         key_path: P,
-        key_hash_alg: Option<HashAlg>,
+        horse: &HorseOptions,
         user: impl Into<String>,
-        addrs: A,
+        addr: SocketAddr,
         forward_host: Option<String>,
         forward_port: Option<u32>,
     ) -> Result<Self> {
         let key_pair = load_secret_key(key_path, None)?;
-        let config = client::Config {
-            inactivity_timeout: Some(Duration::from_secs(60)),
-            keepalive_interval: Some(Duration::from_secs(3)),
-            ..<_>::default()
+        let policy = if horse.accept_new {
+            known_hosts::HostKeyPolicy::AcceptNew
+        } else {
+            known_hosts::HostKeyPolicy::Ask
         };
+        let host_key = known_hosts::HostKeyCheck::new(addr, policy);
+
+        #[cfg(unix)]
+        if horse.forward_agent && std::env::var_os("SSH_AUTH_SOCK").is_none() {
+            eprintln!("{}", "SSH_AUTH_SOCK 未设置, agent 转发不可用".yellow());
+        }
 
-        let config = Arc::new(config);
-        let sh = Client {
+        let mut handle = connect_session(
+            addr,
+            host_key,
             forward_host,
             forward_port,
-        };
-
-        let mut handle = client::connect(config, addrs, sh).await?;
+            horse.forward_agent,
+        )
+        .await?;
+        let user = user.into();
         let auth_res = handle
             .authenticate_publickey(
-                user,
-                PrivateKeyWithHashAlg::new(Arc::new(key_pair), key_hash_alg)?,
+                user.clone(),
+                PrivateKeyWithHashAlg::new(Arc::new(key_pair), horse.key_hash_alg)?,
             )
             .await?;
 
+        // 公钥还没有登记时, 使用邀请码绑定
+        let auth_res = match &horse.invite {
+            Some(token) if !auth_res => redeem_invite(&mut handle, user, token).await?,
+            _ => auth_res,
+        };
+
         if !auth_res {
-            bail!("Authentication failed");
+            bail!(ExitError::Auth);
         }
 
-        Ok(Self { handle })
+        Ok(Self {
+            handle,
+            forward_agent: horse.forward_agent,
+        })
+    }
+
+    /// 打开会话通道, 指定 `-A` 时同时请求 agent 转发
+    pub async fn channel_open_session(&self) -> Result<Channel<Msg>> {
+        let channel = self.handle.channel_open_session().await?;
+        if self.forward_agent {
+            channel.agent_forward(false).await?;
+        }
+        Ok(channel)
     }
 
     // Run interactive shell or other commands
     // The `command` will be attached a pseudo-terminal and executed on the server.
     async fn shell(&mut self, command: &str) -> Result<u32> {
-        let mut channel = self.handle.channel_open_session().await?;
+        let mut channel = self.channel_open_session().await?;
         let (cols, rows) = crossterm::terminal::size().unwrap_or((80, 24));
         // Request an interactive PTY from the server
         channel
@@ -285,7 +396,7 @@ impl HorseClient {
                             if !stdin_closed {
                                 channel.eof().await?;
                             }
-                            break;
+                            return Err(ExitError::Disconnected.into());
                         }
                     }
                 },
@@ -304,6 +415,38 @@ impl HorseClient {
     }
 }
 
+/// 建立 SSH 连接并校验服务端公钥, 不进行认证
+async fn connect_session(
+    addr: SocketAddr,
+    host_key: known_hosts::HostKeyCheck,
+    forward_host: Option<String>,
+    forward_port: Option<u32>,
+    forward_agent: bool,
+) -> Result<Handle<Client>> {
+    let config = client::Config {
+        inactivity_timeout: Some(Duration::from_secs(60)),
+        keepalive_interval: Some(Duration::from_secs(3)),
+        ..<_>::default()
+    };
+
+    let config = Arc::new(config);
+    let sh = Client {
+        forward_host,
+        forward_port,
+        host_key: host_key.clone(),
+        forward_agent,
+    };
+
+    match client::connect(config, addr, sh).await {
+        Ok(handle) => Ok(handle),
+        // 公钥校验失败时返回具体原因
+        Err(err) => match host_key.rejection() {
+            Some(rejected) => Err(rejected.into()),
+            None => Err(Report::new(err).wrap_err(ExitError::Connect)),
+        },
+    }
+}
+
 impl Deref for HorseClient {
     type Target = Handle<Client>;
     fn deref(&self) -> &Self::Target {
@@ -317,6 +460,35 @@ impl DerefMut for HorseClient {
     }
 }
 
+/// 等待通道消息, 期间按下 Ctrl-C 会以 SIGINT 转发给远端任务,
+/// 再次按下 Ctrl-C 则不再等待, 直接断开连接
+pub async fn wait_forwarding_interrupt(
+    channel: &mut Channel<Msg>,
+    interrupted: &mut bool,
+) -> Option<ChannelMsg> {
+    loop {
+        tokio::select! {
+            msg = channel.wait() => return msg,
+            _ = tokio::signal::ctrl_c() => {
+                if *interrupted {
+                    eprintln!("{}", "再次中断, 断开连接".yellow());
+                    return None;
+                }
+
+                *interrupted = true;
+                eprintln!(
+                    "{}",
+                    "已向远端任务发送中断信号, 再按一次 Ctrl-C 直接断开".yellow()
+                );
+                if let Err(err) = channel.signal(Sig::INT).await {
+                    tracing::warn!("发送中断信号失败: {err}");
+                    return None;
+                }
+            }
+        }
+    }
+}
+
 pub fn new_trace_id(action: &str) -> String {
     if !debug_enabled() {
         return String::new();
@@ -383,6 +555,27 @@ fn find_host(options: &HorseOptions) -> Option<SocketAddr> {
     options.repo.as_ref().and_then(|s| extract_host(s))
 }
 
+/// 依次从环境变量 `HORSED`、`--repo` 和 git remote 获取服务端地址
+fn resolve_host(options: &HorseOptions) -> Result<SocketAddr> {
+    if let Ok(host) = std::env::var("HORSED") {
+        return host
+            .parse()
+            .wrap_err_with(|| format!("解析环境变量 HORSED 失败: {host}"));
+    }
+    if let Some(host) = find_host(options) {
+        return Ok(host);
+    }
+
+    let repo = Repository::discover(".")?;
+    let Some(horsed) = find_remote(&repo, options) else {
+        bail!("找不到 horsed 远程仓库!");
+    };
+    horsed
+        .url()
+        .and_then(extract_host)
+        .context("获取 horsed 远程仓库 HOST 失败")
+}
+
 fn extract_host(url: &str) -> Option<SocketAddr> {
     let url = Url::parse(url).ok()?;
 
@@ -509,50 +702,136 @@ async fn git_push_remote(repo: &Repository, remote: &str) -> Result<std::process
     cmd.output().await.wrap_err("执行自动 push 失败")
 }
 
-pub async fn collect_remote_patch(repo: &Repository, push_remote: Option<&str>) -> Result<Vec<u8>> {
-    let push_remote = push_remote.unwrap_or("horsed");
+/// 同步到远端工作区的代码快照
+pub struct SyncPatch {
+    pub patch: Vec<u8>,
+    /// 快照中包含的未跟踪文件数量
+    pub untracked: usize,
+}
+
+/// 未跟踪 (且未被忽略) 的文件生成新增文件补丁, 二进制文件同样包含在内
+fn untracked_patch(repo: &Repository, max_bytes: u64) -> Result<(Vec<u8>, usize)> {
+    let workdir = repo.workdir().context("裸仓库无法同步工作区")?;
+
+    let mut status_options = git2::StatusOptions::new();
+    status_options
+        .include_untracked(true)
+        .recurse_untracked_dirs(true)
+        .include_ignored(false)
+        .exclude_submodules(true);
+    let statuses = repo
+        .statuses(Some(&mut status_options))
+        .wrap_err("读取工作区状态失败")?;
+
+    let mut paths = vec![];
+    let mut total = 0_u64;
+    for entry in statuses.iter() {
+        if !entry.status().contains(git2::Status::WT_NEW) {
+            continue;
+        }
+        let Some(path) = entry.path() else {
+            tracing::warn!("跳过非 UTF-8 路径的未跟踪文件");
+            continue;
+        };
+        total += std::fs::symlink_metadata(workdir.join(path))
+            .map(|meta| meta.len())
+            .unwrap_or(0);
+        paths.push(path.to_string());
+    }
+
+    if paths.is_empty() {
+        return Ok((vec![], 0));
+    }
+
+    if total > max_bytes {
+        bail!(
+            "未跟踪文件共 {} 个 {total} 字节, 超过同步上限 {max_bytes} 字节; 请提交或忽略这些文件, 或使用 --untracked-max-size 调整上限, --no-untracked 跳过未跟踪文件",
+            paths.len()
+        );
+    }
+
+    let mut diff_options = git2::DiffOptions::new();
+    diff_options
+        .include_untracked(true)
+        .recurse_untracked_dirs(true)
+        .show_untracked_content(true)
+        .show_binary(true)
+        .disable_pathspec_match(true);
+    for path in &paths {
+        diff_options.pathspec(path);
+    }
+    let diff = repo
+        .diff_index_to_workdir(None, Some(&mut diff_options))
+        .wrap_err("生成未跟踪文件补丁失败")?;
+
     let mut patch = vec![];
+    diff.print(git2::DiffFormat::Patch, |_, _, line| {
+        if matches!(line.origin(), '+' | '-' | ' ') {
+            patch.push(line.origin() as u8);
+        }
+        patch.extend_from_slice(line.content());
+        true
+    })
+    .wrap_err("生成未跟踪文件补丁失败")?;
+
+    Ok((patch, paths.len()))
+}
+
+/// 本地分支领先上游时自动 push, 失败时返回仍未推送的上游分支和提交数量
+async fn push_unpushed(repo: &Repository, horse: &HorseOptions) -> Result<Option<(String, usize)>> {
+    let push_remote = horse.remote.as_deref().unwrap_or("horsed");
+    let Some((upstream, ahead)) = upstream_status(repo)? else {
+        return Ok(None);
+    };
+    if ahead == 0 {
+        return Ok(None);
+    }
 
-    if let Some((upstream, ahead)) = upstream_status(repo)? {
-        if ahead > 0 {
+    tracing::warn!(
+        "检测到本地分支领先上游 {ahead} 个提交，将同步未推送提交到远端工作区: {upstream}"
+    );
+
+    match git_push_remote(repo, push_remote).await {
+        Ok(output) => {
+            let summary = summarize_git_output(&output);
+            if output.status.success() {
+                tracing::warn!("自动 push 结果: 成功 (remote={push_remote}; {summary})");
+                // The target bare repository now contains HEAD, so the
+                // server checkout already includes these commits. Only
+                // the remaining worktree diff must be applied.
+                return Ok(None);
+            }
             tracing::warn!(
-                "检测到本地分支领先上游 {ahead} 个提交，将同步未推送提交到远端工作区: {upstream}"
+                "自动 push 结果: 失败 (remote={push_remote}; {summary})，将继续通过补丁同步未推送提交"
             );
+        }
+        Err(err) => {
+            tracing::warn!(
+                "自动 push 结果: 失败 (remote={push_remote}; {err})，将继续通过补丁同步未推送提交"
+            );
+        }
+    }
 
-            let mut need_commit_patch = true;
-            match git_push_remote(repo, push_remote).await {
-                Ok(output) => {
-                    let summary = summarize_git_output(&output);
-                    if output.status.success() {
-                        tracing::warn!("自动 push 结果: 成功 (remote={push_remote}; {summary})");
-                        // The target bare repository now contains HEAD, so the
-                        // server checkout already includes these commits. Only
-                        // the remaining worktree diff must be applied.
-                        need_commit_patch = false;
-                    } else {
-                        tracing::warn!(
-                            "自动 push 结果: 失败 (remote={push_remote}; {summary})，将继续通过补丁同步未推送提交"
-                        );
-                    }
-                }
-                Err(err) => {
-                    tracing::warn!(
-                        "自动 push 结果: 失败 (remote={push_remote}; {err})，将继续通过补丁同步未推送提交"
-                    );
-                }
-            }
+    Ok(Some((upstream, ahead)))
+}
 
-            if need_commit_patch {
-                let range = format!("{upstream}..HEAD");
-                let commit_patch = git_diff(repo, &["--binary", range.as_str()]).await?;
-                if commit_patch.is_empty() {
-                    tracing::warn!(
-                        "本地分支领先上游 {ahead} 个提交，但提交补丁为空，将继续仅同步工作区改动"
-                    );
-                } else {
-                    patch.extend_from_slice(&commit_patch);
-                }
-            }
+/// 生成补丁同步的内容, `unpushed` 是 `push_unpushed` 的结果, 这里不会再次 push
+pub async fn collect_remote_patch(
+    repo: &Repository,
+    horse: &HorseOptions,
+    unpushed: Option<(String, usize)>,
+) -> Result<SyncPatch> {
+    let mut patch = vec![];
+
+    if let Some((upstream, ahead)) = unpushed {
+        let range = format!("{upstream}..HEAD");
+        let commit_patch = git_diff(repo, &["--binary", range.as_str()]).await?;
+        if commit_patch.is_empty() {
+            tracing::warn!(
+                "本地分支领先上游 {ahead} 个提交，但提交补丁为空，将继续仅同步工作区改动"
+            );
+        } else {
+            patch.extend_from_slice(&commit_patch);
         }
     }
 
@@ -560,7 +839,19 @@ pub async fn collect_remote_patch(repo: &Repository, push_remote: Option<&str>)
     let worktree_patch = git_diff(repo, &["--binary", "HEAD"]).await?;
     patch.extend_from_slice(&worktree_patch);
 
-    Ok(patch)
+    // `git diff` 不包含未跟踪的文件, 新建但未 `git add` 的文件需要单独同步
+    let mut untracked = 0;
+    if !horse.no_untracked {
+        let max_bytes = horse.untracked_max_size.unwrap_or(UNTRACKED_MAX_BYTES);
+        let (untracked_patch, count) = untracked_patch(repo, max_bytes)?;
+        if count > 0 {
+            tracing::info!("同步未跟踪文件: {count} 个");
+        }
+        patch.extend_from_slice(&untracked_patch);
+        untracked = count;
+    }
+
+    Ok(SyncPatch { patch, untracked })
 }
 
 #[cfg(feature = "use-system-ssh")]
@@ -609,8 +900,38 @@ where
 
 #[cfg(test)]
 mod tests {
+    use super::*;
     use url::Url;
 
+    #[test]
+    fn untracked_patch_includes_new_files() {
+        let dir = std::env::temp_dir().join(format!(
+            "cargo-work-untracked-{}",
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        ));
+        std::fs::create_dir_all(dir.join("src/new")).unwrap();
+        let repo = Repository::init(&dir).unwrap();
+        std::fs::write(dir.join(".gitignore"), "ignored.txt\n").unwrap();
+        std::fs::write(dir.join("ignored.txt"), "skip").unwrap();
+        std::fs::write(dir.join("src/new/lib.rs"), "pub fn hello() {}\n").unwrap();
+        std::fs::write(dir.join("blob.bin"), [0_u8, 1, 2, 255]).unwrap();
+
+        let (patch, count) = untracked_patch(&repo, UNTRACKED_MAX_BYTES).unwrap();
+        let patch = String::from_utf8_lossy(&patch);
+        assert_eq!(count, 3);
+        assert!(patch.contains("+pub fn hello() {}"));
+        assert!(patch.contains("GIT binary patch"));
+        assert!(!patch.contains("b/ignored.txt"));
+
+        // 超过上限时拒绝同步
+        assert!(untracked_patch(&repo, 4).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
     #[test]
     fn test_url_parse() {
         Url::parse("ssh://git@127.0.0.1:2222").ok().unwrap();
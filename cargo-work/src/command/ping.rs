@@ -86,8 +86,7 @@ pub async fn run(sk: &Path, mut options: PingOptions) -> Result<()> {
 
         let now = Instant::now();
         super::log_stage(&trace_id, action, "connect.start");
-        let mut ssh =
-            HorseClient::connect(sk, options.horse.key_hash_alg, "ping", host, None, None).await?;
+        let mut ssh = HorseClient::connect(sk, &options.horse, "ping", host, None, None).await?;
         let mut channel = ssh.channel_open_session().await?;
         if !trace_id.is_empty() {
             channel
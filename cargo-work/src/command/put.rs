@@ -1,7 +1,7 @@
 use super::*;
 use crate::options::PutOptions;
 use clean_path::Clean;
-use color_eyre::eyre::{anyhow, bail, ContextCompat, Result, WrapErr};
+use color_eyre::eyre::{anyhow, ContextCompat, Result, WrapErr};
 use git2::Repository;
 use std::io::{IsTerminal, Write};
 use std::path::Path;
@@ -156,8 +156,7 @@ pub async fn run(sk: &Path, options: PutOptions) -> Result<()> {
     #[cfg(not(feature = "use-system-ssh"))]
     {
         super::log_stage(&trace_id, action, "connect.start");
-        let mut ssh =
-            HorseClient::connect(sk, options.horse.key_hash_alg, "put", host, None, None).await?;
+        let mut ssh = HorseClient::connect(sk, &options.horse, "put", host, None, None).await?;
         let mut channel = ssh.channel_open_session().await?;
 
         if !trace_id.is_empty() {
@@ -222,9 +221,7 @@ pub async fn run(sk: &Path, options: PutOptions) -> Result<()> {
             ssh.close().await?;
         }
 
-        if got_exit_status && code != 0 {
-            bail!("remote put failed with exit status {code}");
-        }
+        exit::remote_status(got_exit_status.then_some(code))?;
         super::log_stage(&trace_id, action, "done");
     }
 
@@ -269,12 +266,7 @@ pub async fn run(sk: &Path, options: PutOptions) -> Result<()> {
         futures::future::try_join(write_out, write_err).await?;
 
         let status = ssh.wait().await?;
-        if !status.success() {
-            bail!(
-                "remote put failed with exit status {}",
-                status.code().unwrap_or(128)
-            );
-        }
+        exit::process_status(status)?;
         super::log_stage(&trace_id, action, "done");
     }
 
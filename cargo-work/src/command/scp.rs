@@ -52,8 +52,7 @@ pub async fn run(sk: &Path, options: ScpOptions) -> Result<()> {
     let mut channel = {
         use color_eyre::eyre::WrapErr;
         super::log_stage(&trace_id, action, "connect.start");
-        let ssh =
-            HorseClient::connect(sk, options.horse.key_hash_alg, "scp", host, None, None).await?;
+        let ssh = HorseClient::connect(sk, &options.horse, "scp", host, None, None).await?;
         let channel = ssh.channel_open_session().await?;
         if !trace_id.is_empty() {
             channel
@@ -81,8 +80,6 @@ pub async fn run(sk: &Path, options: ScpOptions) -> Result<()> {
 
         channel
     };
-    #[cfg(not(feature = "use-system-ssh"))]
-    let mut stdout = channel.make_reader();
 
     #[cfg(feature = "use-system-ssh")]
     let mut ssh = {
@@ -114,15 +111,41 @@ pub async fn run(sk: &Path, options: ScpOptions) -> Result<()> {
 
         cmd.spawn()?
     };
-    #[cfg(feature = "use-system-ssh")]
-    let mut stdout = ssh.stdout.take().unwrap();
-
     let mut file = tokio::fs::File::create_new(&options.dest).await?;
 
-    while let Ok(len) = tokio::io::copy(&mut stdout, &mut file).await {
-        if len == 0 {
-            break;
+    #[cfg(not(feature = "use-system-ssh"))]
+    let status = {
+        use tokio::io::AsyncWriteExt;
+        let mut code = None;
+        let mut stderr = tokio::io::stderr();
+        while let Some(msg) = channel.wait().await {
+            match msg {
+                ChannelMsg::Data { ref data } => file.write_all(data).await?,
+                ChannelMsg::ExtendedData { ref data, .. } => stderr.write_all(data).await?,
+                ChannelMsg::ExitStatus { exit_status } => code = Some(exit_status),
+                _ => {}
+            }
+        }
+        file.flush().await?;
+        exit::remote_status(code)
+    };
+
+    #[cfg(feature = "use-system-ssh")]
+    let status = {
+        let mut stdout = ssh.stdout.take().unwrap();
+        while let Ok(len) = tokio::io::copy(&mut stdout, &mut file).await {
+            if len == 0 {
+                break;
+            }
         }
+        exit::process_status(ssh.wait().await?)
+    };
+
+    if let Err(err) = status {
+        // 不留下不完整的文件
+        drop(file);
+        tokio::fs::remove_file(&options.dest).await?;
+        return Err(err.into());
     }
     super::log_stage(&trace_id, action, "done");
 
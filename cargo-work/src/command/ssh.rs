@@ -65,8 +65,7 @@ pub async fn connect_forward_l(
     forward_local_port: impl AsRef<str>,
     options: &SshOptions,
 ) -> Result<()> {
-    let mut ssh =
-        HorseClient::connect(sk, options.horse.key_hash_alg, "ssh", host, None, None).await?;
+    let mut ssh = HorseClient::connect(sk, &options.horse, "ssh", host, None, None).await?;
 
     let mut addrs = forward_local_port
         .as_ref()
@@ -148,7 +147,7 @@ pub async fn connect_forward_r(
 
     let mut ssh = HorseClient::connect(
         sk,
-        options.horse.key_hash_alg,
+        &options.horse,
         "ssh",
         host,
         Some(local_host),
@@ -175,8 +174,7 @@ pub async fn connect_shell(
     super::log_stage(trace_id, action, "proxy.ready");
 
     super::log_stage(trace_id, action, "connect.start");
-    let mut ssh =
-        HorseClient::connect(sk, options.horse.key_hash_alg, "ssh", host, None, None).await?;
+    let mut ssh = HorseClient::connect(sk, &options.horse, "ssh", host, None, None).await?;
 
     let channel = ssh.channel_open_session().await?;
     if !trace_id.is_empty() {
@@ -226,7 +224,9 @@ pub async fn connect_shell(
         Ok::<_, color_eyre::Report>(())
     });
 
-    let code = ssh.shell(&options.commands.join(" ")).await?;
+    let code = ssh.shell(&options.commands.join(" ")).await;
+    crossterm::terminal::disable_raw_mode()?;
+    let code = code?;
     if super::debug_enabled() && !trace_id.is_empty() {
         tracing::info!(
             trace_id = %trace_id,
@@ -236,8 +236,8 @@ pub async fn connect_shell(
         );
     }
 
-    crossterm::terminal::disable_raw_mode()?;
-    std::process::exit(code as _);
+    exit::remote_status(Some(code))?;
+    Ok(())
 }
 
 pub async fn start_proxy(
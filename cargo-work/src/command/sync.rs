@@ -0,0 +1,299 @@
+//! 增量代码同步
+//!
+//! 1. 以服务端已有的提交为基准, 列出工作区所有变动文件的 blob 哈希
+//! 2. 通过 `sync` 请求上传服务端缺少的 blob, 内容直接从文件流式发送
+//! 3. 构建请求只携带清单, 服务端从基准提交和已缓存的 blob 重建工作区
+//!
+//! 服务端不支持增量同步时回退为补丁同步.
+use super::*;
+use color_eyre::eyre::{bail, ContextCompat, Result, WrapErr};
+use git2::Repository;
+use stable::data::sync::{
+    SyncEntry, SyncFrame, SyncManifest, MODE_EXECUTABLE, MODE_FILE, MODE_SYMLINK,
+};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// 构建请求需要携带的同步数据
+pub struct SyncPayload {
+    /// 写入远端命令标准输入的内容
+    pub stdin: Vec<u8>,
+    /// 需要设置的环境变量
+    pub env: Vec<(&'static str, String)>,
+}
+
+/// 上传 blob 时的内容来源
+enum BlobSource {
+    File(PathBuf),
+    /// 符号链接的目标
+    Link(Vec<u8>),
+}
+
+/// 远端仓库与分支
+pub struct SyncTarget<'a> {
+    pub sk: &'a Path,
+    pub host: SocketAddr,
+    pub repo_name: &'a str,
+    pub branch: &'a str,
+    pub trace_id: &'a str,
+}
+
+/// 准备构建请求的代码同步, 优先使用增量同步
+pub async fn prepare(
+    repo: &Repository,
+    horse: &HorseOptions,
+    target: SyncTarget<'_>,
+) -> Result<SyncPayload> {
+    let unpushed = push_unpushed(repo, horse).await?;
+    let base = match &unpushed {
+        // 未推送的提交不在服务端, 以上游提交为基准
+        Some((upstream, _)) => repo
+            .revparse_single(&upstream)
+            .and_then(|object| object.peel_to_commit())
+            .wrap_err_with(|| format!("读取上游分支 `{upstream}` 失败"))?
+            .id(),
+        None => repo.head()?.peel_to_commit()?.id(),
+    };
+
+    let (manifest, sources) = build_manifest(repo, horse, base)?;
+    let total = manifest.total_bytes();
+
+    let uploaded = match upload_missing(horse, &target, &manifest, &sources).await {
+        Ok(uploaded) => uploaded,
+        Err(err) => {
+            tracing::warn!("增量同步失败, 回退为补丁同步: {err:#}");
+            let patch = collect_remote_patch(repo, horse, unpushed).await?;
+            return Ok(SyncPayload {
+                stdin: patch.patch,
+                env: vec![(SYNC_UNTRACKED_ENV, patch.untracked.to_string())],
+            });
+        }
+    };
+
+    tracing::info!(
+        "增量同步: files={} total_bytes={total} uploaded_bytes={uploaded}",
+        manifest.entries.len()
+    );
+
+    Ok(SyncPayload {
+        env: vec![
+            (SYNC_MODE_ENV, "manifest".to_string()),
+            (SYNC_UNTRACKED_ENV, manifest.untracked.to_string()),
+            (SYNC_UPLOADED_ENV, uploaded.to_string()),
+        ],
+        stdin: SyncFrame::Manifest(manifest).encode()?,
+    })
+}
+
+/// 列出相对基准提交有变动的文件, 包括暂存/未暂存的改动以及未跟踪的文件
+fn build_manifest(
+    repo: &Repository,
+    horse: &HorseOptions,
+    base: git2::Oid,
+) -> Result<(SyncManifest, HashMap<String, BlobSource>)> {
+    let workdir = repo.workdir().context("裸仓库无法同步工作区")?;
+    let tree = repo.find_commit(base)?.tree()?;
+
+    let mut options = git2::DiffOptions::new();
+    options
+        .include_untracked(!horse.no_untracked)
+        .recurse_untracked_dirs(true)
+        .include_typechange(true);
+    let diff = repo
+        .diff_tree_to_workdir_with_index(Some(&tree), Some(&mut options))
+        .wrap_err("读取工作区改动失败")?;
+
+    let mut entries = vec![];
+    let mut sources = HashMap::new();
+    let (mut untracked, mut untracked_bytes) = (0, 0_u64);
+
+    for delta in diff.deltas() {
+        let file = match delta.status() {
+            git2::Delta::Deleted => delta.old_file(),
+            git2::Delta::Added
+            | git2::Delta::Modified
+            | git2::Delta::Typechange
+            | git2::Delta::Renamed
+            | git2::Delta::Copied
+            | git2::Delta::Untracked => delta.new_file(),
+            _ => continue,
+        };
+        // 子模块不参与同步
+        if file.mode() == git2::FileMode::Commit {
+            continue;
+        }
+        let Some(path) = file.path().and_then(|path| path.to_str()) else {
+            tracing::warn!("跳过非 UTF-8 路径: {:?}", file.path());
+            continue;
+        };
+        let path = path.replace('\\', "/");
+
+        if delta.status() == git2::Delta::Deleted {
+            entries.push(SyncEntry {
+                path,
+                oid: None,
+                mode: 0,
+                size: 0,
+            });
+            continue;
+        }
+
+        let full = workdir.join(&path);
+        let meta =
+            std::fs::symlink_metadata(&full).wrap_err_with(|| format!("读取 {path} 失败"))?;
+        let (oid, mode, size, source) = if meta.file_type().is_symlink() {
+            let link = link_bytes(&std::fs::read_link(&full)?);
+            let oid = git2::Oid::hash_object(git2::ObjectType::Blob, &link)?;
+            (oid, MODE_SYMLINK, link.len() as u64, BlobSource::Link(link))
+        } else {
+            let oid = git2::Oid::hash_file(git2::ObjectType::Blob, &full)?;
+            let mode = if file.mode() == git2::FileMode::BlobExecutable {
+                MODE_EXECUTABLE
+            } else {
+                MODE_FILE
+            };
+            (oid, mode, meta.len(), BlobSource::File(full))
+        };
+
+        if delta.status() == git2::Delta::Untracked {
+            untracked += 1;
+            untracked_bytes += size;
+        }
+
+        sources.entry(oid.to_string()).or_insert(source);
+        entries.push(SyncEntry {
+            path,
+            oid: Some(oid.to_string()),
+            mode,
+            size,
+        });
+    }
+
+    let max_bytes = horse.untracked_max_size.unwrap_or(UNTRACKED_MAX_BYTES);
+    if untracked_bytes > max_bytes {
+        bail!(
+            "未跟踪文件共 {untracked} 个 {untracked_bytes} 字节, 超过同步上限 {max_bytes} 字节; 请提交或忽略这些文件, 或使用 --untracked-max-size 调整上限, --no-untracked 跳过未跟踪文件"
+        );
+    }
+
+    let manifest = SyncManifest {
+        base: base.to_string(),
+        entries,
+        untracked,
+    };
+    Ok((manifest, sources))
+}
+
+#[cfg(unix)]
+fn link_bytes(link: &Path) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    link.as_os_str().as_bytes().to_vec()
+}
+
+#[cfg(not(unix))]
+fn link_bytes(link: &Path) -> Vec<u8> {
+    link.to_string_lossy().replace('\\', "/").into_bytes()
+}
+
+/// 发送清单并上传服务端缺少的 blob, 返回上传的字节数
+async fn upload_missing(
+    horse: &HorseOptions,
+    target: &SyncTarget<'_>,
+    manifest: &SyncManifest,
+    sources: &HashMap<String, BlobSource>,
+) -> Result<u64> {
+    let action = "sync";
+    super::log_stage(target.trace_id, action, "connect.start");
+    let mut ssh = HorseClient::connect(target.sk, horse, action, target.host, None, None).await?;
+    let mut channel = ssh.channel_open_session().await?;
+    if !target.trace_id.is_empty() {
+        channel
+            .set_env(true, super::TRACE_ID_ENV, target.trace_id)
+            .await?;
+    }
+    channel.set_env(true, "REPO", target.repo_name).await?;
+    channel.set_env(true, "BRANCH", target.branch).await?;
+    channel.exec(true, "manifest").await.wrap_err("exec")?;
+
+    let mut writer = channel.make_writer();
+    SyncFrame::Manifest(manifest.clone())
+        .write(&mut writer)
+        .await?;
+
+    let missing = {
+        let mut reader = channel.make_reader();
+        match SyncFrame::read(&mut reader)
+            .await
+            .wrap_err("读取缺少的 blob 列表失败")?
+        {
+            SyncFrame::Missing(missing) => missing,
+            other => bail!("sync 失败, 收到非预期的响应: {other:?}"),
+        }
+    };
+    super::log_stage(target.trace_id, action, "manifest.done");
+
+    let mut uploaded = 0_u64;
+    for oid in &missing {
+        let source = sources
+            .get(oid)
+            .with_context(|| format!("服务端请求了未知的 blob: {oid}"))?;
+        match source {
+            BlobSource::Link(link) => {
+                SyncFrame::Blob {
+                    oid: oid.clone(),
+                    size: link.len() as u64,
+                }
+                .write(&mut writer)
+                .await?;
+                writer.write_all(link).await?;
+                uploaded += link.len() as u64;
+            }
+            BlobSource::File(path) => {
+                let file = tokio::fs::File::open(path).await?;
+                let size = file.metadata().await?.len();
+                SyncFrame::Blob {
+                    oid: oid.clone(),
+                    size,
+                }
+                .write(&mut writer)
+                .await?;
+                let sent = tokio::io::copy(&mut file.take(size), &mut writer).await?;
+                if sent != size {
+                    bail!("文件在同步期间被修改: {}", path.display());
+                }
+                uploaded += size;
+            }
+        }
+    }
+    SyncFrame::Done.write(&mut writer).await?;
+    writer.shutdown().await?;
+    drop(writer);
+
+    let mut code = None;
+    let mut stderr = tokio::io::stderr();
+    while let Some(msg) = channel.wait().await {
+        match msg {
+            ChannelMsg::ExtendedData { ref data, .. } => {
+                stderr.write_all(data).await?;
+            }
+            ChannelMsg::ExitStatus { exit_status } => {
+                code = Some(exit_status);
+            }
+            _ => {}
+        }
+    }
+    if !ssh.is_closed() {
+        ssh.close().await?;
+    }
+
+    match code {
+        Some(0) => {
+            super::log_stage(target.trace_id, action, "done");
+            Ok(uploaded)
+        }
+        Some(code) => bail!("sync 失败 (exit={code})"),
+        None => bail!("sync 失败, 未收到退出码"),
+    }
+}
@@ -0,0 +1,24 @@
+use super::known_hosts::{HostKeyCheck, HostKeyPolicy};
+use super::*;
+use crate::options::TrustOptions;
+use color_eyre::eyre::Result;
+
+/// 连接服务端并信任当前的公钥, 替换 `~/.config/cargo-work/known_hosts` 中已有的记录
+pub async fn run(mut options: TrustOptions) -> Result<()> {
+    if let Some(remote) = options.remote.take() {
+        if remote.contains("://") {
+            options.horse.repo.replace(remote);
+        } else {
+            options.horse.remote.replace(remote);
+        }
+    }
+
+    let host = resolve_host(&options.horse)?;
+    let host_key = HostKeyCheck::new(host, HostKeyPolicy::Replace);
+    let handle = connect_session(host, host_key, None, None, false).await?;
+    handle
+        .disconnect(Disconnect::ByApplication, "", "English")
+        .await?;
+
+    Ok(())
+}
@@ -0,0 +1,142 @@
+//! 进程退出码
+//!
+//! | 退出码 | 含义 |
+//! | --- | --- |
+//! | 0 | 成功 |
+//! | 1 | 本地错误 (读取仓库、参数校验等) |
+//! | 2 | 命令行参数错误 |
+//! | 69 | 连接 horsed 失败 |
+//! | 76 | 连接中断, 远端没有返回退出码 |
+//! | 77 | 认证失败 |
+//! | 78 | 服务端公钥未被信任或者发生变化 |
+//! | 其他 | 远端命令的退出码, 大于 255 时为 255 |
+use crate::command::known_hosts::HostKeyError;
+use color_eyre::eyre::Report;
+use std::fmt;
+
+/// 本地错误
+pub const FAILURE: u8 = 1;
+/// 命令行参数错误, 与 clap 一致
+pub const USAGE: u8 = 2;
+/// 连接 horsed 失败 (`EX_UNAVAILABLE`)
+pub const CONNECT: u8 = 69;
+/// 连接中断, 没有收到远端的退出码 (`EX_PROTOCOL`)
+pub const DISCONNECTED: u8 = 76;
+/// 认证失败 (`EX_NOPERM`)
+pub const AUTH: u8 = 77;
+/// 服务端公钥未被信任或者发生变化 (`EX_CONFIG`)
+pub const HOST_KEY: u8 = 78;
+
+/// 需要以特定退出码结束进程的错误
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitError {
+    /// 远端命令以非零退出码结束
+    Remote(u32),
+    /// 连接 horsed 失败
+    Connect,
+    /// 认证失败
+    Auth,
+    /// 连接中断, 没有收到远端的退出码
+    Disconnected,
+}
+
+impl ExitError {
+    pub fn code(&self) -> u8 {
+        match self {
+            ExitError::Remote(code) => u8::try_from(*code).unwrap_or(u8::MAX),
+            ExitError::Connect => CONNECT,
+            ExitError::Auth => AUTH,
+            ExitError::Disconnected => DISCONNECTED,
+        }
+    }
+}
+
+impl fmt::Display for ExitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExitError::Remote(code) => write!(f, "remote command failed with exit status {code}"),
+            ExitError::Connect => f.write_str("failed to connect to horsed"),
+            ExitError::Auth => f.write_str("Authentication failed"),
+            ExitError::Disconnected => {
+                f.write_str("connection closed before the remote command exited")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ExitError {}
+
+/// 远端命令的退出状态, `None` 表示没有收到退出码
+pub fn remote_status(code: Option<u32>) -> Result<(), ExitError> {
+    match code {
+        Some(0) => Ok(()),
+        Some(code) => Err(ExitError::Remote(code)),
+        None => Err(ExitError::Disconnected),
+    }
+}
+
+/// 系统 ssh 进程的退出状态, 被信号终止时为 128
+pub fn process_status(status: std::process::ExitStatus) -> Result<(), ExitError> {
+    match status.code() {
+        Some(0) => Ok(()),
+        code => Err(ExitError::Remote(code.unwrap_or(128) as u32)),
+    }
+}
+
+/// 错误对应的进程退出码
+pub fn code_of(err: &Report) -> u8 {
+    if let Some(err) = err.downcast_ref::<ExitError>() {
+        return err.code();
+    }
+    if err.downcast_ref::<HostKeyError>().is_some() {
+        return HOST_KEY;
+    }
+    if err.chain().any(|cause| {
+        cause
+            .downcast_ref::<std::io::Error>()
+            .is_some_and(|err| is_connect_error(err.kind()))
+    }) {
+        return CONNECT;
+    }
+    FAILURE
+}
+
+fn is_connect_error(kind: std::io::ErrorKind) -> bool {
+    use std::io::ErrorKind::*;
+    matches!(
+        kind,
+        ConnectionRefused | ConnectionReset | ConnectionAborted | NotConnected | TimedOut
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use color_eyre::eyre::WrapErr;
+
+    #[test]
+    fn remote_status_maps_exit_codes() {
+        assert_eq!(remote_status(Some(0)), Ok(()));
+        assert_eq!(remote_status(Some(101)).unwrap_err().code(), 101);
+        assert_eq!(remote_status(Some(256)).unwrap_err().code(), 255);
+        assert_eq!(remote_status(None).unwrap_err().code(), DISCONNECTED);
+    }
+
+    #[test]
+    fn code_of_finds_wrapped_errors() {
+        let err = Err::<(), _>(ExitError::Auth).wrap_err("ssh").unwrap_err();
+        assert_eq!(code_of(&err), AUTH);
+
+        let err = Report::new(HostKeyError::Unknown {
+            host: "[10.0.0.1]:2222".to_string(),
+            fingerprint: "ssh-ed25519 SHA256:xxx".to_string(),
+        });
+        assert_eq!(code_of(&err), HOST_KEY);
+
+        let err = Report::new(std::io::Error::from(std::io::ErrorKind::ConnectionRefused));
+        assert_eq!(code_of(&err), CONNECT);
+
+        let err = color_eyre::eyre::eyre!("找不到 horsed 远程仓库!");
+        assert_eq!(code_of(&err), FAILURE);
+    }
+}
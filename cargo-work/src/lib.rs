@@ -5,6 +5,7 @@
 )]
 pub mod command;
 mod exec;
+pub mod exit;
 pub mod logger;
 mod mac;
 pub mod options;
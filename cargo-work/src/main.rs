@@ -1,22 +1,34 @@
 #[allow(unused_imports)]
 use cargo_work::{
     command::{
-        admin, cargo, cmd, get, health, job, just, logs, ping, pull, push, put, scp, ssh, watch,
+        admin, cargo, cmd, get, health, job, just, logs, ping, pull, push, put, scp, ssh, trust,
+        watch,
     },
-    logger,
+    exit, logger,
     options::*,
 };
 use clap::Parser;
-use color_eyre::Result;
+use color_eyre::{eyre::bail, Result};
 use std::path::PathBuf;
+use std::process::ExitCode;
 
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() -> Result<ExitCode> {
     color_eyre::install()?;
     let cli = Cli::parse();
 
     logger::init()?;
 
+    // 远端命令的退出码作为进程退出码, 本地错误见 `cargo_work::exit`
+    if let Err(err) = run(cli).await {
+        tracing::error!("执行失败: {:#}", err);
+        return Ok(ExitCode::from(exit::code_of(&err)));
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+async fn run(cli: Cli) -> Result<()> {
     let key = if let Some(key) = cli.horse.key.clone().take() {
         key
     } else {
@@ -33,8 +45,7 @@ async fn main() -> Result<()> {
         } else if path.join("id_ed25519").exists() {
             path.join("id_ed25519")
         } else {
-            tracing::error!("没有可以使用的私钥文件: {}", path.display());
-            return Ok(());
+            bail!("没有可以使用的私钥文件: {}", path.display());
         }
     };
 
@@ -54,164 +65,118 @@ async fn main() -> Result<()> {
             // cargo work -- <SCRIPTS>
             // e.g. cargo work -- ls -al
             if !scripts.is_empty() {
-                if let Err(err) = cmd::run(&key, horse, scripts, cmd::CodeSync::Disabled).await {
-                    tracing::error!("执行失败: {}", err);
-                }
+                cmd::run(&key, horse, scripts, cmd::CodeSync::Disabled).await
             } else if let Some(commands) = w_opt.commands {
                 match commands {
                     Commands::Init(_options) => {
                         // TODO: 初始化工作目录
+                        Ok(())
                     }
                     Commands::Build(mut options) => {
                         merge_options(&mut options.horse, &horse);
-                        if let Err(err) = cargo::run(&key, options).await {
-                            tracing::error!("执行失败: {}", err);
-                        }
+                        cargo::run(&key, options).await
                     }
                     Commands::Zigbuild(mut options) => {
                         merge_options(&mut options.horse, &horse);
-                        if let Err(err) = cargo::run(&key, options).await {
-                            tracing::error!("执行失败: {}", err);
-                        }
+                        cargo::run(&key, options).await
                     }
                     Commands::Check(mut options) => {
                         merge_options(&mut options.horse, &horse);
-                        if let Err(err) = cargo::run(&key, options).await {
-                            tracing::error!("执行失败: {}", err);
-                        }
+                        cargo::run(&key, options).await
                     }
                     Commands::Clean(mut options) => {
                         merge_options(&mut options.horse, &horse);
-                        if let Err(err) = cargo::run(&key, options).await {
-                            tracing::error!("执行失败: {}", err);
-                        }
+                        cargo::run(&key, options).await
                     }
                     Commands::Clippy(mut options) => {
                         merge_options(&mut options.horse, &horse);
-                        if let Err(err) = cargo::run(&key, options).await {
-                            tracing::error!("执行失败: {}", err);
-                        }
+                        cargo::run(&key, options).await
                     }
                     Commands::Doc(mut options) => {
                         merge_options(&mut options.horse, &horse);
-                        if let Err(err) = cargo::run(&key, options).await {
-                            tracing::error!("执行失败: {}", err);
-                        }
+                        cargo::run(&key, options).await
                     }
                     Commands::Install(mut options) => {
                         merge_options(&mut options.horse, &horse);
-                        if let Err(err) = cargo::run(&key, options).await {
-                            tracing::error!("执行失败: {}", err);
-                        }
+                        cargo::run(&key, options).await
                     }
                     Commands::Metadata(mut options) => {
                         merge_options(&mut options.horse, &horse);
-                        if let Err(err) = cargo::run(&key, options).await {
-                            tracing::error!("执行失败: {}", err);
-                        }
+                        cargo::run(&key, options).await
                     }
                     Commands::Run(mut options) => {
                         merge_options(&mut options.horse, &horse);
-                        if let Err(err) = cargo::run(&key, options).await {
-                            tracing::error!("执行失败: {}", err);
-                        }
+                        cargo::run(&key, options).await
                     }
                     Commands::Rustc(mut options) => {
                         merge_options(&mut options.horse, &horse);
-                        if let Err(err) = cargo::run(&key, options).await {
-                            tracing::error!("执行失败: {}", err);
-                        }
+                        cargo::run(&key, options).await
                     }
                     Commands::Test(mut options) => {
                         merge_options(&mut options.horse, &horse);
-                        if let Err(err) = cargo::run(&key, options).await {
-                            tracing::error!("执行失败: {}", err);
-                        }
+                        cargo::run(&key, options).await
                     }
                     Commands::Just(mut options) => {
                         merge_options(&mut options.horse, &horse);
-                        if let Err(err) = just::run(&key, options).await {
-                            tracing::error!("执行失败: {}", err);
-                        }
+                        just::run(&key, options).await
                     }
                     Commands::Get(mut options) => {
                         merge_options(&mut options.horse, &horse);
                         // 处理 Windows 中的路径
                         options.file = options.file.replace("\\", "/");
-                        if let Err(err) = get::run(&key, options).await {
-                            tracing::error!("执行失败: {}", err);
-                        }
+                        get::run(&key, options).await
                     }
                     Commands::Scp(mut options) => {
                         merge_options(&mut options.horse, &horse);
-                        if let Err(err) = scp::run(&key, options).await {
-                            tracing::error!("执行失败: {}", err);
-                        }
+                        scp::run(&key, options).await
                     }
                     Commands::Put(mut options) => {
                         merge_options(&mut options.horse, &horse);
                         options.dest = options.dest.replace("\\", "/");
-                        if let Err(err) = put::run(&key, options).await {
-                            tracing::error!("执行失败: {}", err);
-                        }
-                    }
-                    Commands::Push(options) => {
-                        if let Err(err) = push::run(&key, options).await {
-                            tracing::error!("执行失败: {}", err);
-                        }
+                        put::run(&key, options).await
                     }
+                    Commands::Push(options) => push::run(&key, options).await,
                     Commands::Pull(mut options) => {
                         merge_options(&mut options.horse, &horse);
-                        if let Err(err) = pull::run(&key, options).await {
-                            tracing::error!("执行失败: {}", err);
-                        }
+                        pull::run(&key, options).await
                     }
 
                     Commands::Ping(mut options) => {
                         merge_options(&mut options.horse, &horse);
-                        if let Err(err) = ping::run(&key, options).await {
-                            tracing::error!("执行失败: {}", err);
-                        }
+                        ping::run(&key, options).await
+                    }
+
+                    Commands::Trust(mut options) => {
+                        merge_options(&mut options.horse, &horse);
+                        trust::run(options).await
                     }
 
                     Commands::Ssh(mut options) => {
                         merge_options(&mut options.horse, &horse);
-                        if let Err(err) = ssh::run(&key, options).await {
-                            tracing::error!("执行失败: {}", err);
-                        }
+                        ssh::run(&key, options).await
                     }
 
                     Commands::Logs(mut options) => {
                         merge_options(&mut options.horse, &horse);
-                        if let Err(err) = logs::run(&key, options).await {
-                            tracing::error!("执行失败: {}", err);
-                        }
+                        logs::run(&key, options).await
                     }
                     Commands::Job(mut options) => {
                         merge_options(&mut options.horse, &horse);
-                        if let Err(err) = job::run(&key, options).await {
-                            tracing::error!("执行失败: {}", err);
-                        }
+                        job::run(&key, options).await
                     }
 
                     Commands::Watch(mut options) => {
                         merge_options(&mut options.horse, &horse);
-                        if let Err(err) = watch::run(&key, options).await {
-                            tracing::error!("执行失败: {}", err);
-                        }
+                        watch::run(&key, options).await
                     }
 
                     Commands::Health(mut options) => {
                         merge_options(&mut options.horse, &horse);
-                        if let Err(err) = health::run(&key, options).await {
-                            tracing::error!("执行失败: {}", err);
-                        }
+                        health::run(&key, options).await
                     }
                     Commands::Admin(mut options) => {
                         merge_options(&mut options.horse, &horse);
-                        if let Err(err) = admin::run(&key, options).await {
-                            tracing::error!("执行失败: {}", err);
-                        }
+                        admin::run(&key, options).await
                     }
                     Commands::Exec(mut options) => {
                         merge_options(&mut options.horse, &horse);
@@ -220,9 +185,7 @@ async fn main() -> Result<()> {
                         } else {
                             cmd::CodeSync::Enabled
                         };
-                        if let Err(err) = exec_stdin(&key, options.horse, sync).await {
-                            tracing::error!("执行失败: {}", err);
-                        }
+                        exec_stdin(&key, options.horse, sync).await
                     }
                 }
             } else {
@@ -232,27 +195,26 @@ async fn main() -> Result<()> {
                     count: Some(3),
                     host: None,
                 };
-                if let Err(err) = ping::run(&key, options).await {
-                    tracing::error!("执行失败: {}", err);
-                }
+                ping::run(&key, options).await
             }
         }
 
         // 直接调用 cargo 命令
-        SubCommands::Cargo(opt) => match opt {
-            Commands::Build(build) => tracing::info!("{:?}", build.command()),
-            Commands::Just(just) => tracing::info!("{:?}", just),
-            Commands::Push(options) => {
-                tracing::info!("{:?}", options);
-            }
-            Commands::Pull(_) => {
-                let _ = cargo_work::ui::init();
+        SubCommands::Cargo(opt) => {
+            match opt {
+                Commands::Build(build) => tracing::info!("{:?}", build.command()),
+                Commands::Just(just) => tracing::info!("{:?}", just),
+                Commands::Push(options) => {
+                    tracing::info!("{:?}", options);
+                }
+                Commands::Pull(_) => {
+                    let _ = cargo_work::ui::init();
+                }
+                opt => tracing::info!("{:?}", opt),
             }
-            opt => tracing::info!("{:?}", opt),
-        },
+            Ok(())
+        }
     }
-
-    Ok(())
 }
 
 /// Read a whole script from stdin and run it verbatim on the server. The script
@@ -309,6 +271,16 @@ fn merge_options(options: &mut HorseOptions, horse: &HorseOptions) {
     }
 
     options.watch = options.watch || horse.watch;
+    options.detach = options.detach || horse.detach;
+    options.no_untracked = options.no_untracked || horse.no_untracked;
+    options.accept_new = options.accept_new || horse.accept_new;
+    options.forward_agent = options.forward_agent || horse.forward_agent;
+    if options.invite.is_none() {
+        options.invite = horse.invite.clone();
+    }
+    if options.untracked_max_size.is_none() {
+        options.untracked_max_size = horse.untracked_max_size;
+    }
     options.enable_proxy = options.enable_proxy || horse.enable_proxy;
     if options.all_proxy.is_none() {
         options.all_proxy = horse.all_proxy.clone();
@@ -60,6 +60,33 @@ pub struct HorseOptions {
     pub pty: bool,
     #[clap(short, long, help = "检测代码变动")]
     pub watch: bool,
+    #[clap(
+        long,
+        help = "任务转入后台运行, 打印 job_id 后立即返回, 之后可用 job attach 查看输出"
+    )]
+    pub detach: bool,
+    #[clap(long, help = "同步代码时不包含未跟踪的文件")]
+    pub no_untracked: bool,
+    #[clap(
+        long,
+        value_name = "BYTES",
+        help = "同步的未跟踪文件总大小上限, 默认 16MiB"
+    )]
+    pub untracked_max_size: Option<u64>,
+    #[clap(long, help = "自动信任第一次连接的服务端公钥, 公钥变化时仍然拒绝连接")]
+    pub accept_new: bool,
+    #[clap(
+        long,
+        value_name = "TOKEN",
+        help = "使用管理员发放的邀请码把当前公钥绑定到账户, 只在第一次连接时需要"
+    )]
+    pub invite: Option<String>,
+    #[clap(
+        short = 'A',
+        long = "forward-agent",
+        help = "转发本地 ssh-agent, 远端的 cmd/cargo/just/ssh 可以使用本地的密钥"
+    )]
+    pub forward_agent: bool,
 }
 
 #[derive(Clone, Debug, Subcommand)]
@@ -125,6 +152,8 @@ pub enum Commands {
     Pull(PullOptions),
     #[command(name = "ping", about = "服务器状态检查")]
     Ping(PingOptions),
+    #[command(name = "trust", about = "信任服务端当前的公钥, 替换已有记录")]
+    Trust(TrustOptions),
     #[command(name = "ssh", about = "连接服务器")]
     Ssh(SshOptions),
     #[command(name = "logs", about = "查看服务器日志")]
@@ -183,6 +212,77 @@ mod tests {
     fn exec_can_disable_code_sync_explicitly() {
         assert!(exec_options(&["cargo-work", "work", "exec", "--no-sync"]).no_sync);
     }
+
+    #[test]
+    fn exec_can_run_detached() {
+        assert!(!exec_options(&["cargo-work", "work", "exec"]).horse.detach);
+        assert!(
+            exec_options(&["cargo-work", "work", "exec", "--detach"])
+                .horse
+                .detach
+        );
+    }
+
+    #[test]
+    fn exec_can_skip_untracked_files() {
+        let options = exec_options(&["cargo-work", "work", "exec"]);
+        assert!(!options.horse.no_untracked);
+        assert_eq!(options.horse.untracked_max_size, None);
+
+        let options = exec_options(&[
+            "cargo-work",
+            "work",
+            "exec",
+            "--no-untracked",
+            "--untracked-max-size",
+            "1024",
+        ]);
+        assert!(options.horse.no_untracked);
+        assert_eq!(options.horse.untracked_max_size, Some(1024));
+    }
+
+    #[test]
+    fn trust_takes_optional_remote() {
+        let cli = Cli::try_parse_from(["cargo-work", "work", "trust", "horsed", "--accept-new"])
+            .expect("trust arguments should parse");
+        let SubCommands::Work(work) = cli.sub_commands else {
+            panic!("expected cargo work command");
+        };
+        let Some(Commands::Trust(options)) = work.commands else {
+            panic!("expected trust command");
+        };
+        assert_eq!(options.remote.as_deref(), Some("horsed"));
+        assert!(options.horse.accept_new);
+    }
+
+    #[test]
+    fn exec_takes_invite_token() {
+        assert_eq!(
+            exec_options(&["cargo-work", "work", "exec"]).horse.invite,
+            None
+        );
+        let options = exec_options(&["cargo-work", "work", "exec", "--invite", "abc123"]);
+        assert_eq!(options.horse.invite.as_deref(), Some("abc123"));
+    }
+
+    #[test]
+    fn exec_takes_forward_agent() {
+        assert!(
+            !exec_options(&["cargo-work", "work", "exec"])
+                .horse
+                .forward_agent
+        );
+        assert!(
+            exec_options(&["cargo-work", "work", "exec", "-A"])
+                .horse
+                .forward_agent
+        );
+        assert!(
+            exec_options(&["cargo-work", "work", "exec", "--forward-agent"])
+                .horse
+                .forward_agent
+        );
+    }
 }
 
 #[derive(Clone, Debug, Args)]
@@ -255,7 +355,9 @@ pub struct LogsOptions {
 pub struct JobOptions {
     #[clap(flatten)]
     pub horse: HorseOptions,
-    #[clap(help = "job 命令, 例如: list / attach [job_id] [-- --no-follow]")]
+    #[clap(
+        help = "job 命令, 例如: list [-- --limit N --before <job_id> --owner <user> --status <status>] / attach [job_id] [-- --no-follow]"
+    )]
     pub command: Vec<String>,
 }
 
@@ -280,6 +382,14 @@ pub struct PingOptions {
     pub host: Option<String>,
 }
 
+#[derive(Clone, Debug, Args)]
+pub struct TrustOptions {
+    #[clap(flatten)]
+    pub horse: HorseOptions,
+    #[clap(value_name = "GIT_REMOTE")]
+    pub remote: Option<String>,
+}
+
 #[derive(Clone, Debug, Args)]
 pub struct PushOptions {
     #[clap(help = "远程仓库地址, 默认: horsed")]
@@ -318,6 +428,8 @@ pub struct HealthOptions {
 pub struct AdminOptions {
     #[clap(flatten)]
     pub horse: HorseOptions,
-    #[clap(help = "管理员命令, 例如: users list / keys add <user> <alg> <key> [comment]")]
+    #[clap(
+        help = "管理员命令, 例如: users list / keys add <user> <alg> <key> [comment] / keys import <user> [authorized_keys]"
+    )]
     pub command: Vec<String>,
 }
@@ -3,6 +3,15 @@ pub use sea_orm_migration::prelude::*;
 mod m20250104_174457_create_user;
 mod m20250125_083941_create_ssh_pk;
 mod m20260307_090000_add_user_role_and_enable;
+mod m20261017_090000_create_repo_acl;
+mod m20261017_100000_create_job;
+mod m20261017_110000_add_repo_workspace;
+mod m20261017_120000_create_user_ca;
+mod m20261017_130000_create_invite;
+mod m20261017_140000_create_audit;
+mod m20261017_150000_create_ban;
+mod m20261017_160000_add_ssh_pk_options;
+mod m20261017_170000_add_capabilities;
 
 pub struct Migrator;
 
@@ -13,6 +22,15 @@ impl MigratorTrait for Migrator {
             Box::new(m20250104_174457_create_user::Migration),
             Box::new(m20250125_083941_create_ssh_pk::Migration),
             Box::new(m20260307_090000_add_user_role_and_enable::Migration),
+            Box::new(m20261017_090000_create_repo_acl::Migration),
+            Box::new(m20261017_100000_create_job::Migration),
+            Box::new(m20261017_110000_add_repo_workspace::Migration),
+            Box::new(m20261017_120000_create_user_ca::Migration),
+            Box::new(m20261017_130000_create_invite::Migration),
+            Box::new(m20261017_140000_create_audit::Migration),
+            Box::new(m20261017_150000_create_ban::Migration),
+            Box::new(m20261017_160000_add_ssh_pk_options::Migration),
+            Box::new(m20261017_170000_add_capabilities::Migration),
         ]
     }
 }
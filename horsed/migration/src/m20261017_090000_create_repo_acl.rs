@@ -0,0 +1,86 @@
+use super::m20250104_174457_create_user::User;
+use sea_orm_migration::{prelude::*, schema::*};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(Repo::Table)
+                    .if_not_exists()
+                    .col(pk_auto(Repo::Id))
+                    .col(ColumnDef::new(Repo::Name).string().not_null().unique_key())
+                    .col(ColumnDef::new(Repo::OwnerId).integer().not_null())
+                    .foreign_key(
+                        ForeignKey::create()
+                            .from(Repo::Table, Repo::OwnerId)
+                            .to(User::Table, User::Id)
+                            .on_delete(ForeignKeyAction::Cascade)
+                            .on_update(ForeignKeyAction::Cascade),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_table(
+                Table::create()
+                    .table(RepoMember::Table)
+                    .if_not_exists()
+                    .col(ColumnDef::new(RepoMember::RepoId).integer().not_null())
+                    .col(ColumnDef::new(RepoMember::UserId).integer().not_null())
+                    .col(ColumnDef::new(RepoMember::Level).string().not_null())
+                    .primary_key(
+                        Index::create()
+                            .col(RepoMember::RepoId)
+                            .col(RepoMember::UserId),
+                    )
+                    .foreign_key(
+                        ForeignKey::create()
+                            .from(RepoMember::Table, RepoMember::RepoId)
+                            .to(Repo::Table, Repo::Id)
+                            .on_delete(ForeignKeyAction::Cascade)
+                            .on_update(ForeignKeyAction::Cascade),
+                    )
+                    .foreign_key(
+                        ForeignKey::create()
+                            .from(RepoMember::Table, RepoMember::UserId)
+                            .to(User::Table, User::Id)
+                            .on_delete(ForeignKeyAction::Cascade)
+                            .on_update(ForeignKeyAction::Cascade),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(RepoMember::Table).to_owned())
+            .await?;
+
+        manager
+            .drop_table(Table::drop().table(Repo::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(DeriveIden)]
+pub enum Repo {
+    Table,
+    Id,
+    Name,
+    OwnerId,
+}
+
+#[derive(DeriveIden)]
+enum RepoMember {
+    Table,
+    RepoId,
+    UserId,
+    Level,
+}
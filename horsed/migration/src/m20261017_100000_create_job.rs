@@ -0,0 +1,73 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(Job::Table)
+                    .if_not_exists()
+                    .col(ColumnDef::new(Job::Id).string().not_null().primary_key())
+                    .col(ColumnDef::new(Job::Owner).string().not_null())
+                    .col(ColumnDef::new(Job::Action).string().not_null())
+                    .col(ColumnDef::new(Job::Command).text().not_null())
+                    .col(ColumnDef::new(Job::Status).string().not_null())
+                    .col(ColumnDef::new(Job::ExitCode).integer().null())
+                    .col(ColumnDef::new(Job::StartedAt).big_integer().not_null())
+                    .col(ColumnDef::new(Job::FinishedAt).big_integer().null())
+                    .col(
+                        ColumnDef::new(Job::OutputBytes)
+                            .big_integer()
+                            .not_null()
+                            .default(0),
+                    )
+                    // 结束任务时发送的信号, 以及宽限期后是否强制结束了进程组
+                    .col(ColumnDef::new(Job::Signal).string().null())
+                    .col(
+                        ColumnDef::new(Job::Escalated)
+                            .boolean()
+                            .not_null()
+                            .default(false),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .if_not_exists()
+                    .name("idx_job_started_at")
+                    .table(Job::Table)
+                    .col(Job::StartedAt)
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(Job::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(DeriveIden)]
+pub enum Job {
+    Table,
+    Id,
+    Owner,
+    Action,
+    Command,
+    Status,
+    ExitCode,
+    StartedAt,
+    FinishedAt,
+    OutputBytes,
+    Signal,
+    Escalated,
+}
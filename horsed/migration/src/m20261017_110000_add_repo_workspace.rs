@@ -0,0 +1,59 @@
+use super::m20261017_090000_create_repo_acl::Repo;
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // 为空时使用 horsed.toml 中 [workspace] 的默认值
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Repo::Table)
+                    .add_column(ColumnDef::new(RepoWorkspace::WorkspaceMode).string().null())
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Repo::Table)
+                    .add_column(
+                        ColumnDef::new(RepoWorkspace::WorkspacePrune)
+                            .boolean()
+                            .null(),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Repo::Table)
+                    .drop_column(RepoWorkspace::WorkspacePrune)
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Repo::Table)
+                    .drop_column(RepoWorkspace::WorkspaceMode)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+#[derive(DeriveIden)]
+enum RepoWorkspace {
+    WorkspaceMode,
+    WorkspacePrune,
+}
@@ -0,0 +1,68 @@
+use super::m20261017_100000_create_job::Job;
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(UserCa::Table)
+                    .if_not_exists()
+                    .col(ColumnDef::new(UserCa::Alg).string().not_null())
+                    .col(ColumnDef::new(UserCa::Key).string().not_null())
+                    .col(
+                        ColumnDef::new(UserCa::Enabled)
+                            .boolean()
+                            .not_null()
+                            .default(true),
+                    )
+                    .col(ColumnDef::new(UserCa::Comment).string().null())
+                    .primary_key(Index::create().col(UserCa::Alg).col(UserCa::Key))
+                    .to_owned(),
+            )
+            .await?;
+
+        // 证书登录时记录证书的 key id 与序列号, 公钥登录时为空
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Job::Table)
+                    .add_column(ColumnDef::new(JobCert::OwnerCert).string().null())
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Job::Table)
+                    .drop_column(JobCert::OwnerCert)
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .drop_table(Table::drop().table(UserCa::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(DeriveIden)]
+enum UserCa {
+    Table,
+    Alg,
+    Key,
+    Enabled,
+    Comment,
+}
+
+#[derive(DeriveIden)]
+enum JobCert {
+    OwnerCert,
+}
@@ -0,0 +1,55 @@
+use sea_orm_migration::{prelude::*, schema::*};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // 邀请码只保存 sha256, 兑换后记录绑定的公钥和客户端地址
+        manager
+            .create_table(
+                Table::create()
+                    .table(Invite::Table)
+                    .if_not_exists()
+                    .col(pk_auto(Invite::Id))
+                    .col(
+                        ColumnDef::new(Invite::TokenHash)
+                            .string()
+                            .not_null()
+                            .unique_key(),
+                    )
+                    .col(ColumnDef::new(Invite::UserName).string().not_null())
+                    .col(ColumnDef::new(Invite::Role).string().not_null())
+                    .col(ColumnDef::new(Invite::CreatedBy).string().not_null())
+                    .col(ColumnDef::new(Invite::CreatedAt).big_integer().not_null())
+                    .col(ColumnDef::new(Invite::ExpiresAt).big_integer().not_null())
+                    .col(ColumnDef::new(Invite::RedeemedAt).big_integer().null())
+                    .col(ColumnDef::new(Invite::RedeemedKey).string().null())
+                    .col(ColumnDef::new(Invite::RedeemedPeer).string().null())
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(Invite::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(DeriveIden)]
+enum Invite {
+    Table,
+    Id,
+    TokenHash,
+    UserName,
+    Role,
+    CreatedBy,
+    CreatedAt,
+    ExpiresAt,
+    RedeemedAt,
+    RedeemedKey,
+    RedeemedPeer,
+}
@@ -0,0 +1,73 @@
+use sea_orm_migration::{prelude::*, schema::*};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(Audit::Table)
+                    .if_not_exists()
+                    .col(pk_auto(Audit::Id))
+                    .col(ColumnDef::new(Audit::At).big_integer().not_null())
+                    // login / action / admin
+                    .col(ColumnDef::new(Audit::Kind).string().not_null())
+                    .col(ColumnDef::new(Audit::User).string().null())
+                    .col(ColumnDef::new(Audit::Peer).string().null())
+                    .col(ColumnDef::new(Audit::Action).string().not_null())
+                    .col(ColumnDef::new(Audit::Outcome).string().not_null())
+                    .col(ColumnDef::new(Audit::Fingerprint).string().null())
+                    .col(ColumnDef::new(Audit::Command).text().null())
+                    .col(ColumnDef::new(Audit::Repo).string().null())
+                    .col(ColumnDef::new(Audit::Branch).string().null())
+                    .col(ColumnDef::new(Audit::JobId).string().null())
+                    .col(ColumnDef::new(Audit::ExitCode).integer().null())
+                    .col(ColumnDef::new(Audit::DurationMs).big_integer().null())
+                    .col(ColumnDef::new(Audit::Before).text().null())
+                    .col(ColumnDef::new(Audit::After).text().null())
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .if_not_exists()
+                    .name("idx_audit_at")
+                    .table(Audit::Table)
+                    .col(Audit::At)
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(Audit::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(DeriveIden)]
+enum Audit {
+    Table,
+    Id,
+    At,
+    Kind,
+    User,
+    Peer,
+    Action,
+    Outcome,
+    Fingerprint,
+    Command,
+    Repo,
+    Branch,
+    JobId,
+    ExitCode,
+    DurationMs,
+    Before,
+    After,
+}
@@ -0,0 +1,42 @@
+use sea_orm_migration::{prelude::*, schema::*};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // 认证失败过多的客户端地址, 到期后自动失效
+        manager
+            .create_table(
+                Table::create()
+                    .table(Ban::Table)
+                    .if_not_exists()
+                    .col(pk_auto(Ban::Id))
+                    .col(ColumnDef::new(Ban::Ip).string().not_null().unique_key())
+                    .col(ColumnDef::new(Ban::Reason).string().not_null())
+                    .col(ColumnDef::new(Ban::Failures).integer().not_null())
+                    .col(ColumnDef::new(Ban::CreatedAt).big_integer().not_null())
+                    .col(ColumnDef::new(Ban::ExpiresAt).big_integer().not_null())
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(Ban::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(DeriveIden)]
+enum Ban {
+    Table,
+    Id,
+    Ip,
+    Reason,
+    Failures,
+    CreatedAt,
+    ExpiresAt,
+}
@@ -0,0 +1,30 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // authorized_keys 的公钥选项, 如 `from="10.0.0.0/8",no-port-forwarding`
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Alias::new("ssh_pk"))
+                    .add_column(ColumnDef::new(Alias::new("options")).string().null())
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Alias::new("ssh_pk"))
+                    .drop_column(Alias::new("options"))
+                    .to_owned(),
+            )
+            .await
+    }
+}
@@ -0,0 +1,652 @@
+//! 用户与公钥管理
+//!
+//! 远程的 `admin users/keys` 与本地的 `horsed user`/`horsed key` 共用这里的实现.
+//! 本地命令没有登录用户 (`actor` 为空), 仍然不能禁用、降级或删除最后一个启用中的管理员.
+use crate::db::entity::prelude::{SshPk, User};
+use crate::db::entity::{ssh_pk, user};
+use crate::ssh::{Capabilities, KeyOptions};
+use anyhow::{anyhow, bail, Context};
+use russh::keys::{HashAlg, PublicKey};
+use sea_orm::{
+    ActiveModelTrait, ColumnTrait, DatabaseConnection, EntityTrait, ModelTrait, PaginatorTrait,
+    QueryFilter, QueryOrder, Set,
+};
+use serde::Serialize;
+
+pub const ROLE_ADMIN: &str = "admin";
+pub const ROLE_USER: &str = "user";
+
+#[derive(Serialize, Debug)]
+pub struct UserRow {
+    pub id: i32,
+    pub name: String,
+    pub nick: Option<String>,
+    pub email: Option<String>,
+    pub role: String,
+    pub enabled: bool,
+    pub caps: String,
+    pub key_count: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct KeyRow {
+    pub alg: String,
+    pub key: String,
+    pub user_id: i32,
+    pub user_name: Option<String>,
+    pub enabled: bool,
+    pub comment: Option<String>,
+    pub options: Option<String>,
+    pub caps: String,
+}
+
+/// 新用户
+#[derive(Clone, Debug)]
+pub struct NewUser {
+    pub name: String,
+    pub nick: Option<String>,
+    pub email: Option<String>,
+    pub role: String,
+}
+
+/// 修改用户, 为空的字段保持不变; `nick`/`email` 为空字符串时清除
+#[derive(Clone, Debug, Default)]
+pub struct UserChanges {
+    pub nick: Option<String>,
+    pub email: Option<String>,
+    pub role: Option<String>,
+    pub enabled: Option<bool>,
+    /// 操作权限, 如 `git-only`、`build,read`, `all` 表示不限制
+    pub caps: Option<String>,
+}
+
+/// `authorized_keys` 中的一行
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AuthorizedKey {
+    /// 公钥前的选项, 如 `from="10.0.0.0/8",no-pty`
+    pub options: Option<String>,
+    pub alg: String,
+    pub key: String,
+    pub comment: Option<String>,
+}
+
+/// `import_keys` 的结果
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    /// 导入的公钥指纹
+    pub added: Vec<String>,
+    /// 跳过的行号与原因
+    pub skipped: Vec<(usize, String)>,
+}
+
+pub fn parse_role(role: &str) -> anyhow::Result<String> {
+    let role = role.trim().to_ascii_lowercase();
+    if role != ROLE_ADMIN && role != ROLE_USER {
+        bail!("角色必须是 admin 或 user");
+    }
+    Ok(role)
+}
+
+/// 校验公钥并转换为数据库中的格式: (算法, base64)
+pub fn parse_key(alg: &str, key: &str) -> anyhow::Result<(String, String)> {
+    let pk = PublicKey::from_openssh(&format!("{alg} {key}"))
+        .map_err(|err| anyhow!("无效公钥: {err}"))?;
+    #[allow(deprecated)]
+    let data = base64::encode(&pk.to_bytes().context("pk bytes")?);
+    Ok((pk.algorithm().to_string(), data))
+}
+
+/// 公钥指纹, 用于输出
+pub fn fingerprint(alg: &str, key: &str) -> String {
+    match PublicKey::from_openssh(&format!("{alg} {key}")) {
+        Ok(pk) => format!("{alg} {}", pk.fingerprint(HashAlg::Sha256)),
+        Err(_) => alg.to_string(),
+    }
+}
+
+/// 解析 `authorized_keys` 中的一行, 空行与注释返回 `None`
+pub fn parse_authorized_key(line: &str) -> anyhow::Result<Option<AuthorizedKey>> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+
+    // 公钥之前可以有选项, 选项中双引号内的空格不分隔字段
+    let mut quoted = false;
+    let end = line
+        .char_indices()
+        .find(|(_, c)| {
+            if *c == '"' {
+                quoted = !quoted;
+            }
+            c.is_whitespace() && !quoted
+        })
+        .map(|(index, _)| index)
+        .unwrap_or(line.len());
+    let first = &line[..end];
+    let is_alg = ["ssh-", "ecdsa-", "sk-"]
+        .iter()
+        .any(|prefix| first.starts_with(prefix));
+    let (options, rest) = if is_alg {
+        (None, line)
+    } else {
+        (Some(first.to_string()), line[end..].trim_start())
+    };
+
+    let mut parts = rest.splitn(3, char::is_whitespace);
+    let (Some(alg), Some(key)) = (parts.next(), parts.next()) else {
+        bail!("缺少公钥");
+    };
+    let (alg, key) = parse_key(alg, key)?;
+    let comment = parts
+        .next()
+        .map(str::trim)
+        .filter(|it| !it.is_empty())
+        .map(String::from);
+
+    Ok(Some(AuthorizedKey {
+        options,
+        alg,
+        key,
+        comment,
+    }))
+}
+
+pub async fn find_user(db: &DatabaseConnection, name: &str) -> anyhow::Result<user::Model> {
+    User::find()
+        .filter(user::Column::Name.eq(name))
+        .one(db)
+        .await?
+        .ok_or_else(|| anyhow!("用户不存在: {name}"))
+}
+
+async fn find_key(db: &DatabaseConnection, alg: &str, key: &str) -> anyhow::Result<ssh_pk::Model> {
+    SshPk::find_by_id((alg.to_string(), key.to_string()))
+        .one(db)
+        .await?
+        .context("公钥不存在")
+}
+
+async fn enabled_admins(db: &DatabaseConnection) -> anyhow::Result<u64> {
+    Ok(User::find()
+        .filter(user::Column::Role.eq(ROLE_ADMIN))
+        .filter(user::Column::Enabled.eq(true))
+        .count(db)
+        .await?)
+}
+
+pub async fn list_users(db: &DatabaseConnection) -> anyhow::Result<Vec<UserRow>> {
+    let users = User::find().order_by_asc(user::Column::Id).all(db).await?;
+    let mut rows = Vec::with_capacity(users.len());
+    for user in users {
+        let key_count = user.find_related(SshPk).count(db).await?;
+        rows.push(UserRow {
+            id: user.id,
+            name: user.name,
+            nick: user.nick,
+            email: user.email,
+            caps: Capabilities::load(user.caps.as_deref()).to_string(),
+            role: user.role,
+            enabled: user.enabled,
+            key_count,
+        });
+    }
+    Ok(rows)
+}
+
+pub async fn add_user(db: &DatabaseConnection, new: NewUser) -> anyhow::Result<user::Model> {
+    let role = parse_role(&new.role)?;
+    if User::find()
+        .filter(user::Column::Name.eq(new.name.as_str()))
+        .one(db)
+        .await?
+        .is_some()
+    {
+        bail!("用户已存在: {}", new.name);
+    }
+
+    let user = user::ActiveModel {
+        name: Set(new.name),
+        nick: Set(new.nick.filter(|it| !it.is_empty())),
+        email: Set(new.email.filter(|it| !it.is_empty())),
+        role: Set(role),
+        enabled: Set(true),
+        ..Default::default()
+    }
+    .insert(db)
+    .await?;
+    Ok(user)
+}
+
+/// 修改用户; `actor` 为当前登录的管理员, 不能禁用自己
+pub async fn modify_user(
+    db: &DatabaseConnection,
+    name: &str,
+    changes: UserChanges,
+    actor: Option<i32>,
+) -> anyhow::Result<user::Model> {
+    let target = find_user(db, name).await?;
+    let role = changes.role.as_deref().map(parse_role).transpose()?;
+    let caps = changes
+        .caps
+        .as_deref()
+        .map(Capabilities::parse)
+        .transpose()?;
+    let disable = changes.enabled == Some(false) && target.enabled;
+    let demote = role.as_deref() == Some(ROLE_USER) && target.is_admin() && target.enabled;
+
+    if disable && actor == Some(target.id) {
+        bail!("不能禁用当前登录管理员");
+    }
+    if (disable || demote) && target.is_admin() && enabled_admins(db).await? <= 1 {
+        if disable {
+            bail!("不能禁用最后一个启用中的管理员");
+        }
+        bail!("不能降级最后一个启用中的管理员");
+    }
+
+    let mut active: user::ActiveModel = target.clone().into();
+    if let Some(nick) = changes.nick {
+        active.nick = Set(Some(nick).filter(|it| !it.is_empty()));
+    }
+    if let Some(email) = changes.email {
+        active.email = Set(Some(email).filter(|it| !it.is_empty()));
+    }
+    if let Some(role) = role {
+        active.role = Set(role);
+    }
+    if let Some(enabled) = changes.enabled {
+        active.enabled = Set(enabled);
+    }
+    if let Some(caps) = caps {
+        active.caps = Set(caps.to_stored());
+    }
+    if !active.is_changed() {
+        return Ok(target);
+    }
+    Ok(active.update(db).await?)
+}
+
+/// 删除用户; `actor` 为当前登录的管理员, 不能删除自己
+pub async fn delete_user(
+    db: &DatabaseConnection,
+    name: &str,
+    actor: Option<i32>,
+) -> anyhow::Result<user::Model> {
+    let target = find_user(db, name).await?;
+    if actor == Some(target.id) {
+        bail!("不能删除当前登录管理员");
+    }
+    if target.enabled && target.is_admin() && enabled_admins(db).await? <= 1 {
+        bail!("不能删除最后一个启用中的管理员");
+    }
+
+    target.clone().delete(db).await?;
+    Ok(target)
+}
+
+/// 列出公钥, `user` 为空时列出全部
+pub async fn list_keys(db: &DatabaseConnection, user: Option<&str>) -> anyhow::Result<Vec<KeyRow>> {
+    let keys = match user {
+        Some(name) => {
+            let owner = find_user(db, name).await?;
+            owner.find_related(SshPk).all(db).await?
+        }
+        None => {
+            SshPk::find()
+                .order_by_asc(ssh_pk::Column::UserId)
+                .order_by_asc(ssh_pk::Column::Alg)
+                .all(db)
+                .await?
+        }
+    };
+
+    let mut rows = Vec::with_capacity(keys.len());
+    for key in keys {
+        let owner = key.find_related(User).one(db).await?;
+        rows.push(KeyRow {
+            alg: key.alg,
+            key: key.key,
+            user_id: key.user_id,
+            user_name: owner.map(|u| u.name),
+            enabled: key.enabled,
+            comment: key.comment,
+            options: key.options,
+            caps: Capabilities::load(key.caps.as_deref()).to_string(),
+        });
+    }
+    Ok(rows)
+}
+
+/// 校验 `authorized_keys` 公钥选项, 空字符串视为没有选项
+fn check_options(options: Option<String>) -> anyhow::Result<Option<String>> {
+    let Some(options) = options
+        .map(|it| it.trim().to_string())
+        .filter(|it| !it.is_empty())
+    else {
+        return Ok(None);
+    };
+    KeyOptions::parse(&options)?;
+    Ok(Some(options))
+}
+
+pub async fn add_key(
+    db: &DatabaseConnection,
+    user: &str,
+    alg: &str,
+    key: &str,
+    comment: Option<String>,
+    options: Option<String>,
+) -> anyhow::Result<ssh_pk::Model> {
+    let owner = find_user(db, user).await?;
+    let (alg, key) = parse_key(alg, key)?;
+    let options = check_options(options)?;
+    if let Some(existing) = SshPk::find_by_id((alg.clone(), key.clone()))
+        .one(db)
+        .await?
+    {
+        if existing.user_id == owner.id {
+            bail!("公钥已存在: {}", fingerprint(&alg, &key));
+        }
+        bail!("公钥已属于其他用户: user_id={}", existing.user_id);
+    }
+
+    let model = ssh_pk::ActiveModel {
+        alg: Set(alg),
+        key: Set(key),
+        user_id: Set(owner.id),
+        enabled: Set(true),
+        comment: Set(comment.filter(|it| !it.is_empty())),
+        options: Set(options),
+        caps: Set(None),
+    }
+    .insert(db)
+    .await?;
+    Ok(model)
+}
+
+pub async fn set_key_enabled(
+    db: &DatabaseConnection,
+    alg: &str,
+    key: &str,
+    enabled: bool,
+) -> anyhow::Result<ssh_pk::Model> {
+    let target = find_key(db, alg, key).await?;
+    if target.enabled == enabled {
+        return Ok(target);
+    }
+
+    let mut active: ssh_pk::ActiveModel = target.into();
+    active.enabled = Set(enabled);
+    Ok(active.update(db).await?)
+}
+
+/// 设置公钥的操作权限, 登录后的有效权限是用户权限与公钥权限的交集
+pub async fn set_key_caps(
+    db: &DatabaseConnection,
+    alg: &str,
+    key: &str,
+    caps: &str,
+) -> anyhow::Result<ssh_pk::Model> {
+    let caps = Capabilities::parse(caps)?;
+    let target = find_key(db, alg, key).await?;
+    let mut active: ssh_pk::ActiveModel = target.into();
+    active.caps = Set(caps.to_stored());
+    Ok(active.update(db).await?)
+}
+
+pub async fn delete_key(db: &DatabaseConnection, alg: &str, key: &str) -> anyhow::Result<()> {
+    find_key(db, alg, key).await?.delete(db).await?;
+    Ok(())
+}
+
+/// 从 `authorized_keys` 格式的内容导入公钥, 公钥选项一并保存
+///
+/// 无法解析、已经登记或者带有不支持的选项的行会跳过并记录原因, 不影响其他行.
+pub async fn import_keys(
+    db: &DatabaseConnection,
+    user: &str,
+    content: &str,
+) -> anyhow::Result<ImportReport> {
+    let owner = find_user(db, user).await?;
+    let mut report = ImportReport::default();
+
+    for (index, line) in content.lines().enumerate() {
+        let lineno = index + 1;
+        let entry = match parse_authorized_key(line) {
+            Ok(Some(entry)) => entry,
+            Ok(None) => continue,
+            Err(err) => {
+                report.skipped.push((lineno, err.to_string()));
+                continue;
+            }
+        };
+        let fingerprint = fingerprint(&entry.alg, &entry.key);
+
+        let options = match check_options(entry.options) {
+            Ok(options) => options,
+            Err(err) => {
+                report
+                    .skipped
+                    .push((lineno, format!("{fingerprint}: {err}")));
+                continue;
+            }
+        };
+
+        if let Some(existing) = SshPk::find_by_id((entry.alg.clone(), entry.key.clone()))
+            .one(db)
+            .await?
+        {
+            let reason = if existing.user_id == owner.id {
+                format!("{fingerprint}: 已存在")
+            } else {
+                format!("{fingerprint}: 已属于其他用户 user_id={}", existing.user_id)
+            };
+            report.skipped.push((lineno, reason));
+            continue;
+        }
+
+        ssh_pk::ActiveModel {
+            alg: Set(entry.alg),
+            key: Set(entry.key),
+            user_id: Set(owner.id),
+            enabled: Set(true),
+            comment: Set(entry.comment),
+            options: Set(options),
+            caps: Set(None),
+        }
+        .insert(db)
+        .await?;
+        report.added.push(fingerprint);
+    }
+
+    Ok(report)
+}
+
+/// 导出用户的公钥为 `authorized_keys` 格式, 禁用的公钥以注释输出
+pub async fn export_keys(db: &DatabaseConnection, user: &str) -> anyhow::Result<String> {
+    let owner = find_user(db, user).await?;
+    let keys = owner
+        .find_related(SshPk)
+        .order_by_asc(ssh_pk::Column::Alg)
+        .all(db)
+        .await?;
+
+    let mut content = format!("# horsed: {}\n", owner.name);
+    for key in keys {
+        let line = [key.options, Some(key.alg), Some(key.key), key.comment]
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>()
+            .join(" ");
+        if !key.enabled {
+            content.push_str("# disabled: ");
+        }
+        content.push_str(&line);
+        content.push('\n');
+    }
+    Ok(content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use migration::{Migrator, MigratorTrait};
+    use rand_core::OsRng;
+    use russh::keys::{Algorithm, PrivateKey};
+    use sea_orm::Database;
+
+    fn public_key() -> String {
+        PrivateKey::random(&mut OsRng, Algorithm::Ed25519)
+            .unwrap()
+            .public_key()
+            .to_openssh()
+            .unwrap()
+    }
+
+    fn new_user(name: &str, role: &str) -> NewUser {
+        NewUser {
+            name: name.to_string(),
+            nick: None,
+            email: None,
+            role: role.to_string(),
+        }
+    }
+
+    #[test]
+    fn authorized_key_line_is_parsed() {
+        let key = public_key();
+        let entry = parse_authorized_key(&format!("{key} alice@laptop"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(entry.alg, "ssh-ed25519");
+        assert_eq!(entry.options, None);
+        assert_eq!(entry.comment.as_deref(), Some("alice@laptop"));
+
+        let line = format!(r#"from="10.0.0.1, 10.0.0.2",no-pty {key}"#);
+        let entry = parse_authorized_key(&line).unwrap().unwrap();
+        assert_eq!(
+            entry.options.as_deref(),
+            Some(r#"from="10.0.0.1, 10.0.0.2",no-pty"#)
+        );
+        assert_eq!(entry.comment, None);
+
+        assert!(parse_authorized_key("  # comment").unwrap().is_none());
+        assert!(parse_authorized_key("ssh-ed25519 AAAA").is_err());
+    }
+
+    #[tokio::test]
+    async fn last_admin_is_protected() {
+        let db = Database::connect("sqlite::memory:").await.unwrap();
+        Migrator::up(&db, None).await.unwrap();
+
+        let root = add_user(&db, new_user("root", "admin")).await.unwrap();
+        add_user(&db, new_user("alice", "user")).await.unwrap();
+        assert!(add_user(&db, new_user("alice", "user")).await.is_err());
+        assert!(add_user(&db, new_user("bob", "owner")).await.is_err());
+
+        let demote = UserChanges {
+            role: Some("user".to_string()),
+            ..Default::default()
+        };
+        let disable = UserChanges {
+            enabled: Some(false),
+            ..Default::default()
+        };
+        assert!(modify_user(&db, "root", demote.clone(), None)
+            .await
+            .is_err());
+        assert!(modify_user(&db, "root", disable.clone(), None)
+            .await
+            .is_err());
+        assert!(delete_user(&db, "root", None).await.is_err());
+
+        let promote = UserChanges {
+            role: Some("admin".to_string()),
+            nick: Some("Alice".to_string()),
+            caps: Some("build-only".to_string()),
+            ..Default::default()
+        };
+        let alice = modify_user(&db, "alice", promote, None).await.unwrap();
+        assert!(alice.is_admin());
+        assert_eq!(alice.nick.as_deref(), Some("Alice"));
+        assert_eq!(alice.caps.as_deref(), Some("build,read"));
+        let invalid = UserChanges {
+            caps: Some("sudo".to_string()),
+            ..Default::default()
+        };
+        assert!(modify_user(&db, "alice", invalid, None).await.is_err());
+
+        assert!(modify_user(&db, "root", disable.clone(), Some(root.id))
+            .await
+            .is_err());
+        let root = modify_user(&db, "root", disable, None).await.unwrap();
+        assert!(!root.enabled);
+        assert_eq!(list_users(&db).await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn import_keeps_options_and_skips_invalid_keys() {
+        let db = Database::connect("sqlite::memory:").await.unwrap();
+        Migrator::up(&db, None).await.unwrap();
+        add_user(&db, new_user("alice", "user")).await.unwrap();
+        add_user(&db, new_user("bob", "user")).await.unwrap();
+
+        let (alice_key, bob_key, new_key) = (public_key(), public_key(), public_key());
+        let mut parts = alice_key.split(' ');
+        let (alg, key) = (parts.next().unwrap(), parts.next().unwrap());
+        add_key(&db, "alice", alg, key, None, None).await.unwrap();
+        assert!(add_key(&db, "bob", alg, key, None, None).await.is_err());
+        let mut parts = bob_key.split(' ');
+        add_key(
+            &db,
+            "bob",
+            parts.next().unwrap(),
+            parts.next().unwrap(),
+            None,
+            Some("no-agent-forwarding,pty".to_string()),
+        )
+        .await
+        .unwrap();
+
+        let content = format!(
+            "# keys\n{alice_key}\n{bob_key}\nssh-rsa broken\ncommand=\"true\" {}\n\nno-pty,from=\"10.0.0.0/8\" {new_key} desktop\n",
+            public_key()
+        );
+        let report = import_keys(&db, "alice", &content).await.unwrap();
+        assert_eq!(report.added.len(), 1);
+        let lines = report
+            .skipped
+            .iter()
+            .map(|(line, _)| *line)
+            .collect::<Vec<_>>();
+        assert_eq!(lines, [2, 3, 4, 5]);
+
+        let keys = list_keys(&db, Some("alice")).await.unwrap();
+        assert_eq!(keys.len(), 2);
+        let imported = keys
+            .iter()
+            .find(|key| key.comment.as_deref() == Some("desktop"))
+            .unwrap();
+        assert_eq!(
+            imported.options.as_deref(),
+            Some(r#"no-pty,from="10.0.0.0/8""#)
+        );
+
+        // 导出的内容可以重新导入
+        let exported = export_keys(&db, "alice").await.unwrap();
+        assert!(exported.contains(&format!(r#"no-pty,from="10.0.0.0/8" {new_key} desktop"#)));
+        let report = import_keys(&db, "bob", &exported).await.unwrap();
+        assert!(report.added.is_empty());
+        assert_eq!(report.skipped.len(), 2);
+
+        let key = set_key_caps(&db, alg, key, "git-only").await.unwrap();
+        assert_eq!(key.caps.as_deref(), Some("git"));
+        assert!(set_key_caps(&db, alg, &key.key, "root").await.is_err());
+
+        let key = set_key_enabled(&db, alg, &key.key, false).await.unwrap();
+        assert!(!key.enabled);
+        delete_key(&db, &key.alg, &key.key).await.unwrap();
+        assert!(delete_key(&db, &key.alg, &key.key).await.is_err());
+    }
+}
@@ -0,0 +1,117 @@
+//! `horsed ctl ...`: 通过本地 socket 管理正在运行的服务
+use crate::ipc::data::{Request, Response};
+use crate::options::{Ctl, CtlCommand};
+use crate::ssh::{format_utc, parse_ttl};
+use anyhow::{anyhow, bail};
+
+fn duration(secs: u64) -> String {
+    match (secs / 86400, secs / 3600 % 24, secs / 60 % 60, secs % 60) {
+        (0, 0, 0, s) => format!("{s}s"),
+        (0, 0, m, s) => format!("{m}m{s}s"),
+        (0, h, m, _) => format!("{h}h{m}m"),
+        (d, h, _, _) => format!("{d}d{h}h"),
+    }
+}
+
+fn print(response: &Response) {
+    match response {
+        Response::Status(status) => {
+            println!("版本: {}", status.version);
+            println!("进程: {}", status.pid);
+            println!("监听地址: {}", status.listen);
+            if let Some(config) = &status.config {
+                println!("配置文件: {config}");
+            }
+            if status.started_at_ms > 0 {
+                println!(
+                    "启动时间: {} (已运行 {})",
+                    format_utc(status.started_at_ms as i64),
+                    duration(status.uptime_secs)
+                );
+            } else {
+                println!("正式服务尚未启动");
+            }
+            if status.draining {
+                println!("正在 drain: 不再接受新的请求");
+            }
+            println!("连接: {}", status.sessions);
+            println!(
+                "任务: {} 运行中, {} 排队中",
+                status.running_jobs, status.queued_jobs
+            );
+        }
+        Response::Sessions { sessions } => {
+            for session in sessions {
+                println!(
+                    "{:<6} {:<12} {:<6} {:<10} {:<22} {:>3} {:>12} {:>12} {}",
+                    session.id,
+                    session.user.as_deref().unwrap_or("-"),
+                    session.role.as_deref().unwrap_or("-"),
+                    session.action.as_deref().unwrap_or("-"),
+                    session.peer.as_deref().unwrap_or("-"),
+                    session.channels,
+                    session.bytes_in,
+                    session.bytes_out,
+                    format_utc(session.connected_at_ms as i64),
+                );
+            }
+        }
+        Response::Jobs { jobs } => {
+            for job in jobs {
+                println!(
+                    "{:<24} {:<11} {:<12} {:<20} {}",
+                    job.id,
+                    job.status,
+                    job.owner,
+                    format_utc(job.started_at_ms as i64),
+                    job.command,
+                );
+            }
+        }
+        Response::Done { message } => println!("{message}"),
+        Response::Error { .. } => {}
+    }
+}
+
+pub fn run(ctl: Ctl) -> anyhow::Result<()> {
+    let request = match ctl.commands {
+        CtlCommand::Status => Request::Status,
+        CtlCommand::Sessions => Request::Sessions,
+        CtlCommand::Jobs(jobs) => Request::Jobs {
+            all: jobs.all,
+            limit: jobs.limit,
+        },
+        CtlCommand::Kill(kill) => Request::KillJob {
+            id: kill.id,
+            signal: kill.signal,
+        },
+        CtlCommand::Reload => Request::ReloadConfig,
+        CtlCommand::RotateLogs => Request::RotateLogs,
+        CtlCommand::Shutdown(shutdown) if shutdown.now => Request::Shutdown,
+        // 不带 --now 时与 drain 相同, 不中断运行中的任务
+        CtlCommand::Shutdown(_) => Request::Drain { timeout_secs: None },
+        CtlCommand::Drain(drain) => Request::Drain {
+            timeout_secs: drain
+                .timeout
+                .map(|timeout| parse_ttl(&timeout).ok_or_else(|| anyhow!("无效的时长: {timeout}")))
+                .transpose()?,
+        },
+    };
+
+    let response = stable::prelude::handle()
+        .block_on(crate::ipc::request(&request))
+        .map_err(|err| {
+            anyhow!("无法连接 horsed, 服务是否在运行? --dir/--config 是否与服务一致? ({err})")
+        })?;
+
+    if ctl.json {
+        println!("{}", serde_json::to_string_pretty(&response)?);
+    } else {
+        print(&response);
+    }
+
+    if let Response::Error { code, message } = response {
+        bail!("{code}: {message}");
+    }
+    Ok(())
+}
@@ -0,0 +1,78 @@
+//! `horsed hostkey ...`: 主机密钥管理
+//!
+//! 服务端在启动时载入主机密钥, 生成或轮换密钥后需要重启 horsed.
+use crate::key::{self, HostKey, HostKeyAlg};
+use crate::options::HostKeyCommand;
+use anyhow::{anyhow, Context};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+fn parse_alg(alg: &str) -> anyhow::Result<HostKeyAlg> {
+    HostKeyAlg::parse(alg)
+        .ok_or_else(|| anyhow!("不支持的密钥算法: {alg}, 可选: ed25519|ecdsa|rsa"))
+}
+
+/// 旧密钥剩余的宽限时间
+fn remaining(until_ms: i64) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0);
+    let mins = (until_ms - now).max(0) / 60_000;
+    match (mins / (24 * 60), mins / 60 % 24, mins % 60) {
+        (0, 0, m) => format!("{m}m"),
+        (0, h, m) => format!("{h}h{m}m"),
+        (d, h, _) => format!("{d}d{h}h"),
+    }
+}
+
+fn status(key: &HostKey) -> String {
+    match key.retire_at {
+        Some(until) => format!("retiring({})", remaining(until)),
+        None => "active".to_string(),
+    }
+}
+
+pub fn run(command: HostKeyCommand) -> anyhow::Result<()> {
+    match command {
+        HostKeyCommand::List(_) => {
+            for key in key::load_host_keys()? {
+                println!(
+                    "{:<16} {:<8} {} {}",
+                    status(&key),
+                    key.alg.as_str(),
+                    key.fingerprint(),
+                    key.path.display()
+                );
+            }
+        }
+        HostKeyCommand::Fingerprint(_) => {
+            for key in key::load_host_keys()? {
+                println!("{} ({})", key.fingerprint(), key.key.algorithm());
+            }
+        }
+        HostKeyCommand::Generate(options) => {
+            let key = key::generate(parse_alg(&options.alg)?)?;
+            println!(
+                "已生成主机密钥: {} {}",
+                key.fingerprint(),
+                key.path.display()
+            );
+            println!("重启 horsed 后生效");
+        }
+        HostKeyCommand::Rotate(options) => {
+            let alg = parse_alg(&options.alg)?;
+            let grace = crate::ssh::parse_ttl(&options.grace)
+                .with_context(|| format!("无效的宽限期: {}", options.grace))?;
+            let (old, new) = key::rotate(alg, Duration::from_secs(grace))?;
+            println!(
+                "旧密钥: {} {} (宽限期 {})",
+                old.fingerprint(),
+                old.path.display(),
+                remaining(old.retire_at.unwrap_or_default())
+            );
+            println!("新密钥: {} {}", new.fingerprint(), new.path.display());
+            println!("重启 horsed 后新旧密钥同时提供, 宽限期结束后只提供新密钥");
+        }
+    }
+    Ok(())
+}
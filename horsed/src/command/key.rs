@@ -0,0 +1,119 @@
+//! `horsed key ...`: 本地公钥管理, 与远程的 `admin keys` 共用 [`crate::account`]
+//!
+//! 管理员丢失私钥时, 可以在服务器上直接为其添加新的公钥.
+use crate::account::{self, KeyRow};
+use crate::options::KeyCommand;
+use crate::ssh::Capabilities;
+use anyhow::{bail, Context};
+use std::io::Read;
+
+fn print_keys(rows: &[KeyRow]) {
+    println!(
+        "{:<9} {:<16} {:<60} {:<16} COMMENT",
+        "STATUS", "USER", "FINGERPRINT", "CAPS"
+    );
+    for row in rows {
+        let status = if row.enabled { "enabled" } else { "disabled" };
+        let user = match &row.user_name {
+            Some(name) => name.clone(),
+            None => format!("#{}", row.user_id),
+        };
+        println!(
+            "{:<9} {:<16} {:<60} {:<16} {}",
+            status,
+            user,
+            account::fingerprint(&row.alg, &row.key),
+            row.caps,
+            row.comment.as_deref().unwrap_or("")
+        );
+    }
+}
+
+pub fn run(command: KeyCommand) -> anyhow::Result<()> {
+    stable::prelude::handle().block_on(async move {
+        let db = crate::db::db();
+        match command {
+            KeyCommand::List(options) => {
+                let rows = account::list_keys(&db, options.user.as_deref()).await?;
+                if options.json {
+                    println!("{}", serde_json::to_string_pretty(&rows)?);
+                } else {
+                    print_keys(&rows);
+                }
+            }
+            KeyCommand::Add(options) => {
+                let comment = Some(options.comment.join(" "));
+                let key = account::add_key(
+                    &db,
+                    &options.user,
+                    &options.alg,
+                    &options.key,
+                    comment,
+                    options.options,
+                )
+                .await?;
+                println!(
+                    "公钥已添加到用户 {}: {}",
+                    options.user,
+                    account::fingerprint(&key.alg, &key.key)
+                );
+            }
+            KeyCommand::Enable(id) => {
+                account::set_key_enabled(&db, &id.alg, &id.key, true).await?;
+                println!("公钥已启用: {}", account::fingerprint(&id.alg, &id.key));
+            }
+            KeyCommand::Disable(id) => {
+                account::set_key_enabled(&db, &id.alg, &id.key, false).await?;
+                println!("公钥已禁用: {}", account::fingerprint(&id.alg, &id.key));
+            }
+            KeyCommand::Delete(id) => {
+                account::delete_key(&db, &id.alg, &id.key).await?;
+                println!("公钥已删除: {}", account::fingerprint(&id.alg, &id.key));
+            }
+            KeyCommand::Caps(options) => {
+                let key =
+                    account::set_key_caps(&db, &options.alg, &options.key, &options.caps).await?;
+                println!(
+                    "公钥权限已更新: {} => {}",
+                    account::fingerprint(&key.alg, &key.key),
+                    Capabilities::load(key.caps.as_deref())
+                );
+            }
+            KeyCommand::Import(options) => {
+                let content = if options.file.as_os_str() == "-" {
+                    let mut content = String::new();
+                    std::io::stdin().read_to_string(&mut content)?;
+                    content
+                } else {
+                    std::fs::read_to_string(&options.file)
+                        .with_context(|| format!("读取文件失败: {}", options.file.display()))?
+                };
+
+                let report = account::import_keys(&db, &options.user, &content).await?;
+                for fingerprint in &report.added {
+                    println!("已导入: {fingerprint}");
+                }
+                for (line, reason) in &report.skipped {
+                    eprintln!("跳过第 {line} 行: {reason}");
+                }
+                println!(
+                    "导入 {} 个公钥, 跳过 {} 行",
+                    report.added.len(),
+                    report.skipped.len()
+                );
+                if report.added.is_empty() && !report.skipped.is_empty() {
+                    bail!("没有导入任何公钥");
+                }
+            }
+            KeyCommand::Export(options) => {
+                let content = account::export_keys(&db, &options.user).await?;
+                match &options.output {
+                    Some(path) => std::fs::write(path, content)
+                        .with_context(|| format!("写入文件失败: {}", path.display()))?,
+                    None => print!("{content}"),
+                }
+            }
+        }
+        Ok(())
+    })
+}
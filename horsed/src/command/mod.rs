@@ -1 +1,4 @@
+pub mod ctl;
+pub mod hostkey;
+pub mod key;
 pub mod user;
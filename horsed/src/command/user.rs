@@ -1 +1,98 @@
+//! `horsed user ...`: 本地账号管理, 与远程的 `admin users` 共用 [`crate::account`]
+use crate::account::{self, NewUser, UserChanges, UserRow};
+use crate::options::UserCommand;
+use crate::ssh::Capabilities;
+use anyhow::bail;
 
+fn print_users(rows: &[UserRow]) {
+    println!(
+        "{:<6} {:<16} {:<6} {:<9} {:<16} {:<5} NICK/EMAIL",
+        "ID", "NAME", "ROLE", "STATUS", "CAPS", "KEYS"
+    );
+    for row in rows {
+        let status = if row.enabled { "enabled" } else { "disabled" };
+        let contact = [row.nick.as_deref(), row.email.as_deref()]
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>()
+            .join(" ");
+        println!(
+            "{:<6} {:<16} {:<6} {:<9} {:<16} {:<5} {}",
+            row.id, row.name, row.role, status, row.caps, row.key_count, contact
+        );
+    }
+}
+
+pub fn run(command: UserCommand) -> anyhow::Result<()> {
+    stable::prelude::handle().block_on(async move {
+        let db = crate::db::db();
+        match command {
+            UserCommand::Add(options) => {
+                // 先校验公钥, 避免只创建了用户
+                if let Some(key) = &options.key {
+                    account::parse_key(&key.method, &key.key)?;
+                }
+                let new = NewUser {
+                    name: options.name,
+                    nick: options.nick,
+                    email: options.email,
+                    role: options.role,
+                };
+                let user = account::add_user(&db, new).await?;
+                println!("用户添加成功: name: {}, id: {}", user.name, user.id);
+
+                if let Some(key) = options.key {
+                    let key =
+                        account::add_key(&db, &user.name, &key.method, &key.key, key.comment, None)
+                            .await?;
+                    println!("公钥已添加: {}", account::fingerprint(&key.alg, &key.key));
+                }
+            }
+            UserCommand::Del(options) => {
+                let user = account::delete_user(&db, &options.name, None).await?;
+                println!("用户删除成功: {}", user.id);
+            }
+            UserCommand::Mod(options) => {
+                let enabled = match (options.enable, options.disable) {
+                    (true, _) => Some(true),
+                    (_, true) => Some(false),
+                    _ => None,
+                };
+                let changes = UserChanges {
+                    nick: options.nick,
+                    email: options.email,
+                    role: options.role,
+                    enabled,
+                    caps: options.caps,
+                };
+                if changes.nick.is_none()
+                    && changes.email.is_none()
+                    && changes.role.is_none()
+                    && changes.enabled.is_none()
+                    && changes.caps.is_none()
+                {
+                    bail!("没有需要修改的内容, 参见 horsed user mod --help");
+                }
+                let user = account::modify_user(&db, &options.name, changes, None).await?;
+                println!(
+                    "用户已修改: {} role={} enabled={} caps={} nick={} email={}",
+                    user.name,
+                    user.role,
+                    user.enabled,
+                    Capabilities::load(user.caps.as_deref()),
+                    user.nick.as_deref().unwrap_or("-"),
+                    user.email.as_deref().unwrap_or("-"),
+                );
+            }
+            UserCommand::List(options) => {
+                let rows = account::list_users(&db).await?;
+                if options.json {
+                    println!("{}", serde_json::to_string_pretty(&rows)?);
+                } else {
+                    print_users(&rows);
+                }
+            }
+        }
+        Ok(())
+    })
+}
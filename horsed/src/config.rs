@@ -0,0 +1,691 @@
+//! horsed 配置文件
+//!
+//! 默认读取工作目录下的 `horsed.toml`, 可以通过 `--config` 指定其他路径.
+//! 文件不存在时使用内置默认值, 所有字段都可以省略.
+//!
+//! ```toml
+//! [server]
+//! listen = "0.0.0.0:2222"
+//! setup_listen = "0.0.0.0:2223"
+//!
+//! [paths]
+//! data = "."
+//! repos = "repos"
+//! workspace = "workspace"
+//! host_key = "horsed.key"
+//! host_keys = "hostkeys"
+//!
+//! [database]
+//! url = "sqlite://horsed.db3?mode=rwc"
+//!
+//! [ssh]
+//! keepalive_secs = 5
+//! inactivity_timeout_secs = 0
+//! setup_inactivity_timeout_secs = 3600
+//! agent_forwarding = false
+//!
+//! [ssh.idle_timeout_secs]
+//! admin = 0
+//! user = 1800
+//!
+//! [auth]
+//! max_failures = 10
+//! window_secs = 600
+//! ban_secs = 3600
+//!
+//! [jobs]
+//! dir = "jobs"
+//! max_jobs = 256
+//! max_output_bytes = 4194304
+//! kill_grace_secs = 10
+//! max_concurrent = 0
+//! drain_timeout_secs = 300
+//!
+//! [workspace]
+//! mode = "shared"
+//! prune = false
+//!
+//! [log]
+//! dir = "."
+//! level = "info"
+//! max_files = 15
+//! ring_capacity = 30
+//!
+//! [metrics]
+//! listen = "127.0.0.1:9187"
+//! ```
+use crate::account::{ROLE_ADMIN, ROLE_USER};
+use anyhow::{bail, Context};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// 默认配置文件名
+pub const CONFIG_FILE: &str = "horsed.toml";
+
+static CONFIG: Lazy<RwLock<Arc<ServerConfig>>> =
+    Lazy::new(|| RwLock::new(Arc::new(ServerConfig::default())));
+
+/// 当前生效的配置
+pub fn config() -> Arc<ServerConfig> {
+    CONFIG.read().unwrap().clone()
+}
+
+/// 替换当前生效的配置
+pub fn set_config(config: ServerConfig) {
+    *CONFIG.write().unwrap() = Arc::new(config);
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub server: ServerSection,
+    pub paths: PathsSection,
+    pub database: DatabaseSection,
+    pub ssh: SshSection,
+    pub auth: AuthSection,
+    pub jobs: JobsSection,
+    pub workspace: WorkspaceSection,
+    pub log: LogSection,
+    pub metrics: MetricsSection,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSection {
+    /// 正式服务监听地址
+    pub listen: String,
+    /// 临时 (初始化) 服务监听地址
+    pub setup_listen: String,
+}
+
+impl Default for ServerSection {
+    fn default() -> Self {
+        Self {
+            listen: "0.0.0.0:2222".to_string(),
+            setup_listen: "0.0.0.0:2223".to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PathsSection {
+    /// 数据目录, 其余相对路径都以此为基准
+    pub data: PathBuf,
+    /// 裸仓库根目录
+    pub repos: PathBuf,
+    /// 工作目录根目录
+    pub workspace: PathBuf,
+    /// 服务端私钥 (Ed25519)
+    pub host_key: PathBuf,
+    /// 其他算法的主机密钥与轮换下来的旧密钥, 见 [`crate::key`]
+    pub host_keys: PathBuf,
+}
+
+impl Default for PathsSection {
+    fn default() -> Self {
+        Self {
+            data: PathBuf::from("."),
+            repos: PathBuf::from("repos"),
+            workspace: PathBuf::from("workspace"),
+            host_key: PathBuf::from("horsed.key"),
+            host_keys: PathBuf::from("hostkeys"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseSection {
+    /// 数据库地址, 未设置时使用数据目录下的 `horsed.db3`
+    pub url: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SshSection {
+    /// 心跳间隔, 0 表示关闭
+    pub keepalive_secs: u64,
+    /// 正式服务空闲断开时间, 0 表示不限制
+    pub inactivity_timeout_secs: u64,
+    /// 临时服务空闲断开时间, 0 表示不限制
+    pub setup_inactivity_timeout_secs: u64,
+    /// 是否允许 agent 转发; 任务都以服务用户运行, 其他用户的任务也能访问转发的 agent, 默认关闭
+    pub agent_forwarding: bool,
+    /// 按角色的空闲断开时间: 没有打开的通道和转发, 或者交互终端没有输入; 0 或未设置表示不限制
+    pub idle_timeout_secs: BTreeMap<String, u64>,
+}
+
+impl Default for SshSection {
+    fn default() -> Self {
+        Self {
+            keepalive_secs: 5,
+            inactivity_timeout_secs: 0,
+            setup_inactivity_timeout_secs: 3600,
+            agent_forwarding: false,
+            idle_timeout_secs: BTreeMap::new(),
+        }
+    }
+}
+
+/// 认证失败限流与地址封禁
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthSection {
+    /// 统计窗口内同一地址认证失败的连接数上限, 超出后封禁该地址, 0 表示不限制
+    pub max_failures: u32,
+    /// 失败次数的统计窗口
+    pub window_secs: u64,
+    /// 封禁时长
+    pub ban_secs: u64,
+}
+
+impl Default for AuthSection {
+    fn default() -> Self {
+        Self {
+            max_failures: 10,
+            window_secs: 600,
+            ban_secs: 3600,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JobsSection {
+    /// 任务输出日志目录, 每个任务一个 `<job_id>.log`
+    pub dir: PathBuf,
+    /// 内存中保留的任务数量, 超出时淘汰最早结束的任务 (历史记录仍在数据库中)
+    pub max_jobs: usize,
+    /// 每个任务在内存中缓存的输出字节数
+    pub max_output_bytes: usize,
+    /// 结束任务时, 发送 TERM/INT 之后等待多久再强制 KILL
+    pub kill_grace_secs: u64,
+    /// 同时运行的任务上限, 超出的任务排队等待, 0 表示不限制
+    /// 同一个工作区内的任务总是依次运行
+    pub max_concurrent: usize,
+    /// 停机 (drain) 时等待运行中任务结束的最长时间, 超时后结束任务并记录为 interrupted
+    pub drain_timeout_secs: u64,
+}
+
+impl Default for JobsSection {
+    fn default() -> Self {
+        Self {
+            dir: PathBuf::from("jobs"),
+            max_jobs: 256,
+            max_output_bytes: 4 * 1024 * 1024,
+            kill_grace_secs: 10,
+            max_concurrent: 0,
+            drain_timeout_secs: 300,
+        }
+    }
+}
+
+/// 任务工作目录模式
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WorkspaceMode {
+    /// 所有分支共用 `<workspace>/<repo>`, 切换分支时原地检出
+    #[default]
+    Shared,
+    /// 每个分支一个 `git worktree`, 不同分支可以并行构建
+    Branch,
+    /// 每个任务一个临时 `git worktree`, 任务结束后删除
+    Job,
+}
+
+impl WorkspaceMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WorkspaceMode::Shared => "shared",
+            WorkspaceMode::Branch => "branch",
+            WorkspaceMode::Job => "job",
+        }
+    }
+
+    pub fn parse(mode: &str) -> Option<Self> {
+        match mode.trim().to_ascii_lowercase().as_str() {
+            "shared" => Some(WorkspaceMode::Shared),
+            "branch" => Some(WorkspaceMode::Branch),
+            "job" => Some(WorkspaceMode::Job),
+            _ => None,
+        }
+    }
+}
+
+/// 工作目录默认设置, 可以通过 `admin repos workspace` 按仓库覆盖
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WorkspaceSection {
+    pub mode: WorkspaceMode,
+    /// 检出后清理上游已删除的文件和未跟踪的文件 (保留被忽略的文件与 `target/`)
+    pub prune: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogSection {
+    /// 日志文件目录
+    pub dir: PathBuf,
+    /// 未设置 RUST_LOG 时的默认过滤级别
+    pub level: String,
+    /// 保留的日志文件数量 (按天滚动)
+    pub max_files: usize,
+    /// `logs` 可查看的最近日志行数
+    pub ring_capacity: usize,
+}
+
+impl Default for LogSection {
+    fn default() -> Self {
+        Self {
+            dir: PathBuf::from("."),
+            level: "info".to_string(),
+            max_files: 15,
+            ring_capacity: 30,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsSection {
+    /// 提供 `GET /metrics` 的 HTTP 监听地址, 未设置时不开启
+    ///
+    /// 没有认证, 只能是 `127.0.0.1`/`::1` 等回环地址
+    pub listen: Option<String>,
+}
+
+impl ServerConfig {
+    /// 读取配置文件
+    ///
+    /// 显式指定的文件必须存在; 未指定时尝试当前目录下的 `horsed.toml`.
+    /// 读取后会校验配置, 并把数据目录转换为绝对路径.
+    pub fn load(path: Option<&Path>) -> anyhow::Result<Self> {
+        let mut config = match path {
+            Some(path) => Self::from_file(path)?,
+            None if Path::new(CONFIG_FILE).exists() => Self::from_file(Path::new(CONFIG_FILE))?,
+            None => Self::default(),
+        };
+
+        config.validate()?;
+
+        if config.paths.data.is_relative() {
+            config.paths.data = std::env::current_dir()?.join(&config.paths.data);
+        }
+
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("读取配置文件失败: {}", path.display()))?;
+        Self::parse(&text).with_context(|| format!("配置文件格式错误: {}", path.display()))
+    }
+
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        Ok(toml::from_str(text)?)
+    }
+
+    /// 校验配置, 一次列出全部问题
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let mut errors = vec![];
+
+        if self.server.listen.parse::<SocketAddr>().is_err() {
+            errors.push(format!(
+                "server.listen 不是合法地址: {}",
+                self.server.listen
+            ));
+        }
+        if self.server.setup_listen.parse::<SocketAddr>().is_err() {
+            errors.push(format!(
+                "server.setup_listen 不是合法地址: {}",
+                self.server.setup_listen
+            ));
+        }
+        if self.server.listen == self.server.setup_listen {
+            errors.push("server.listen 与 server.setup_listen 不能相同".to_string());
+        }
+        if self.paths.repos.as_os_str().is_empty() {
+            errors.push("paths.repos 不能为空".to_string());
+        }
+        if self.paths.workspace.as_os_str().is_empty() {
+            errors.push("paths.workspace 不能为空".to_string());
+        }
+        if self.paths.repos == self.paths.workspace {
+            errors.push("paths.repos 与 paths.workspace 不能相同".to_string());
+        }
+        if self
+            .database
+            .url
+            .as_deref()
+            .is_some_and(|url| url.trim().is_empty())
+        {
+            errors.push("database.url 不能为空".to_string());
+        }
+        for role in self.ssh.idle_timeout_secs.keys() {
+            if role != ROLE_ADMIN && role != ROLE_USER {
+                errors.push(format!("ssh.idle_timeout_secs 未知的角色: {role}"));
+            }
+        }
+        let throttled = self.auth.max_failures > 0;
+        if throttled && self.auth.window_secs == 0 {
+            errors.push("auth.window_secs 必须大于 0".to_string());
+        }
+        if throttled && self.auth.ban_secs == 0 {
+            errors.push("auth.ban_secs 必须大于 0".to_string());
+        }
+        if self.jobs.dir.as_os_str().is_empty() {
+            errors.push("jobs.dir 不能为空".to_string());
+        }
+        if self.jobs.max_jobs == 0 {
+            errors.push("jobs.max_jobs 必须大于 0".to_string());
+        }
+        if self.jobs.max_output_bytes == 0 {
+            errors.push("jobs.max_output_bytes 必须大于 0".to_string());
+        }
+        if tracing_subscriber::EnvFilter::try_new(&self.log.level).is_err() {
+            errors.push(format!("log.level 无法解析: {}", self.log.level));
+        }
+        if self.log.max_files == 0 {
+            errors.push("log.max_files 必须大于 0".to_string());
+        }
+        if self.log.ring_capacity == 0 {
+            errors.push("log.ring_capacity 必须大于 0".to_string());
+        }
+        if let Some(listen) = &self.metrics.listen {
+            match listen.parse::<SocketAddr>() {
+                // `/metrics` 没有认证, 只能监听本机, 远程抓取使用 `metrics` action 或反向代理
+                Ok(addr) if !addr.ip().is_loopback() => {
+                    errors.push(format!("metrics.listen 只能是本机回环地址: {listen}"));
+                }
+                Ok(_) => {}
+                Err(_) => errors.push(format!("metrics.listen 不是合法地址: {listen}")),
+            }
+        }
+
+        if !errors.is_empty() {
+            bail!("配置校验失败:\n  - {}", errors.join("\n  - "));
+        }
+
+        Ok(())
+    }
+
+    /// `horsed ctl reload` 重新读取的配置
+    ///
+    /// 只有处理请求时读取的 `workspace`, `ssh.idle_timeout_secs`, `jobs.kill_grace_secs`
+    /// 与 `jobs.drain_timeout_secs` 立即生效,
+    /// 其余配置在启动时使用, 保持当前的值并返回发生变化的配置段, 重启后生效.
+    pub fn reload(&self, new: &ServerConfig) -> (ServerConfig, Vec<&'static str>) {
+        let mut config = self.clone();
+        config.workspace = new.workspace.clone();
+        config.ssh.idle_timeout_secs = new.ssh.idle_timeout_secs.clone();
+        config.jobs.kill_grace_secs = new.jobs.kill_grace_secs;
+        config.jobs.drain_timeout_secs = new.jobs.drain_timeout_secs;
+
+        let mut restart = vec![];
+        if self.server != new.server {
+            restart.push("server");
+        }
+        if self.paths != new.paths {
+            restart.push("paths");
+        }
+        if self.database != new.database {
+            restart.push("database");
+        }
+        if config.ssh != new.ssh {
+            restart.push("ssh");
+        }
+        if self.auth != new.auth {
+            restart.push("auth");
+        }
+        if config.jobs != new.jobs {
+            restart.push("jobs");
+        }
+        if self.log != new.log {
+            restart.push("log");
+        }
+        if self.metrics != new.metrics {
+            restart.push("metrics");
+        }
+        (config, restart)
+    }
+
+    /// 相对路径以数据目录为基准
+    fn resolve(&self, path: &Path) -> PathBuf {
+        self.paths.data.join(path)
+    }
+
+    pub fn repos_dir(&self) -> PathBuf {
+        self.resolve(&self.paths.repos)
+    }
+
+    pub fn workspace_dir(&self) -> PathBuf {
+        self.resolve(&self.paths.workspace)
+    }
+
+    pub fn jobs_dir(&self) -> PathBuf {
+        self.resolve(&self.jobs.dir)
+    }
+
+    pub fn host_key_path(&self) -> PathBuf {
+        self.resolve(&self.paths.host_key)
+    }
+
+    pub fn host_keys_dir(&self) -> PathBuf {
+        self.resolve(&self.paths.host_keys)
+    }
+
+    pub fn log_dir(&self) -> PathBuf {
+        self.resolve(&self.log.dir)
+    }
+
+    /// `horsed ctl` 使用的控制 socket, 所在目录只有服务用户可以访问
+    pub fn ipc_path(&self) -> PathBuf {
+        self.resolve(Path::new("run/horsed.sock"))
+    }
+
+    pub fn database_url(&self) -> String {
+        match &self.database.url {
+            Some(url) => url.clone(),
+            None => format!(
+                "sqlite://{}?mode=rwc",
+                self.resolve(Path::new("horsed.db3")).display()
+            ),
+        }
+    }
+
+    pub fn listen_addr(&self) -> SocketAddr {
+        self.server.listen.parse().expect("配置未校验")
+    }
+
+    pub fn setup_listen_addr(&self) -> SocketAddr {
+        self.server.setup_listen.parse().expect("配置未校验")
+    }
+
+    pub fn metrics_addr(&self) -> Option<SocketAddr> {
+        self.metrics
+            .listen
+            .as_ref()
+            .map(|listen| listen.parse().expect("配置未校验"))
+    }
+
+    pub fn keepalive_interval(&self) -> Option<Duration> {
+        secs(self.ssh.keepalive_secs)
+    }
+
+    pub fn inactivity_timeout(&self) -> Option<Duration> {
+        secs(self.ssh.inactivity_timeout_secs)
+    }
+
+    /// `role` 角色的空闲断开时间
+    pub fn idle_timeout(&self, role: &str) -> Option<Duration> {
+        secs(self.ssh.idle_timeout_secs.get(role).copied().unwrap_or(0))
+    }
+
+    pub fn auth_window(&self) -> Duration {
+        Duration::from_secs(self.auth.window_secs)
+    }
+
+    pub fn ban_duration(&self) -> Duration {
+        Duration::from_secs(self.auth.ban_secs)
+    }
+
+    pub fn kill_grace(&self) -> Duration {
+        Duration::from_secs(self.jobs.kill_grace_secs)
+    }
+
+    pub fn drain_timeout(&self) -> Duration {
+        Duration::from_secs(self.jobs.drain_timeout_secs)
+    }
+
+    pub fn setup_inactivity_timeout(&self) -> Option<Duration> {
+        secs(self.ssh.setup_inactivity_timeout_secs)
+    }
+}
+
+fn secs(secs: u64) -> Option<Duration> {
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_config_is_valid() {
+        let config = ServerConfig::default();
+        config.validate().unwrap();
+        assert_eq!(config.listen_addr().port(), 2222);
+        assert_eq!(config.setup_listen_addr().port(), 2223);
+        assert_eq!(config.keepalive_interval(), Some(Duration::from_secs(5)));
+        assert_eq!(config.inactivity_timeout(), None);
+        assert_eq!(config.metrics_addr(), None);
+    }
+
+    #[test]
+    fn partial_config_keeps_defaults() {
+        let config = ServerConfig::parse(
+            r#"
+            [server]
+            listen = "127.0.0.1:3333"
+
+            [paths]
+            data = "/srv/horsed"
+
+            [jobs]
+            max_jobs = 8
+            "#,
+        )
+        .unwrap();
+        config.validate().unwrap();
+
+        assert_eq!(config.server.setup_listen, "0.0.0.0:2223");
+        assert!(!config.ssh.agent_forwarding);
+        assert_eq!(config.repos_dir(), PathBuf::from("/srv/horsed/repos"));
+        assert_eq!(
+            config.database_url(),
+            "sqlite:///srv/horsed/horsed.db3?mode=rwc"
+        );
+        assert_eq!(config.jobs.max_jobs, 8);
+        assert_eq!(config.jobs.max_output_bytes, 4 * 1024 * 1024);
+    }
+
+    #[test]
+    fn workspace_mode_parses_from_toml() {
+        let config = ServerConfig::parse("[workspace]\nmode = \"branch\"\nprune = true\n").unwrap();
+        assert_eq!(config.workspace.mode, WorkspaceMode::Branch);
+        assert!(config.workspace.prune);
+        assert_eq!(
+            ServerConfig::default().workspace.mode,
+            WorkspaceMode::Shared
+        );
+        assert!(ServerConfig::parse("[workspace]\nmode = \"tmp\"\n").is_err());
+    }
+
+    #[test]
+    fn reload_applies_request_time_settings_only() {
+        let current = ServerConfig::default();
+        let new = ServerConfig::parse(
+            r#"
+            [server]
+            listen = "127.0.0.1:3333"
+
+            [ssh.idle_timeout_secs]
+            user = 1800
+
+            [jobs]
+            kill_grace_secs = 3
+            drain_timeout_secs = 60
+
+            [workspace]
+            mode = "job"
+            "#,
+        )
+        .unwrap();
+
+        let (config, restart) = current.reload(&new);
+        assert_eq!(config.workspace.mode, WorkspaceMode::Job);
+        assert_eq!(config.kill_grace(), Duration::from_secs(3));
+        assert_eq!(config.drain_timeout(), Duration::from_secs(60));
+        assert_eq!(config.idle_timeout("user"), Some(Duration::from_secs(1800)));
+        assert_eq!(config.idle_timeout("admin"), None);
+        assert_eq!(config.server.listen, "0.0.0.0:2222");
+        assert_eq!(restart, ["server"]);
+        assert!(current.reload(&current).1.is_empty());
+    }
+
+    #[test]
+    fn unknown_field_is_rejected() {
+        assert!(ServerConfig::parse("[server]\nport = 2222\n").is_err());
+    }
+
+    #[test]
+    fn validate_reports_every_problem() {
+        let config = ServerConfig::parse(
+            r#"
+            [server]
+            listen = "nowhere"
+
+            [ssh.idle_timeout_secs]
+            guest = 60
+
+            [auth]
+            ban_secs = 0
+
+            [jobs]
+            max_jobs = 0
+
+            [metrics]
+            listen = "9187"
+            "#,
+        )
+        .unwrap();
+
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("server.listen"));
+        assert!(err.contains("ssh.idle_timeout_secs"));
+        assert!(err.contains("auth.ban_secs"));
+        assert!(err.contains("jobs.max_jobs"));
+        assert!(err.contains("metrics.listen"));
+    }
+
+    #[test]
+    fn metrics_listen_must_be_loopback() {
+        let metrics = |listen: &str| {
+            ServerConfig::parse(&format!("[metrics]\nlisten = \"{listen}\"\n"))
+                .unwrap()
+                .validate()
+        };
+        metrics("127.0.0.1:9187").unwrap();
+        metrics("[::1]:9187").unwrap();
+        for listen in ["0.0.0.0:9187", "[::]:9187", "192.168.1.10:9187"] {
+            let err = metrics(listen).unwrap_err().to_string();
+            assert!(err.contains("回环地址"), "{err}");
+        }
+    }
+}
@@ -2,5 +2,12 @@
 
 pub mod prelude;
 
+pub mod audit;
+pub mod ban;
+pub mod invite;
+pub mod job;
+pub mod repo;
+pub mod repo_member;
 pub mod ssh_pk;
 pub mod user;
+pub mod user_ca;
@@ -1,4 +1,11 @@
 //! `SeaORM` Entity, @generated by sea-orm-codegen 1.1.3
 
+pub use super::audit::Entity as Audit;
+pub use super::ban::Entity as Ban;
+pub use super::invite::Entity as Invite;
+pub use super::job::Entity as Job;
+pub use super::repo::Entity as Repo;
+pub use super::repo_member::Entity as RepoMember;
 pub use super::ssh_pk::Entity as SshPk;
 pub use super::user::Entity as User;
+pub use super::user_ca::Entity as UserCa;
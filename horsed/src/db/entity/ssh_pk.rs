@@ -12,6 +12,8 @@ pub struct Model {
     pub user_id: i32,
     pub enabled: bool,
     pub comment: Option<String>,
+    pub options: Option<String>,
+    pub caps: Option<String>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -13,14 +13,31 @@ pub struct Model {
     pub email: Option<String>,
     pub role: String,
     pub enabled: bool,
+    pub caps: Option<String>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
 pub enum Relation {
+    #[sea_orm(has_many = "super::repo::Entity")]
+    Repo,
+    #[sea_orm(has_many = "super::repo_member::Entity")]
+    RepoMember,
     #[sea_orm(has_many = "super::ssh_pk::Entity")]
     SshPk,
 }
 
+impl Related<super::repo::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Repo.def()
+    }
+}
+
+impl Related<super::repo_member::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::RepoMember.def()
+    }
+}
+
 impl Related<super::ssh_pk::Entity> for Entity {
     fn to() -> RelationDef {
         Relation::SshPk.def()
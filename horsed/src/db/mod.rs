@@ -1,3 +1,4 @@
+use crate::config::config;
 use anyhow::Context;
 use once_cell::sync::Lazy;
 use sea_orm::{ConnectOptions, Database, DatabaseConnection};
@@ -6,8 +7,8 @@ use std::time::Duration;
 pub mod entity;
 
 pub static DB: Lazy<DatabaseConnection> = Lazy::new(|| {
-    let url = "sqlite://horsed.db3?mode=rwc";
-    let mut opt = ConnectOptions::new(url);
+    let url = config().database_url();
+    let mut opt = ConnectOptions::new(&url);
     opt.connect_timeout(Duration::from_secs(8))
         .acquire_timeout(Duration::from_secs(8))
         .idle_timeout(Duration::from_secs(8))
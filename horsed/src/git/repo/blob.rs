@@ -0,0 +1,298 @@
+//! 增量同步的 blob 缓存
+//!
+//! 客户端上传的文件内容作为 blob 对象写入仓库的对象库, 按哈希去重,
+//! 检出时再从对象库还原到工作目录.
+use super::{git_command, Repo};
+use crate::prelude::*;
+use anyhow::Context;
+use stable::data::sync::{SyncEntry, MODE_EXECUTABLE, MODE_SYMLINK};
+use std::path::{Component, Path, PathBuf};
+use std::process::Stdio;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader};
+
+impl Repo {
+    /// 对象库中缺少的对象
+    pub async fn missing_objects(&self, oids: &[&str]) -> HorseResult<Vec<String>> {
+        if oids.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let mut child = git_command()
+            .current_dir(&self.dir)
+            .arg("cat-file")
+            .arg("--batch-check")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        let mut stdin = child.stdin.take().context("git cat-file stdin")?;
+        let mut stdout = child.stdout.take().context("git cat-file stdout")?;
+
+        // 边写边读, 避免输出填满管道
+        let input = oids
+            .iter()
+            .map(|oid| format!("{oid}\n"))
+            .collect::<String>();
+        let write = async move {
+            stdin.write_all(input.as_bytes()).await?;
+            stdin.shutdown().await
+        };
+        let read = async {
+            let mut out = String::new();
+            stdout.read_to_string(&mut out).await?;
+            Ok::<_, std::io::Error>(out)
+        };
+        let ((), out) = futures::try_join!(write, read)?;
+        child.wait().await?.exit_ok()?;
+
+        Ok(out
+            .lines()
+            .filter_map(|line| line.strip_suffix(" missing"))
+            .map(String::from)
+            .collect())
+    }
+
+    /// 把读到的内容写入对象库, 返回对象哈希和内容大小
+    pub async fn write_blob<R: AsyncRead + Unpin>(
+        &self,
+        mut reader: R,
+    ) -> HorseResult<(String, u64)> {
+        let mut child = git_command()
+            .current_dir(&self.dir)
+            .arg("hash-object")
+            .arg("-w")
+            .arg("--no-filters")
+            .arg("--stdin")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+
+        let mut stdin = child.stdin.take().context("git hash-object stdin")?;
+        let size = tokio::io::copy(&mut reader, &mut stdin).await?;
+        stdin.shutdown().await?;
+        drop(stdin);
+
+        let out = child.wait_with_output().await?;
+        if !out.status.success() {
+            let err = String::from_utf8_lossy(&out.stderr).trim().to_string();
+            tracing::error!("[git] hash-object failed: {}", err);
+            return Err(anyhow::anyhow!("git hash-object failed: {err}").into());
+        }
+
+        Ok((
+            String::from_utf8_lossy(&out.stdout).trim().to_string(),
+            size,
+        ))
+    }
+
+    /// 按清单把对象库中的文件还原到工作目录, 没有哈希的条目表示删除
+    #[tracing::instrument(skip(to, entries), fields(to = ?to.as_ref(), entries = entries.len()))]
+    pub async fn materialize(
+        &self,
+        to: impl AsRef<Path>,
+        entries: &[SyncEntry],
+    ) -> HorseResult<()> {
+        let to = to.as_ref();
+        let mut child = git_command()
+            .current_dir(&self.dir)
+            .arg("cat-file")
+            .arg("--batch")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        let mut stdin = child.stdin.take().context("git cat-file stdin")?;
+        let mut stdout = BufReader::new(child.stdout.take().context("git cat-file stdout")?);
+
+        for entry in entries {
+            let path = work_file(to, &entry.path)?;
+            let Some(oid) = &entry.oid else {
+                remove_path(&path).await?;
+                continue;
+            };
+
+            stdin.write_all(format!("{oid}\n").as_bytes()).await?;
+            stdin.flush().await?;
+            let mut header = String::new();
+            stdout.read_line(&mut header).await?;
+            let size = batch_object_size(&header).with_context(|| format!("对象不存在: {oid}"))?;
+
+            if let Some(parent) = path.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            remove_path(&path).await?;
+
+            let mut content = (&mut stdout).take(size);
+            if entry.mode == MODE_SYMLINK {
+                let mut link = vec![];
+                content.read_to_end(&mut link).await?;
+                write_symlink(&path, &link).await?;
+            } else {
+                let mut file = tokio::fs::File::create(&path).await?;
+                tokio::io::copy(&mut content, &mut file).await?;
+                file.flush().await?;
+                set_executable(&path, entry.mode == MODE_EXECUTABLE)?;
+            }
+
+            // 每个对象的内容后面跟一个换行
+            let mut newline = [0u8; 1];
+            stdout.read_exact(&mut newline).await?;
+        }
+
+        stdin.shutdown().await?;
+        drop(stdin);
+        child.wait().await?.exit_ok()?;
+
+        tracing::info!("[git] materialize done");
+        Ok(())
+    }
+}
+
+/// 清单中的路径转换为工作目录下的路径
+///
+/// 只接受普通的相对路径, 不允许写入 `.git` 或者经由符号链接写到工作目录之外
+fn work_file(to: &Path, path: &str) -> HorseResult<PathBuf> {
+    let relative = Path::new(path);
+    let mut components = relative.components().peekable();
+    if components.peek().is_none() {
+        return Err(anyhow::anyhow!("无效的同步路径: {path:?}").into());
+    }
+
+    let mut target = to.to_path_buf();
+    for (idx, component) in components.enumerate() {
+        let Component::Normal(name) = component else {
+            return Err(anyhow::anyhow!("无效的同步路径: {path:?}").into());
+        };
+        if idx == 0 && name.eq_ignore_ascii_case(".git") {
+            return Err(anyhow::anyhow!("不允许同步 .git 目录: {path:?}").into());
+        }
+        if target != to && target.is_symlink() {
+            return Err(anyhow::anyhow!("同步路径经过符号链接: {path:?}").into());
+        }
+        target.push(name);
+    }
+
+    Ok(target)
+}
+
+/// 解析 `git cat-file --batch` 的对象头: `<oid> blob <size>`
+fn batch_object_size(header: &str) -> Option<u64> {
+    let mut parts = header.split_whitespace();
+    let _oid = parts.next()?;
+    if parts.next()? != "blob" {
+        return None;
+    }
+    parts.next()?.parse().ok()
+}
+
+async fn remove_path(path: &Path) -> HorseResult<()> {
+    match tokio::fs::symlink_metadata(path).await {
+        Ok(meta) if meta.is_dir() => tokio::fs::remove_dir_all(path).await?,
+        Ok(_) => tokio::fs::remove_file(path).await?,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err.into()),
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+async fn write_symlink(path: &Path, link: &[u8]) -> HorseResult<()> {
+    use std::os::unix::ffi::OsStrExt;
+    tokio::fs::symlink(std::ffi::OsStr::from_bytes(link), path).await?;
+    Ok(())
+}
+
+/// 与 `core.symlinks=false` 一致, 链接目标写成普通文件
+#[cfg(not(unix))]
+async fn write_symlink(path: &Path, link: &[u8]) -> HorseResult<()> {
+    tokio::fs::write(path, link).await?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn set_executable(path: &Path, executable: bool) -> HorseResult<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = if executable { 0o755 } else { 0o644 };
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_executable(_path: &Path, _executable: bool) -> HorseResult<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use stable::data::sync::MODE_FILE;
+
+    #[test]
+    fn work_file_rejects_escapes() {
+        let to = Path::new("/ws/app");
+        assert_eq!(
+            work_file(to, "src/lib.rs").unwrap(),
+            PathBuf::from("/ws/app/src/lib.rs")
+        );
+        assert!(work_file(to, "../etc/passwd").is_err());
+        assert!(work_file(to, "/etc/passwd").is_err());
+        assert!(work_file(to, "src/../../x").is_err());
+        assert!(work_file(to, ".git/config").is_err());
+        assert!(work_file(to, "").is_err());
+    }
+
+    #[test]
+    fn batch_object_size_parses_header() {
+        assert_eq!(batch_object_size("abc blob 12\n"), Some(12));
+        assert_eq!(batch_object_size("abc missing\n"), None);
+        assert_eq!(batch_object_size("abc tree 12\n"), None);
+    }
+
+    #[tokio::test]
+    async fn blobs_roundtrip_through_object_store() {
+        let dir = std::env::temp_dir().join(format!(
+            "horsed-blob-{}",
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        ));
+        let repo = Repo::create_bare(dir.join("repo.git")).await.unwrap();
+        let work = dir.join("work");
+        std::fs::create_dir_all(work.join("stale")).unwrap();
+        std::fs::write(work.join("stale/old.rs"), "old").unwrap();
+
+        // `hello\n` 的 blob 哈希
+        let hello = "ce013625030ba8dba906f756967f9e9ca394464a";
+        assert_eq!(repo.missing_objects(&[hello]).await.unwrap(), [hello]);
+
+        let (oid, size) = repo.write_blob(&b"hello\n"[..]).await.unwrap();
+        assert_eq!((oid.as_str(), size), (hello, 6));
+        assert!(repo.missing_objects(&[hello]).await.unwrap().is_empty());
+
+        let entries = [
+            SyncEntry {
+                path: "src/hello.txt".to_string(),
+                oid: Some(hello.to_string()),
+                mode: MODE_FILE,
+                size,
+            },
+            SyncEntry {
+                path: "stale/old.rs".to_string(),
+                oid: None,
+                mode: 0,
+                size: 0,
+            },
+        ];
+        repo.materialize(&work, &entries).await.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(work.join("src/hello.txt")).unwrap(),
+            "hello\n"
+        );
+        assert!(!work.join("stale/old.rs").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -6,7 +6,9 @@ use std::{
 };
 use tokio::process::Command;
 
-#[derive(Debug)]
+mod blob;
+
+#[derive(Clone, Debug)]
 pub struct Repo {
     dir: PathBuf,
 }
@@ -130,6 +132,143 @@ impl Repo {
         Ok(Repo::from(to))
     }
 
+    /// 检出代码到独立的 `git worktree`, 工作目录已存在时原地切换
+    ///
+    /// 使用游离的 HEAD, 同一个分支可以同时检出到多个工作目录
+    #[tracing::instrument(skip(to), fields(to = ?to.as_ref()))]
+    pub async fn worktree_checkout(&self, to: impl AsRef<Path>, branch: &str) -> HorseResult<Self> {
+        let to = to.as_ref();
+
+        if to.join(".git").exists() {
+            let out = git_command()
+                .current_dir(to)
+                .arg("checkout")
+                .arg("-f")
+                .arg("--detach")
+                .arg(branch)
+                .output()
+                .await?;
+            if out.status.success() {
+                tracing::info!("[git] worktree checkout done");
+                return Ok(Repo::from(to));
+            }
+            // 工作目录损坏或者元数据已经被清理, 重新创建
+            tracing::warn!(
+                "[git] worktree checkout failed, recreate: {}",
+                String::from_utf8_lossy(&out.stderr).trim()
+            );
+        }
+
+        if to.exists() {
+            tokio::fs::remove_dir_all(to).await?;
+        }
+        self.worktree_prune().await?;
+
+        let out = git_command()
+            .current_dir(&self.dir)
+            .arg("worktree")
+            .arg("add")
+            .arg("--force")
+            .arg("--detach")
+            .arg(to)
+            .arg(branch)
+            .output()
+            .await?;
+        if !out.status.success() {
+            let err = String::from_utf8_lossy(&out.stderr).trim().to_string();
+            tracing::error!("{}", err);
+            return Err(anyhow::anyhow!("git worktree add failed: {err}").into());
+        }
+
+        tracing::info!("[git] worktree add done");
+        Ok(Repo::from(to))
+    }
+
+    /// 删除 `git worktree` 及其工作目录
+    #[tracing::instrument(skip(to), fields(to = ?to.as_ref()))]
+    pub async fn worktree_remove(&self, to: impl AsRef<Path>) -> HorseResult<()> {
+        let to = to.as_ref();
+        let out = git_command()
+            .current_dir(&self.dir)
+            .arg("worktree")
+            .arg("remove")
+            .arg("--force")
+            .arg(to)
+            .output()
+            .await?;
+        if !out.status.success() {
+            tracing::warn!(
+                "[git] worktree remove failed: {}",
+                String::from_utf8_lossy(&out.stderr).trim()
+            );
+            if to.exists() {
+                tokio::fs::remove_dir_all(to).await?;
+            }
+            self.worktree_prune().await?;
+        }
+        Ok(())
+    }
+
+    /// 清理已经不存在的 `git worktree` 记录
+    async fn worktree_prune(&self) -> HorseResult<()> {
+        git_command()
+            .current_dir(&self.dir)
+            .arg("worktree")
+            .arg("prune")
+            .output()
+            .await?
+            .status
+            .exit_ok()?;
+        Ok(())
+    }
+
+    /// 清理工作目录中未跟踪的文件, 包括上游已经删除的文件
+    ///
+    /// 被忽略的文件以及 `target/` 目录保留, 避免丢失构建缓存
+    #[tracing::instrument(skip(to), fields(to = ?to.as_ref()))]
+    pub async fn clean(&self, to: impl AsRef<Path>) -> HorseResult<()> {
+        let to = to.as_ref();
+        let mut cmd = git_command();
+        cmd.current_dir(to);
+        // `git --work-tree` 方式检出的目录没有 .git, 需要指定裸仓库
+        if !to.join(".git").exists() {
+            cmd.arg("--git-dir")
+                .arg(&self.dir)
+                .arg("--work-tree")
+                .arg(to);
+        }
+
+        let out = cmd
+            .arg("clean")
+            .arg("-fd")
+            .arg("-e")
+            .arg("/target")
+            .output()
+            .await?;
+        if !out.status.success() {
+            let err = String::from_utf8_lossy(&out.stderr).trim().to_string();
+            tracing::error!("{}", err);
+            return Err(anyhow::anyhow!("git clean failed: {err}").into());
+        }
+
+        tracing::info!("[git] clean done");
+        Ok(())
+    }
+
+    /// 裸仓库的 HEAD 指向分支, 检出游离提交之后恢复默认分支
+    pub async fn set_head(&self, branch: &str) -> HorseResult<()> {
+        git_command()
+            .current_dir(&self.dir)
+            .arg("symbolic-ref")
+            .arg("HEAD")
+            .arg(format!("refs/heads/{branch}"))
+            .output()
+            .await?
+            .status
+            .exit_ok()?;
+        Ok(())
+    }
+
     pub async fn rev_parse(&self, revision: impl AsRef<str>) -> HorseResult<String> {
         let mut cmd = Command::new("git");
 
@@ -152,8 +291,9 @@ impl Repo {
         Ok(stdout.trim().to_string())
     }
 
-    #[tracing::instrument(skip(to, patch), fields(to = ?to.as_ref(), patch = patch.as_ref().len()))]
-    pub async fn apply(&self, to: impl AsRef<Path>, patch: impl AsRef<[u8]>) -> HorseResult<()> {
+    /// 在 `to` 中应用补丁文件, 补丁通过标准输入交给 `git apply`, 不读入内存
+    #[tracing::instrument(skip(to, patch), fields(to = ?to.as_ref(), patch = ?patch.as_ref()))]
+    pub async fn apply(&self, to: impl AsRef<Path>, patch: impl AsRef<Path>) -> HorseResult<()> {
         let mut cmd = Command::new("git");
 
         #[cfg(target_os = "windows")]
@@ -163,19 +303,15 @@ impl Repo {
             cmd.creation_flags(CREATE_NO_WINDOW);
         }
 
-        let mut cmd = cmd
+        let patch = std::fs::File::open(patch.as_ref())?;
+        let cmd = cmd
             .current_dir(to.as_ref())
             .arg("apply")
             .arg("--allow-empty")
-            .stdin(Stdio::piped())
+            .stdin(Stdio::from(patch))
+            .stderr(Stdio::piped())
             .spawn()?;
 
-        let mut stdin = cmd.stdin.take().unwrap();
-        use tokio::io::AsyncWriteExt;
-        stdin.write_all(patch.as_ref()).await?;
-        // send eof
-        drop(stdin);
-
         let output = cmd.wait_with_output().await?;
         if !output.status.success() {
             let err = String::from_utf8_lossy(&output.stderr).trim().to_string();
@@ -220,3 +356,17 @@ impl Repo {
         Ok(())
     }
 }
+
+fn git_command() -> Command {
+    #[allow(unused_mut)]
+    let mut cmd = Command::new("git");
+
+    #[cfg(target_os = "windows")]
+    {
+        use std::os::windows::process::CommandExt;
+        const CREATE_NO_WINDOW: u32 = 0x08000000;
+        cmd.creation_flags(CREATE_NO_WINDOW);
+    }
+
+    cmd
+}
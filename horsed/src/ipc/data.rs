@@ -1,11 +1,110 @@
-use serde::{Deserialize, Serialize};
-
-/// Horsed Ipc 数据
-#[derive(Serialize, Deserialize, Clone)]
-#[serde(rename_all = "snake_case")]
-pub enum Data {
-    /// Git Hooks 事件
-    GitHook { kind: String, args: Vec<String> },
-    /// 退出应用
-    Exit,
-}
+//! `horsed ctl` 协议
+//!
+//! 客户端与服务端通过本地 socket 交换 JSON, 每行一条消息: 客户端发送一个 [`Request`],
+//! 服务端回复一个 [`Response`], 同一个连接可以依次发送多个请求.
+use crate::ssh::JobSummary;
+use serde::{Deserialize, Serialize};
+
+/// ctl 请求
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+pub enum Request {
+    /// 服务状态
+    Status,
+    /// 当前连接
+    Sessions,
+    /// 任务列表, 默认只列出运行中和排队中的任务
+    Jobs { all: bool, limit: usize },
+    /// 结束任务, `signal` 为 TERM/INT/KILL
+    KillJob { id: String, signal: String },
+    /// 重新读取配置文件
+    ReloadConfig,
+    /// 切换到新的日志文件
+    RotateLogs,
+    /// 立即停止服务, 运行中的任务会被中断; `horsed ctl shutdown` 只有带 `--now` 时才发送
+    Shutdown,
+    /// 不再接受新的请求, 等待任务结束后退出; `timeout_secs` 为空时使用 `jobs.drain_timeout_secs`
+    Drain { timeout_secs: Option<u64> },
+}
+
+/// ctl 回复
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Response {
+    Status(ServerStatus),
+    Sessions {
+        sessions: Vec<SessionInfo>,
+    },
+    Jobs {
+        jobs: Vec<JobSummary>,
+    },
+    /// 操作完成
+    Done {
+        message: String,
+    },
+    /// 操作失败, `code` 为 `HSSH_*` 错误码
+    Error {
+        code: String,
+        message: String,
+    },
+}
+
+impl Response {
+    pub fn done(message: impl Into<String>) -> Self {
+        Response::Done {
+            message: message.into(),
+        }
+    }
+
+    pub fn error(code: &str, message: impl Into<String>) -> Self {
+        Response::Error {
+            code: code.to_string(),
+            message: message.into(),
+        }
+    }
+}
+
+/// `horsed ctl status`
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ServerStatus {
+    pub version: String,
+    pub pid: u32,
+    pub listen: String,
+    pub started_at_ms: u64,
+    pub uptime_secs: u64,
+    pub sessions: usize,
+    pub running_jobs: usize,
+    pub queued_jobs: usize,
+    /// 正在 drain, 不再接受新的请求
+    #[serde(default)]
+    pub draining: bool,
+    /// 配置文件路径, 使用默认配置时为空
+    pub config: Option<String>,
+}
+
+/// 一个 SSH 连接
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SessionInfo {
+    pub id: usize,
+    pub peer: Option<String>,
+    /// 认证通过前为空
+    pub user: Option<String>,
+    #[serde(default)]
+    pub role: Option<String>,
+    pub action: Option<String>,
+    pub connected_at_ms: u64,
+    /// 打开的通道数
+    #[serde(default)]
+    pub channels: usize,
+    /// 端口转发, `L host:port` 为本地转发的目标, `R addr:port` 为远程转发的监听地址
+    #[serde(default)]
+    pub forwards: Vec<String>,
+    /// 收到和发送的字节数, 包括 SSH 协议本身的数据
+    #[serde(default)]
+    pub bytes_in: u64,
+    #[serde(default)]
+    pub bytes_out: u64,
+    /// 最近一次活动: 打开或关闭通道, 交互终端的输入
+    #[serde(default)]
+    pub last_active_ms: u64,
+}
@@ -1,165 +1,338 @@
+//! 本地控制通道
+//!
+//! 正式服务在本地 socket 上提供 [`data::Request`]/[`data::Response`] 协议,
+//! `horsed ctl` 通过它查看和管理正在运行的服务, 不需要建立 SSH 连接.
+//!
+//! Unix 下 socket 是数据目录中的 `run/horsed.sock` (见 [`crate::config::ServerConfig::ipc_path`]),
+//! 目录权限 0700, socket 权限 0600, 只有运行 horsed 的用户可以连接.
+//! 不能使用 Linux 的抽象命名空间 socket: 它没有文件权限, 本机任何用户都可以发送 shutdown/kill_job.
 use crate::prelude::*;
-use anyhow::Context;
+use anyhow::{anyhow, Context};
+use data::{Request, Response, ServerStatus};
 use interprocess::local_socket::{
     tokio::{prelude::*, Listener, Stream},
-    GenericNamespaced, ListenerOptions,
+    ListenerOptions, Name,
 };
+use stable::task::SpawnEssentialTaskHandle;
 use std::io::ErrorKind::AddrInUse;
+use std::path::{Path, PathBuf};
 
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
 pub mod data;
 
-static IPC: &str = "horsed.sock";
-
 /// 创建一个 ipc 监听
 pub async fn listen() -> HorseResult<Listener> {
-    let ipc_name = IPC.to_ns_name::<GenericNamespaced>()?;
-    let opts = ListenerOptions::new().name(ipc_name);
+    listen_on(&crate::config::config().ipc_path()).await
+}
 
-    let listener = match opts.create_tokio() {
-        // ipc 已被占用
-        Err(err) if err.kind() == AddrInUse => {
-            let stream = match connect().await {
-                #[allow(unused)]
-                Err(err) => {
-                    // FIXME: interprocess 库在 macOS 上退出不会自动清理 ipc 文件
-                    #[cfg(target_os = "macos")]
-                    {
-                        // 尝试连接 ipc, 如果失败, 则删除 ipc 文件
-                        let ipc_path = std::path::PathBuf::from("/tmp").join(IPC);
-                        if ipc_path.exists() {
-                            std::fs::remove_file(ipc_path)?;
-                        }
-
-                        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
-                        tracing::info!("尝试重新连接: {}", IPC);
-                        return Box::pin(listen()).await;
-                    }
+#[cfg(unix)]
+fn ipc_name(path: &Path) -> HorseResult<Name<'_>> {
+    use interprocess::local_socket::{GenericFilePaths, ToFsName};
+    Ok(path.to_fs_name::<GenericFilePaths>()?)
+}
 
-                    #[allow(unreachable_code)]
-                    Err(err).context("FIXME: IPC 既无法创建, 也无法连接.")?
-                }
-                x => x?,
-            };
+/// Windows 下使用命名管道, 不在文件系统中
+#[cfg(not(unix))]
+fn ipc_name(path: &Path) -> HorseResult<Name<'static>> {
+    use interprocess::local_socket::{GenericNamespaced, ToNsName};
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "horsed.sock".to_string());
+    Ok(name.to_ns_name::<GenericNamespaced>()?)
+}
 
-            // TODO: 检查连接状态
-            let _ = stream;
+/// 创建 socket 所在的目录并收紧权限
+#[cfg(unix)]
+fn private_dir(path: &Path) -> HorseResult<()> {
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
 
-            return Err(err.into());
+    let Some(dir) = path.parent() else {
+        return Ok(());
+    };
+    std::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)
+        .with_context(|| format!("无法创建目录: {}", dir.display()))?;
+    // 目录可能是之前以其他权限创建的
+    std::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o700))?;
+    Ok(())
+}
+
+async fn listen_on(path: &Path) -> HorseResult<Listener> {
+    #[cfg(unix)]
+    private_dir(path)?;
+
+    let listener = match ListenerOptions::new().name(ipc_name(path)?).create_tokio() {
+        // ipc 已被占用
+        Err(err) if err.kind() == AddrInUse => {
+            if connect_to(path).await.is_ok() {
+                // 另一个 horsed 正在运行
+                return Err(err.into());
+            }
+
+            // 上次退出时没有清理的 socket 文件
+            tracing::info!("删除遗留的 ipc 文件: {}", path.display());
+            std::fs::remove_file(path)?;
+            ListenerOptions::new()
+                .name(ipc_name(path)?)
+                .create_tokio()?
         }
         x => x?,
     };
 
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    }
+
     Ok(listener)
 }
 
 /// 创建一个 ipc 连接
 pub async fn connect() -> HorseResult<Stream> {
-    let name = IPC.to_ns_name::<GenericNamespaced>().unwrap();
-    Ok(Stream::connect(name).await?)
+    connect_to(&crate::config::config().ipc_path()).await
+}
+
+async fn connect_to(path: &Path) -> HorseResult<Stream> {
+    Ok(Stream::connect(ipc_name(path)?).await?)
+}
+
+/// 发送一个请求并等待回复
+pub async fn request(request: &Request) -> HorseResult<Response> {
+    request_on(connect().await?, request).await
+}
+
+async fn request_on(conn: Stream, request: &Request) -> HorseResult<Response> {
+    let (recver, mut sender) = conn.split();
+    let mut line = serde_json::to_string(request).context("序列化请求失败")?;
+    line.push('\n');
+    sender.write_all(line.as_bytes()).await?;
+
+    let mut reply = String::new();
+    BufReader::new(recver).read_line(&mut reply).await?;
+    if reply.is_empty() {
+        return Err(anyhow!("服务端关闭了连接").into());
+    }
+    Ok(serde_json::from_str(&reply).context("无法解析服务端回复")?)
+}
+
+fn not_ready() -> Response {
+    Response::error("HSSH_CTL_NOT_READY", "正式服务尚未启动")
+}
+
+/// 控制通道的服务端
+#[derive(Clone)]
+pub struct Control {
+    /// 启动时指定的配置文件, `reload_config` 重新读取它
+    config_path: Option<PathBuf>,
+    /// 结束服务
+    exit: SpawnEssentialTaskHandle,
 }
 
-pub async fn run() -> HorseResult<()> {
-    loop {
-        let conn = match listen().await?.accept().await {
-            Ok(c) => c,
-            Err(err) => {
-                tracing::info!("Error while accepting connection: {err}");
+impl Control {
+    pub fn new(config_path: Option<PathBuf>, exit: SpawnEssentialTaskHandle) -> Self {
+        Self { config_path, exit }
+    }
+
+    /// 在控制 socket 上处理 ctl 请求
+    pub async fn run(self) -> HorseResult<()> {
+        self.serve(listen().await?).await
+    }
+
+    async fn serve(self, listener: Listener) -> HorseResult<()> {
+        loop {
+            let conn = match listener.accept().await {
+                Ok(c) => c,
+                Err(err) => {
+                    tracing::info!("Error while accepting connection: {err}");
+                    continue;
+                }
+            };
+
+            let control = self.clone();
+            tokio::spawn(async move {
+                if let Err(err) = control.handle_conn(conn).await {
+                    tracing::error!("Error while handling connection: {err}");
+                }
+            });
+        }
+    }
+
+    async fn handle_conn(&self, conn: Stream) -> HorseResult<()> {
+        let (recver, mut sender) = conn.split();
+        let mut lines = BufReader::new(recver).lines();
+
+        while let Some(line) = lines.next_line().await? {
+            if line.trim().is_empty() {
                 continue;
             }
-        };
+            let request = serde_json::from_str::<Request>(&line);
+            tracing::info!("IPC 请求: {}", line.trim());
+
+            let (response, shutdown) = match request {
+                Ok(request) => (self.handle(&request).await, request == Request::Shutdown),
+                Err(err) => (
+                    Response::error("HSSH_CTL_BAD_REQUEST", format!("无法解析请求: {err}")),
+                    false,
+                ),
+            };
 
-        tokio::spawn(async move {
-            if let Err(err) = handle_conn(conn).await {
-                tracing::error!("Error while handling connection: {err}");
+            let mut reply = serde_json::to_string(&response).context("序列化回复失败")?;
+            reply.push('\n');
+            sender.write_all(reply.as_bytes()).await?;
+
+            if shutdown {
+                tracing::warn!("收到 ctl shutdown, 停止服务");
+                self.exit.exit();
+                break;
             }
-        });
+        }
+        Ok(())
     }
-}
 
-async fn handle_conn(conn: Stream) -> HorseResult<()> {
-    let mut recver = BufReader::new(&conn);
-    let mut sender = &conn;
+    async fn handle(&self, request: &Request) -> Response {
+        let server = crate::ssh::server();
 
-    // Allocate a sizeable buffer for receiving. This size should be big enough and easy to
-    // find for the allocator.
-    let mut buffer = String::with_capacity(128);
+        match request {
+            Request::Status => {
+                let mut status = match server {
+                    Some(server) => server.status().await,
+                    None => ServerStatus::default(),
+                };
+                status.version = env!("CARGO_PKG_VERSION").to_string();
+                status.pid = std::process::id();
+                status.listen = crate::config::config().listen_addr().to_string();
+                status.draining = crate::ssh::drain::is_draining();
+                status.config = self
+                    .config_path
+                    .as_ref()
+                    .map(|path| path.display().to_string());
+                Response::Status(status)
+            }
+            Request::ReloadConfig => self.reload_config(),
+            Request::RotateLogs => match crate::logger::rotate() {
+                Ok(Some(path)) => Response::done(format!("旧日志已移动到: {}", path.display())),
+                Ok(None) => Response::done("已重新打开日志文件"),
+                Err(err) => Response::error("HSSH_CTL_FAILED", format!("{err:#}")),
+            },
+            Request::Shutdown => Response::done("服务正在停止"),
+            Request::Drain { timeout_secs } => match server {
+                Some(_) => {
+                    let timeout = timeout_secs.map(std::time::Duration::from_secs);
+                    if crate::ssh::drain::start(timeout) {
+                        Response::done("开始 drain: 不再接受新的请求, 任务结束后服务退出")
+                    } else {
+                        Response::done("服务已经在 drain")
+                    }
+                }
+                None => not_ready(),
+            },
+            Request::Sessions => match server {
+                Some(server) => server.sessions(),
+                None => not_ready(),
+            },
+            Request::Jobs { all, limit } => match server {
+                Some(server) => server.jobs(*all, *limit).await,
+                None => not_ready(),
+            },
+            Request::KillJob { id, signal } => match server {
+                Some(server) => server.kill_job(id, signal).await,
+                None => not_ready(),
+            },
+        }
+    }
 
-    // Describe the receive operation as receiving a line into our big buffer.
-    let _recv = recver.read_line(&mut buffer).await;
-    sender.write_all(b"Hello from server!\n").await?;
+    /// 重新读取配置文件, 见 [`crate::config::ServerConfig::reload`]
+    fn reload_config(&self) -> Response {
+        let new = match crate::config::ServerConfig::load(self.config_path.as_deref()) {
+            Ok(config) => config,
+            Err(err) => return Response::error("HSSH_CONFIG_INVALID", format!("{err:#}")),
+        };
 
-    // Produce our output!
-    println!("Client answered: {}", buffer.trim());
-    Ok(())
+        let (config, restart) = crate::config::config().reload(&new);
+        crate::config::set_config(config);
+        tracing::info!("配置已重新载入, 需要重启的配置: {restart:?}");
+
+        if restart.is_empty() {
+            Response::done("配置已重新载入")
+        } else {
+            Response::done(format!(
+                "配置已重新载入, 以下配置需要重启 horsed 后生效: {}",
+                restart.join(", ")
+            ))
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{connect, listen, IPC};
-    use interprocess::local_socket::tokio::prelude::*;
+    use super::*;
     use stable::prelude::handle;
     use stable::task::TaskManager;
-    use tokio::{
-        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
-        try_join,
-    };
+
+    #[test]
+    fn request_wire_format() {
+        let request = Request::KillJob {
+            id: "job-1".to_string(),
+            signal: "TERM".to_string(),
+        };
+        let line = serde_json::to_string(&request).unwrap();
+        assert_eq!(line, r#"{"cmd":"kill_job","id":"job-1","signal":"TERM"}"#);
+        assert_eq!(serde_json::from_str::<Request>(&line).unwrap(), request);
+        assert_eq!(
+            serde_json::from_str::<Request>(r#"{"cmd":"status"}"#).unwrap(),
+            Request::Status
+        );
+    }
 
     #[test]
     fn test_ipc_stream() {
-        let mut tm = TaskManager::default();
-        let handler = tm.spawn_essential_handle();
-        let h = handler.clone();
-        handler.spawn(async move {
-            let listener = listen().await.unwrap();
-
-            h.spawn(async move {
-                println!("Connecting to {}", IPC);
-                let conn = connect().await.unwrap();
-
-                // This consumes our connection and splits it into two halves, so that we can concurrently use
-                // both.
-                let (recver, mut sender) = conn.split();
-                let mut recver = BufReader::new(recver);
-
-                // Allocate a sizeable buffer for receiving. This size should be enough and should be easy to
-                // find for the allocator.
-                let mut buffer = String::with_capacity(128);
-
-                // Describe the send operation as writing our whole string.
-                let send = sender.write_all(b"Hello from client!\n");
-                // Describe the receive operation as receiving until a newline into our buffer.
-                let recv = recver.read_line(&mut buffer);
-
-                // Concurrently perform both operations.
-                try_join!(send, recv).unwrap();
-
-                // Close the connection a bit earlier than you'd think we would. Nice practice!
-                drop((recver, sender));
-
-                // Display the results when we're done!
-                println!("Server answered: {}", buffer.trim());
-                Ok(())
-            });
+        let dir = std::env::temp_dir().join(format!("horsed-ipc-{}", std::process::id()));
+        let path = dir.join("run").join("horsed-test.sock");
 
-            loop {
-                let conn = match listener.accept().await {
-                    Ok(c) => c,
-                    Err(err) => {
-                        eprintln!("Error while accepting connection: {err}");
-                        continue;
-                    }
-                };
+        let tm = TaskManager::default();
+        let control = Control::new(None, tm.spawn_essential_handle());
 
-                h.spawn(async move {
-                    if let Err(err) = super::handle_conn(conn).await {
-                        eprintln!("Error while handling connection: {err}");
-                    }
-                    Ok(())
-                });
+        handle().block_on(async move {
+            let listener = listen_on(&path).await.unwrap();
+            tokio::spawn(control.serve(listener));
+            let path = path.as_path();
+
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode();
+                assert_eq!(mode(path) & 0o777, 0o600);
+                assert_eq!(mode(path.parent().unwrap()) & 0o777, 0o700);
             }
+
+            let reply = request_on(connect_to(path).await.unwrap(), &Request::Status)
+                .await
+                .unwrap();
+            let Response::Status(status) = reply else {
+                panic!("unexpected reply: {reply:?}");
+            };
+            assert_eq!(status.pid, std::process::id());
+
+            // 正式服务没有启动
+            let reply = request_on(connect_to(path).await.unwrap(), &Request::Sessions)
+                .await
+                .unwrap();
+            assert!(
+                matches!(reply, Response::Error { ref code, .. } if code == "HSSH_CTL_NOT_READY")
+            );
+
+            let conn = connect_to(path).await.unwrap();
+            let (recver, mut sender) = conn.split();
+            sender.write_all(b"{\"cmd\":\"nope\"}\n").await.unwrap();
+            let mut reply = String::new();
+            BufReader::new(recver).read_line(&mut reply).await.unwrap();
+            assert!(reply.contains("HSSH_CTL_BAD_REQUEST"));
         });
 
-        let _ = handle().block_on(tm.future());
+        let _ = std::fs::remove_dir_all(dir);
     }
 }
@@ -1,18 +1,145 @@
+//! 服务端主机密钥
+//!
+//! `paths.host_key` (默认 `horsed.key`) 为当前的 Ed25519 密钥, RSA/ECDSA 密钥为
+//! `paths.host_keys` 目录 (默认 `hostkeys`) 下的 `rsa.key`/`ecdsa.key`, 用于不支持 Ed25519 的旧客户端.
+//!
+//! 轮换时旧密钥移动到 `<host_keys>/retired/<alg>-<到期毫秒>.key`, 宽限期内与新密钥一起提供,
+//! 同一算法的当前密钥排在前面, 握手时使用新密钥. 登录后服务端通过 `hostkeys-00@openssh.com`
+//! 通告全部公钥 (见 `ssh::hostkeys`), OpenSSH 的 `UpdateHostKeys` 据此更新 known_hosts.
+//! 过期的旧密钥在下次启动或 `horsed hostkey list` 时删除.
+use crate::config::config;
+use anyhow::{anyhow, bail, Context};
 use rand_core::OsRng;
-use russh::keys::{Algorithm, PrivateKey};
-use std::path::Path;
+use russh::keys::ssh_key::EcdsaCurve;
+use russh::keys::{Algorithm, HashAlg, PrivateKey};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-const KEY_FILE: &str = "horsed.key";
+/// 支持的主机密钥算法
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HostKeyAlg {
+    Ed25519,
+    Ecdsa,
+    Rsa,
+}
+
+impl HostKeyAlg {
+    /// 提供给客户端的顺序
+    pub const ALL: [HostKeyAlg; 3] = [HostKeyAlg::Ed25519, HostKeyAlg::Ecdsa, HostKeyAlg::Rsa];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HostKeyAlg::Ed25519 => "ed25519",
+            HostKeyAlg::Ecdsa => "ecdsa",
+            HostKeyAlg::Rsa => "rsa",
+        }
+    }
+
+    pub fn parse(alg: &str) -> Option<Self> {
+        match alg.trim().to_ascii_lowercase().as_str() {
+            "ed25519" | "ssh-ed25519" => Some(HostKeyAlg::Ed25519),
+            "ecdsa" | "ecdsa-sha2-nistp256" => Some(HostKeyAlg::Ecdsa),
+            "rsa" | "ssh-rsa" => Some(HostKeyAlg::Rsa),
+            _ => None,
+        }
+    }
+
+    fn of(key: &PrivateKey) -> Option<Self> {
+        match key.algorithm() {
+            Algorithm::Ed25519 => Some(HostKeyAlg::Ed25519),
+            Algorithm::Ecdsa { .. } => Some(HostKeyAlg::Ecdsa),
+            Algorithm::Rsa { .. } => Some(HostKeyAlg::Rsa),
+            _ => None,
+        }
+    }
+
+    fn generate(&self) -> anyhow::Result<PrivateKey> {
+        let algorithm = match self {
+            HostKeyAlg::Ed25519 => Algorithm::Ed25519,
+            HostKeyAlg::Ecdsa => Algorithm::Ecdsa {
+                curve: EcdsaCurve::NistP256,
+            },
+            HostKeyAlg::Rsa => Algorithm::Rsa { hash: None },
+        };
+        PrivateKey::random(&mut OsRng, algorithm).map_err(|err| anyhow!("无法生成私钥: {err}"))
+    }
+
+    /// 当前密钥的路径
+    pub fn path(&self) -> PathBuf {
+        match self {
+            HostKeyAlg::Ed25519 => config().host_key_path(),
+            alg => config()
+                .host_keys_dir()
+                .join(format!("{}.key", alg.as_str())),
+        }
+    }
+}
+
+/// 已载入的主机密钥
+pub struct HostKey {
+    pub alg: HostKeyAlg,
+    pub path: PathBuf,
+    pub key: PrivateKey,
+    /// 轮换下来的旧密钥的到期时间 (毫秒), 当前密钥为空
+    pub retire_at: Option<i64>,
+}
+
+impl HostKey {
+    pub fn fingerprint(&self) -> String {
+        self.key
+            .public_key()
+            .fingerprint(HashAlg::Sha256)
+            .to_string()
+    }
+}
+
+fn now_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+fn retired_dir() -> PathBuf {
+    config().host_keys_dir().join("retired")
+}
+
+/// `<alg>-<到期毫秒>.key`
+fn parse_retired_name(path: &Path) -> Option<(HostKeyAlg, i64)> {
+    let stem = path.file_name()?.to_str()?.strip_suffix(".key")?;
+    let (alg, until) = stem.rsplit_once('-')?;
+    Some((HostKeyAlg::parse(alg)?, until.parse().ok()?))
+}
+
+fn write_key(path: &Path, key: &PrivateKey) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    #[cfg(windows)]
+    let line_ending = russh::keys::ssh_key::LineEnding::CRLF;
+    #[cfg(not(windows))]
+    let line_ending = russh::keys::ssh_key::LineEnding::LF;
+
+    key.write_openssh_file(path, line_ending)
+        .with_context(|| format!("无法写入私钥文件: {}", path.display()))
+}
+
+fn read_key(path: &Path) -> anyhow::Result<PrivateKey> {
+    PrivateKey::read_openssh_file(path)
+        .with_context(|| format!("无效的私钥文件: {}", path.display()))
+}
 
 pub fn key_exists() -> bool {
-    Path::new(KEY_FILE).exists()
+    config().host_key_path().exists()
 }
 
+/// 当前的 Ed25519 密钥, 不存在时生成
 pub fn key_init() -> PrivateKey {
     static LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
     let _lock = LOCK.lock().unwrap();
 
-    let path = Path::new(KEY_FILE);
+    let path = &config().host_key_path();
 
     if path.exists() {
         tracing::info!("载入密钥文件: {}", path.display());
@@ -20,15 +147,191 @@ pub fn key_init() -> PrivateKey {
     }
 
     tracing::info!("生成密钥文件: {}", path.display());
-    let key = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).expect("无法生成私钥");
+    let key = HostKeyAlg::Ed25519.generate().expect("无法生成私钥");
+    write_key(path, &key).expect("无法写入私钥文件");
 
-    #[cfg(windows)]
-    key.write_openssh_file(path, russh::keys::ssh_key::LineEnding::CRLF)
-        .expect("无法写入私钥文件");
+    key
+}
 
-    #[cfg(not(windows))]
-    key.write_openssh_file(path, russh::keys::ssh_key::LineEnding::LF)
-        .expect("无法写入私钥文件");
+/// 载入全部主机密钥: 按算法排列, 同一算法的当前密钥在前, 宽限期内的旧密钥在后, 过期的旧密钥被删除
+pub fn load_host_keys() -> anyhow::Result<Vec<HostKey>> {
+    let mut keys = Vec::new();
+    let dir = retired_dir();
+    if dir.exists() {
+        let now = now_ms();
+        for entry in std::fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let Some((alg, until)) = parse_retired_name(&path) else {
+                continue;
+            };
+            if until <= now {
+                tracing::info!("删除过期的主机密钥: {}", path.display());
+                std::fs::remove_file(&path)?;
+                continue;
+            }
+            keys.push(HostKey {
+                alg,
+                key: read_key(&path)?,
+                path,
+                retire_at: Some(until),
+            });
+        }
+    }
 
-    key
+    for alg in HostKeyAlg::ALL {
+        let path = alg.path();
+        if !path.exists() {
+            continue;
+        }
+        keys.push(HostKey {
+            alg,
+            key: read_key(&path)?,
+            path,
+            retire_at: None,
+        });
+    }
+    sort_offered(&mut keys);
+
+    Ok(keys)
+}
+
+/// 握手时使用同一算法中排在最前的密钥, 当前密钥必须排在旧密钥之前
+fn sort_offered(keys: &mut [HostKey]) {
+    keys.sort_by_key(|key| {
+        let order = HostKeyAlg::ALL.iter().position(|alg| *alg == key.alg);
+        (order, key.retire_at.is_some(), key.retire_at)
+    });
+}
+
+/// 服务端提供的主机密钥
+pub fn host_keys() -> Vec<PrivateKey> {
+    // Ed25519 密钥总是存在
+    let primary = key_init();
+    match load_host_keys() {
+        Ok(keys) => {
+            for key in keys.iter() {
+                tracing::info!(
+                    "主机密钥: {} {} {}",
+                    key.alg.as_str(),
+                    key.fingerprint(),
+                    key.path.display()
+                );
+            }
+            keys.into_iter().map(|key| key.key).collect()
+        }
+        Err(err) => {
+            tracing::error!(
+                "载入主机密钥失败: {err:#}, 只使用 {}",
+                config().host_key_path().display()
+            );
+            vec![primary]
+        }
+    }
+}
+
+/// 生成当前不存在的密钥
+pub fn generate(alg: HostKeyAlg) -> anyhow::Result<HostKey> {
+    let path = alg.path();
+    if path.exists() {
+        bail!("密钥已存在: {}, 更换密钥请使用 rotate", path.display());
+    }
+
+    let key = alg.generate()?;
+    write_key(&path, &key)?;
+    Ok(HostKey {
+        alg,
+        path,
+        key,
+        retire_at: None,
+    })
+}
+
+/// 轮换密钥: 当前密钥在宽限期内继续提供, 返回旧密钥与新密钥
+pub fn rotate(alg: HostKeyAlg, grace: Duration) -> anyhow::Result<(HostKey, HostKey)> {
+    let path = alg.path();
+    if !path.exists() {
+        bail!("密钥不存在: {}, 请先使用 generate", path.display());
+    }
+    let old = read_key(&path)?;
+    if HostKeyAlg::of(&old) != Some(alg) {
+        bail!("密钥算法不匹配: {} ({})", path.display(), old.algorithm());
+    }
+
+    let until = now_ms().saturating_add(grace.as_millis() as i64);
+    let retired = retired_dir().join(format!("{}-{until}.key", alg.as_str()));
+    std::fs::create_dir_all(retired_dir())?;
+    std::fs::rename(&path, &retired)
+        .with_context(|| format!("移动旧密钥失败: {}", path.display()))?;
+
+    let new = generate(alg)?;
+    Ok((
+        HostKey {
+            alg,
+            path: retired,
+            key: old,
+            retire_at: Some(until),
+        },
+        new,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_retired_key_names() {
+        assert_eq!(
+            parse_retired_name(Path::new("hostkeys/retired/ed25519-1792226096000.key")),
+            Some((HostKeyAlg::Ed25519, 1_792_226_096_000))
+        );
+        assert_eq!(
+            parse_retired_name(Path::new("rsa-42.key")),
+            Some((HostKeyAlg::Rsa, 42))
+        );
+        assert_eq!(parse_retired_name(Path::new("rsa.key")), None);
+        assert_eq!(parse_retired_name(Path::new("dsa-42.key")), None);
+        assert_eq!(parse_retired_name(Path::new("ed25519-42.pub")), None);
+    }
+
+    #[test]
+    fn current_keys_are_offered_first() {
+        let key = |alg: HostKeyAlg, retire_at: Option<i64>| HostKey {
+            alg,
+            path: PathBuf::new(),
+            key: alg.generate().unwrap(),
+            retire_at,
+        };
+        let mut keys = vec![
+            key(HostKeyAlg::Ecdsa, Some(2)),
+            key(HostKeyAlg::Ed25519, Some(1)),
+            key(HostKeyAlg::Ecdsa, None),
+            key(HostKeyAlg::Ed25519, Some(0)),
+            key(HostKeyAlg::Ed25519, None),
+        ];
+        sort_offered(&mut keys);
+        let order = keys
+            .iter()
+            .map(|key| (key.alg, key.retire_at))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            order,
+            vec![
+                (HostKeyAlg::Ed25519, None),
+                (HostKeyAlg::Ed25519, Some(0)),
+                (HostKeyAlg::Ed25519, Some(1)),
+                (HostKeyAlg::Ecdsa, None),
+                (HostKeyAlg::Ecdsa, Some(2)),
+            ]
+        );
+    }
+
+    #[test]
+    fn generated_keys_match_algorithm() {
+        for alg in [HostKeyAlg::Ed25519, HostKeyAlg::Ecdsa] {
+            let key = alg.generate().unwrap();
+            assert_eq!(HostKeyAlg::of(&key), Some(alg));
+            assert_eq!(HostKeyAlg::parse(alg.as_str()), Some(alg));
+        }
+    }
 }
@@ -1,7 +1,9 @@
 #[macro_use]
 mod mac;
 
+pub mod account;
 pub mod command;
+pub mod config;
 pub mod db;
 pub mod error;
 pub mod git;
@@ -2,35 +2,100 @@
 mod otel;
 mod ring;
 
+use crate::config::config;
 use once_cell::sync::Lazy;
 #[cfg(feature = "opentelemetry")]
 use opentelemetry::trace::TracerProvider;
 #[cfg(feature = "opentelemetry")]
 use otel::*;
 use ring::RingWriter;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
 use tracing_appender::{
     non_blocking::{NonBlocking, WorkerGuard},
-    rolling::Rotation,
+    rolling::{RollingFileAppender, Rotation},
 };
 use tracing_subscriber::fmt::MakeWriter;
 
+/// 日志文件名前缀, 按天滚动为 `horsed.log.<日期>`
+const LOG_PREFIX: &str = "horsed.log";
+
 pub static STDOUT_GUARD: Lazy<(NonBlocking, WorkerGuard)> =
     Lazy::new(|| tracing_appender::non_blocking(std::io::stdout()));
 
-pub static FILE_GUARD: Lazy<(NonBlocking, WorkerGuard)> = Lazy::new(|| {
-    let Ok(file_appender) = tracing_appender::rolling::Builder::new()
+pub static LOG_FILE: Lazy<LogFile> = Lazy::new(|| {
+    LogFile(Arc::new(Mutex::new(
+        appender().expect("Failed to create file appender"),
+    )))
+});
+
+pub static FILE_GUARD: Lazy<(NonBlocking, WorkerGuard)> =
+    Lazy::new(|| tracing_appender::non_blocking(LOG_FILE.clone()));
+
+fn appender() -> anyhow::Result<RollingFileAppender> {
+    let config = config();
+    Ok(tracing_appender::rolling::Builder::new()
         .rotation(Rotation::DAILY)
-        .max_log_files(15)
-        .filename_prefix("horsed.log")
-        .build(".")
-    else {
-        panic!("Failed to create file appender");
+        .max_log_files(config.log.max_files)
+        .filename_prefix(LOG_PREFIX)
+        .build(config.log_dir())?)
+}
+
+/// 日志文件, 可以通过 [`rotate`] 重新打开
+#[derive(Clone)]
+pub struct LogFile(Arc<Mutex<RollingFileAppender>>);
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+}
+
+/// 把当天的日志文件重命名为 `horsed.log.<日期>.<秒>` 并打开新的日志文件,
+/// 返回重命名后的路径; 日志输出到终端时不做处理
+pub fn rotate() -> anyhow::Result<Option<PathBuf>> {
+    let Some(file) = Lazy::get(&LOG_FILE) else {
+        anyhow::bail!("日志输出到终端, 没有日志文件");
+    };
+    let mut guard = file.0.lock().unwrap();
+    guard.flush()?;
+
+    // 按天滚动的文件名为 horsed.log.YYYY-MM-DD, 取最新的一个
+    let current = std::fs::read_dir(config().log_dir())?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_prefix(LOG_PREFIX)?.strip_prefix('.'))
+                .is_some_and(|date| date.len() == 10 && !date.contains('.'))
+        })
+        .max();
+
+    let rotated = match current {
+        Some(path) => {
+            let secs = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            let mut name = path.file_name().unwrap_or_default().to_os_string();
+            name.push(format!(".{secs}"));
+            let target = path.with_file_name(name);
+            std::fs::rename(&path, &target)?;
+            Some(target)
+        }
+        None => None,
     };
 
-    tracing_appender::non_blocking(file_appender)
-});
+    *guard = appender()?;
+    Ok(rotated)
+}
 
-pub static RING_LOG: Lazy<RingWriter> = Lazy::new(|| RingWriter::new(30));
+pub static RING_LOG: Lazy<RingWriter> = Lazy::new(|| RingWriter::new(config().log.ring_capacity));
 
 #[cfg(feature = "opentelemetry")]
 pub static OTEL_GUARD: Lazy<OtelGuard> = Lazy::new(init_otel);
@@ -38,7 +103,8 @@ pub static OTEL_GUARD: Lazy<OtelGuard> = Lazy::new(init_otel);
 pub fn init(show_log: bool) {
     use tracing_subscriber::{filter::EnvFilter, prelude::*};
 
-    let env_filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let env_filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(&config().log.level));
 
     let non_blocking = if show_log {
         STDOUT_GUARD.0.clone()
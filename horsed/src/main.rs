@@ -29,6 +29,16 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             .with_context(|| format!("切换工作目录失败: {}", dir.display()))?;
     }
     let work_dir = &std::env::current_dir().unwrap();
+    // 子进程会重新切换工作目录, 这里先转换为绝对路径
+    let config_path = cli.config.as_ref().map(|path| work_dir.join(path));
+
+    match horsed::config::ServerConfig::load(config_path.as_deref()) {
+        Ok(config) => horsed::config::set_config(config),
+        Err(err) => {
+            eprintln!("{err:#}");
+            std::process::exit(2);
+        }
+    }
 
     if cli.daemon {
         let mut cmd = std::process::Command::new(std::env::current_exe()?);
@@ -42,11 +52,11 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             cmd.creation_flags(CREATE_NO_WINDOW | DETACHED_PROCESS);
         }
 
-        cmd.arg("-f")
-            .arg("--dir")
-            .arg(work_dir)
-            .spawn()
-            .context("启动服务失败")?;
+        cmd.arg("-f").arg("--dir").arg(work_dir);
+        if let Some(path) = &config_path {
+            cmd.arg("--config").arg(path);
+        }
+        cmd.spawn().context("启动服务失败")?;
 
         return Ok(());
     }
@@ -57,7 +67,6 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
         let handler = tm.spawn_essential_handle();
         let task = tm.spawn_handle();
-        let h = tm.spawn_handle();
 
         task.spawn(async move {
             horsed::logger::init(cli.show_log);
@@ -101,36 +110,17 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             Ok(())
         });
 
+        let control = horsed::ipc::Control::new(config_path.clone(), handler.clone());
         handler.spawn(async move {
-            use horsed::ipc::*;
             tracing::info!("IPC Server Running...");
+            control.run().await?;
+            Ok(())
+        });
 
-            let listener = listen().await?;
-            loop {
-                let conn = match listener.accept().await {
-                    Ok(c) => c,
-                    Err(err) => {
-                        tracing::info!("Error while accepting connection: {err}");
-                        continue;
-                    }
-                };
-
-                tracing::info!("IPC 新连接");
-
-                h.spawn(async move {
-                    let mut recver = BufReader::new(&conn);
-
-                    // Allocate a sizeable buffer for receiving. This size should be big enough and easy to
-                    // find for the allocator.
-                    let mut buffer = String::with_capacity(128);
-
-                    // Describe the receive operation as receiving a line into our big buffer.
-                    let recv = recver.read_line(&mut buffer).await?;
-
-                    tracing::info!("接收 IPC 消息: [{recv}] {}", buffer.trim());
-                    Ok(())
-                });
-            }
+        // 收到停止信号或者 `ctl drain` 之后, 等待任务结束再退出
+        handler.spawn(async move {
+            horsed::ssh::drain::until_stopped().await?;
+            Ok(())
         });
 
         stable::prelude::handle().block_on(tm.future())?;
@@ -139,74 +129,58 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     }
 
     if let Some(commands) = cli.commands {
-        use horsed::db::entity::{self, prelude::*, user};
-        use sea_orm::entity::prelude::*;
-        use sea_orm::ActiveModelTrait;
-        use sea_orm::ActiveValue::{NotSet, Set, Unchanged};
-        use sea_orm::{EntityTrait, ModelTrait};
         // 调用子命令
         match commands {
             Commands::User(sub) => {
-                match sub.commands {
-                    UserCommand::Add(user) => {
-                        stable::prelude::handle().block_on(async move {
-                            // TODO: 添加用户
-                            let db = horsed::db::db();
-                            let user = entity::user::ActiveModel {
-                                name: Set(user.name),
-                                nick: Set(user.nick),
-                                email: Set(user.email),
-                                role: Set("user".to_string()),
-                                enabled: Set(true),
-                                ..Default::default()
-                            };
-                            match user.insert(&db).await {
-                                Ok(user) => {
-                                    println!("用户添加成功: name: {}, id: {}", user.name, user.id);
-                                }
-                                Err(err) => {
-                                    eprintln!("添加用户失败: {err}");
-                                }
-                            }
-                        });
-                    }
-                    UserCommand::Del(user) => {
-                        stable::prelude::handle().block_on(async move {
-                            let db = horsed::db::db();
-                            match User::find()
-                                .filter(user::Column::Name.eq(&user.name))
-                                .one(&db)
-                                .await
-                            {
-                                Ok(Some(user)) => {
-                                    let id = user.id;
-                                    match user.delete(&db).await {
-                                        Ok(_) => {
-                                            println!("用户删除成功: {}", id);
-                                        }
-                                        Err(err) => {
-                                            eprintln!("删除用户失败: {err}");
-                                        }
-                                    }
-                                }
-                                Ok(None) => {
-                                    eprintln!("用户不存在: {}", user.name);
-                                }
-                                Err(err) => {
-                                    eprintln!("查找用户失败: {err}");
-                                }
-                            }
-                        });
-                    }
-                    UserCommand::Mod(_user) => {
-                        eprintln!("`horsed user mod` 尚未实现");
-                        std::process::exit(2);
+                if let Err(err) = horsed::command::user::run(sub.commands) {
+                    eprintln!("{err:#}");
+                    std::process::exit(1);
+                }
+            }
+            Commands::Key(sub) => {
+                if let Err(err) = horsed::command::key::run(sub.commands) {
+                    eprintln!("{err:#}");
+                    std::process::exit(1);
+                }
+            }
+            Commands::Config(sub) => match sub.commands {
+                // 配置在启动时已经读取并校验, 走到这里说明配置有效
+                ConfigCommand::Check(check) => {
+                    let config = horsed::config::config();
+                    let default_path = work_dir.join(horsed::config::CONFIG_FILE);
+                    match &config_path {
+                        Some(path) => println!("配置检查通过: {}", path.display()),
+                        None if default_path.exists() => {
+                            println!("配置检查通过: {}", default_path.display())
+                        }
+                        None => println!("未找到配置文件, 使用默认配置"),
                     }
-                    UserCommand::List(_user) => {
-                        eprintln!("`horsed user list` 尚未实现");
-                        std::process::exit(2);
+                    println!("监听地址: {}", config.listen_addr());
+                    println!("仓库目录: {}", config.repos_dir().display());
+                    println!("工作目录: {}", config.workspace_dir().display());
+                    println!(
+                        "工作目录模式: {} (prune={})",
+                        config.workspace.mode.as_str(),
+                        config.workspace.prune
+                    );
+                    println!("数据库: {}", config.database_url());
+                    if check.print {
+                        println!();
+                        print!("{}", toml::to_string_pretty(config.as_ref())?);
                     }
                 }
+            },
+            Commands::HostKey(sub) => {
+                if let Err(err) = horsed::command::hostkey::run(sub.commands) {
+                    eprintln!("{err:#}");
+                    std::process::exit(1);
+                }
+            }
+            Commands::Ctl(ctl) => {
+                if let Err(err) = horsed::command::ctl::run(ctl) {
+                    eprintln!("{err:#}");
+                    std::process::exit(1);
+                }
             }
         }
     } else {
@@ -222,7 +196,11 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             cmd.creation_flags(CREATE_NO_WINDOW | DETACHED_PROCESS);
         }
 
-        cmd.current_dir(work_dir).arg("--daemon").spawn()?.wait()?;
+        cmd.current_dir(work_dir).arg("--daemon");
+        if let Some(path) = &config_path {
+            cmd.arg("--config").arg(path);
+        }
+        cmd.spawn()?.wait()?;
     }
 
     Ok(())
@@ -0,0 +1,20 @@
+use clap::{Parser, Subcommand};
+
+#[derive(Clone, Debug, Parser)]
+#[command(version, display_order = 1)]
+pub struct Config {
+    #[clap(subcommand)]
+    pub commands: ConfigCommand,
+}
+
+#[derive(Clone, Debug, Subcommand)]
+pub enum ConfigCommand {
+    #[command(name = "check", about = "检查配置文件")]
+    Check(CheckConfig),
+}
+
+#[derive(Clone, Debug, Parser)]
+pub struct CheckConfig {
+    #[clap(long, help = "输出生效的完整配置")]
+    pub print: bool,
+}
@@ -0,0 +1,65 @@
+use clap::{Parser, Subcommand};
+
+#[derive(Clone, Debug, Parser)]
+#[command(version, display_order = 1)]
+pub struct Ctl {
+    #[clap(long, global = true, help = "以 JSON 格式输出")]
+    pub json: bool,
+
+    #[clap(subcommand)]
+    pub commands: CtlCommand,
+}
+
+#[derive(Clone, Debug, Subcommand)]
+pub enum CtlCommand {
+    #[command(name = "status", about = "服务状态")]
+    Status,
+    #[command(name = "sessions", about = "当前的 SSH 连接")]
+    Sessions,
+    #[command(name = "jobs", about = "任务列表, 默认只列出运行中和排队中的任务")]
+    Jobs(CtlJobs),
+    #[command(name = "kill", about = "结束任务")]
+    Kill(CtlKill),
+    #[command(name = "reload", about = "重新读取配置文件")]
+    Reload,
+    #[command(name = "rotate-logs", about = "切换到新的日志文件")]
+    RotateLogs,
+    #[command(
+        name = "shutdown",
+        about = "停止服务: 默认与 drain 相同, 等待任务结束; --now 立即停止并中断运行中的任务"
+    )]
+    Shutdown(CtlShutdown),
+    #[command(name = "drain", about = "不再接受新的请求, 等待任务结束后停止服务")]
+    Drain(CtlDrain),
+}
+
+#[derive(Clone, Debug, Parser)]
+pub struct CtlJobs {
+    #[clap(long, help = "包括已经结束的任务")]
+    pub all: bool,
+    #[clap(long, default_value_t = 50, help = "最多列出的任务数")]
+    pub limit: usize,
+}
+
+#[derive(Clone, Debug, Parser)]
+pub struct CtlDrain {
+    #[clap(
+        long,
+        help = "等待任务结束的最长时间, 如 90s/10m/1h, 默认使用 jobs.drain_timeout_secs"
+    )]
+    pub timeout: Option<String>,
+}
+
+#[derive(Clone, Debug, Parser)]
+pub struct CtlShutdown {
+    #[clap(long, help = "立即停止服务, 运行中和排队中的任务会被直接结束")]
+    pub now: bool,
+}
+
+#[derive(Clone, Debug, Parser)]
+pub struct CtlKill {
+    #[clap(help = "任务 id")]
+    pub id: String,
+    #[clap(short, long, default_value = "TERM", help = "信号: TERM|INT|KILL")]
+    pub signal: String,
+}
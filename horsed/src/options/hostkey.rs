@@ -0,0 +1,44 @@
+use clap::{Parser, Subcommand};
+
+#[derive(Clone, Debug, Parser)]
+#[command(version, display_order = 1)]
+pub struct HostKey {
+    #[clap(subcommand)]
+    pub commands: HostKeyCommand,
+}
+
+#[derive(Clone, Debug, Subcommand)]
+pub enum HostKeyCommand {
+    #[command(name = "list", about = "列出主机密钥")]
+    List(ListHostKey),
+    #[command(name = "generate", about = "生成其他算法的主机密钥")]
+    Generate(GenerateHostKey),
+    #[command(name = "rotate", about = "轮换主机密钥, 旧密钥在宽限期内继续提供")]
+    Rotate(RotateHostKey),
+    #[command(name = "fingerprint", about = "输出主机公钥指纹")]
+    Fingerprint(FingerprintHostKey),
+}
+
+#[derive(Clone, Debug, Parser)]
+pub struct ListHostKey {}
+
+#[derive(Clone, Debug, Parser)]
+pub struct GenerateHostKey {
+    #[clap(help = "密钥算法: ed25519|ecdsa|rsa")]
+    pub alg: String,
+}
+
+#[derive(Clone, Debug, Parser)]
+pub struct RotateHostKey {
+    #[clap(default_value = "ed25519", help = "密钥算法: ed25519|ecdsa|rsa")]
+    pub alg: String,
+    #[clap(
+        long,
+        default_value = "7d",
+        help = "旧密钥继续提供的时间, 例如 12h, 7d"
+    )]
+    pub grace: String,
+}
+
+#[derive(Clone, Debug, Parser)]
+pub struct FingerprintHostKey {}
@@ -0,0 +1,94 @@
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+
+#[derive(Clone, Debug, Parser)]
+#[command(version, display_order = 1)]
+pub struct Key {
+    #[clap(subcommand)]
+    pub commands: KeyCommand,
+}
+
+#[derive(Clone, Debug, Subcommand)]
+pub enum KeyCommand {
+    #[command(name = "list", about = "列出用户公钥")]
+    List(ListKey),
+    #[command(name = "add", about = "为用户添加公钥")]
+    Add(AddKey),
+    #[command(name = "enable", about = "启用公钥")]
+    Enable(KeyId),
+    #[command(name = "disable", about = "禁用公钥")]
+    Disable(KeyId),
+    #[command(name = "delete", about = "删除公钥")]
+    Delete(KeyId),
+    #[command(name = "caps", about = "设置公钥的操作权限")]
+    Caps(KeyCaps),
+    #[command(
+        name = "import",
+        about = "从 authorized_keys 文件导入公钥, 保留公钥选项"
+    )]
+    Import(ImportKey),
+    #[command(name = "export", about = "导出用户公钥为 authorized_keys 格式")]
+    Export(ExportKey),
+}
+
+#[derive(Clone, Debug, Parser)]
+pub struct ListKey {
+    #[clap(short, long, help = "只列出该用户的公钥")]
+    pub user: Option<String>,
+    #[clap(long, help = "以 JSON 格式输出")]
+    pub json: bool,
+}
+
+#[derive(Clone, Debug, Parser)]
+pub struct AddKey {
+    #[clap(help = "用户名")]
+    pub user: String,
+    #[clap(help = "密钥类型, 如 ssh-ed25519")]
+    pub alg: String,
+    #[clap(help = "密钥字串 (base64)")]
+    pub key: String,
+    #[clap(
+        short,
+        long,
+        help = "authorized_keys 公钥选项, 如 from=\"10.0.0.0/8\",no-port-forwarding"
+    )]
+    pub options: Option<String>,
+    #[clap(help = "密钥备注")]
+    pub comment: Vec<String>,
+}
+
+#[derive(Clone, Debug, Parser)]
+pub struct KeyId {
+    #[clap(help = "密钥类型, 如 ssh-ed25519")]
+    pub alg: String,
+    #[clap(help = "密钥字串 (base64)")]
+    pub key: String,
+}
+
+#[derive(Clone, Debug, Parser)]
+pub struct KeyCaps {
+    #[clap(help = "密钥类型, 如 ssh-ed25519")]
+    pub alg: String,
+    #[clap(help = "密钥字串 (base64)")]
+    pub key: String,
+    #[clap(
+        help = "操作权限: all|none|git-only|build-only|read-only 或 git,build,shell,forward,read,write 的组合"
+    )]
+    pub caps: String,
+}
+
+#[derive(Clone, Debug, Parser)]
+pub struct ImportKey {
+    #[clap(help = "用户名")]
+    pub user: String,
+    #[clap(help = "authorized_keys 文件, - 表示标准输入")]
+    pub file: PathBuf,
+}
+
+#[derive(Clone, Debug, Parser)]
+pub struct ExportKey {
+    #[clap(help = "用户名")]
+    pub user: String,
+    #[clap(short, long, help = "写入文件, 默认输出到标准输出")]
+    pub output: Option<PathBuf>,
+}
@@ -1,8 +1,16 @@
 use anstyle::{AnsiColor, Effects};
 use clap::{Parser, Subcommand};
 use std::path::PathBuf;
+pub mod config;
+pub mod ctl;
+pub mod hostkey;
+pub mod key;
 pub mod user;
 
+pub use config::*;
+pub use ctl::*;
+pub use hostkey::*;
+pub use key::*;
 pub use user::*;
 
 pub fn styles() -> clap::builder::Styles {
@@ -39,6 +47,9 @@ pub struct Cli {
     #[clap(long, help = "指定工作目录")]
     pub dir: Option<PathBuf>,
 
+    #[clap(short, long, help = "指定配置文件 (默认: 工作目录下的 horsed.toml)")]
+    pub config: Option<PathBuf>,
+
     #[clap(subcommand)]
     pub commands: Option<Commands>,
 }
@@ -48,4 +59,12 @@ pub struct Cli {
 pub enum Commands {
     #[command(name = "user", about = "账号管理")]
     User(User),
+    #[command(name = "key", about = "用户公钥管理")]
+    Key(Key),
+    #[command(name = "config", about = "配置管理")]
+    Config(Config),
+    #[command(name = "hostkey", about = "主机密钥管理")]
+    HostKey(HostKey),
+    #[command(name = "ctl", about = "管理正在运行的服务")]
+    Ctl(Ctl),
 }
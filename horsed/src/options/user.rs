@@ -27,6 +27,8 @@ pub struct AddUser {
     pub nick: Option<String>,
     #[clap(short, long, help = "用户邮箱")]
     pub email: Option<String>,
+    #[clap(short, long, default_value = "user", help = "用户角色: admin|user")]
+    pub role: String,
     #[clap(short, long, help = "用户密钥", value_parser = parse_user_key)]
     pub key: Option<UserKey>,
 }
@@ -66,7 +68,28 @@ pub struct DelUser {
 }
 
 #[derive(Clone, Debug, Parser)]
-pub struct ModUser {}
+pub struct ModUser {
+    #[clap(help = "用户名")]
+    pub name: String,
+    #[clap(long, help = "用户昵称, 空字符串表示清除")]
+    pub nick: Option<String>,
+    #[clap(short, long, help = "用户邮箱, 空字符串表示清除")]
+    pub email: Option<String>,
+    #[clap(short, long, help = "用户角色: admin|user")]
+    pub role: Option<String>,
+    #[clap(
+        long,
+        help = "操作权限: all|none|git-only|build-only|read-only 或 git,build,shell,forward,read,write 的组合"
+    )]
+    pub caps: Option<String>,
+    #[clap(long, conflicts_with = "disable", help = "启用用户")]
+    pub enable: bool,
+    #[clap(long, help = "禁用用户")]
+    pub disable: bool,
+}
 
 #[derive(Clone, Debug, Parser)]
-pub struct ListUser {}
+pub struct ListUser {
+    #[clap(long, help = "以 JSON 格式输出")]
+    pub json: bool,
+}
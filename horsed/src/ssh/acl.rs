@@ -0,0 +1,630 @@
+//! 仓库访问控制
+//!
+//! 每个仓库有一个所有者, 以及若干 read/write/admin 级别的协作者.
+//! 系统管理员拥有所有仓库的全部权限; 未登记的仓库只有管理员可以访问.
+use super::workspace::WorkspaceSettings;
+use super::*;
+use crate::config::WorkspaceMode;
+use crate::db::entity::{repo, repo_member};
+use std::path::Component;
+
+/// 仓库访问级别, 高级别包含低级别的全部权限
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RepoLevel {
+    Read,
+    Write,
+    Admin,
+}
+
+impl RepoLevel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RepoLevel::Read => "read",
+            RepoLevel::Write => "write",
+            RepoLevel::Admin => "admin",
+        }
+    }
+
+    pub fn parse(level: &str) -> Option<Self> {
+        match level.trim().to_ascii_lowercase().as_str() {
+            "read" => Some(RepoLevel::Read),
+            "write" => Some(RepoLevel::Write),
+            "admin" => Some(RepoLevel::Admin),
+            _ => None,
+        }
+    }
+}
+
+/// 请求解析出的仓库位置
+#[derive(Clone, Debug)]
+pub struct RepoTarget {
+    /// 规范化后的仓库名称, 如 `ns/name`
+    pub name: String,
+    /// 裸仓库目录: `<paths.repos>/<name>.git`
+    pub repo_path: PathBuf,
+    /// 工作目录: `<paths.workspace>/<name>`
+    pub work_path: PathBuf,
+    /// 工作目录模式, 见 [`super::workspace`]
+    pub workspace: WorkspaceSettings,
+}
+
+impl RepoTarget {
+    pub fn new(name: impl Into<String>) -> HorseResult<Self> {
+        let name = name.into();
+        let current_dir = std::env::current_dir()?;
+        let config = crate::config::config();
+        Ok(Self {
+            repo_path: current_dir
+                .join(config.repos_dir())
+                .join(format!("{name}.git")),
+            work_path: current_dir.join(config.workspace_dir()).join(&name),
+            workspace: WorkspaceSettings::default(),
+            name,
+        })
+    }
+
+    pub fn repo(&self) -> Repo {
+        Repo::from(&self.repo_path)
+    }
+}
+
+/// 规范化仓库名称, 所有入口 (git 地址, REPO 环境变量) 共用
+///
+/// `/repos/ns/name.git`, `ns/name.git`, `/ns/name` => `ns/name`
+///
+/// 包含 `..` 等非法路径时返回 `None`. 工作目录把 `.` 开头的目录 (`.jobs`) 和 `<name>@<branch>`
+/// 留给 job/branch 工作目录, 所以仓库名称的每一级都不能以 `.` 开头或包含 `@`,
+/// 上级目录也不能以 `.git` 结尾 (位于其他裸仓库内).
+pub fn normalize_repo_name(raw: &str) -> Option<String> {
+    let mut path = PathBuf::from(raw.trim());
+    // 去除开头的 /
+    if let Ok(stripped) = path.strip_prefix("/") {
+        path = stripped.to_path_buf();
+    }
+    // 清理路径
+    path = path.clean();
+    // 仓库存放在 repos 目录下
+    if let Ok(stripped) = path.strip_prefix("repos") {
+        path = stripped.to_path_buf();
+    }
+
+    let mut parts = Vec::new();
+    for component in path.components() {
+        let Component::Normal(part) = component else {
+            return None;
+        };
+        let part = part.to_str()?;
+        if part.starts_with('.') || part.contains('@') {
+            return None;
+        }
+        parts.push(part);
+    }
+    if let Some((_, parents)) = parts.split_last() {
+        if parents.iter().any(|part| part.ends_with(".git")) {
+            return None;
+        }
+    }
+
+    // 仓库名称不包含 .git 后缀, 其他后缀 (如 `a.b`) 保留
+    if let Some(last) = parts.last_mut() {
+        let name = *last;
+        *last = name.strip_suffix(".git").unwrap_or(name);
+    }
+    if parts.last().is_none_or(|last| last.is_empty()) {
+        return None;
+    }
+
+    Some(parts.join("/"))
+}
+
+/// 两个仓库的目录是否重叠: 一个仓库嵌套在另一个仓库的目录下
+fn repo_names_overlap(a: &str, b: &str) -> bool {
+    let nested = |outer: &str, inner: &str| {
+        inner
+            .strip_prefix(outer)
+            .is_some_and(|rest| rest.starts_with('/'))
+    };
+    nested(a, b) || nested(b, a)
+}
+
+/// 查找与新仓库目录重叠的仓库, 如已有 `team/app` 时的 `team`, 或已有 `team` 时的 `team/app`
+///
+/// 重叠的仓库共用工作目录, 新仓库的所有者可以通过自己的仓库读写另一个仓库的工作目录
+pub(super) async fn repo_name_conflict(
+    db: &DatabaseConnection,
+    name: &str,
+) -> HorseResult<Option<String>> {
+    let registered = repo::Entity::find().all(db).await?;
+    if let Some(other) = registered
+        .into_iter()
+        .find(|other| repo_names_overlap(&other.name, name))
+    {
+        return Ok(Some(other.name));
+    }
+
+    // 未登记但已存在的裸仓库
+    let target = RepoTarget::new(name)?;
+    let last = name.rsplit('/').next().unwrap_or(name);
+    if target.repo_path.with_file_name(last).is_dir() {
+        return Ok(Some(format!("{name}/...")));
+    }
+    for (at, _) in name.match_indices('/') {
+        let parent = &name[..at];
+        if RepoTarget::new(parent)?.repo_path.exists() {
+            return Ok(Some(parent.to_string()));
+        }
+    }
+
+    Ok(None)
+}
+
+/// 查询用户对仓库的访问级别
+pub(super) async fn repo_level(
+    db: &DatabaseConnection,
+    user: &SessionUser,
+    name: &str,
+) -> HorseResult<Option<RepoLevel>> {
+    if user.is_admin() {
+        return Ok(Some(RepoLevel::Admin));
+    }
+
+    let Some(repo) = repo::Entity::find()
+        .filter(repo::Column::Name.eq(name))
+        .one(db)
+        .await?
+    else {
+        return Ok(None);
+    };
+
+    if repo.owner_id == user.id {
+        return Ok(Some(RepoLevel::Admin));
+    }
+
+    let member = repo_member::Entity::find_by_id((repo.id, user.id))
+        .one(db)
+        .await?;
+
+    Ok(member.and_then(|member| RepoLevel::parse(&member.level)))
+}
+
+/// 一次查询用户对全部已登记仓库的访问级别, 没有权限的仓库级别为 `None`
+pub(super) async fn repo_levels(
+    db: &DatabaseConnection,
+    user: &SessionUser,
+) -> HorseResult<Vec<(String, Option<RepoLevel>)>> {
+    let repos = repo::Entity::find().all(db).await?;
+    let members = repo_member::Entity::find()
+        .filter(repo_member::Column::UserId.eq(user.id))
+        .all(db)
+        .await?
+        .into_iter()
+        .filter_map(|member| Some((member.repo_id, RepoLevel::parse(&member.level)?)))
+        .collect::<HashMap<_, _>>();
+
+    Ok(repos
+        .into_iter()
+        .map(|repo| {
+            let level = if user.is_admin() || repo.owner_id == user.id {
+                Some(RepoLevel::Admin)
+            } else {
+                members.get(&repo.id).copied()
+            };
+            (repo.name, level)
+        })
+        .collect())
+}
+
+impl AppServer {
+    /// 解析请求中的仓库, 并检查当前用户的访问权限
+    ///
+    /// 校验失败时直接回复客户端并结束通道, 返回 `None`
+    pub(super) async fn authorize_repo(
+        &mut self,
+        raw: &str,
+        need: RepoLevel,
+    ) -> HorseResult<Option<RepoTarget>> {
+        let Some(name) = normalize_repo_name(raw) else {
+            tracing::error!("无效仓库路径: {raw}");
+            if let Some(handle) = self.handle.take() {
+                handle
+                    .fail_with_error(2, "HSSH_REPO_PATH_INVALID", format!("无效仓库路径: {raw}"))
+                    .await?;
+            }
+            return Ok(None);
+        };
+
+        let user = self.user.clone().context("未获取登录用户")?;
+        let level = repo_level(&self.db, &user, &name).await?;
+        if level.is_some_and(|level| level >= need) {
+            let mut target = RepoTarget::new(name)?;
+            target.workspace = WorkspaceSettings::load(&self.db, &target.name).await?;
+            return Ok(Some(target));
+        }
+
+        tracing::warn!(
+            "拒绝仓库访问: user={} repo={} need={}",
+            user.name,
+            name,
+            need.as_str()
+        );
+        if let Some(handle) = self.handle.take() {
+            handle
+                .fail_with_error(
+                    3,
+                    "HSSH_REPO_FORBIDDEN",
+                    format!("无权访问仓库: {name} (需要 {} 权限)", need.as_str()),
+                )
+                .await?;
+        }
+        Ok(None)
+    }
+
+    /// 第一次推送新仓库时, 推送者成为仓库所有者
+    ///
+    /// 新仓库与已有仓库的目录重叠时回复客户端并结束通道, 返回 `false`
+    pub(super) async fn claim_new_repo(&mut self, raw: &str) -> HorseResult<bool> {
+        let Some(name) = normalize_repo_name(raw) else {
+            return Ok(true);
+        };
+        let Some(user) = self.user.clone() else {
+            return Ok(true);
+        };
+
+        let registered = repo::Entity::find()
+            .filter(repo::Column::Name.eq(name.as_str()))
+            .one(&self.db)
+            .await?;
+        if registered.is_some() || RepoTarget::new(name.as_str())?.repo_path.exists() {
+            return Ok(true);
+        }
+
+        if let Some(other) = repo_name_conflict(&self.db, &name).await? {
+            tracing::warn!("拒绝登记仓库: {} 与 {} 目录重叠", name, other);
+            if let Some(handle) = self.handle.take() {
+                handle
+                    .fail_with_error(
+                        3,
+                        "HSSH_REPO_NAME_CONFLICT",
+                        format!("仓库名称与已有仓库 {other} 的目录重叠: {name}"),
+                    )
+                    .await?;
+            }
+            return Ok(false);
+        }
+
+        let res = repo::ActiveModel {
+            name: Set(name.clone()),
+            owner_id: Set(user.id),
+            ..Default::default()
+        }
+        .insert(&self.db)
+        .await;
+
+        match res {
+            Ok(_) => tracing::info!("登记仓库: {} owner={}", name, user.name),
+            // 并发推送同名仓库时, 以先登记者为准
+            Err(err) => tracing::warn!("登记仓库失败: {} {:?}", name, err),
+        }
+
+        Ok(true)
+    }
+}
+
+#[derive(serde::Serialize)]
+struct AdminRepoRow {
+    id: i32,
+    name: String,
+    owner: Option<String>,
+    members: Vec<AdminRepoMemberRow>,
+    exists: bool,
+    /// 仓库单独设置的工作目录模式, 为空时使用默认值
+    workspace_mode: Option<String>,
+    workspace_prune: Option<bool>,
+}
+
+#[derive(serde::Serialize)]
+struct AdminRepoMemberRow {
+    user: Option<String>,
+    level: String,
+}
+
+async fn find_user(db: &DatabaseConnection, name: &str) -> anyhow::Result<user::Model> {
+    User::find()
+        .filter(user::Column::Name.eq(name))
+        .one(db)
+        .await?
+        .with_context(|| format!("用户不存在: {name}"))
+}
+
+async fn find_repo(db: &DatabaseConnection, raw: &str) -> anyhow::Result<repo::Model> {
+    let name = normalize_repo_name(raw).with_context(|| format!("无效仓库路径: {raw}"))?;
+    repo::Entity::find()
+        .filter(repo::Column::Name.eq(name.as_str()))
+        .one(db)
+        .await?
+        .with_context(|| format!("仓库未登记: {name}, 请先执行: repos owner {name} <user>"))
+}
+
+/// `admin repos <list|owner|grant|revoke|workspace> ...`
+pub(super) async fn admin_repos(
+    db: &DatabaseConnection,
+    args: &[String],
+) -> anyhow::Result<String> {
+    let command = args.get(1).map(String::as_str).unwrap_or("");
+
+    let output = match command {
+        "list" => {
+            let repos = repo::Entity::find()
+                .order_by_asc(repo::Column::Name)
+                .all(db)
+                .await?;
+            let mut rows = Vec::with_capacity(repos.len());
+            for repo in repos {
+                let owner = repo.find_related(User).one(db).await?;
+                let mut members = Vec::new();
+                for member in repo.find_related(repo_member::Entity).all(db).await? {
+                    let user = member.find_related(User).one(db).await?;
+                    members.push(AdminRepoMemberRow {
+                        user: user.map(|u| u.name),
+                        level: member.level,
+                    });
+                }
+                rows.push(AdminRepoRow {
+                    exists: RepoTarget::new(repo.name.as_str())?.repo_path.exists(),
+                    id: repo.id,
+                    name: repo.name,
+                    owner: owner.map(|u| u.name),
+                    members,
+                    workspace_mode: repo.workspace_mode,
+                    workspace_prune: repo.workspace_prune,
+                });
+            }
+            serde_json::to_string_pretty(&rows)?
+        }
+        "owner" => {
+            let usage = "用法: repos owner <repo> <user>";
+            let raw = args.get(2).context(usage)?;
+            let owner = find_user(db, args.get(3).context(usage)?).await?;
+            let name = normalize_repo_name(raw).with_context(|| format!("无效仓库路径: {raw}"))?;
+
+            match repo::Entity::find()
+                .filter(repo::Column::Name.eq(name.as_str()))
+                .one(db)
+                .await?
+            {
+                Some(target) => {
+                    let mut active: repo::ActiveModel = target.into();
+                    active.owner_id = Set(owner.id);
+                    active.update(db).await?;
+                }
+                None => {
+                    if let Some(other) = repo_name_conflict(db, &name).await? {
+                        return Err(anyhow!("仓库名称与已有仓库 {other} 的目录重叠: {name}"));
+                    }
+                    repo::ActiveModel {
+                        name: Set(name.clone()),
+                        owner_id: Set(owner.id),
+                        ..Default::default()
+                    }
+                    .insert(db)
+                    .await?;
+                }
+            }
+
+            format!("仓库所有者已更新: {} => {}", name, owner.name)
+        }
+        "grant" => {
+            let usage = "用法: repos grant <repo> <user> <read|write|admin>";
+            let target = find_repo(db, args.get(2).context(usage)?).await?;
+            let member = find_user(db, args.get(3).context(usage)?).await?;
+            let level = args
+                .get(4)
+                .and_then(|level| RepoLevel::parse(level))
+                .context(usage)?;
+
+            match repo_member::Entity::find_by_id((target.id, member.id))
+                .one(db)
+                .await?
+            {
+                Some(existing) => {
+                    let mut active: repo_member::ActiveModel = existing.into();
+                    active.level = Set(level.as_str().to_string());
+                    active.update(db).await?;
+                }
+                None => {
+                    repo_member::ActiveModel {
+                        repo_id: Set(target.id),
+                        user_id: Set(member.id),
+                        level: Set(level.as_str().to_string()),
+                    }
+                    .insert(db)
+                    .await?;
+                }
+            }
+
+            format!(
+                "已授权: {} => {} ({})",
+                member.name,
+                target.name,
+                level.as_str()
+            )
+        }
+        "revoke" => {
+            let usage = "用法: repos revoke <repo> <user>";
+            let target = find_repo(db, args.get(2).context(usage)?).await?;
+            let member = find_user(db, args.get(3).context(usage)?).await?;
+
+            let res = repo_member::Entity::delete_by_id((target.id, member.id))
+                .exec(db)
+                .await?;
+            if res.rows_affected == 0 {
+                return Err(anyhow!("{} 不是仓库 {} 的协作者", member.name, target.name));
+            }
+
+            format!("已撤销授权: {} => {}", member.name, target.name)
+        }
+        "workspace" => {
+            let usage =
+                "用法: repos workspace <repo> <shared|branch|job|default> [--prune|--no-prune]";
+            let target = find_repo(db, args.get(2).context(usage)?).await?;
+            let mode = match args.get(3).map(String::as_str).context(usage)? {
+                "default" => None,
+                mode => Some(WorkspaceMode::parse(mode).context(usage)?),
+            };
+            let prune = match args.get(4).map(String::as_str) {
+                None => None,
+                Some("--prune") => Some(true),
+                Some("--no-prune") => Some(false),
+                Some(_) => return Err(anyhow!(usage)),
+            };
+
+            let name = target.name.clone();
+            let mut active: repo::ActiveModel = target.into();
+            active.workspace_mode = Set(mode.map(|mode| mode.as_str().to_string()));
+            active.workspace_prune = Set(prune);
+            active.update(db).await?;
+
+            let defaults = crate::config::config();
+            format!(
+                "工作目录模式已更新: {} => {} (prune={})",
+                name,
+                mode.unwrap_or(defaults.workspace.mode).as_str(),
+                prune.unwrap_or(defaults.workspace.prune)
+            )
+        }
+        _ => {
+            return Err(anyhow!(
+                "不支持的 repos 命令, 用法: repos <list|owner|grant|revoke|workspace> ..."
+            ));
+        }
+    };
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use migration::{Migrator, MigratorTrait};
+    use sea_orm::Database;
+
+    #[test]
+    fn normalize_repo_name_accepts_all_entry_forms() {
+        assert_eq!(
+            normalize_repo_name("/repos/ns/a.git").as_deref(),
+            Some("ns/a")
+        );
+        assert_eq!(normalize_repo_name("ns/a.git").as_deref(), Some("ns/a"));
+        assert_eq!(normalize_repo_name("/ns/a").as_deref(), Some("ns/a"));
+        assert_eq!(normalize_repo_name("a").as_deref(), Some("a"));
+        assert_eq!(normalize_repo_name("a.b").as_deref(), Some("a.b"));
+        assert_eq!(normalize_repo_name("ns/a.b.git").as_deref(), Some("ns/a.b"));
+    }
+
+    #[test]
+    fn normalize_repo_name_rejects_escapes() {
+        assert_eq!(normalize_repo_name("../a"), None);
+        assert_eq!(normalize_repo_name("/repos/../../etc"), None);
+        assert_eq!(normalize_repo_name("/"), None);
+        assert_eq!(normalize_repo_name(""), None);
+        assert_eq!(normalize_repo_name(".git"), None);
+    }
+
+    #[test]
+    fn normalize_repo_name_rejects_reserved_workspace_names() {
+        // job 工作目录: `<workspace>/.jobs/<repo>/<job_id>`
+        assert_eq!(normalize_repo_name(".jobs/team/app"), None);
+        assert_eq!(normalize_repo_name("team/.hidden"), None);
+        // branch 工作目录: `<workspace>/<repo>@<branch>`
+        assert_eq!(normalize_repo_name("app@main"), None);
+        assert_eq!(normalize_repo_name("team/app@feature_a"), None);
+        // 位于其他裸仓库内
+        assert_eq!(normalize_repo_name("team/app.git/x"), None);
+    }
+
+    #[test]
+    fn repo_names_overlap_when_nested() {
+        assert!(repo_names_overlap("team", "team/app"));
+        assert!(repo_names_overlap("team/app/x", "team/app"));
+        assert!(!repo_names_overlap("team/app", "team/app"));
+        assert!(!repo_names_overlap("team/app", "team/app2"));
+        assert!(!repo_names_overlap("team/app", "team/other"));
+    }
+
+    #[test]
+    fn repo_level_ordering() {
+        assert!(RepoLevel::Admin > RepoLevel::Write);
+        assert!(RepoLevel::Write > RepoLevel::Read);
+        assert_eq!(RepoLevel::parse("WRITE"), Some(RepoLevel::Write));
+        assert_eq!(RepoLevel::parse("owner"), None);
+    }
+
+    #[tokio::test]
+    async fn repo_level_follows_owner_and_members() {
+        let db = Database::connect("sqlite::memory:").await.unwrap();
+        Migrator::up(&db, None).await.unwrap();
+
+        let mut users = Vec::new();
+        for name in ["alice", "bob", "carol"] {
+            let model = user::ActiveModel {
+                name: Set(name.to_string()),
+                role: Set("user".to_string()),
+                enabled: Set(true),
+                ..Default::default()
+            }
+            .insert(&db)
+            .await
+            .unwrap();
+            users.push(SessionUser {
+                id: model.id,
+                name: model.name,
+                role: model.role,
+                cert: None,
+                options: KeyOptions::default(),
+                caps: Capabilities::default(),
+            });
+        }
+
+        let repo = repo::ActiveModel {
+            name: Set("team/app".to_string()),
+            owner_id: Set(users[0].id),
+            ..Default::default()
+        }
+        .insert(&db)
+        .await
+        .unwrap();
+
+        repo_member::ActiveModel {
+            repo_id: Set(repo.id),
+            user_id: Set(users[1].id),
+            level: Set("read".to_string()),
+        }
+        .insert(&db)
+        .await
+        .unwrap();
+
+        let level = |user: usize, name: &'static str| {
+            let db = db.clone();
+            let user = users[user].clone();
+            async move { repo_level(&db, &user, name).await.unwrap() }
+        };
+
+        assert_eq!(level(0, "team/app").await, Some(RepoLevel::Admin));
+        assert_eq!(level(1, "team/app").await, Some(RepoLevel::Read));
+        assert_eq!(level(2, "team/app").await, None);
+        assert_eq!(level(0, "team/other").await, None);
+
+        let levels = repo_levels(&db, &users[1]).await.unwrap();
+        assert_eq!(levels, [("team/app".to_string(), Some(RepoLevel::Read))]);
+        let levels = repo_levels(&db, &users[2]).await.unwrap();
+        assert_eq!(levels, [("team/app".to_string(), None)]);
+
+        let conflict = |name: &'static str| {
+            let db = db.clone();
+            async move { repo_name_conflict(&db, name).await.unwrap() }
+        };
+        assert_eq!(conflict("team").await.as_deref(), Some("team/app"));
+        assert_eq!(conflict("team/app/x").await.as_deref(), Some("team/app"));
+        assert_eq!(conflict("team/other").await, None);
+    }
+}
@@ -0,0 +1,93 @@
+//! SSH agent 转发
+//!
+//! 客户端请求 agent 转发 (`auth-agent-req@openssh.com`) 后, 为当前连接创建一个 Unix socket,
+//! `cmd`/`cargo`/`just`/`ssh` 子进程通过 `SSH_AUTH_SOCK` 访问. socket 上的每个连接都会向客户端
+//! 打开一个 `auth-agent@openssh.com` 通道, 由客户端转发给本地的 ssh-agent.
+//!
+//! socket 位于 `<tmp>/horsed-agent-<pid>-<连接>/agent.sock`, 目录权限为 0700, 连接断开时删除,
+//! 后台任务在连接断开后无法继续使用 agent.
+//!
+//! 所有任务都以服务用户运行, 目录权限挡不住其他用户的任务: 它们可以找到 socket 并用转发的 agent 签名.
+//! 因此 agent 转发需要在配置中打开 `ssh.agent_forwarding`, 默认关闭.
+use super::*;
+use std::os::unix::fs::DirBuilderExt;
+use tokio::net::UnixListener;
+use tokio::sync::oneshot;
+
+/// 当前连接的 agent socket, 释放时停止监听并删除 socket
+pub(super) struct AgentSocket {
+    dir: PathBuf,
+    path: PathBuf,
+    _stop: oneshot::Sender<()>,
+}
+
+impl AgentSocket {
+    pub(super) fn listen(handle: Handle, id: usize, task: SpawnTaskHandle) -> HorseResult<Self> {
+        let dir = std::env::temp_dir().join(format!("horsed-agent-{}-{id}", std::process::id()));
+        // 上次异常退出时遗留的目录
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+        let path = dir.join("agent.sock");
+        let listener = UnixListener::bind(&path)?;
+        let (stop, mut stopped) = oneshot::channel::<()>();
+
+        let agent_span = tracing::info_span!("agent", path = %path.display());
+        let conn_task = task.clone();
+        task.spawn(
+            async move {
+                loop {
+                    let mut stream = tokio::select! {
+                        accepted = listener.accept() => match accepted {
+                            Ok((stream, _)) => stream,
+                            Err(err) => {
+                                tracing::error!("agent accept: {:?}", err);
+                                break;
+                            }
+                        },
+                        _ = &mut stopped => break,
+                    };
+
+                    let handle = handle.clone();
+                    conn_task.spawn(async move {
+                        let channel = match handle.channel_open_agent().await {
+                            Ok(channel) => channel,
+                            Err(err) => {
+                                tracing::warn!("打开 agent 通道失败: {:?}", err);
+                                return Ok(());
+                            }
+                        };
+                        let mut ch_stream = channel.into_stream();
+                        if let Err(err) =
+                            tokio::io::copy_bidirectional(&mut stream, &mut ch_stream).await
+                        {
+                            tracing::debug!("agent io: {:?}", err);
+                        }
+                        Ok(())
+                    });
+                }
+                tracing::info!("agent 转发结束");
+                Ok(())
+            }
+            .instrument(agent_span),
+        );
+
+        tracing::info!("agent 转发: {}", path.display());
+        Ok(Self {
+            dir,
+            path,
+            _stop: stop,
+        })
+    }
+
+    pub(super) fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for AgentSocket {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_dir_all(&self.dir) {
+            tracing::warn!("删除 agent socket 失败: {} {:?}", self.dir.display(), err);
+        }
+    }
+}
@@ -0,0 +1,550 @@
+//! 审计日志
+//!
+//! 记录三类事件, 保存在数据库 `audit` 表中:
+//!
+//! - `login`: 公钥、证书、邀请码登录, 成功或拒绝, 以及公钥指纹和客户端地址
+//! - `action`: `exec_request` 分发的每个请求, 通道结束时记录退出码和耗时
+//! - `admin`: `admin` 的每个修改操作, 记录修改前后的数据
+//!
+//! 管理员通过 `admin audit list` 查询.
+use super::*;
+use crate::db::entity::prelude::{Audit, Ban, Invite, UserCa};
+use crate::db::entity::{audit, ban, invite, repo, repo_member, user_ca};
+use sea_orm::{Condition, QuerySelect};
+use serde_json::json;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+const AUDIT_USAGE: &str =
+    "用法: audit list [--user <name>] [--action <action-name|login|admin>] [--since <30m|24h|7d|毫秒时间戳>] [--limit N] [--json]";
+
+/// 默认最多返回的记录数
+const DEFAULT_LIMIT: u64 = 100;
+
+fn now_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+/// 一条审计记录
+#[derive(Clone, Debug, Default)]
+pub(super) struct AuditEntry {
+    /// `login` / `action` / `admin`
+    pub kind: &'static str,
+    pub user: Option<String>,
+    pub peer: Option<String>,
+    /// 登录方式 (`publickey`/`cert`/`invite`), 请求的 action, 或者 `users.add` 这样的管理操作
+    pub action: String,
+    /// `accepted`/`rejected`, `success`/`failed`/`detached`/`aborted`, `ok`/`failed`
+    pub outcome: String,
+    pub fingerprint: Option<String>,
+    pub command: Option<String>,
+    pub repo: Option<String>,
+    pub branch: Option<String>,
+    pub job_id: Option<String>,
+    pub exit_code: Option<i32>,
+    pub duration_ms: Option<i64>,
+    pub before: Option<String>,
+    pub after: Option<String>,
+}
+
+/// 写入审计日志, 失败时只记录日志, 不影响请求本身
+pub(super) async fn record(db: &DatabaseConnection, entry: AuditEntry) {
+    let model = audit::ActiveModel {
+        at: Set(now_ms()),
+        kind: Set(entry.kind.to_string()),
+        user: Set(entry.user),
+        peer: Set(entry.peer),
+        action: Set(entry.action),
+        outcome: Set(entry.outcome),
+        fingerprint: Set(entry.fingerprint),
+        command: Set(entry.command),
+        repo: Set(entry.repo),
+        branch: Set(entry.branch),
+        job_id: Set(entry.job_id),
+        exit_code: Set(entry.exit_code),
+        duration_ms: Set(entry.duration_ms),
+        before: Set(entry.before),
+        after: Set(entry.after),
+        ..Default::default()
+    };
+    if let Err(err) = Audit::insert(model).exec(db).await {
+        tracing::warn!("写入审计日志失败: {err}");
+    }
+}
+
+/// 通道上正在执行的请求, 通道结束时写入审计日志
+pub(super) struct ActionAudit {
+    db: DatabaseConnection,
+    entry: AuditEntry,
+    started: Instant,
+}
+
+impl ActionAudit {
+    pub fn new(db: DatabaseConnection, entry: AuditEntry) -> Self {
+        Self {
+            db,
+            entry,
+            started: Instant::now(),
+        }
+    }
+
+    pub fn set_job_id(&mut self, job_id: &str) {
+        self.entry.job_id = Some(job_id.to_string());
+    }
+
+    fn finished(
+        mut self,
+        outcome: &str,
+        exit_code: Option<i32>,
+    ) -> (DatabaseConnection, AuditEntry) {
+        self.entry.outcome = outcome.to_string();
+        self.entry.exit_code = exit_code;
+        self.entry.duration_ms = Some(self.started.elapsed().as_millis() as i64);
+        (self.db, self.entry)
+    }
+
+    pub async fn finish(self, outcome: &str, exit_code: Option<i32>) {
+        let (db, entry) = self.finished(outcome, exit_code);
+        record(&db, entry).await;
+    }
+
+    /// 通道没有发送退出码就被释放, 在后台写入
+    pub fn abort(self) {
+        let (db, entry) = self.finished("aborted", None);
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(async move { record(&db, entry).await });
+        }
+    }
+}
+
+/// 会修改数据的 admin 命令
+pub(super) fn is_mutation(args: &[String]) -> bool {
+    let section = args.first().map(String::as_str).unwrap_or("");
+    let command = args.get(1).map(String::as_str).unwrap_or("");
+    section != "audit" && !matches!(command, "list" | "export" | "")
+}
+
+/// admin 命令操作对象的当前数据, 用于记录修改前后的值
+pub(super) async fn snapshot(db: &DatabaseConnection, args: &[String]) -> Option<String> {
+    match snapshot_value(db, args).await {
+        Ok(value) => value.map(|value| value.to_string()),
+        Err(err) => {
+            tracing::warn!("读取审计快照失败: {err}");
+            None
+        }
+    }
+}
+
+async fn snapshot_value(
+    db: &DatabaseConnection,
+    args: &[String],
+) -> anyhow::Result<Option<serde_json::Value>> {
+    let section = args.first().map(String::as_str).unwrap_or("");
+    let command = args.get(1).map(String::as_str).unwrap_or("");
+    let arg = |index: usize| args.get(index).map(String::as_str).unwrap_or("");
+
+    let value = match (section, command) {
+        ("users", _) => User::find()
+            .filter(user::Column::Name.eq(arg(2)))
+            .one(db)
+            .await?
+            .map(|it| {
+                json!({
+                    "id": it.id,
+                    "name": it.name,
+                    "nick": it.nick,
+                    "email": it.email,
+                    "role": it.role,
+                    "enabled": it.enabled,
+                    "caps": it.caps,
+                })
+            }),
+        ("keys", "import") => {
+            let Some(owner) = User::find()
+                .filter(user::Column::Name.eq(arg(2)))
+                .one(db)
+                .await?
+            else {
+                return Ok(None);
+            };
+            let keys = owner
+                .find_related(SshPk)
+                .all(db)
+                .await?
+                .into_iter()
+                .map(|it| json!({ "alg": it.alg, "key": it.key, "options": it.options }))
+                .collect::<Vec<_>>();
+            Some(json!({ "user_id": owner.id, "keys": keys }))
+        }
+        ("keys", _) => {
+            let (alg, key) = if command == "add" {
+                (arg(3), arg(4))
+            } else {
+                (arg(2), arg(3))
+            };
+            SshPk::find_by_id((alg.to_string(), key.to_string()))
+                .one(db)
+                .await?
+                .map(|it| {
+                    json!({
+                        "alg": it.alg,
+                        "key": it.key,
+                        "user_id": it.user_id,
+                        "enabled": it.enabled,
+                        "comment": it.comment,
+                        "options": it.options,
+                        "caps": it.caps,
+                    })
+                })
+        }
+        ("repos", _) => {
+            let Some(name) = acl::normalize_repo_name(arg(2)) else {
+                return Ok(None);
+            };
+            let Some(it) = repo::Entity::find()
+                .filter(repo::Column::Name.eq(name))
+                .one(db)
+                .await?
+            else {
+                return Ok(None);
+            };
+            let members = it
+                .find_related(repo_member::Entity)
+                .all(db)
+                .await?
+                .into_iter()
+                .map(|member| json!({ "user_id": member.user_id, "level": member.level }))
+                .collect::<Vec<_>>();
+            Some(json!({
+                "id": it.id,
+                "name": it.name,
+                "owner_id": it.owner_id,
+                "workspace_mode": it.workspace_mode,
+                "workspace_prune": it.workspace_prune,
+                "members": members,
+            }))
+        }
+        ("cas", _) => UserCa::find_by_id((arg(2).to_string(), arg(3).to_string()))
+            .one(db)
+            .await?
+            .map(|it| {
+                json!({
+                    "alg": it.alg,
+                    "key": it.key,
+                    "enabled": it.enabled,
+                    "comment": it.comment,
+                })
+            }),
+        ("invites", _) => {
+            // 邀请码本身不进入审计日志
+            let select = if command == "create" {
+                Invite::find()
+                    .filter(invite::Column::UserName.eq(arg(2)))
+                    .order_by_desc(invite::Column::Id)
+            } else {
+                Invite::find().filter(invite::Column::Id.eq(arg(2).parse::<i32>().unwrap_or(0)))
+            };
+            select.one(db).await?.map(|it| {
+                json!({
+                    "id": it.id,
+                    "user_name": it.user_name,
+                    "role": it.role,
+                    "created_by": it.created_by,
+                    "expires_at_ms": it.expires_at,
+                    "redeemed_at_ms": it.redeemed_at,
+                })
+            })
+        }
+        ("bans", _) => Ban::find()
+            .filter(ban::Column::Ip.eq(arg(2)))
+            .one(db)
+            .await?
+            .map(|it| {
+                json!({
+                    "ip": it.ip,
+                    "reason": it.reason,
+                    "failures": it.failures,
+                    "expires_at_ms": it.expires_at,
+                })
+            }),
+        _ => None,
+    };
+
+    Ok(value)
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+struct AuditQuery {
+    user: Option<String>,
+    action: Option<String>,
+    since_ms: Option<i64>,
+    limit: u64,
+    json: bool,
+}
+
+fn parse_audit_list_args(args: &[String], now: i64) -> anyhow::Result<AuditQuery> {
+    let mut query = AuditQuery {
+        limit: DEFAULT_LIMIT,
+        ..Default::default()
+    };
+    let mut rest = args.iter();
+    while let Some(flag) = rest.next() {
+        let mut value = || rest.next().context(AUDIT_USAGE);
+        match flag.as_str() {
+            "--user" => query.user = Some(value()?.clone()),
+            "--action" => query.action = Some(value()?.clone()),
+            "--since" => {
+                let since = value()?;
+                query.since_ms = Some(if since.chars().all(|c| c.is_ascii_digit()) {
+                    since.parse::<i64>()?
+                } else {
+                    let secs = super::invite::parse_ttl(since)
+                        .with_context(|| format!("无效的时间: {since}, {AUDIT_USAGE}"))?;
+                    let ms = secs
+                        .checked_mul(1000)
+                        .and_then(|ms| i64::try_from(ms).ok())
+                        .with_context(|| format!("时间过长: {since}"))?;
+                    now.saturating_sub(ms)
+                });
+            }
+            "--limit" => query.limit = value()?.parse::<u64>().context(AUDIT_USAGE)?,
+            "--json" => query.json = true,
+            unknown => return Err(anyhow!("未知参数: {unknown}, {AUDIT_USAGE}")),
+        }
+    }
+    Ok(query)
+}
+
+/// 毫秒时间戳格式化为 UTC 时间
+pub fn format_utc(ms: i64) -> String {
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let secs = ms.div_euclid(1000);
+    let (days, rem) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02}Z",
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+#[derive(serde::Serialize)]
+struct AuditRow {
+    id: i32,
+    at_ms: i64,
+    kind: String,
+    user: Option<String>,
+    peer: Option<String>,
+    action: String,
+    outcome: String,
+    fingerprint: Option<String>,
+    command: Option<String>,
+    repo: Option<String>,
+    branch: Option<String>,
+    job_id: Option<String>,
+    exit_code: Option<i32>,
+    duration_ms: Option<i64>,
+    before: Option<serde_json::Value>,
+    after: Option<serde_json::Value>,
+}
+
+impl From<audit::Model> for AuditRow {
+    fn from(model: audit::Model) -> Self {
+        let parse = |it: Option<String>| it.and_then(|it| serde_json::from_str(&it).ok());
+        Self {
+            id: model.id,
+            at_ms: model.at,
+            kind: model.kind,
+            user: model.user,
+            peer: model.peer,
+            action: model.action,
+            outcome: model.outcome,
+            fingerprint: model.fingerprint,
+            command: model.command,
+            repo: model.repo,
+            branch: model.branch,
+            job_id: model.job_id,
+            exit_code: model.exit_code,
+            duration_ms: model.duration_ms,
+            before: parse(model.before),
+            after: parse(model.after),
+        }
+    }
+}
+
+fn format_row(row: &audit::Model) -> String {
+    let mut line = format!(
+        "{} {:<6} {:<8} {} {} {}",
+        format_utc(row.at),
+        row.kind,
+        row.outcome,
+        row.user.as_deref().unwrap_or("-"),
+        row.peer.as_deref().unwrap_or("-"),
+        row.action,
+    );
+    let fields = [
+        ("repo", row.repo.clone()),
+        ("branch", row.branch.clone()),
+        ("job_id", row.job_id.clone()),
+        ("exit", row.exit_code.map(|it| it.to_string())),
+        ("duration_ms", row.duration_ms.map(|it| it.to_string())),
+        ("key", row.fingerprint.clone()),
+    ];
+    for (name, value) in fields {
+        if let Some(value) = value {
+            line.push_str(&format!(" {name}={value}"));
+        }
+    }
+    if let Some(command) = &row.command {
+        line.push_str(&format!(" -- {command}"));
+    }
+    line
+}
+
+/// `admin audit list ...`: 查询审计日志
+pub(super) async fn admin_audit(
+    db: &DatabaseConnection,
+    args: &[String],
+) -> anyhow::Result<String> {
+    if args.get(1).map(String::as_str) != Some("list") {
+        return Err(anyhow!("不支持的 audit 命令, {AUDIT_USAGE}"));
+    }
+    let query = parse_audit_list_args(&args[2..], now_ms())?;
+
+    let mut select = Audit::find();
+    if let Some(user) = &query.user {
+        select = select.filter(audit::Column::User.eq(user.as_str()));
+    }
+    if let Some(action) = &query.action {
+        // `--action login` 按类型过滤, 其他按 action 过滤
+        select = select.filter(
+            Condition::any()
+                .add(audit::Column::Action.eq(action.as_str()))
+                .add(audit::Column::Kind.eq(action.as_str())),
+        );
+    }
+    if let Some(since) = query.since_ms {
+        select = select.filter(audit::Column::At.gte(since));
+    }
+    let rows = select
+        .order_by_desc(audit::Column::Id)
+        .limit(query.limit)
+        .all(db)
+        .await?;
+
+    if query.json {
+        let rows = rows.into_iter().map(AuditRow::from).collect::<Vec<_>>();
+        Ok(serde_json::to_string_pretty(&rows)?)
+    } else {
+        Ok(rows.iter().map(format_row).collect::<Vec<_>>().join("\n"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use migration::{Migrator, MigratorTrait};
+    use sea_orm::Database;
+
+    #[test]
+    fn format_utc_handles_epoch_and_leap_years() {
+        assert_eq!(format_utc(0), "1970-01-01 00:00:00Z");
+        assert_eq!(format_utc(951_782_400_000), "2000-02-29 00:00:00Z");
+        assert_eq!(format_utc(1_792_226_096_000), "2026-10-17 08:34:56Z");
+    }
+
+    #[test]
+    fn parse_audit_list_args_accepts_durations() {
+        let args = ["--user", "alice", "--since", "1h", "--json"].map(String::from);
+        let query = parse_audit_list_args(&args, 7_200_000).unwrap();
+        assert_eq!(query.user.as_deref(), Some("alice"));
+        assert_eq!(query.since_ms, Some(3_600_000));
+        assert_eq!(query.limit, DEFAULT_LIMIT);
+        assert!(query.json);
+
+        let args = ["--since", "1700000000000"].map(String::from);
+        let query = parse_audit_list_args(&args, 0).unwrap();
+        assert_eq!(query.since_ms, Some(1_700_000_000_000));
+
+        let args = ["--since"].map(String::from);
+        assert!(parse_audit_list_args(&args, 0).is_err());
+
+        // 换算成毫秒时溢出
+        for since in ["200000000000d", "300000000000d"] {
+            let args = ["--since", since].map(String::from);
+            assert!(parse_audit_list_args(&args, 0).is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn admin_mutations_record_before_and_after() {
+        let db = Database::connect("sqlite::memory:").await.unwrap();
+        Migrator::up(&db, None).await.unwrap();
+
+        let args = ["users", "role", "alice", "admin"].map(String::from);
+        assert!(is_mutation(&args));
+        assert!(!is_mutation(&["users", "list"].map(String::from)));
+        assert!(!is_mutation(&["keys", "export", "alice"].map(String::from)));
+        assert!(snapshot(&db, &args).await.is_none());
+
+        user::ActiveModel {
+            name: Set("alice".to_string()),
+            role: Set("user".to_string()),
+            enabled: Set(true),
+            ..Default::default()
+        }
+        .insert(&db)
+        .await
+        .unwrap();
+        let before = snapshot(&db, &args).await.unwrap();
+        assert!(before.contains("\"role\":\"user\""));
+
+        record(
+            &db,
+            AuditEntry {
+                kind: "admin",
+                user: Some("root".to_string()),
+                action: "users.role".to_string(),
+                outcome: "ok".to_string(),
+                before: Some(before),
+                ..Default::default()
+            },
+        )
+        .await;
+        ActionAudit::new(
+            db.clone(),
+            AuditEntry {
+                kind: "action",
+                user: Some("alice".to_string()),
+                action: "cargo".to_string(),
+                ..Default::default()
+            },
+        )
+        .finish("failed", Some(101))
+        .await;
+
+        let args = ["audit", "list", "--json"].map(String::from);
+        let rows: Vec<serde_json::Value> =
+            serde_json::from_str(&admin_audit(&db, &args).await.unwrap()).unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0]["action"], "cargo");
+        assert_eq!(rows[0]["exit_code"], 101);
+        assert_eq!(rows[1]["before"]["role"], "user");
+
+        let args = ["audit", "list", "--action", "admin"].map(String::from);
+        let text = admin_audit(&db, &args).await.unwrap();
+        assert_eq!(text.lines().count(), 1);
+        assert!(text.contains("users.role"));
+    }
+}
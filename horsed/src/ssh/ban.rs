@@ -0,0 +1,421 @@
+//! 认证失败限流与地址封禁
+//!
+//! 认证失败按客户端地址计数, 每个最终没有登录成功的连接计一次失败, 连接中尝试了多少个公钥不影响计数.
+//! SSH 用户名是请求的 action (`cargo`, `git` 等), 所有客户端共用, 不能用来区分账户, 所以不参与计数.
+//! 计数只保存在内存中, 同一地址在 `auth.window_secs` 内失败超过 `auth.max_failures` 次, 封禁该地址.
+//!
+//! 封禁保存在数据库 `ban` 表中, 重启后仍然有效, 时长为 `auth.ban_secs`.
+//! 被封禁的地址在 `accept` 之后直接断开, 已经建立的连接后续的认证请求全部拒绝.
+//! 认证成功会清空该地址的失败计数.
+//!
+//! 失败计数每个窗口清理一次, 删除窗口内已经没有失败的地址; 最多保留 [`MAX_TRACKED`] 个地址,
+//! 超出时丢弃最近一次失败最早的记录.
+//!
+//! 管理员通过 `admin bans list` 查看, `admin bans clear <ip>|--all` 解除封禁.
+use super::*;
+use crate::db::entity::ban;
+use crate::db::entity::prelude::Ban;
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const BANS_USAGE: &str = "用法: bans list | bans clear <ip>|--all";
+
+/// 失败计数最多保留的地址数
+const MAX_TRACKED: usize = 10_000;
+
+fn now_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+/// 限流参数, 取自 `[auth]` 配置
+#[derive(Clone, Copy, Debug)]
+pub(super) struct BanPolicy {
+    max_failures: u32,
+    window_ms: i64,
+    ban_ms: i64,
+}
+
+impl Default for BanPolicy {
+    fn default() -> Self {
+        let config = crate::config::config();
+        Self {
+            max_failures: config.auth.max_failures,
+            window_ms: config.auth_window().as_millis() as i64,
+            ban_ms: config.ban_duration().as_millis() as i64,
+        }
+    }
+}
+
+#[derive(Default)]
+struct BanState {
+    /// 各地址在窗口内的失败时间
+    ips: HashMap<IpAddr, VecDeque<i64>>,
+    /// 封禁中的地址及到期时间
+    bans: HashMap<IpAddr, i64>,
+    /// 上次清理的时间
+    swept_at: i64,
+}
+
+impl BanState {
+    /// 每个窗口清理一次已经过期的失败计数和封禁
+    fn sweep(&mut self, now: i64, window_ms: i64) {
+        if now.saturating_sub(self.swept_at) < window_ms {
+            return;
+        }
+        self.swept_at = now;
+        let alive =
+            |window: &mut VecDeque<i64>| window.back().is_some_and(|at| *at > now - window_ms);
+        self.ips.retain(|_, window| alive(window));
+        self.bans.retain(|_, expires_at| *expires_at > now);
+    }
+}
+
+/// 超过上限时丢弃最近一次失败最早的记录, 一次清理到上限的 90%, 避免每次失败都要排序
+fn cap(map: &mut HashMap<IpAddr, VecDeque<i64>>) {
+    if map.len() < MAX_TRACKED {
+        return;
+    }
+    let mut keys = map
+        .iter()
+        .map(|(ip, window)| (window.back().copied().unwrap_or(i64::MIN), *ip))
+        .collect::<Vec<_>>();
+    keys.sort_unstable_by_key(|(at, _)| *at);
+    let excess = map.len() - MAX_TRACKED * 9 / 10;
+    for (_, ip) in keys.into_iter().take(excess) {
+        map.remove(&ip);
+    }
+}
+
+/// 新增的封禁
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(super) struct NewBan {
+    pub ip: IpAddr,
+    pub reason: String,
+    pub failures: u32,
+    pub expires_at: i64,
+}
+
+/// 所有连接共享的失败计数与封禁列表
+#[derive(Clone, Default)]
+pub(super) struct BanList {
+    policy: BanPolicy,
+    state: Arc<std::sync::Mutex<BanState>>,
+}
+
+/// 记录一次失败并返回窗口内的失败次数
+fn push_failure(window: &mut VecDeque<i64>, now: i64, window_ms: i64) -> u32 {
+    window.push_back(now);
+    while window.front().is_some_and(|at| *at <= now - window_ms) {
+        window.pop_front();
+    }
+    window.len() as u32
+}
+
+impl BanList {
+    #[cfg(test)]
+    fn new(policy: BanPolicy) -> Self {
+        Self {
+            policy,
+            state: Default::default(),
+        }
+    }
+
+    /// 从数据库载入未到期的封禁, 同时删除已到期的记录
+    pub(super) async fn load(&self, db: &DatabaseConnection) -> HorseResult<usize> {
+        let now = now_ms();
+        Ban::delete_many()
+            .filter(ban::Column::ExpiresAt.lte(now))
+            .exec(db)
+            .await?;
+
+        let records = Ban::find().all(db).await?;
+        let mut state = self.state.lock().unwrap();
+        state.bans.clear();
+        for record in records {
+            match record.ip.parse::<IpAddr>() {
+                Ok(ip) => {
+                    state.bans.insert(ip, record.expires_at);
+                }
+                Err(_) => tracing::warn!("忽略无效的封禁地址: {}", record.ip),
+            }
+        }
+        Ok(state.bans.len())
+    }
+
+    /// 地址是否处于封禁中
+    pub(super) fn is_banned(&self, ip: IpAddr) -> bool {
+        self.is_banned_at(ip, now_ms())
+    }
+
+    fn is_banned_at(&self, ip: IpAddr, now: i64) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match state.bans.get(&ip) {
+            Some(expires_at) if *expires_at > now => true,
+            Some(_) => {
+                state.bans.remove(&ip);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// 认证成功, 清空地址的失败计数
+    pub(super) fn success(&self, ip: Option<IpAddr>) {
+        if let Some(ip) = ip {
+            self.state.lock().unwrap().ips.remove(&ip);
+        }
+    }
+
+    /// 计入一次认证失败, 超出阈值时返回新增的封禁
+    fn failure_at(&self, ip: IpAddr, now: i64) -> Option<NewBan> {
+        let policy = self.policy;
+        if policy.max_failures == 0 {
+            return None;
+        }
+        let mut state = self.state.lock().unwrap();
+        state.sweep(now, policy.window_ms);
+
+        if !state.ips.contains_key(&ip) {
+            cap(&mut state.ips);
+        }
+        let window = state.ips.entry(ip).or_default();
+        let ip_failures = push_failure(window, now, policy.window_ms);
+        if ip_failures <= policy.max_failures {
+            return None;
+        }
+        let reason = format!("地址认证失败 {ip_failures} 次");
+
+        let expires_at = now.saturating_add(policy.ban_ms);
+        state.bans.insert(ip, expires_at);
+        state.ips.remove(&ip);
+        Some(NewBan {
+            ip,
+            reason,
+            failures: ip_failures,
+            expires_at,
+        })
+    }
+
+    /// 计入一次认证失败, 超出阈值时封禁地址并写入数据库
+    pub(super) async fn failure(&self, db: &DatabaseConnection, ip: IpAddr) -> Option<NewBan> {
+        let now = now_ms();
+        let banned = self.failure_at(ip, now)?;
+        tracing::warn!(
+            "封禁地址: {} ({}), 到期: {}",
+            banned.ip,
+            banned.reason,
+            audit::format_utc(banned.expires_at)
+        );
+        if let Err(err) = save(db, &banned, now).await {
+            tracing::error!("保存封禁记录失败: {err}");
+        }
+        Some(banned)
+    }
+
+    /// 解除封禁, `ip` 为空时解除全部
+    async fn clear(&self, db: &DatabaseConnection, ip: Option<IpAddr>) -> HorseResult<u64> {
+        let mut delete = Ban::delete_many();
+        if let Some(ip) = ip {
+            delete = delete.filter(ban::Column::Ip.eq(ip.to_string()));
+        }
+        let deleted = delete.exec(db).await?.rows_affected;
+
+        let mut state = self.state.lock().unwrap();
+        match ip {
+            Some(ip) => {
+                state.bans.remove(&ip);
+                state.ips.remove(&ip);
+            }
+            None => {
+                state.bans.clear();
+                state.ips.clear();
+            }
+        }
+        Ok(deleted)
+    }
+}
+
+/// 同一地址只保留最近的一条封禁
+async fn save(db: &DatabaseConnection, banned: &NewBan, now: i64) -> HorseResult<()> {
+    Ban::delete_many()
+        .filter(ban::Column::Ip.eq(banned.ip.to_string()))
+        .exec(db)
+        .await?;
+    ban::ActiveModel {
+        ip: Set(banned.ip.to_string()),
+        reason: Set(banned.reason.clone()),
+        failures: Set(banned.failures as i32),
+        created_at: Set(now),
+        expires_at: Set(banned.expires_at),
+        ..Default::default()
+    }
+    .insert(db)
+    .await?;
+    Ok(())
+}
+
+#[derive(serde::Serialize)]
+struct AdminBanRow {
+    ip: String,
+    reason: String,
+    failures: i32,
+    created_at: String,
+    expires_at: String,
+    created_at_ms: i64,
+    expires_at_ms: i64,
+}
+
+/// `admin bans ...`: 查看与解除封禁
+pub(super) async fn admin_bans(
+    db: &DatabaseConnection,
+    bans: &BanList,
+    args: &[String],
+) -> anyhow::Result<String> {
+    let command = args.get(1).map(String::as_str).unwrap_or("");
+
+    let output = match command {
+        "list" => {
+            let records = Ban::find()
+                .filter(ban::Column::ExpiresAt.gt(now_ms()))
+                .order_by_desc(ban::Column::CreatedAt)
+                .all(db)
+                .await?;
+            let rows = records
+                .into_iter()
+                .map(|record| AdminBanRow {
+                    created_at: audit::format_utc(record.created_at),
+                    expires_at: audit::format_utc(record.expires_at),
+                    ip: record.ip,
+                    reason: record.reason,
+                    failures: record.failures,
+                    created_at_ms: record.created_at,
+                    expires_at_ms: record.expires_at,
+                })
+                .collect::<Vec<_>>();
+            serde_json::to_string_pretty(&rows)?
+        }
+        "clear" => {
+            let target = args.get(2).context(BANS_USAGE)?;
+            if target == "--all" {
+                let deleted = bans.clear(db, None).await?;
+                format!("已解除全部封禁: {deleted} 个地址")
+            } else {
+                let ip = target
+                    .parse::<IpAddr>()
+                    .map_err(|_| anyhow!("无效的地址: {target}"))?;
+                if bans.clear(db, Some(ip)).await? == 0 {
+                    return Err(anyhow!("地址未被封禁: {ip}"));
+                }
+                format!("已解除封禁: {ip}")
+            }
+        }
+        _ => return Err(anyhow!("不支持的 bans 命令, {BANS_USAGE}")),
+    };
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use migration::{Migrator, MigratorTrait};
+    use sea_orm::Database;
+
+    fn policy(max_failures: u32) -> BanPolicy {
+        BanPolicy {
+            max_failures,
+            window_ms: 60_000,
+            ban_ms: 3_600_000,
+        }
+    }
+
+    #[test]
+    fn bans_address_over_threshold() {
+        let bans = BanList::new(policy(3));
+        let ip: IpAddr = "10.0.0.9".parse().unwrap();
+
+        for at in 0..3 {
+            assert_eq!(bans.failure_at(ip, at * 1000), None);
+        }
+        let banned = bans.failure_at(ip, 3000).unwrap();
+        assert_eq!(banned.failures, 4);
+        assert_eq!(banned.expires_at, 3000 + 3_600_000);
+        assert!(bans.is_banned_at(ip, 3001));
+        assert!(!bans.is_banned_at(ip, banned.expires_at));
+
+        // 窗口外的失败不计数
+        let other: IpAddr = "10.0.0.10".parse().unwrap();
+        for at in 0..10 {
+            assert_eq!(bans.failure_at(other, at * 60_000), None);
+        }
+
+        // 认证成功清空计数
+        let third: IpAddr = "10.0.0.11".parse().unwrap();
+        for _ in 0..3 {
+            bans.failure_at(third, 0);
+        }
+        bans.success(Some(third));
+        assert_eq!(bans.failure_at(third, 0), None);
+
+        // 不限制时不计数
+        let bans = BanList::new(policy(0));
+        for _ in 0..100 {
+            assert_eq!(bans.failure_at(ip, 0), None);
+        }
+        assert!(bans.state.lock().unwrap().ips.is_empty());
+    }
+
+    #[test]
+    fn failure_counts_are_evicted_and_capped() {
+        let bans = BanList::new(policy(100));
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        bans.failure_at(ip, 0);
+        assert_eq!(bans.state.lock().unwrap().ips.len(), 1);
+
+        // 窗口过后再次失败时清理掉已经没有失败的记录
+        let other: IpAddr = "10.0.0.2".parse().unwrap();
+        bans.failure_at(other, 60_000);
+        assert_eq!(
+            bans.state.lock().unwrap().ips.keys().collect::<Vec<_>>(),
+            vec![&other]
+        );
+
+        // 窗口内的大量地址也不会超过上限
+        let addr = |n: usize| IpAddr::from(std::net::Ipv4Addr::from(0x0a01_0000 + n as u32));
+        for n in 0..MAX_TRACKED + 10 {
+            bans.failure_at(addr(n), 60_001 + n as i64);
+        }
+        let state = bans.state.lock().unwrap();
+        assert!(state.ips.len() <= MAX_TRACKED);
+        assert!(state.ips.contains_key(&addr(MAX_TRACKED + 9)));
+        assert!(!state.ips.contains_key(&addr(0)));
+    }
+
+    #[tokio::test]
+    async fn bans_survive_restart() {
+        let db = Database::connect("sqlite::memory:").await.unwrap();
+        Migrator::up(&db, None).await.unwrap();
+
+        let ip: IpAddr = "10.1.2.3".parse().unwrap();
+        let bans = BanList::new(policy(1));
+        assert!(bans.failure(&db, ip).await.is_none());
+        assert!(bans.failure(&db, ip).await.is_some());
+
+        let restarted = BanList::new(policy(1));
+        assert_eq!(restarted.load(&db).await.unwrap(), 1);
+        assert!(restarted.is_banned(ip));
+
+        let list = ["bans", "list"].map(String::from);
+        let output = admin_bans(&db, &restarted, &list).await.unwrap();
+        assert!(output.contains("10.1.2.3"));
+
+        let clear = ["bans", "clear", "10.1.2.3"].map(String::from);
+        admin_bans(&db, &restarted, &clear).await.unwrap();
+        assert!(!restarted.is_banned(ip));
+        assert!(admin_bans(&db, &restarted, &clear).await.is_err());
+        assert_eq!(Ban::find().count(&db).await.unwrap(), 0);
+    }
+}
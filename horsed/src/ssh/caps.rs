@@ -0,0 +1,189 @@
+//! 用户与公钥的操作权限 (capabilities)
+//!
+//! 权限可以分别设置在用户和公钥上, 登录后的有效权限是两者的交集; 未设置时不限制.
+//! `exec_request`、`pty_request`、`tcpip_forward` 和 `channel_open_direct_tcpip`
+//! 在分发之前检查, 拒绝时返回 `HSSH_FORBIDDEN`.
+//!
+//! `health`/`ping`/`logs`/`metrics`/`job` 不需要额外权限, `admin` 仍然只看管理员角色.
+use anyhow::bail;
+use std::fmt;
+
+/// 单项权限
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Capability {
+    /// git push/clone
+    Git,
+    /// cargo/just 构建, 以及 apply/sync 同步代码
+    Build,
+    /// cmd/ssh 执行任意命令和 pty
+    Shell,
+    /// 端口转发 (`-L`/`-R`) 和 agent 转发
+    Forward,
+    /// 下载产物: get/scp, 只读的 sftp
+    Read,
+    /// 上传文件: put, sftp 写入
+    Write,
+}
+
+const ALL: [Capability; 6] = [
+    Capability::Git,
+    Capability::Build,
+    Capability::Shell,
+    Capability::Forward,
+    Capability::Read,
+    Capability::Write,
+];
+
+/// 预设的权限组合
+const PRESETS: [(&str, &[Capability]); 3] = [
+    ("git-only", &[Capability::Git]),
+    ("build-only", &[Capability::Build, Capability::Read]),
+    ("read-only", &[Capability::Read]),
+];
+
+impl Capability {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Capability::Git => "git",
+            Capability::Build => "build",
+            Capability::Shell => "shell",
+            Capability::Forward => "forward",
+            Capability::Read => "read",
+            Capability::Write => "write",
+        }
+    }
+
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+
+    /// exec action 需要的权限, 不需要权限的 action 返回 `None`
+    pub fn for_action(action: &str) -> Option<Self> {
+        match action {
+            "git" => Some(Capability::Git),
+            "cargo" | "just" | "apply" | "sync" => Some(Capability::Build),
+            "cmd" | "cmd-sync" | "ssh" => Some(Capability::Shell),
+            "get" | "scp" => Some(Capability::Read),
+            "put" => Some(Capability::Write),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// 一组权限
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Capabilities(u8);
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Self::all()
+    }
+}
+
+impl Capabilities {
+    pub fn all() -> Self {
+        Self(ALL.iter().fold(0, |bits, cap| bits | cap.bit()))
+    }
+
+    pub fn none() -> Self {
+        Self(0)
+    }
+
+    /// 解析逗号分隔的权限或预设, 如 `git,read`、`build-only`、`all`、`none`
+    pub fn parse(spec: &str) -> anyhow::Result<Self> {
+        let mut caps = Self::none();
+        for name in spec.split(',').map(str::trim) {
+            let name = name.to_ascii_lowercase();
+            match name.as_str() {
+                "all" => caps = Self::all(),
+                "none" => {}
+                _ => {
+                    if let Some((_, preset)) = PRESETS.iter().find(|(preset, _)| *preset == name) {
+                        caps.0 |= preset.iter().fold(0, |bits, cap| bits | cap.bit());
+                    } else if let Some(cap) = ALL.iter().find(|cap| cap.as_str() == name) {
+                        caps.0 |= cap.bit();
+                    } else {
+                        bail!(
+                            "未知的权限: {name}, 可选: all, none, git-only, build-only, read-only, {}",
+                            ALL.map(|cap| cap.as_str()).join(", ")
+                        );
+                    }
+                }
+            }
+        }
+        Ok(caps)
+    }
+
+    /// 数据库中保存的权限, 为空表示不限制; 无法解析时不授予任何权限
+    pub fn load(stored: Option<&str>) -> Self {
+        let Some(stored) = stored else {
+            return Self::all();
+        };
+        Self::parse(stored).unwrap_or_else(|err| {
+            tracing::error!("无效的权限设置: {stored}: {err}");
+            Self::none()
+        })
+    }
+
+    /// 保存到数据库的值, 不限制时为空
+    pub fn to_stored(self) -> Option<String> {
+        (self != Self::all()).then(|| self.to_string())
+    }
+
+    pub fn allows(self, cap: Capability) -> bool {
+        self.0 & cap.bit() != 0
+    }
+
+    pub fn intersect(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if *self == Self::all() {
+            return f.write_str("all");
+        }
+        let names = ALL
+            .iter()
+            .filter(|cap| self.allows(**cap))
+            .map(Capability::as_str)
+            .collect::<Vec<_>>();
+        if names.is_empty() {
+            return f.write_str("none");
+        }
+        f.write_str(&names.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capabilities_are_parsed_and_intersected() {
+        let user = Capabilities::parse("build-only, git").unwrap();
+        assert_eq!(user.to_string(), "git,build,read");
+        assert!(!user.allows(Capability::Shell));
+
+        let key = Capabilities::parse("git-only").unwrap();
+        let caps = user.intersect(key);
+        assert!(caps.allows(Capability::Git));
+        assert!(!caps.allows(Capability::Build));
+
+        assert_eq!(Capabilities::parse("ALL").unwrap().to_stored(), None);
+        assert_eq!(Capabilities::parse("none").unwrap().to_string(), "none");
+        assert_eq!(Capabilities::load(None), Capabilities::all());
+        assert_eq!(Capabilities::load(Some("sudo")), Capabilities::none());
+        assert!(Capabilities::parse("git,sudo").is_err());
+
+        assert_eq!(Capability::for_action("cmd"), Some(Capability::Shell));
+        assert_eq!(Capability::for_action("job"), None);
+    }
+}
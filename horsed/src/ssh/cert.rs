@@ -0,0 +1,383 @@
+//! OpenSSH 用户证书登录
+//!
+//! 管理员通过 `admin cas add` 登记受信任的 CA 公钥. 证书需要满足:
+//!
+//! - 类型是用户证书, 由启用中的 CA 签发, 签名有效, 当前时间在有效期内
+//! - 按顺序第一个对应启用中用户的 principal 作为登录用户, principal 为空的证书直接拒绝
+//! - 关键选项只支持 `source-address`, 其他关键选项 (包括 `force-command`) 一律拒绝
+//! - 扩展按 OpenSSH 的语义处理: 证书没有 `permit-pty`/`permit-port-forwarding`/`permit-agent-forwarding`
+//!   时禁止对应的功能, 与公钥选项的 `no-pty` 等相同, 见 [`super::KeyOptions::from_extensions`]
+//!
+//! 登录后证书的 key id 与序列号记录在日志和任务的 `owner_cert` 中.
+use super::*;
+use crate::db::entity::prelude::UserCa;
+use crate::db::entity::user_ca;
+use russh::keys::ssh_key::certificate::CertType;
+use russh::keys::HashAlg;
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(serde::Serialize)]
+struct AdminCaRow {
+    alg: String,
+    key: String,
+    fingerprint: Option<String>,
+    enabled: bool,
+    comment: Option<String>,
+}
+
+/// 证书标识: `<key_id>#<serial>`
+pub(super) fn identity(cert: &Certificate) -> String {
+    format!("{}#{}", cert.key_id(), cert.serial())
+}
+
+/// 校验证书, 返回证书对应的用户; 拒绝时记录原因并返回 `None`
+pub(super) async fn verify(
+    db: &DatabaseConnection,
+    cert: &Certificate,
+    peer: Option<IpAddr>,
+) -> HorseResult<Option<user::Model>> {
+    let identity = identity(cert);
+    let ca = PublicKey::from(cert.signature_key().clone());
+    #[allow(deprecated)]
+    let data = base64::encode(&ca.to_bytes().context("ca bytes")?);
+
+    let Some(record) = UserCa::find_by_id((ca.algorithm().to_string(), data))
+        .one(db)
+        .await?
+    else {
+        tracing::error!(
+            "证书签发者不受信任: {identity} ({} {})",
+            ca.algorithm(),
+            ca.fingerprint(HashAlg::Sha256)
+        );
+        return Ok(None);
+    };
+
+    if !record.enabled {
+        tracing::warn!(
+            "证书签发者已禁用: {identity} ({} {})",
+            ca.algorithm(),
+            ca.fingerprint(HashAlg::Sha256)
+        );
+        return Ok(None);
+    }
+
+    if cert.cert_type() != CertType::User {
+        tracing::error!("不是用户证书: {identity}");
+        return Ok(None);
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|it| it.as_secs())
+        .unwrap_or(0);
+    if now < cert.valid_after() || now >= cert.valid_before() {
+        tracing::warn!(
+            "证书不在有效期内: {identity} ({}..{})",
+            cert.valid_after(),
+            cert.valid_before()
+        );
+        return Ok(None);
+    }
+
+    let fingerprint = ca.fingerprint(HashAlg::Sha256);
+    if let Err(err) = cert.validate_at(now, [&fingerprint]) {
+        tracing::error!("证书校验失败: {identity}: {err}");
+        return Ok(None);
+    }
+
+    if let Err(reason) = check_critical_options(cert.critical_options().iter(), peer) {
+        tracing::warn!("证书关键选项不满足: {identity}: {reason}");
+        return Ok(None);
+    }
+
+    let principals = cert.valid_principals();
+    let users = User::find()
+        .filter(user::Column::Name.is_in(principals.iter().map(String::as_str)))
+        .filter(user::Column::Enabled.eq(true))
+        .all(db)
+        .await?;
+    let Some(user) = principals
+        .iter()
+        .find_map(|name| users.iter().find(|user| &user.name == name))
+    else {
+        tracing::warn!("证书 principal 没有对应的启用用户: {identity} {principals:?}");
+        return Ok(None);
+    };
+
+    Ok(Some(user.clone()))
+}
+
+/// 检查证书的关键选项, 返回不满足的原因
+fn check_critical_options<'a>(
+    options: impl IntoIterator<Item = (&'a String, &'a String)>,
+    peer: Option<IpAddr>,
+) -> Result<(), String> {
+    for (name, value) in options {
+        match name.as_str() {
+            "source-address" => {
+                let Some(peer) = peer else {
+                    return Err("无法获取客户端地址".to_string());
+                };
+                if !source_address_allows(value, peer) {
+                    return Err(format!("客户端地址 {peer} 不在 source-address 中: {value}"));
+                }
+            }
+            unknown => return Err(format!("不支持的关键选项: {unknown}")),
+        }
+    }
+    Ok(())
+}
+
+/// `source-address` 是逗号分隔的 CIDR 列表, 任意一项匹配即可
+fn source_address_allows(list: &str, peer: IpAddr) -> bool {
+    let peer = peer.to_canonical();
+    list.split(',')
+        .map(str::trim)
+        .filter(|it| !it.is_empty())
+        .any(|pattern| cidr_contains(pattern, peer))
+}
+
+pub(super) fn cidr_contains(pattern: &str, peer: IpAddr) -> bool {
+    let (addr, prefix) = match pattern.split_once('/') {
+        Some((addr, prefix)) => (addr, prefix.parse::<u32>().ok()),
+        None => (pattern, None),
+    };
+    let Ok(addr) = addr.parse::<IpAddr>() else {
+        return false;
+    };
+
+    match (addr.to_canonical(), peer) {
+        (IpAddr::V4(addr), IpAddr::V4(peer)) => {
+            let prefix = prefix.unwrap_or(32);
+            if prefix > 32 {
+                return false;
+            }
+            let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+            u32::from(addr) & mask == u32::from(peer) & mask
+        }
+        (IpAddr::V6(addr), IpAddr::V6(peer)) => {
+            let prefix = prefix.unwrap_or(128);
+            if prefix > 128 {
+                return false;
+            }
+            let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+            u128::from(addr) & mask == u128::from(peer) & mask
+        }
+        _ => false,
+    }
+}
+
+/// `admin cas ...`: 管理受信任的用户证书 CA
+pub(super) async fn admin_cas(db: &DatabaseConnection, args: &[String]) -> anyhow::Result<String> {
+    let command = args.get(1).map(String::as_str).unwrap_or("");
+
+    let output = match command {
+        "list" => {
+            let cas = UserCa::find()
+                .order_by_asc(user_ca::Column::Alg)
+                .order_by_asc(user_ca::Column::Key)
+                .all(db)
+                .await?;
+            let rows = cas
+                .into_iter()
+                .map(|ca| AdminCaRow {
+                    fingerprint: PublicKey::from_openssh(&format!("{} {}", ca.alg, ca.key))
+                        .ok()
+                        .map(|key| key.fingerprint(HashAlg::Sha256).to_string()),
+                    alg: ca.alg,
+                    key: ca.key,
+                    enabled: ca.enabled,
+                    comment: ca.comment,
+                })
+                .collect::<Vec<_>>();
+            serde_json::to_string_pretty(&rows)?
+        }
+        "add" => {
+            let usage = "用法: cas add <alg> <key> [comment]";
+            let alg = args.get(2).context(usage)?;
+            let key = args.get(3).context(usage)?;
+            let comment = if args.len() > 4 {
+                Some(args[4..].join(" "))
+            } else {
+                None
+            };
+
+            let public = PublicKey::from_openssh(&format!("{alg} {key}"))
+                .map_err(|err| anyhow!("无法解析 CA 公钥: {err}"))?;
+
+            user_ca::ActiveModel {
+                alg: Set(alg.to_string()),
+                key: Set(key.to_string()),
+                enabled: Set(true),
+                comment: Set(comment),
+            }
+            .insert(db)
+            .await?;
+
+            format!("CA 已添加: {}", public.fingerprint(HashAlg::Sha256))
+        }
+        "enable" | "disable" => {
+            let enabled = command == "enable";
+            let usage = format!("用法: cas {command} <alg> <key>");
+            let alg = args.get(2).context(usage.clone())?;
+            let key = args.get(3).context(usage)?;
+            let Some(target) = UserCa::find_by_id((alg.to_string(), key.to_string()))
+                .one(db)
+                .await?
+            else {
+                return Err(anyhow!("CA 不存在"));
+            };
+
+            if target.enabled != enabled {
+                let mut active: user_ca::ActiveModel = target.into();
+                active.enabled = Set(enabled);
+                active.update(db).await?;
+            }
+
+            if enabled {
+                format!("CA 已启用: {alg}")
+            } else {
+                format!("CA 已禁用: {alg}")
+            }
+        }
+        "delete" => {
+            let alg = args.get(2).context("用法: cas delete <alg> <key>")?;
+            let key = args.get(3).context("用法: cas delete <alg> <key>")?;
+            let Some(target) = UserCa::find_by_id((alg.to_string(), key.to_string()))
+                .one(db)
+                .await?
+            else {
+                return Err(anyhow!("CA 不存在"));
+            };
+
+            target.delete(db).await?;
+            "CA 已删除".to_string()
+        }
+        _ => {
+            return Err(anyhow!(
+                "不支持的 cas 命令, 用法: cas <list|add|enable|disable|delete> ..."
+            ));
+        }
+    };
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use migration::{Migrator, MigratorTrait};
+    use rand_core::OsRng;
+    use russh::keys::ssh_key::certificate::Builder;
+    use russh::keys::{Algorithm, PrivateKey};
+    use sea_orm::Database;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn source_address_matches_cidr_lists() {
+        let peer: IpAddr = "10.1.2.3".parse().unwrap();
+        assert!(source_address_allows("10.0.0.0/8", peer));
+        assert!(source_address_allows("192.168.0.0/16, 10.1.2.3", peer));
+        assert!(!source_address_allows("10.1.3.0/24", peer));
+        assert!(!source_address_allows("10.0.0.0/33,::/0", peer));
+        assert!(source_address_allows("0.0.0.0/0", peer));
+
+        // IPv4-mapped IPv6 地址按 IPv4 处理
+        let mapped: IpAddr = "::ffff:10.1.2.3".parse().unwrap();
+        assert!(source_address_allows("10.1.2.0/24", mapped));
+
+        let v6: IpAddr = "2001:db8::1".parse().unwrap();
+        assert!(source_address_allows("2001:db8::/32", v6));
+        assert!(!source_address_allows("2001:db9::/32", v6));
+    }
+
+    #[test]
+    fn unknown_critical_options_are_rejected() {
+        let peer = Some("127.0.0.1".parse().unwrap());
+        let mut options = BTreeMap::new();
+        options.insert("source-address".to_string(), "127.0.0.0/8".to_string());
+        assert!(check_critical_options(&options, peer).is_ok());
+        assert!(check_critical_options(&options, None).is_err());
+
+        options.insert("force-command".to_string(), "/bin/true".to_string());
+        assert!(check_critical_options(&options, peer).is_err());
+    }
+
+    fn sign(ca: &PrivateKey, principals: &[&str], valid: std::ops::Range<u64>) -> Certificate {
+        let subject = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+        let mut builder = Builder::new_with_random_nonce(
+            &mut OsRng,
+            subject.public_key().key_data().clone(),
+            valid.start,
+            valid.end,
+        )
+        .unwrap();
+        builder.serial(42).unwrap();
+        builder.key_id("ci-runner").unwrap();
+        builder.cert_type(CertType::User).unwrap();
+        for principal in principals {
+            builder.valid_principal(*principal).unwrap();
+        }
+        builder.sign(ca).unwrap()
+    }
+
+    #[tokio::test]
+    async fn certificate_maps_principal_to_user() {
+        let db = Database::connect("sqlite::memory:").await.unwrap();
+        Migrator::up(&db, None).await.unwrap();
+
+        for (name, enabled) in [("alice", false), ("bob", true)] {
+            user::ActiveModel {
+                name: Set(name.to_string()),
+                role: Set("user".to_string()),
+                enabled: Set(enabled),
+                ..Default::default()
+            }
+            .insert(&db)
+            .await
+            .unwrap();
+        }
+
+        let ca = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let cert = sign(&ca, &["alice", "bob"], now - 60..now + 60);
+        assert_eq!(identity(&cert), "ci-runner#42");
+
+        // CA 未登记
+        assert!(verify(&db, &cert, None).await.unwrap().is_none());
+
+        let public = ca.public_key();
+        #[allow(deprecated)]
+        let key = base64::encode(public.to_bytes().unwrap());
+        admin_cas(
+            &db,
+            &[
+                "cas".to_string(),
+                "add".to_string(),
+                public.algorithm().to_string(),
+                key,
+            ],
+        )
+        .await
+        .unwrap();
+
+        // alice 已禁用, 使用下一个 principal
+        let user = verify(&db, &cert, None).await.unwrap().unwrap();
+        assert_eq!(user.name, "bob");
+
+        let expired = sign(&ca, &["bob"], now - 120..now - 60);
+        assert!(verify(&db, &expired, None).await.unwrap().is_none());
+
+        let unknown = sign(&ca, &["carol"], now - 60..now + 60);
+        assert!(verify(&db, &unknown, None).await.unwrap().is_none());
+
+        let other = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+        let forged = sign(&other, &["bob"], now - 60..now + 60);
+        assert!(verify(&db, &forged, None).await.unwrap().is_none());
+    }
+}
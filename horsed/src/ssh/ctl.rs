@@ -0,0 +1,115 @@
+//! `horsed ctl` 访问的服务状态
+//!
+//! 正式服务启动时登记连接列表和任务管理, IPC 服务通过 [`server`] 读取.
+use super::jobs::{JobQuery, JobRegistry, JobSignal, JobStatus};
+use super::session::SessionRegistry;
+use crate::ipc::data::{Response, ServerStatus};
+use once_cell::sync::OnceCell;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+static SERVER: OnceCell<ServerHandle> = OnceCell::new();
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// 正式服务的共享状态
+#[derive(Clone)]
+pub struct ServerHandle {
+    pub(super) jobs: JobRegistry,
+    pub(super) sessions: SessionRegistry,
+    started_at_ms: u64,
+}
+
+/// 正式服务尚未启动时为空
+pub fn server() -> Option<&'static ServerHandle> {
+    SERVER.get()
+}
+
+pub(super) fn register(jobs: JobRegistry, sessions: SessionRegistry) {
+    let handle = ServerHandle {
+        jobs,
+        sessions,
+        started_at_ms: now_ms(),
+    };
+    if SERVER.set(handle).is_err() {
+        tracing::warn!("正式服务已经登记");
+    }
+}
+
+impl ServerHandle {
+    pub async fn status(&self) -> ServerStatus {
+        let jobs = self.jobs.list_visible("", true).await;
+        let count = |status: JobStatus| {
+            jobs.iter()
+                .filter(|job| job.status == status.as_str())
+                .count()
+        };
+        ServerStatus {
+            started_at_ms: self.started_at_ms,
+            uptime_secs: now_ms().saturating_sub(self.started_at_ms) / 1000,
+            sessions: self.sessions.count(),
+            running_jobs: count(JobStatus::Running),
+            queued_jobs: count(JobStatus::Queued),
+            ..Default::default()
+        }
+    }
+
+    pub fn sessions(&self) -> Response {
+        Response::Sessions {
+            sessions: self.sessions.list(),
+        }
+    }
+
+    /// `all` 为 false 时只列出运行中和排队中的任务
+    pub async fn jobs(&self, all: bool, limit: usize) -> Response {
+        if !all {
+            let jobs = self
+                .jobs
+                .list_visible("", true)
+                .await
+                .into_iter()
+                .filter(|job| {
+                    job.status == JobStatus::Running.as_str()
+                        || job.status == JobStatus::Queued.as_str()
+                })
+                .take(limit)
+                .collect();
+            return Response::Jobs { jobs };
+        }
+
+        let query = JobQuery {
+            limit,
+            ..Default::default()
+        };
+        match self.jobs.list("", true, &query).await {
+            Ok(jobs) => Response::Jobs { jobs },
+            Err(err) => Response::error("HSSH_CTL_FAILED", err.to_string()),
+        }
+    }
+
+    pub async fn kill_job(&self, id: &str, signal: &str) -> Response {
+        let Some(signal) = JobSignal::parse(signal) else {
+            return Response::error("HSSH_JOB_BAD_REQUEST", format!("不支持的信号: {signal}"));
+        };
+        let Some(job) = self.jobs.get_visible(id, "", true).await else {
+            return match self.jobs.find_history(id, "", true).await {
+                Ok(Some(_)) => {
+                    Response::error("HSSH_JOB_NOT_RUNNING", format!("任务已经结束: {id}"))
+                }
+                Ok(None) => Response::error("HSSH_JOB_NOT_FOUND", format!("未找到任务: {id}")),
+                Err(err) => Response::error("HSSH_CTL_FAILED", err.to_string()),
+            };
+        };
+
+        let grace = crate::config::config().kill_grace();
+        match job.kill(signal, grace).await {
+            Ok(true) => Response::done(format!("已向任务 {id} 发送 {}", signal.as_str())),
+            Ok(false) => Response::error("HSSH_JOB_NOT_RUNNING", format!("任务已经结束: {id}")),
+            Err(err) => Response::error("HSSH_CTL_FAILED", err.to_string()),
+        }
+    }
+}
@@ -0,0 +1,203 @@
+//! 停机排空 (drain)
+//!
+//! 进入 drain 后不再接受新的请求, 客户端收到 `HSSH_SERVER_DRAINING`;
+//! 查看状态和管理任务的 `health`/`ping`/`logs`/`metrics`/`admin`/`job` 仍然可用.
+//! 运行中和排队中的任务、正在执行的请求 (push、上传下载等) 全部结束后服务退出,
+//! 超过 `jobs.drain_timeout_secs` 仍未结束的任务被结束并记录为 `interrupted`.
+//!
+//! 通过 SIGTERM/SIGINT (再次收到信号时立即退出), `horsed ctl drain` 或 `admin drain` 触发.
+use super::handle::ChannelHandle;
+use super::invite::parse_ttl;
+use crate::prelude::HorseResult;
+use once_cell::sync::Lazy;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+static DRAINING: AtomicBool = AtomicBool::new(false);
+static INFLIGHT: AtomicUsize = AtomicUsize::new(0);
+static FINISHED: Lazy<watch::Sender<bool>> = Lazy::new(|| watch::channel(false).0);
+
+/// drain 期间仍然接受的 action, 这些请求不影响 drain 结束
+const EXEMPT: [&str; 6] = ["health", "ping", "logs", "metrics", "admin", "job"];
+
+/// 强制结束任务之后, 等待任务记录写入的时间
+const INTERRUPT_WAIT: Duration = Duration::from_secs(5);
+
+pub fn is_draining() -> bool {
+    DRAINING.load(Ordering::SeqCst)
+}
+
+/// 查看状态和管理任务的请求, drain 期间仍然接受
+pub(super) fn exempt(action: &str) -> bool {
+    EXEMPT.contains(&action)
+}
+
+/// 当前是否接受 `action` 请求
+pub(super) fn accepts(action: &str) -> bool {
+    !is_draining() || exempt(action)
+}
+
+/// 拒绝 drain 期间的新请求
+pub(super) async fn reject(handle: ChannelHandle) -> HorseResult<()> {
+    handle
+        .fail_with_error(
+            75,
+            "HSSH_SERVER_DRAINING",
+            "服务正在停止, 不再接受新的请求, 请稍后重试",
+        )
+        .await
+}
+
+/// 正在执行的请求, drain 等待它释放
+pub(super) struct Inflight(());
+
+impl Inflight {
+    pub(super) fn new() -> Self {
+        INFLIGHT.fetch_add(1, Ordering::SeqCst);
+        Self(())
+    }
+}
+
+impl Drop for Inflight {
+    fn drop(&mut self) {
+        INFLIGHT.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// 开始 drain, `timeout` 为空时使用 `jobs.drain_timeout_secs`
+///
+/// 已经在 drain 时返回 `false`
+pub fn start(timeout: Option<Duration>) -> bool {
+    if DRAINING.swap(true, Ordering::SeqCst) {
+        return false;
+    }
+
+    let timeout = timeout.unwrap_or_else(|| crate::config::config().drain_timeout());
+    tracing::warn!("开始 drain: 不再接受新的请求, 最多等待 {timeout:?}");
+    tokio::spawn(async move {
+        wait(timeout).await;
+        FINISHED.send_replace(true);
+    });
+    true
+}
+
+/// 等待 drain 结束
+pub async fn finished() {
+    let mut done = FINISHED.subscribe();
+    let _ = done.wait_for(|done| *done).await;
+}
+
+/// 超时时刻, 时长过大无法表示时视为不超时
+fn deadline(timeout: Duration) -> Option<Instant> {
+    Instant::now().checked_add(timeout)
+}
+
+async fn wait(timeout: Duration) {
+    let deadline = deadline(timeout);
+    let jobs = super::server().map(|server| server.jobs.clone());
+
+    loop {
+        let running = match &jobs {
+            Some(jobs) => jobs.unfinished().await,
+            None => 0,
+        };
+        let inflight = INFLIGHT.load(Ordering::SeqCst);
+        if running == 0 && inflight == 0 {
+            tracing::info!("drain 完成");
+            return;
+        }
+
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            tracing::warn!("drain 超时: 仍有 {running} 个任务, {inflight} 个请求未结束");
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+
+    let Some(jobs) = jobs else {
+        return;
+    };
+    let count = jobs.interrupt_unfinished().await;
+    tracing::warn!("已结束 {count} 个任务");
+
+    // 进程退出后任务记录才会写入退出码和输出大小
+    let deadline = Instant::now() + INTERRUPT_WAIT;
+    while jobs.unfinished().await > 0 && Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+/// 服务退出的时机: drain 结束, 或者 drain 期间再次收到停止信号
+///
+/// 收到停止信号 (SIGTERM/SIGINT, windows 下为 Ctrl-C) 时开始 drain
+pub async fn until_stopped() -> std::io::Result<()> {
+    loop {
+        tokio::select! {
+            _ = finished() => return Ok(()),
+            res = stop_signal() => res?,
+        }
+        tracing::warn!("收到停止信号");
+        if !start(None) {
+            tracing::warn!("drain 期间再次收到停止信号, 立即退出");
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn stop_signal() -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut term = signal(SignalKind::terminate())?;
+    let mut int = signal(SignalKind::interrupt())?;
+    tokio::select! {
+        _ = term.recv() => {}
+        _ = int.recv() => {}
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+async fn stop_signal() -> std::io::Result<()> {
+    tokio::signal::ctrl_c().await
+}
+
+/// `admin drain [--timeout 10m]`
+pub(super) fn admin_drain(args: &[String]) -> anyhow::Result<String> {
+    let timeout = match &args[1..] {
+        [] => None,
+        [flag, value] if flag == "--timeout" => match parse_ttl(value) {
+            Some(secs) => Some(Duration::from_secs(secs)),
+            None => anyhow::bail!("无效的时长: {value}"),
+        },
+        _ => anyhow::bail!("用法: admin drain [--timeout 10m]"),
+    };
+
+    if start(timeout) {
+        Ok("开始 drain: 不再接受新的请求, 任务结束后服务退出".to_string())
+    } else {
+        Ok("服务已经在 drain".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn admin_drain_rejects_bad_arguments() {
+        let args = ["drain", "--timeout", "1w"].map(String::from);
+        assert!(admin_drain(&args).is_err());
+        let args = ["drain", "now"].map(String::from);
+        assert!(admin_drain(&args).is_err());
+        assert!(!is_draining());
+        assert!(accepts("cargo"));
+    }
+
+    #[test]
+    fn huge_timeout_never_expires() {
+        assert!(deadline(Duration::MAX).is_none());
+        assert!(deadline(Duration::from_secs(u64::MAX)).is_none());
+        assert!(deadline(Duration::from_secs(60)).is_some());
+    }
+}
@@ -1,3 +1,5 @@
+use super::audit::ActionAudit;
+use super::drain::Inflight;
 use crate::prelude::HorseResult;
 use colored::{ColoredString, Colorize};
 use russh::{
@@ -7,6 +9,7 @@ use russh::{
 use std::ops::{Deref, DerefMut};
 use std::process::ExitStatus;
 use std::process::Stdio;
+use std::sync::Mutex;
 use tokio::process::Command;
 use tokio::{
     io::{AsyncRead, AsyncWrite},
@@ -17,6 +20,12 @@ pub struct ChannelHandle {
     pub(crate) handle: Handle,
     pub(crate) id: ChannelId,
     pub(crate) ch: Channel<Msg>,
+    /// 任务已转入后台, 通道已关闭, 后续输出全部丢弃
+    pub(crate) detached: bool,
+    /// 当前通道执行的请求, 发送退出码时写入审计日志
+    audit: Mutex<Option<ActionAudit>>,
+    /// 请求执行期间阻止 drain 结束
+    inflight: Option<Inflight>,
 }
 
 impl ChannelHandle {
@@ -25,9 +34,65 @@ impl ChannelHandle {
             handle: session.handle(),
             id: channel.id(),
             ch: channel,
+            detached: false,
+            audit: Mutex::new(None),
+            inflight: None,
         }
     }
 
+    /// 通道开始执行请求, 通道释放之前 drain 不会结束
+    pub(super) fn track_inflight(&mut self) {
+        self.inflight.get_or_insert_with(Inflight::new);
+    }
+
+    /// 记录当前通道执行的请求, 通道结束时写入审计日志
+    pub(super) fn audit(&self, audit: ActionAudit) {
+        if let Ok(mut slot) = self.audit.lock() {
+            slot.replace(audit);
+        }
+    }
+
+    /// 请求创建的任务 id
+    pub(super) fn audit_job(&self, job_id: &str) {
+        if let Ok(mut slot) = self.audit.lock() {
+            if let Some(audit) = slot.as_mut() {
+                audit.set_job_id(job_id);
+            }
+        }
+    }
+
+    async fn finish_audit(&self, outcome: &str, exit_code: Option<i32>) {
+        let audit = self.audit.lock().ok().and_then(|mut slot| slot.take());
+        if let Some(audit) = audit {
+            audit.finish(outcome, exit_code).await;
+        }
+    }
+
+    /// 将当前任务转入后台: 告知客户端 job_id 并以 0 结束通道,
+    /// 之后的日志与退出状态都不再发送, 任务输出仅保存在任务记录中
+    ///
+    /// 客户端已经断开时同样转入后台, 任务继续运行
+    #[tracing::instrument(skip(self), level = "debug")]
+    pub async fn detach(&mut self, job_id: &str) -> HorseResult<()> {
+        if self.detached {
+            return Ok(());
+        }
+        if let Err(err) = self
+            .info(format!("任务已转入后台: cargo work job attach {job_id}"))
+            .await
+        {
+            tracing::debug!("客户端已断开: {err}");
+        }
+        self.finish_audit("detached", None).await;
+        self.exit_code(0).await?;
+        self.detached = true;
+        Ok(())
+    }
+
+    pub fn is_detached(&self) -> bool {
+        self.detached
+    }
+
     pub fn make_io_pair(&mut self) -> (impl AsyncWrite, impl AsyncRead + '_) {
         (self.make_writer(), self.make_reader())
     }
@@ -48,6 +113,9 @@ impl ChannelHandle {
     #[tracing::instrument(skip(self), level = "debug")]
     pub async fn eof(&self) -> HorseResult<()> {
         tracing::debug!("eof");
+        if self.detached {
+            return Ok(());
+        }
         let _ = self.handle.eof(self.id).await;
         Ok(())
     }
@@ -55,6 +123,9 @@ impl ChannelHandle {
     #[tracing::instrument(skip(self), level = "debug")]
     pub async fn close(&self) -> HorseResult<()> {
         tracing::debug!("close");
+        if self.detached {
+            return Ok(());
+        }
         let _ = self.handle.close(self.id).await;
         Ok(())
     }
@@ -67,11 +138,15 @@ impl ChannelHandle {
         } else {
             tracing::error!("channel exit");
         }
+        let code = status.code().unwrap_or(128);
+        let outcome = if code == 0 { "success" } else { "failed" };
+        self.finish_audit(outcome, Some(code)).await;
 
-        let _ = self
-            .handle
-            .exit_status_request(self.id, status.code().unwrap_or(128) as _)
-            .await;
+        if self.detached {
+            return Ok(());
+        }
+
+        let _ = self.handle.exit_status_request(self.id, code as _).await;
 
         self.eof().await?;
         self.close().await?;
@@ -82,6 +157,15 @@ impl ChannelHandle {
     /// `exec_request`, 发送请求状态，并结束通道
     #[tracing::instrument(skip(self), level = "debug")]
     pub async fn exit_code(&self, status_code: u32) -> HorseResult<()> {
+        let outcome = if status_code == 0 {
+            "success"
+        } else {
+            "failed"
+        };
+        self.finish_audit(outcome, Some(status_code as i32)).await;
+        if self.detached {
+            return Ok(());
+        }
         let _ = self.handle.exit_status_request(self.id, status_code).await;
 
         self.eof().await?;
@@ -179,6 +263,9 @@ impl ChannelHandle {
             "]".bold(),
             text.as_ref(),
         );
+        if self.detached {
+            return Ok(());
+        }
         let msg = CryptoVec::from(msg);
         if let Err(vec) = self.handle.extended_data(self.id, 1, msg).await {
             return Err(anyhow::anyhow!("SEND MESSAGE: {:?}", vec))?;
@@ -189,6 +276,9 @@ impl ChannelHandle {
     /// 类似 `self.log` 方法, 但是输出 &[u8] 数据到前端
     #[allow(unused)]
     pub async fn log_raw(&self, raw: impl AsRef<[u8]>) -> HorseResult<()> {
+        if self.detached {
+            return Ok(());
+        }
         let raw = CryptoVec::from(raw.as_ref());
         if let Err(vec) = self.handle.extended_data(self.id, 1, raw).await {
             return Err(anyhow::anyhow!("SEND MESSAGE: {:?}", vec))?;
@@ -200,6 +290,9 @@ impl ChannelHandle {
     /// 发送扩展数据到前端
     #[allow(dead_code)]
     pub async fn extended_data(&self, ext: u32, data: impl AsRef<[u8]>) -> HorseResult<()> {
+        if self.detached {
+            return Ok(());
+        }
         let raw = CryptoVec::from(data.as_ref());
         if let Err(vec) = self.handle.extended_data(self.id, ext, raw).await {
             return Err(anyhow::anyhow!(
@@ -230,5 +323,8 @@ impl Drop for ChannelHandle {
     #[tracing::instrument(skip(self), fields(id=%self.id), name = "ChannelHandle::drop", level = "debug")]
     fn drop(&mut self) {
         tracing::debug!("cleanup");
+        if let Some(audit) = self.audit.get_mut().ok().and_then(Option::take) {
+            audit.abort();
+        }
     }
 }
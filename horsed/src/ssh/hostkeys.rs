@@ -0,0 +1,157 @@
+//! OpenSSH 主机密钥通告 `hostkeys-00@openssh.com`
+//!
+//! 登录成功后服务端发送 `hostkeys-00@openssh.com` 全局请求 (不需要回复), 列出当前提供的全部主机公钥,
+//! 包括宽限期内的旧密钥. 开启 `UpdateHostKeys` 的 OpenSSH 客户端对其中没有见过的公钥发送
+//! `hostkeys-prove-00@openssh.com` 全局请求, 服务端对每个公钥回复一个签名, 证明持有对应私钥:
+//!
+//! ```text
+//! string "hostkeys-prove-00@openssh.com"
+//! string session identifier
+//! string hostkey
+//! ```
+//!
+//! 客户端校验通过后用通告的公钥更新 known_hosts, 轮换密钥后不需要手动更新.
+use crate::prelude::*;
+use anyhow::{anyhow, Context};
+use russh::keys::{PrivateKey, PublicKey};
+use signature::Signer;
+
+/// 服务端通告主机公钥的全局请求
+pub(super) const ANNOUNCE: &str = "hostkeys-00@openssh.com";
+/// 客户端要求证明持有主机密钥的全局请求
+pub(super) const PROVE: &str = "hostkeys-prove-00@openssh.com";
+
+fn put_string(buf: &mut Vec<u8>, data: &[u8]) {
+    buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    buf.extend_from_slice(data);
+}
+
+/// 读取一个 `string`, 数据不完整时返回错误
+fn read_string<'a>(data: &mut &'a [u8]) -> HorseResult<&'a [u8]> {
+    let (len, rest) = data.split_first_chunk::<4>().context("数据不完整")?;
+    let len = u32::from_be_bytes(*len) as usize;
+    if rest.len() < len {
+        return Err(anyhow!("数据不完整").into());
+    }
+    let (value, rest) = rest.split_at(len);
+    *data = rest;
+    Ok(value)
+}
+
+/// `hostkeys-00@openssh.com` 的请求数据: 每个公钥一个 `string`
+pub(super) fn announcement(keys: &[PrivateKey]) -> HorseResult<Vec<u8>> {
+    let mut buf = Vec::new();
+    for key in keys {
+        put_string(&mut buf, &key.public_key().to_bytes()?);
+    }
+    Ok(buf)
+}
+
+/// `hostkeys-prove-00@openssh.com` 的回复数据: 按请求顺序为每个公钥回复一个签名
+///
+/// 请求中包含服务端没有提供的公钥时返回错误, 回复请求失败
+pub(super) fn prove(
+    keys: &[PrivateKey],
+    session_id: &[u8],
+    mut request: &[u8],
+) -> HorseResult<Vec<u8>> {
+    let mut reply = Vec::new();
+    while !request.is_empty() {
+        let blob = read_string(&mut request)?;
+        let requested = PublicKey::from_bytes(blob).context("无效的公钥")?;
+        let key = keys
+            .iter()
+            .find(|key| key.public_key().key_data() == requested.key_data())
+            .with_context(|| format!("不是服务端的主机密钥: {}", requested.algorithm()))?;
+
+        let mut message = Vec::new();
+        put_string(&mut message, PROVE.as_bytes());
+        put_string(&mut message, session_id);
+        put_string(&mut message, blob);
+        // RSA 密钥使用 rsa-sha2-512 签名
+        let signature = key
+            .try_sign(&message)
+            .map_err(|err| anyhow!("签名失败: {err}"))?;
+
+        let mut encoded = Vec::new();
+        put_string(&mut encoded, signature.algorithm().as_str().as_bytes());
+        put_string(&mut encoded, signature.as_bytes());
+        put_string(&mut reply, &encoded);
+    }
+    Ok(reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand_core::OsRng;
+    use russh::keys::ssh_key::{EcdsaCurve, Signature};
+    use russh::keys::Algorithm;
+    use signature::Verifier;
+
+    fn keys() -> Vec<PrivateKey> {
+        [
+            Algorithm::Ed25519,
+            Algorithm::Ecdsa {
+                curve: EcdsaCurve::NistP256,
+            },
+        ]
+        .into_iter()
+        .map(|alg| PrivateKey::random(&mut OsRng, alg).unwrap())
+        .collect()
+    }
+
+    #[test]
+    fn announcement_lists_every_key() {
+        let keys = keys();
+        let data = announcement(&keys).unwrap();
+        let mut rest = data.as_slice();
+        for key in &keys {
+            let blob = read_string(&mut rest).unwrap();
+            assert_eq!(PublicKey::from_bytes(blob).unwrap(), *key.public_key());
+        }
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn prove_signs_requested_keys() {
+        let keys = keys();
+        let session_id = b"session-id";
+        // 只请求第二个密钥
+        let mut request = Vec::new();
+        put_string(&mut request, &keys[1].public_key().to_bytes().unwrap());
+
+        let reply = prove(&keys, session_id, &request).unwrap();
+        let mut rest = reply.as_slice();
+        let mut encoded = read_string(&mut rest).unwrap();
+        assert!(rest.is_empty());
+        let algorithm = std::str::from_utf8(read_string(&mut encoded).unwrap()).unwrap();
+        let signature = Signature::new(
+            Algorithm::new(algorithm).unwrap(),
+            read_string(&mut encoded).unwrap().to_vec(),
+        )
+        .unwrap();
+
+        let mut message = Vec::new();
+        put_string(&mut message, PROVE.as_bytes());
+        put_string(&mut message, session_id);
+        put_string(&mut message, &keys[1].public_key().to_bytes().unwrap());
+        keys[1].public_key().verify(&message, &signature).unwrap();
+        // 签名与会话绑定
+        assert!(keys[1]
+            .public_key()
+            .verify(b"other session", &signature)
+            .is_err());
+    }
+
+    #[test]
+    fn prove_rejects_unknown_keys() {
+        let keys = keys();
+        let other = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+        let mut request = Vec::new();
+        put_string(&mut request, &other.public_key().to_bytes().unwrap());
+        assert!(prove(&keys, b"id", &request).is_err());
+        // 数据不完整
+        assert!(prove(&keys, b"id", &[0, 0, 0, 9, 1]).is_err());
+    }
+}
@@ -0,0 +1,369 @@
+//! 邀请码
+//!
+//! 管理员通过 `admin invites create <user> [--role admin|user] [--ttl 24h]` 生成一次性邀请码.
+//! 新用户使用未登记的公钥连接, 公钥签名校验通过后服务端改用 keyboard-interactive 询问邀请码,
+//! 兑换成功后公钥绑定到邀请码对应的账户, 账户不存在时按邀请码中的角色创建.
+//!
+//! 数据库只保存邀请码的 sha256, 兑换时间、公钥指纹和客户端地址记录在邀请码上.
+use super::*;
+use crate::db::entity::invite;
+use crate::db::entity::prelude::Invite;
+use rand_core::{OsRng, RngCore};
+use russh::keys::HashAlg;
+use sea_orm::{sea_query::Expr, TransactionTrait};
+use sha2::{Digest, Sha256};
+use std::net::SocketAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// 默认有效期 24 小时
+const DEFAULT_TTL_SECS: u64 = 24 * 60 * 60;
+
+const INVITES_USAGE: &str =
+    "用法: invites create <user> [--role admin|user] [--ttl 30m|24h|7d] | invites list | invites revoke <id>";
+
+#[derive(serde::Serialize)]
+struct AdminInviteRow {
+    id: i32,
+    user_name: String,
+    role: String,
+    created_by: String,
+    created_at_ms: i64,
+    expires_at_ms: i64,
+    status: &'static str,
+    redeemed_at_ms: Option<i64>,
+    redeemed_key: Option<String>,
+    redeemed_peer: Option<String>,
+}
+
+fn now_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.trim().as_bytes()))
+}
+
+fn new_token() -> String {
+    let mut bytes = [0_u8; 20];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// 有效期: 纯数字为秒, 支持 `s`/`m`/`h`/`d` 后缀
+pub fn parse_ttl(value: &str) -> Option<u64> {
+    let value = value.trim();
+    let (num, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => value.split_at(index),
+        None => (value, "s"),
+    };
+    let scale = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return None,
+    };
+    num.parse::<u64>()
+        .ok()?
+        .checked_mul(scale)
+        .filter(|secs| *secs > 0)
+}
+
+fn status(invite: &invite::Model, now: i64) -> &'static str {
+    if invite.redeemed_at.is_some() {
+        "redeemed"
+    } else if invite.expires_at <= now {
+        "expired"
+    } else {
+        "pending"
+    }
+}
+
+/// 兑换邀请码, 把公钥绑定到邀请码对应的账户; 拒绝时记录原因并返回 `None`
+pub(super) async fn redeem(
+    db: &DatabaseConnection,
+    token: &str,
+    pk: &PublicKey,
+    peer: Option<SocketAddr>,
+) -> HorseResult<Option<user::Model>> {
+    let alg = pk.algorithm().to_string();
+    #[allow(deprecated)]
+    let key = base64::encode(&pk.to_bytes().context("pk bytes")?);
+    let fingerprint = format!("{alg} {}", pk.fingerprint(HashAlg::Sha256));
+    let now = now_ms();
+
+    // 提前返回时事务回滚
+    let txn = db.begin().await?;
+    let Some(record) = Invite::find()
+        .filter(invite::Column::TokenHash.eq(hash_token(token)))
+        .one(&txn)
+        .await?
+    else {
+        tracing::warn!("邀请码无效: {fingerprint} peer={peer:?}");
+        return Ok(None);
+    };
+
+    match status(&record, now) {
+        "pending" => {}
+        status => {
+            tracing::warn!(
+                "邀请码不可用: #{} ({status}) {fingerprint} peer={peer:?}",
+                record.id
+            );
+            return Ok(None);
+        }
+    }
+
+    if SshPk::find_by_id((alg.clone(), key.clone()))
+        .one(&txn)
+        .await?
+        .is_some()
+    {
+        tracing::warn!("公钥已登记, 不能兑换邀请码: #{} {fingerprint}", record.id);
+        return Ok(None);
+    }
+
+    let user = match User::find()
+        .filter(user::Column::Name.eq(record.user_name.as_str()))
+        .one(&txn)
+        .await?
+    {
+        Some(user) if !user.enabled => {
+            tracing::warn!("用户已禁用, 不能兑换邀请码: #{} {}", record.id, user.name);
+            return Ok(None);
+        }
+        Some(user) => user,
+        None => {
+            user::ActiveModel {
+                name: Set(record.user_name.clone()),
+                role: Set(record.role.clone()),
+                enabled: Set(true),
+                ..Default::default()
+            }
+            .insert(&txn)
+            .await?
+        }
+    };
+
+    ssh_pk::ActiveModel {
+        alg: Set(alg),
+        key: Set(key),
+        user_id: Set(user.id),
+        enabled: Set(true),
+        comment: Set(Some(format!("invite #{}", record.id))),
+        options: Set(None),
+        caps: Set(None),
+    }
+    .insert(&txn)
+    .await?;
+
+    // 同一个邀请码并发兑换时只有一个能成功
+    let claimed = Invite::update_many()
+        .col_expr(invite::Column::RedeemedAt, Expr::value(now))
+        .col_expr(
+            invite::Column::RedeemedKey,
+            Expr::value(fingerprint.clone()),
+        )
+        .col_expr(
+            invite::Column::RedeemedPeer,
+            Expr::value(peer.map(|it| it.to_string())),
+        )
+        .filter(invite::Column::Id.eq(record.id))
+        .filter(invite::Column::RedeemedAt.is_null())
+        .exec(&txn)
+        .await?;
+    if claimed.rows_affected != 1 {
+        tracing::warn!("邀请码已被使用: #{}", record.id);
+        return Ok(None);
+    }
+    txn.commit().await?;
+
+    tracing::info!(
+        "邀请码已兑换: #{} {} ({}) {fingerprint} peer={peer:?}",
+        record.id,
+        user.name,
+        user.role
+    );
+    Ok(Some(user))
+}
+
+/// `admin invites ...`: 管理邀请码
+pub(super) async fn admin_invites(
+    db: &DatabaseConnection,
+    actor: &SessionUser,
+    args: &[String],
+) -> anyhow::Result<String> {
+    let command = args.get(1).map(String::as_str).unwrap_or("");
+
+    let output = match command {
+        "list" => {
+            let now = now_ms();
+            let invites = Invite::find()
+                .order_by_desc(invite::Column::CreatedAt)
+                .all(db)
+                .await?;
+            let rows = invites
+                .into_iter()
+                .map(|invite| AdminInviteRow {
+                    status: status(&invite, now),
+                    id: invite.id,
+                    user_name: invite.user_name,
+                    role: invite.role,
+                    created_by: invite.created_by,
+                    created_at_ms: invite.created_at,
+                    expires_at_ms: invite.expires_at,
+                    redeemed_at_ms: invite.redeemed_at,
+                    redeemed_key: invite.redeemed_key,
+                    redeemed_peer: invite.redeemed_peer,
+                })
+                .collect::<Vec<_>>();
+            serde_json::to_string_pretty(&rows)?
+        }
+        "create" => {
+            let name = args.get(2).context(INVITES_USAGE)?;
+            let mut role = "user".to_string();
+            let mut ttl = DEFAULT_TTL_SECS;
+            let mut rest = args[3..].iter();
+            while let Some(flag) = rest.next() {
+                match flag.as_str() {
+                    "--role" => {
+                        role = rest.next().context(INVITES_USAGE)?.to_ascii_lowercase();
+                        if role != "admin" && role != "user" {
+                            return Err(anyhow!("角色必须是 admin 或 user"));
+                        }
+                    }
+                    "--ttl" => {
+                        let value = rest.next().context(INVITES_USAGE)?;
+                        ttl = parse_ttl(value).with_context(|| format!("无效的有效期: {value}"))?;
+                    }
+                    unknown => return Err(anyhow!("未知参数: {unknown}, {INVITES_USAGE}")),
+                }
+            }
+
+            if let Some(user) = User::find()
+                .filter(user::Column::Name.eq(name.as_str()))
+                .one(db)
+                .await?
+            {
+                if !user.enabled {
+                    return Err(anyhow!("用户已禁用: {}", user.name));
+                }
+            }
+
+            let token = new_token();
+            let now = now_ms();
+            let record = invite::ActiveModel {
+                token_hash: Set(hash_token(&token)),
+                user_name: Set(name.to_string()),
+                role: Set(role.clone()),
+                created_by: Set(actor.name.clone()),
+                created_at: Set(now),
+                expires_at: Set(now.saturating_add((ttl * 1000) as i64)),
+                ..Default::default()
+            }
+            .insert(db)
+            .await?;
+
+            tracing::info!(
+                "邀请码已创建: #{} {} ({role}) by {}",
+                record.id,
+                name,
+                actor.name
+            );
+            format!(
+                "邀请码已创建: id={} user={} role={} ttl={}s\n{token}\n\
+                 新用户可以使用: cargo work --invite {token} health",
+                record.id, name, role, ttl
+            )
+        }
+        "revoke" => {
+            let id = args
+                .get(2)
+                .and_then(|id| id.parse::<i32>().ok())
+                .context(INVITES_USAGE)?;
+            let Some(target) = Invite::find_by_id(id).one(db).await? else {
+                return Err(anyhow!("邀请码不存在: {id}"));
+            };
+            if target.redeemed_at.is_some() {
+                return Err(anyhow!("邀请码已兑换, 不能撤销: {id}"));
+            }
+
+            target.delete(db).await?;
+            format!("邀请码已撤销: {id}")
+        }
+        _ => return Err(anyhow!("不支持的 invites 命令, {INVITES_USAGE}")),
+    };
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use migration::{Migrator, MigratorTrait};
+    use russh::keys::{Algorithm, PrivateKey};
+    use sea_orm::Database;
+
+    #[test]
+    fn parse_ttl_accepts_units() {
+        assert_eq!(parse_ttl("90"), Some(90));
+        assert_eq!(parse_ttl("30m"), Some(30 * 60));
+        assert_eq!(parse_ttl("24h"), Some(24 * 60 * 60));
+        assert_eq!(parse_ttl("7d"), Some(7 * 24 * 60 * 60));
+        assert_eq!(parse_ttl("0"), None);
+        assert_eq!(parse_ttl("1w"), None);
+        assert_eq!(parse_ttl("h"), None);
+    }
+
+    #[tokio::test]
+    async fn invite_binds_key_once() {
+        let db = Database::connect("sqlite::memory:").await.unwrap();
+        Migrator::up(&db, None).await.unwrap();
+
+        let admin = SessionUser {
+            id: 0,
+            name: "root".to_string(),
+            role: "admin".to_string(),
+            cert: None,
+            options: KeyOptions::default(),
+            caps: Capabilities::default(),
+        };
+        let args = [
+            "invites", "create", "alice", "--role", "user", "--ttl", "1h",
+        ]
+        .map(String::from);
+        let output = admin_invites(&db, &admin, &args).await.unwrap();
+        let token = output.lines().nth(1).unwrap().to_string();
+
+        let key = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+        let peer = Some("10.0.0.2:50000".parse().unwrap());
+        assert!(redeem(&db, "nope", key.public_key(), peer)
+            .await
+            .unwrap()
+            .is_none());
+
+        let user = redeem(&db, &token, key.public_key(), peer)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(user.name, "alice");
+        assert_eq!(user.role, "user");
+        assert_eq!(user.find_related(SshPk).count(&db).await.unwrap(), 1);
+
+        // 一次性: 第二个公钥不能再用同一个邀请码
+        let other = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+        assert!(redeem(&db, &token, other.public_key(), peer)
+            .await
+            .unwrap()
+            .is_none());
+
+        let record = Invite::find().one(&db).await.unwrap().unwrap();
+        assert_eq!(status(&record, now_ms()), "redeemed");
+        assert_eq!(record.redeemed_peer.as_deref(), Some("10.0.0.2:50000"));
+        let revoke = ["invites", "revoke", &record.id.to_string()].map(String::from);
+        assert!(admin_invites(&db, &admin, &revoke).await.is_err());
+    }
+}
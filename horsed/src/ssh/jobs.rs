@@ -1,10 +1,19 @@
 use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use serde::Serialize;
-use tokio::sync::{broadcast, Mutex, RwLock};
+use crate::db::entity::{job, prelude::Job};
+use crate::prelude::*;
+use russh::Sig;
+use sea_orm::{
+    sea_query::Expr, ActiveModelTrait, ActiveValue::Set, ColumnTrait, DatabaseConnection,
+    EntityTrait, QueryFilter, QueryOrder, QuerySelect,
+};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{broadcast, Mutex, Notify, RwLock};
 
 #[derive(Clone, Debug)]
 pub enum JobEvent {
@@ -12,6 +21,206 @@ pub enum JobEvent {
     Done(i32),
 }
 
+/// 任务状态, 同时作为数据库中 `job.status` 的取值
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JobStatus {
+    /// 等待工作区或者全局并发名额
+    Queued,
+    Running,
+    Success,
+    Failed,
+    /// 任务运行期间 horsed 退出, 没有拿到退出码
+    Interrupted,
+    /// 通过 `job kill` 或客户端信号结束
+    Cancelled,
+}
+
+impl JobStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobStatus::Queued => "queued",
+            JobStatus::Running => "running",
+            JobStatus::Success => "success",
+            JobStatus::Failed => "failed",
+            JobStatus::Interrupted => "interrupted",
+            JobStatus::Cancelled => "cancelled",
+        }
+    }
+
+    pub fn parse(status: &str) -> Option<Self> {
+        match status.trim().to_ascii_lowercase().as_str() {
+            "queued" => Some(JobStatus::Queued),
+            "running" => Some(JobStatus::Running),
+            "success" => Some(JobStatus::Success),
+            "failed" => Some(JobStatus::Failed),
+            "interrupted" => Some(JobStatus::Interrupted),
+            "cancelled" => Some(JobStatus::Cancelled),
+            _ => None,
+        }
+    }
+
+    fn of(exit_code: Option<i32>, signal: Option<JobSignal>, queued: bool) -> Self {
+        match (exit_code, signal) {
+            (None, _) if queued => JobStatus::Queued,
+            (None, _) => JobStatus::Running,
+            (Some(_), Some(_)) => JobStatus::Cancelled,
+            (Some(0), None) => JobStatus::Success,
+            (Some(_), None) => JobStatus::Failed,
+        }
+    }
+}
+
+/// 可以发送给任务进程组的信号
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JobSignal {
+    Int,
+    Term,
+    Kill,
+}
+
+impl JobSignal {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobSignal::Int => "INT",
+            JobSignal::Term => "TERM",
+            JobSignal::Kill => "KILL",
+        }
+    }
+
+    pub fn parse(signal: &str) -> Option<Self> {
+        let signal = signal.trim().to_ascii_uppercase();
+        match signal.strip_prefix("SIG").unwrap_or(&signal) {
+            "INT" => Some(JobSignal::Int),
+            "TERM" => Some(JobSignal::Term),
+            "KILL" => Some(JobSignal::Kill),
+            _ => None,
+        }
+    }
+
+    /// 客户端通过 SSH 发送的信号, 只处理可以映射为结束任务的几种
+    pub fn from_sig(sig: &Sig) -> Option<Self> {
+        match sig {
+            Sig::INT | Sig::QUIT => Some(JobSignal::Int),
+            Sig::TERM | Sig::HUP => Some(JobSignal::Term),
+            Sig::KILL => Some(JobSignal::Kill),
+            _ => None,
+        }
+    }
+
+    #[cfg(unix)]
+    fn as_libc(&self) -> libc::c_int {
+        match self {
+            JobSignal::Int => libc::SIGINT,
+            JobSignal::Term => libc::SIGTERM,
+            JobSignal::Kill => libc::SIGKILL,
+        }
+    }
+}
+
+/// `job list` 的过滤条件
+#[derive(Clone, Debug)]
+pub struct JobQuery {
+    pub owner: Option<String>,
+    pub status: Option<JobStatus>,
+    /// 只列出在此时间 (毫秒) 之前启动的任务, 用于翻页
+    pub before_ms: Option<u64>,
+    pub limit: usize,
+}
+
+impl Default for JobQuery {
+    fn default() -> Self {
+        Self {
+            owner: None,
+            status: None,
+            before_ms: None,
+            limit: 50,
+        }
+    }
+}
+
+pub const JOB_LIST_USAGE: &str =
+    "list [--limit N] [--before <job_id|毫秒时间戳>] [--owner <user>] [--status <queued|running|success|failed|interrupted|cancelled>]";
+
+/// 解析 `job list` 参数, `--before` 为任务 id 时单独返回, 由调用方查出启动时间
+pub fn parse_job_list_args(args: &[String]) -> Result<(JobQuery, Option<String>), String> {
+    let mut query = JobQuery::default();
+    let mut before_job = None;
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        let (flag, inline) = match arg.split_once('=') {
+            Some((flag, value)) => (flag, Some(value.to_string())),
+            None => (arg.as_str(), None),
+        };
+        let mut value = || {
+            inline
+                .clone()
+                .or_else(|| args.next().cloned())
+                .ok_or_else(|| format!("参数 {flag} 缺少取值"))
+        };
+
+        match flag {
+            "--limit" => {
+                let limit = value()?;
+                query.limit = match limit.parse::<usize>() {
+                    Ok(limit) if (1..=MAX_LIST_LIMIT).contains(&limit) => limit,
+                    _ => return Err(format!("--limit 取值范围 1-{MAX_LIST_LIMIT}: {limit}")),
+                };
+            }
+            "--before" => {
+                let before = value()?;
+                match before.parse::<u64>() {
+                    Ok(ms) => query.before_ms = Some(ms),
+                    Err(_) => before_job = Some(before),
+                }
+            }
+            "--owner" => query.owner = Some(value()?),
+            "--status" => {
+                let status = value()?;
+                query.status =
+                    Some(JobStatus::parse(&status).ok_or_else(|| format!("未知的状态: {status}"))?);
+            }
+            _ => return Err(format!("不支持的参数: {arg}")),
+        }
+    }
+
+    Ok((query, before_job))
+}
+
+const MAX_LIST_LIMIT: usize = 500;
+
+pub const JOB_KILL_USAGE: &str = "kill <job_id> [--signal TERM|INT|KILL]";
+
+/// 解析 `job kill` 参数, 默认发送 TERM
+pub fn parse_job_kill_args(args: &[String]) -> Result<(String, JobSignal), String> {
+    let mut id = None;
+    let mut signal = JobSignal::Term;
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        let (flag, inline) = match arg.split_once('=') {
+            Some((flag, value)) => (flag, Some(value.to_string())),
+            None => (arg.as_str(), None),
+        };
+
+        match flag {
+            "--signal" | "-s" => {
+                let value = inline
+                    .or_else(|| args.next().cloned())
+                    .ok_or_else(|| format!("参数 {flag} 缺少取值"))?;
+                signal =
+                    JobSignal::parse(&value).ok_or_else(|| format!("不支持的信号: {value}"))?;
+            }
+            _ if arg.starts_with('-') => return Err(format!("不支持的参数: {arg}")),
+            _ if id.is_none() => id = Some(arg.clone()),
+            _ => return Err(format!("多余的参数: {arg}")),
+        }
+    }
+
+    let id = id.ok_or_else(|| "缺少 job_id".to_string())?;
+    Ok((id, signal))
+}
+
 #[derive(Clone)]
 pub struct JobRegistry {
     inner: Arc<JobRegistryInner>,
@@ -22,41 +231,117 @@ struct JobRegistryInner {
     jobs: RwLock<HashMap<String, Arc<JobRecord>>>,
     max_jobs: usize,
     max_bytes_per_job: usize,
+    store: Option<JobStore>,
+}
+
+/// 任务持久化: 元数据写入数据库, 输出写入 `<dir>/<job_id>.log`
+#[derive(Clone)]
+struct JobStore {
+    db: DatabaseConnection,
+    dir: PathBuf,
+}
+
+/// 任务所有者
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct JobOwner {
+    pub name: String,
+    /// 证书登录时的证书标识 `<key_id>#<serial>`, 公钥登录时为 `None`
+    pub cert: Option<String>,
+}
+
+impl From<&str> for JobOwner {
+    fn from(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            cert: None,
+        }
+    }
+}
+
+impl From<String> for JobOwner {
+    fn from(name: String) -> Self {
+        Self { name, cert: None }
+    }
 }
 
 #[derive(Clone)]
 pub struct JobRecord {
     id: String,
     owner: String,
+    owner_cert: Option<String>,
     action: String,
     command: String,
     started_at_ms: u64,
     max_bytes: usize,
     state: Arc<Mutex<JobState>>,
     events: broadcast::Sender<JobEvent>,
+    store: Option<JobStore>,
+    /// 排队中的任务被取消
+    cancel: Arc<Notify>,
 }
 
 struct JobState {
     buffer: VecDeque<u8>,
     dropped_bytes: u64,
+    output_bytes: u64,
     finished_at_ms: Option<u64>,
     exit_code: Option<i32>,
+    spool: Option<tokio::fs::File>,
+    /// 任务进程 id, unix 下同时是进程组 id
+    pid: Option<u32>,
+    /// 第一次收到的结束信号
+    signal: Option<JobSignal>,
+    /// 宽限期过后是否强制结束了进程组
+    escalated: bool,
+    /// 排队位置, 前面还有几个任务; `None` 表示没有在排队
+    queue_position: Option<usize>,
+    /// drain 超时被结束, 记录为 [`JobStatus::Interrupted`]
+    interrupted: bool,
 }
 
-#[derive(Clone, Debug, Serialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct JobSummary {
     pub id: String,
     pub owner: String,
+    pub owner_cert: Option<String>,
     pub action: String,
     pub command: String,
+    pub status: String,
     pub started_at_ms: u64,
     pub finished_at_ms: Option<u64>,
     pub exit_code: Option<i32>,
     pub running: bool,
+    pub signal: Option<String>,
+    pub escalated: bool,
+    pub queue_position: Option<usize>,
+    pub output_bytes: u64,
     pub dropped_bytes: u64,
     pub subscribers: usize,
 }
 
+impl From<job::Model> for JobSummary {
+    fn from(model: job::Model) -> Self {
+        Self {
+            running: model.status == JobStatus::Running.as_str(),
+            id: model.id,
+            owner: model.owner,
+            owner_cert: model.owner_cert,
+            action: model.action,
+            command: model.command,
+            status: model.status,
+            started_at_ms: model.started_at as u64,
+            finished_at_ms: model.finished_at.map(|it| it as u64),
+            exit_code: model.exit_code,
+            signal: model.signal,
+            escalated: model.escalated,
+            queue_position: None,
+            output_bytes: model.output_bytes as u64,
+            dropped_bytes: 0,
+            subscribers: 0,
+        }
+    }
+}
+
 impl JobRegistry {
     pub fn new(max_jobs: usize, max_bytes_per_job: usize) -> Self {
         Self {
@@ -65,34 +350,107 @@ impl JobRegistry {
                 jobs: RwLock::new(HashMap::new()),
                 max_jobs,
                 max_bytes_per_job,
+                store: None,
             }),
         }
     }
 
+    /// 启用持久化, 内存中只保留最近的任务, 历史记录从数据库和日志文件读取
+    pub fn with_store(db: DatabaseConnection, dir: impl Into<PathBuf>) -> Self {
+        let config = crate::config::config();
+        Self {
+            inner: Arc::new(JobRegistryInner {
+                seq: AtomicU64::new(1),
+                jobs: RwLock::new(HashMap::new()),
+                max_jobs: config.jobs.max_jobs,
+                max_bytes_per_job: config.jobs.max_output_bytes,
+                store: Some(JobStore {
+                    db,
+                    dir: dir.into(),
+                }),
+            }),
+        }
+    }
+
+    /// 启动时调用: 上次退出前仍在运行的任务标记为 interrupted
+    pub async fn recover_interrupted(&self) -> HorseResult<u64> {
+        let Some(store) = &self.inner.store else {
+            return Ok(0);
+        };
+
+        let result = Job::update_many()
+            .col_expr(
+                job::Column::Status,
+                Expr::value(JobStatus::Interrupted.as_str()),
+            )
+            .col_expr(job::Column::FinishedAt, Expr::value(now_ms() as i64))
+            .filter(
+                job::Column::Status
+                    .is_in([JobStatus::Queued.as_str(), JobStatus::Running.as_str()]),
+            )
+            .exec(&store.db)
+            .await?;
+
+        Ok(result.rows_affected)
+    }
+
     pub async fn create_job(
         &self,
-        owner: impl Into<String>,
+        owner: impl Into<JobOwner>,
         action: impl Into<String>,
         command: impl Into<String>,
     ) -> Arc<JobRecord> {
         let started_at_ms = now_ms();
         let seq = self.inner.seq.fetch_add(1, Ordering::Relaxed);
         let id = format!("job-{started_at_ms:x}-{seq:x}");
+        let JobOwner {
+            name: owner,
+            cert: owner_cert,
+        } = owner.into();
+        let action = action.into();
+        let command = command.into();
+
+        let spool = match &self.inner.store {
+            Some(store) => {
+                store
+                    .open(
+                        &id,
+                        &owner,
+                        owner_cert.as_deref(),
+                        &action,
+                        &command,
+                        started_at_ms,
+                    )
+                    .await
+            }
+            None => None,
+        };
+
         let (events, _rx) = broadcast::channel(512);
         let job = Arc::new(JobRecord {
             id,
-            owner: owner.into(),
-            action: action.into(),
-            command: command.into(),
+            owner,
+            owner_cert,
+            action,
+            command,
             started_at_ms,
             max_bytes: self.inner.max_bytes_per_job,
             state: Arc::new(Mutex::new(JobState {
                 buffer: VecDeque::new(),
                 dropped_bytes: 0,
+                output_bytes: 0,
                 finished_at_ms: None,
                 exit_code: None,
+                spool,
+                pid: None,
+                signal: None,
+                escalated: false,
+                queue_position: None,
+                interrupted: false,
             })),
             events,
+            store: self.inner.store.clone(),
+            cancel: Arc::new(Notify::new()),
         });
 
         let mut jobs = self.inner.jobs.write().await;
@@ -103,7 +461,7 @@ impl JobRegistry {
             let mut candidates = jobs
                 .iter()
                 .filter_map(|(id, job)| {
-                    if job.is_finished_sync() {
+                    if job.is_finished() {
                         Some((id.clone(), job.started_at_ms))
                     } else {
                         None
@@ -137,6 +495,49 @@ impl JobRegistry {
         }
     }
 
+    /// 运行中和排队中的任务数量
+    pub async fn unfinished(&self) -> usize {
+        let jobs = self.inner.jobs.read().await;
+        jobs.values().filter(|job| !job.is_finished()).count()
+    }
+
+    /// 运行中和排队中的任务数量, 分别统计
+    pub async fn depth(&self) -> (usize, usize) {
+        let jobs = self.inner.jobs.read().await;
+        jobs.values()
+            .map(|job| job.summary_sync())
+            .filter(|job| job.running)
+            .fold((0, 0), |(running, queued), job| {
+                if job.queue_position.is_some() {
+                    (running, queued + 1)
+                } else {
+                    (running + 1, queued)
+                }
+            })
+    }
+
+    /// drain 超时: 结束所有未结束的任务, 返回结束的任务数量
+    pub async fn interrupt_unfinished(&self) -> usize {
+        let jobs = self
+            .inner
+            .jobs
+            .read()
+            .await
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let mut count = 0;
+        for job in jobs {
+            match job.interrupt().await {
+                Ok(true) => count += 1,
+                Ok(false) => {}
+                Err(err) => tracing::warn!("结束任务失败: {}: {err}", job.id),
+            }
+        }
+        count
+    }
+
     pub async fn list_visible(&self, user: &str, is_admin: bool) -> Vec<JobSummary> {
         let jobs = self.inner.jobs.read().await;
         let mut rows = jobs
@@ -148,11 +549,182 @@ impl JobRegistry {
         rows.reverse();
         rows
     }
+
+    /// 按条件列出任务, 启用持久化时包含已经不在内存中的历史任务
+    pub async fn list(
+        &self,
+        user: &str,
+        is_admin: bool,
+        query: &JobQuery,
+    ) -> HorseResult<Vec<JobSummary>> {
+        // 普通用户只能看到自己的任务
+        let owner = if is_admin {
+            query.owner.clone()
+        } else {
+            Some(user.to_string())
+        };
+
+        let Some(store) = &self.inner.store else {
+            let rows = self
+                .list_visible(user, is_admin)
+                .await
+                .into_iter()
+                .filter(|row| owner.as_ref().is_none_or(|owner| &row.owner == owner))
+                .filter(|row| {
+                    query
+                        .status
+                        .is_none_or(|status| row.status == status.as_str())
+                })
+                .filter(|row| query.before_ms.is_none_or(|ms| row.started_at_ms < ms))
+                .take(query.limit)
+                .collect();
+            return Ok(rows);
+        };
+
+        let mut select = Job::find();
+        if let Some(owner) = owner {
+            select = select.filter(job::Column::Owner.eq(owner));
+        }
+        if let Some(status) = query.status {
+            select = select.filter(job::Column::Status.eq(status.as_str()));
+        }
+        if let Some(before_ms) = query.before_ms {
+            select = select.filter(job::Column::StartedAt.lt(before_ms as i64));
+        }
+
+        let models = select
+            .order_by_desc(job::Column::StartedAt)
+            .order_by_desc(job::Column::Id)
+            .limit(query.limit as u64)
+            .all(&store.db)
+            .await?;
+
+        // 运行中的任务以内存中的状态为准
+        let jobs = self.inner.jobs.read().await;
+        let rows = models
+            .into_iter()
+            .map(|model| match jobs.get(&model.id) {
+                Some(job) => job.summary_sync(),
+                None => model.into(),
+            })
+            .collect();
+
+        Ok(rows)
+    }
+
+    /// 查找任务的持久化记录, 不可见的任务视为不存在
+    pub async fn find_history(
+        &self,
+        id: &str,
+        user: &str,
+        is_admin: bool,
+    ) -> HorseResult<Option<JobSummary>> {
+        let Some(store) = &self.inner.store else {
+            return Ok(None);
+        };
+
+        let Some(model) = Job::find_by_id(id).one(&store.db).await? else {
+            return Ok(None);
+        };
+
+        if !is_admin && model.owner != user {
+            return Ok(None);
+        }
+
+        Ok(Some(model.into()))
+    }
+
+    /// 任务输出文件, 任务 id 不合法或者文件不存在时返回 `None`
+    pub fn log_path(&self, id: &str) -> Option<PathBuf> {
+        let store = self.inner.store.as_ref()?;
+        let path = store.log_path(id)?;
+        path.exists().then_some(path)
+    }
 }
 
 impl Default for JobRegistry {
     fn default() -> Self {
-        Self::new(256, 4 * 1024 * 1024)
+        let config = crate::config::config();
+        Self::new(config.jobs.max_jobs, config.jobs.max_output_bytes)
+    }
+}
+
+impl JobStore {
+    fn log_path(&self, id: &str) -> Option<PathBuf> {
+        // 任务 id 来自客户端输入, 只允许服务端生成的字符
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            return None;
+        }
+        Some(self.dir.join(format!("{id}.log")))
+    }
+
+    /// 写入任务记录并创建输出文件, 失败时只记录日志, 任务仍然可以在内存中运行
+    async fn open(
+        &self,
+        id: &str,
+        owner: &str,
+        owner_cert: Option<&str>,
+        action: &str,
+        command: &str,
+        started_at_ms: u64,
+    ) -> Option<tokio::fs::File> {
+        let record = job::ActiveModel {
+            id: Set(id.to_string()),
+            owner: Set(owner.to_string()),
+            action: Set(action.to_string()),
+            command: Set(command.to_string()),
+            status: Set(JobStatus::Running.as_str().to_string()),
+            exit_code: Set(None),
+            started_at: Set(started_at_ms as i64),
+            finished_at: Set(None),
+            output_bytes: Set(0),
+            owner_cert: Set(owner_cert.map(str::to_string)),
+            signal: Set(None),
+            escalated: Set(false),
+        };
+        if let Err(err) = Job::insert(record).exec(&self.db).await {
+            tracing::warn!("保存任务记录失败: {id}: {err}");
+        }
+
+        let path = self.log_path(id)?;
+        if let Err(err) = tokio::fs::create_dir_all(&self.dir).await {
+            tracing::warn!("创建任务日志目录失败: {}: {err}", self.dir.display());
+            return None;
+        }
+        match tokio::fs::File::create(&path).await {
+            Ok(file) => Some(file),
+            Err(err) => {
+                tracing::warn!("创建任务日志失败: {}: {err}", path.display());
+                None
+            }
+        }
+    }
+
+    async fn set_status(&self, id: &str, status: JobStatus) {
+        let record = job::ActiveModel {
+            id: Set(id.to_string()),
+            status: Set(status.as_str().to_string()),
+            ..Default::default()
+        };
+        if let Err(err) = record.update(&self.db).await {
+            tracing::warn!("更新任务状态失败: {id}: {err}");
+        }
+    }
+
+    async fn finish(&self, id: &str, status: JobStatus, exit_code: i32, state: &JobState) {
+        let record = job::ActiveModel {
+            id: Set(id.to_string()),
+            status: Set(status.as_str().to_string()),
+            exit_code: Set(Some(exit_code)),
+            finished_at: Set(state.finished_at_ms.map(|ms| ms as i64)),
+            output_bytes: Set(state.output_bytes as i64),
+            signal: Set(state.signal.map(|it| it.as_str().to_string())),
+            escalated: Set(state.escalated),
+            ..Default::default()
+        };
+        if let Err(err) = record.update(&self.db).await {
+            tracing::warn!("更新任务记录失败: {id}: {err}");
+        }
     }
 }
 
@@ -170,6 +742,13 @@ impl JobRecord {
             return;
         }
         let mut state = self.state.lock().await;
+        state.output_bytes = state.output_bytes.saturating_add(bytes.len() as u64);
+        if let Some(spool) = state.spool.as_mut() {
+            if let Err(err) = spool.write_all(bytes).await {
+                tracing::warn!("写入任务日志失败: {}: {err}", self.id);
+                state.spool = None;
+            }
+        }
         state.buffer.extend(bytes.iter().copied());
         let overflow = state.buffer.len().saturating_sub(self.max_bytes);
         if overflow > 0 {
@@ -184,12 +763,121 @@ impl JobRecord {
     pub async fn finish(&self, exit_code: i32) {
         let mut state = self.state.lock().await;
         if state.exit_code.is_none() {
+            let finished_at_ms = now_ms();
             state.exit_code = Some(exit_code);
-            state.finished_at_ms = Some(now_ms());
+            state.finished_at_ms = Some(finished_at_ms);
+            if let Some(mut spool) = state.spool.take() {
+                let _ = spool.flush().await;
+            }
+            let status = if state.interrupted {
+                JobStatus::Interrupted
+            } else {
+                JobStatus::of(Some(exit_code), state.signal, false)
+            };
+            super::metrics::job_finished(
+                &self.action,
+                status.as_str(),
+                exit_code,
+                finished_at_ms.saturating_sub(self.started_at_ms),
+            );
+            if let Some(store) = &self.store {
+                store.finish(&self.id, status, exit_code, &state).await;
+            }
             let _ = self.events.send(JobEvent::Done(exit_code));
         }
     }
 
+    /// 记录任务进程, 之后才能通过 [`JobRecord::kill`] 结束任务
+    pub async fn attach_process(&self, pid: Option<u32>) {
+        self.state.lock().await.pid = pid;
+    }
+
+    /// 更新排队位置, `None` 表示排队结束开始运行
+    pub async fn set_queue_position(&self, position: Option<usize>) {
+        let changed = {
+            let mut state = self.state.lock().await;
+            let changed = state.queue_position.is_some() != position.is_some();
+            state.queue_position = position;
+            changed
+        };
+
+        if let (true, Some(store)) = (changed, &self.store) {
+            let status = if position.is_some() {
+                JobStatus::Queued
+            } else {
+                JobStatus::Running
+            };
+            store.set_status(&self.id, status).await;
+        }
+    }
+
+    /// 等待排队中的任务被 [`JobRecord::kill`] 取消
+    pub async fn cancelled(&self) {
+        self.cancel.notified().await
+    }
+
+    /// 向任务进程组发送信号, `grace` 之后进程组仍然存在则强制结束
+    ///
+    /// 任务未运行或者没有进程时返回 `false`
+    pub async fn kill(self: &Arc<Self>, signal: JobSignal, grace: Duration) -> HorseResult<bool> {
+        let pid = {
+            let mut state = self.state.lock().await;
+            if state.exit_code.is_none() && state.queue_position.is_some() {
+                // 还在排队, 没有进程可以结束, 直接取消排队
+                state.signal.get_or_insert(signal);
+                self.cancel.notify_one();
+                tracing::info!("取消排队中的任务: {}", self.id);
+                return Ok(true);
+            }
+            let Some(pid) = state.pid.filter(|_| state.exit_code.is_none()) else {
+                return Ok(false);
+            };
+            state.signal.get_or_insert(signal);
+            pid
+        };
+
+        tracing::info!("结束任务: {} pid={pid} signal={}", self.id, signal.as_str());
+        signal_group(pid, signal)?;
+
+        #[cfg(unix)]
+        if signal != JobSignal::Kill {
+            let job = self.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(grace).await;
+                // 持有锁检查: 任务已经结束时进程组 id 可能已经被其他进程复用
+                let mut state = job.state.lock().await;
+                if state.exit_code.is_none() && state.pid == Some(pid) && group_alive(pid) {
+                    tracing::warn!("任务 {} 未在 {grace:?} 内退出, 强制结束", job.id);
+                    state.escalated = true;
+                    let _ = signal_group(pid, JobSignal::Kill);
+                }
+            });
+        }
+
+        #[cfg(windows)]
+        let _ = grace;
+
+        Ok(true)
+    }
+
+    /// 停机时直接 KILL 任务进程组 (排队中的任务取消排队), 任务记录为 [`JobStatus::Interrupted`]
+    ///
+    /// 任务已经结束时返回 `false`
+    pub async fn interrupt(self: &Arc<Self>) -> HorseResult<bool> {
+        {
+            let mut state = self.state.lock().await;
+            if state.exit_code.is_some() {
+                return Ok(false);
+            }
+            state.interrupted = true;
+        }
+        if let Some(store) = &self.store {
+            store.set_status(&self.id, JobStatus::Interrupted).await;
+        }
+        tracing::warn!("停机, 结束任务: {}", self.id);
+        self.kill(JobSignal::Kill, Duration::ZERO).await
+    }
+
     pub async fn snapshot(&self) -> (Vec<u8>, Option<i32>, Option<u64>, u64) {
         let state = self.state.lock().await;
         (
@@ -200,7 +888,8 @@ impl JobRecord {
         )
     }
 
-    fn is_finished_sync(&self) -> bool {
+    /// 任务是否已经结束, 状态被占用时视为未结束
+    pub fn is_finished(&self) -> bool {
         if let Ok(state) = self.state.try_lock() {
             state.exit_code.is_some()
         } else {
@@ -209,32 +898,78 @@ impl JobRecord {
     }
 
     fn summary_sync(&self) -> JobSummary {
-        let (exit_code, finished_at_ms, dropped_bytes, running) =
+        let (exit_code, finished_at_ms, output_bytes, dropped_bytes, signal, escalated, queue) =
             if let Ok(state) = self.state.try_lock() {
                 (
                     state.exit_code,
                     state.finished_at_ms,
+                    state.output_bytes,
                     state.dropped_bytes,
-                    state.exit_code.is_none(),
+                    state.signal,
+                    state.escalated,
+                    state.queue_position,
                 )
             } else {
-                (None, None, 0, true)
+                (None, None, 0, 0, None, false, None)
             };
+        let interrupted = self.state.try_lock().is_ok_and(|state| state.interrupted);
+        let status = if interrupted && exit_code.is_some() {
+            JobStatus::Interrupted
+        } else {
+            JobStatus::of(exit_code, signal, queue.is_some())
+        };
         JobSummary {
             id: self.id.clone(),
             owner: self.owner.clone(),
+            owner_cert: self.owner_cert.clone(),
             action: self.action.clone(),
             command: self.command.clone(),
+            status: status.as_str().to_string(),
             started_at_ms: self.started_at_ms,
             finished_at_ms,
             exit_code,
-            running,
+            running: exit_code.is_none(),
+            signal: signal.map(|it| it.as_str().to_string()),
+            escalated,
+            queue_position: queue.filter(|_| exit_code.is_none()),
+            output_bytes,
             dropped_bytes,
             subscribers: self.events.receiver_count(),
         }
     }
 }
 
+/// 向进程组发送信号, 进程由 `process_group(0)` 启动, 进程组 id 等于进程 id
+#[cfg(unix)]
+fn signal_group(pid: u32, signal: JobSignal) -> std::io::Result<()> {
+    let ret = unsafe { libc::kill(-(pid as libc::pid_t), signal.as_libc()) };
+    if ret == 0 {
+        return Ok(());
+    }
+
+    match std::io::Error::last_os_error() {
+        // 进程组已经退出
+        err if err.raw_os_error() == Some(libc::ESRCH) => Ok(()),
+        err => Err(err),
+    }
+}
+
+/// windows 下没有进程组和信号, 直接结束整个进程树
+#[cfg(windows)]
+fn signal_group(pid: u32, _signal: JobSignal) -> std::io::Result<()> {
+    std::process::Command::new("taskkill")
+        .args(["/T", "/F", "/PID", &pid.to_string()])
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status()?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn group_alive(pid: u32) -> bool {
+    unsafe { libc::kill(-(pid as libc::pid_t), 0) == 0 }
+}
+
 fn now_ms() -> u64 {
     SystemTime::now()
         .duration_since(UNIX_EPOCH)
@@ -245,6 +980,8 @@ fn now_ms() -> u64 {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use migration::{Migrator, MigratorTrait};
+    use sea_orm::Database;
 
     #[tokio::test]
     async fn job_registry_filters_by_owner_and_admin() {
@@ -277,4 +1014,207 @@ mod tests {
         assert_eq!(exit_code, Some(7));
         assert!(finished_at_ms.is_some());
     }
+
+    #[test]
+    fn parse_job_list_args_accepts_filters() {
+        let args = [
+            "--limit",
+            "5",
+            "--status=failed",
+            "--before",
+            "job-1-2",
+            "--owner",
+            "bob",
+        ]
+        .map(String::from);
+        let (query, before_job) = parse_job_list_args(&args).unwrap();
+        assert_eq!(query.limit, 5);
+        assert_eq!(query.status, Some(JobStatus::Failed));
+        assert_eq!(query.owner.as_deref(), Some("bob"));
+        assert_eq!(query.before_ms, None);
+        assert_eq!(before_job.as_deref(), Some("job-1-2"));
+
+        let (query, before_job) = parse_job_list_args(&["--before=1700".to_string()]).unwrap();
+        assert_eq!(query.before_ms, Some(1700));
+        assert!(before_job.is_none());
+
+        assert!(parse_job_list_args(&["--limit".to_string(), "0".to_string()]).is_err());
+        assert!(parse_job_list_args(&["--status".to_string(), "done".to_string()]).is_err());
+        assert!(parse_job_list_args(&["--limit".to_string()]).is_err());
+        assert!(parse_job_list_args(&["--all".to_string()]).is_err());
+    }
+
+    #[test]
+    fn parse_job_kill_args_defaults_to_term() {
+        let (id, signal) = parse_job_kill_args(&["job-1".to_string()]).unwrap();
+        assert_eq!(id, "job-1");
+        assert_eq!(signal, JobSignal::Term);
+
+        let args = ["--signal", "kill", "job-2"].map(String::from);
+        let (id, signal) = parse_job_kill_args(&args).unwrap();
+        assert_eq!(id, "job-2");
+        assert_eq!(signal, JobSignal::Kill);
+
+        assert!(parse_job_kill_args(&[]).is_err());
+        assert!(parse_job_kill_args(&["job-1".to_string(), "job-2".to_string()]).is_err());
+        assert!(parse_job_kill_args(&["job-1".to_string(), "--signal=USR1".to_string()]).is_err());
+    }
+
+    #[test]
+    fn job_signal_parse() {
+        assert_eq!(JobSignal::parse("term"), Some(JobSignal::Term));
+        assert_eq!(JobSignal::parse("SIGINT"), Some(JobSignal::Int));
+        assert_eq!(JobSignal::parse("KILL"), Some(JobSignal::Kill));
+        assert_eq!(JobSignal::parse("USR1"), None);
+        assert_eq!(JobSignal::from_sig(&Sig::INT), Some(JobSignal::Int));
+        assert_eq!(JobSignal::from_sig(&Sig::USR1), None);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn job_kill_terminates_process_group() {
+        let jobs = JobRegistry::new(16, 1024);
+        let job = jobs.create_job("alice", "cmd", "sleep").await;
+        assert!(!job.kill(JobSignal::Term, Duration::ZERO).await.unwrap());
+
+        // 子进程 sleep 与 sh 在同一个进程组中
+        let mut child = tokio::process::Command::new("sh")
+            .arg("-c")
+            .arg("sleep 30 & wait")
+            .process_group(0)
+            .spawn()
+            .unwrap();
+        let pid = child.id().unwrap();
+        job.attach_process(Some(pid)).await;
+
+        assert!(job
+            .kill(JobSignal::Term, Duration::from_millis(200))
+            .await
+            .unwrap());
+        let status = child.wait().await.unwrap();
+        job.finish(status.code().unwrap_or(128)).await;
+
+        let summary = job.summary_sync();
+        assert_eq!(summary.status, "cancelled");
+        assert_eq!(summary.signal.as_deref(), Some("TERM"));
+        assert!(!job.kill(JobSignal::Kill, Duration::ZERO).await.unwrap());
+
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        assert!(!group_alive(pid));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn interrupt_unfinished_records_interrupted() {
+        let db = Database::connect("sqlite::memory:").await.unwrap();
+        Migrator::up(&db, None).await.unwrap();
+        let dir = std::env::temp_dir().join(format!("horsed-drain-{}", now_ms()));
+
+        let jobs = JobRegistry::with_store(db, &dir);
+        let done = jobs.create_job("alice", "cargo", "build").await;
+        done.finish(0).await;
+        let queued = jobs.create_job("alice", "cargo", "test").await;
+        queued.set_queue_position(Some(1)).await;
+        let job = jobs.create_job("alice", "cmd", "sleep").await;
+        let mut child = tokio::process::Command::new("sleep")
+            .arg("30")
+            .process_group(0)
+            .spawn()
+            .unwrap();
+        job.attach_process(child.id()).await;
+        assert_eq!(jobs.unfinished().await, 2);
+
+        assert_eq!(jobs.interrupt_unfinished().await, 2);
+        let status = child.wait().await.unwrap();
+        job.finish(status.code().unwrap_or(137)).await;
+        queued.finish(130).await;
+        assert_eq!(jobs.unfinished().await, 0);
+
+        let query = JobQuery {
+            status: Some(JobStatus::Interrupted),
+            ..Default::default()
+        };
+        let rows = jobs.list("alice", false, &query).await.unwrap();
+        assert_eq!(rows.len(), 2);
+        assert!(rows.iter().all(|row| row.finished_at_ms.is_some()));
+        assert_eq!(job.summary_sync().status, "interrupted");
+
+        // 结束任务的信号随结束记录一起保存
+        let history = jobs.find_history(job.id(), "alice", false).await.unwrap();
+        let history = history.unwrap();
+        assert_eq!(history.signal.as_deref(), Some("KILL"));
+        assert!(!history.escalated);
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn queued_job_reports_position_and_can_be_cancelled() {
+        let jobs = JobRegistry::new(16, 1024);
+        let job = jobs.create_job("alice", "cargo.build", "build").await;
+
+        job.set_queue_position(Some(2)).await;
+        let summary = job.summary_sync();
+        assert_eq!(summary.status, "queued");
+        assert_eq!(summary.queue_position, Some(2));
+
+        // 排队中的任务没有进程, kill 直接取消排队
+        assert!(job.kill(JobSignal::Term, Duration::ZERO).await.unwrap());
+        tokio::time::timeout(Duration::from_secs(1), job.cancelled())
+            .await
+            .expect("queued job should be cancelled");
+        job.finish(130).await;
+
+        let summary = job.summary_sync();
+        assert_eq!(summary.status, "cancelled");
+        assert_eq!(summary.queue_position, None);
+    }
+
+    #[tokio::test]
+    async fn job_history_survives_registry_restart() {
+        let db = Database::connect("sqlite::memory:").await.unwrap();
+        Migrator::up(&db, None).await.unwrap();
+        let dir = std::env::temp_dir().join(format!("horsed-jobs-{}", now_ms()));
+
+        let jobs = JobRegistry::with_store(db.clone(), &dir);
+        let done = jobs.create_job("alice", "cargo", "build").await;
+        done.append_output(b"compiling\n").await;
+        done.finish(0).await;
+        let _running = jobs.create_job("alice", "cmd", "sleep 100").await;
+        let bob = JobOwner {
+            name: "bob".to_string(),
+            cert: Some("ci-runner#42".to_string()),
+        };
+        let other = jobs.create_job(bob, "cmd", "ls").await;
+
+        // 模拟重启: 新的 registry 只能从数据库和日志文件读取
+        let jobs = JobRegistry::with_store(db, &dir);
+        assert_eq!(jobs.recover_interrupted().await.unwrap(), 2);
+
+        let rows = jobs
+            .list("alice", false, &JobQuery::default())
+            .await
+            .unwrap();
+        assert_eq!(rows.len(), 2);
+        assert!(rows.iter().all(|row| row.owner == "alice"));
+
+        let query = JobQuery {
+            status: Some(JobStatus::Success),
+            ..Default::default()
+        };
+        let rows = jobs.list("admin", true, &query).await.unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].exit_code, Some(0));
+        assert_eq!(rows[0].output_bytes, 10);
+
+        let history = jobs.find_history(done.id(), "bob", false).await.unwrap();
+        assert!(history.is_none());
+        let history = jobs.find_history(other.id(), "bob", false).await.unwrap();
+        assert_eq!(history.unwrap().owner_cert.as_deref(), Some("ci-runner#42"));
+        let path = jobs.log_path(done.id()).unwrap();
+        assert_eq!(std::fs::read(path).unwrap(), b"compiling\n");
+        assert!(jobs.log_path("../horsed").is_none());
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
 }
@@ -0,0 +1,447 @@
+//! `authorized_keys` 公钥选项
+//!
+//! 支持 OpenSSH 常用的选项:
+//!
+//! - `from="pattern-list"`: 客户端地址限制, 支持 `*`/`?` 通配符、CIDR 和 `!` 取反, 只匹配 IP 地址
+//! - `expiry-time="YYYYMMDD[HHMM[SS]]"`: 过期时间, 按 UTC 解释
+//! - `restrict`: 禁止端口转发、agent 转发和 pty, 可以用 `port-forwarding` 等重新打开
+//! - `no-port-forwarding`/`no-agent-forwarding`/`no-pty` 以及对应的 `port-forwarding`/`agent-forwarding`/`pty`
+//! - `permitopen="host:port"`: 允许 direct-tcpip 连接的目标, 可以出现多次
+//! - `permitlisten="[host:]port"`: 允许 tcpip-forward 监听的地址, 省略主机时只允许监听本机地址
+//!
+//! `no-X11-forwarding`/`no-user-rc` 等对 horsed 没有意义的选项接受但忽略,
+//! `command=`/`environment=` 等无法遵守的选项直接拒绝, 避免限制被静默丢弃.
+use super::cert::cidr_contains;
+use anyhow::{anyhow, bail};
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn now_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or_default()
+}
+
+/// 解析后的公钥选项
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct KeyOptions {
+    from: Vec<String>,
+    expiry_ms: Option<i64>,
+    restrict: bool,
+    port_forwarding: Option<bool>,
+    agent_forwarding: Option<bool>,
+    pty: Option<bool>,
+    permit_open: Vec<String>,
+    permit_listen: Vec<String>,
+}
+
+impl KeyOptions {
+    /// 解析 `authorized_keys` 中公钥之前的选项, 如 `from="10.0.0.0/8",no-pty`
+    pub fn parse(options: &str) -> anyhow::Result<Self> {
+        let mut parsed = Self::default();
+
+        for token in split_options(options)? {
+            let (name, value) = match token.split_once('=') {
+                Some((name, value)) => (name, Some(unquote(value)?)),
+                None => (token, None),
+            };
+            let name = name.to_ascii_lowercase();
+            let value = || {
+                value
+                    .clone()
+                    .ok_or_else(|| anyhow!("公钥选项 {name} 缺少取值"))
+            };
+
+            match name.as_str() {
+                "from" => {
+                    for pattern in value()?.split(',').map(str::trim) {
+                        check_from_pattern(pattern)?;
+                        parsed.from.push(pattern.to_string());
+                    }
+                }
+                "expiry-time" => parsed.expiry_ms = Some(parse_expiry(&value()?)?),
+                "restrict" => parsed.restrict = true,
+                "port-forwarding" => parsed.port_forwarding = Some(true),
+                "no-port-forwarding" => parsed.port_forwarding = Some(false),
+                "agent-forwarding" => parsed.agent_forwarding = Some(true),
+                "no-agent-forwarding" => parsed.agent_forwarding = Some(false),
+                "pty" => parsed.pty = Some(true),
+                "no-pty" => parsed.pty = Some(false),
+                "permitopen" => {
+                    let target = value()?;
+                    if split_host_port(&target).is_none() {
+                        bail!("无效的 permitopen: {target}, 格式为 host:port");
+                    }
+                    parsed.permit_open.push(target);
+                }
+                "permitlisten" => {
+                    let target = value()?;
+                    if split_listen(&target).is_none() {
+                        bail!("无效的 permitlisten: {target}, 格式为 [host:]port");
+                    }
+                    parsed.permit_listen.push(target);
+                }
+                "x11-forwarding" | "no-x11-forwarding" | "user-rc" | "no-user-rc" => {}
+                _ => bail!("不支持的公钥选项: {token}"),
+            }
+        }
+
+        Ok(parsed)
+    }
+
+    /// 证书扩展对应的选项: 与 `restrict` 相同, 只打开证书中列出的
+    /// `permit-pty`/`permit-port-forwarding`/`permit-agent-forwarding`, 其他扩展忽略
+    pub fn from_extensions<'a>(
+        extensions: impl IntoIterator<Item = (&'a String, &'a String)>,
+    ) -> Self {
+        let mut options = Self {
+            restrict: true,
+            ..Self::default()
+        };
+        for (name, _) in extensions {
+            match name.as_str() {
+                "permit-pty" => options.pty = Some(true),
+                "permit-port-forwarding" => options.port_forwarding = Some(true),
+                "permit-agent-forwarding" => options.agent_forwarding = Some(true),
+                _ => {}
+            }
+        }
+        options
+    }
+
+    /// 登录时的检查: 客户端地址与过期时间, 不通过时返回原因
+    pub fn check_login(&self, peer: Option<IpAddr>) -> Result<(), String> {
+        self.check_login_at(peer, now_ms())
+    }
+
+    fn check_login_at(&self, peer: Option<IpAddr>, now_ms: i64) -> Result<(), String> {
+        if let Some(expiry) = self.expiry_ms {
+            if now_ms >= expiry {
+                return Err(format!("公钥已过期: {}", super::format_utc(expiry)));
+            }
+        }
+        if !self.allows_from(peer) {
+            let peer = peer.map(|ip| ip.to_string()).unwrap_or_default();
+            return Err(format!(
+                "客户端地址 {peer} 不在 from 中: {}",
+                self.from.join(",")
+            ));
+        }
+        Ok(())
+    }
+
+    /// `from` 中任意一项匹配且没有被取反的项匹配
+    pub fn allows_from(&self, peer: Option<IpAddr>) -> bool {
+        if self.from.is_empty() {
+            return true;
+        }
+        let Some(peer) = peer.map(|ip| ip.to_canonical()) else {
+            return false;
+        };
+        let text = peer.to_string();
+
+        let mut allowed = false;
+        for pattern in &self.from {
+            let (negated, pattern) = match pattern.strip_prefix('!') {
+                Some(pattern) => (true, pattern),
+                None => (false, pattern.as_str()),
+            };
+            let matched = if pattern.contains('/') {
+                cidr_contains(pattern, peer)
+            } else {
+                wildcard_match(pattern.as_bytes(), text.as_bytes())
+            };
+            if matched && negated {
+                return false;
+            }
+            allowed |= matched;
+        }
+        allowed
+    }
+
+    pub fn port_forwarding(&self) -> bool {
+        self.port_forwarding.unwrap_or(!self.restrict)
+    }
+
+    pub fn agent_forwarding(&self) -> bool {
+        self.agent_forwarding.unwrap_or(!self.restrict)
+    }
+
+    pub fn pty(&self) -> bool {
+        self.pty.unwrap_or(!self.restrict)
+    }
+
+    /// direct-tcpip 是否允许连接 `host:port`
+    pub fn permits_open(&self, host: &str, port: u32) -> bool {
+        if !self.port_forwarding() {
+            return false;
+        }
+        if self.permit_open.is_empty() {
+            return true;
+        }
+        self.permit_open.iter().any(|target| {
+            split_host_port(target).is_some_and(|(allow_host, allow_port)| {
+                host_matches(allow_host, host) && port_matches(allow_port, port)
+            })
+        })
+    }
+
+    /// tcpip-forward 是否允许监听 `host:port`
+    pub fn permits_listen(&self, host: &str, port: u32) -> bool {
+        if !self.port_forwarding() {
+            return false;
+        }
+        if self.permit_listen.is_empty() {
+            return true;
+        }
+        self.permit_listen.iter().any(|target| {
+            split_listen(target).is_some_and(|(allow_host, allow_port)| {
+                let host_ok = match allow_host {
+                    Some(allow_host) => host_matches(allow_host, host),
+                    None => is_loopback(host),
+                };
+                host_ok && port_matches(allow_port, port)
+            })
+        })
+    }
+}
+
+/// 按逗号分隔选项, 双引号内的逗号不分隔
+fn split_options(options: &str) -> anyhow::Result<Vec<&str>> {
+    let mut tokens = Vec::new();
+    let (mut start, mut quoted, mut escaped) = (0, false, false);
+    for (index, c) in options.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                tokens.push(&options[start..index]);
+                start = index + 1;
+            }
+            _ => {}
+        }
+    }
+    if quoted {
+        bail!("公钥选项缺少结束的双引号: {options}");
+    }
+    tokens.push(&options[start..]);
+
+    if tokens.iter().any(|token| token.trim().is_empty()) {
+        bail!("无效的公钥选项: {options}");
+    }
+    Ok(tokens.into_iter().map(str::trim).collect())
+}
+
+/// 去掉取值两端的双引号, `\"` 转为 `"`
+fn unquote(value: &str) -> anyhow::Result<String> {
+    let inner = match value.strip_prefix('"') {
+        Some(rest) => rest
+            .strip_suffix('"')
+            .ok_or_else(|| anyhow!("无效的选项取值: {value}"))?,
+        None => value,
+    };
+    Ok(inner.replace("\\\"", "\""))
+}
+
+fn check_from_pattern(pattern: &str) -> anyhow::Result<()> {
+    let body = pattern.strip_prefix('!').unwrap_or(pattern);
+    if body.is_empty() {
+        bail!("无效的 from: {pattern}");
+    }
+    if let Some((addr, prefix)) = body.split_once('/') {
+        let max = match addr.parse::<IpAddr>() {
+            Ok(IpAddr::V4(_)) => 32,
+            Ok(IpAddr::V6(_)) => 128,
+            Err(_) => bail!("无效的 from: {pattern}"),
+        };
+        if !prefix.parse::<u32>().is_ok_and(|prefix| prefix <= max) {
+            bail!("无效的 from: {pattern}");
+        }
+    }
+    Ok(())
+}
+
+/// `YYYYMMDD`、`YYYYMMDDHHMM` 或 `YYYYMMDDHHMMSS`, 可以带 `Z` 后缀, 返回 UTC 毫秒时间戳
+fn parse_expiry(value: &str) -> anyhow::Result<i64> {
+    let invalid = || anyhow!("无效的 expiry-time: {value}, 格式为 YYYYMMDD[HHMM[SS]]");
+    let digits = value.strip_suffix(['Z', 'z']).unwrap_or(value);
+    if !matches!(digits.len(), 8 | 12 | 14) || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(invalid());
+    }
+
+    let field = |range: std::ops::Range<usize>| digits.get(range).unwrap_or("0").parse::<i64>();
+    let (year, month, day) = (field(0..4)?, field(4..6)?, field(6..8)?);
+    let (hour, minute, second) = (field(8..10)?, field(10..12)?, field(12..14)?);
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let days_in_month = match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    };
+    if !(1..=12).contains(&month)
+        || !(1..=days_in_month).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 59
+    {
+        return Err(invalid());
+    }
+
+    let secs = days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second;
+    Ok(secs * 1000)
+}
+
+// http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// `host:port`, IPv6 地址写作 `[::1]:22`
+fn split_host_port(target: &str) -> Option<(&str, &str)> {
+    let (host, port) = match target.strip_prefix('[') {
+        Some(rest) => rest.split_once("]:")?,
+        None => target.rsplit_once(':')?,
+    };
+    (!host.is_empty() && valid_port(port)).then_some((host, port))
+}
+
+/// `[host:]port`
+fn split_listen(target: &str) -> Option<(Option<&str>, &str)> {
+    if valid_port(target) {
+        return Some((None, target));
+    }
+    split_host_port(target).map(|(host, port)| (Some(host), port))
+}
+
+fn valid_port(port: &str) -> bool {
+    port == "*" || port.parse::<u16>().is_ok()
+}
+
+fn host_matches(allow: &str, host: &str) -> bool {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    allow == "*" || allow.eq_ignore_ascii_case(host)
+}
+
+fn port_matches(allow: &str, port: u32) -> bool {
+    allow == "*" || allow.parse::<u32>() == Ok(port)
+}
+
+fn is_loopback(host: &str) -> bool {
+    host.eq_ignore_ascii_case("localhost")
+        || host
+            .parse::<IpAddr>()
+            .is_ok_and(|ip| ip.to_canonical().is_loopback())
+}
+
+/// `*` 匹配任意字符串, `?` 匹配单个字符
+fn wildcard_match(pattern: &[u8], text: &[u8]) -> bool {
+    match (pattern.split_first(), text.split_first()) {
+        (None, None) => true,
+        (Some((b'*', rest)), _) => {
+            wildcard_match(rest, text) || (!text.is_empty() && wildcard_match(pattern, &text[1..]))
+        }
+        (Some((b'?', rest)), Some((_, text))) => wildcard_match(rest, text),
+        (Some((p, rest)), Some((t, text))) if p.eq_ignore_ascii_case(t) => {
+            wildcard_match(rest, text)
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(addr: &str) -> Option<IpAddr> {
+        Some(addr.parse().unwrap())
+    }
+
+    #[test]
+    fn from_patterns_are_matched() {
+        let options = KeyOptions::parse(r#"from="10.0.0.0/8,192.168.1.?,!10.0.0.5""#).unwrap();
+        assert!(options.allows_from(ip("10.1.2.3")));
+        assert!(options.allows_from(ip("192.168.1.7")));
+        assert!(options.allows_from(ip("::ffff:10.1.2.3")));
+        assert!(!options.allows_from(ip("10.0.0.5")));
+        assert!(!options.allows_from(ip("192.168.1.17")));
+        assert!(!options.allows_from(None));
+        assert!(KeyOptions::default().allows_from(None));
+
+        assert!(KeyOptions::parse(r#"from="10.0.0.0/33""#).is_err());
+        assert!(KeyOptions::parse(r#"from="10.0.0.1"#).is_err());
+    }
+
+    #[test]
+    fn expiry_time_is_utc() {
+        let ms = parse_expiry("20260101").unwrap();
+        assert_eq!(super::super::format_utc(ms), "2026-01-01 00:00:00Z");
+        let ms = parse_expiry("202402291230Z").unwrap();
+        assert_eq!(super::super::format_utc(ms), "2024-02-29 12:30:00Z");
+        assert!(parse_expiry("20230229").is_err());
+        assert!(parse_expiry("2026-01-01").is_err());
+
+        let options = KeyOptions::parse(r#"expiry-time="20260101""#).unwrap();
+        assert!(options.check_login_at(None, ms).is_ok());
+        assert!(options
+            .check_login_at(None, parse_expiry("20260101").unwrap())
+            .is_err());
+    }
+
+    #[test]
+    fn forwarding_restrictions() {
+        let options = KeyOptions::default();
+        assert!(options.permits_open("example.com", 443));
+        assert!(options.permits_listen("0.0.0.0", 8080));
+
+        let options = KeyOptions::parse("restrict,pty").unwrap();
+        assert!(options.pty());
+        assert!(!options.agent_forwarding());
+        assert!(!options.permits_open("localhost", 80));
+
+        let options = KeyOptions::parse(
+            r#"permitopen="db.internal:5432",permitopen="[::1]:*",permitlisten="8080",permitlisten="0.0.0.0:9000""#,
+        )
+        .unwrap();
+        assert!(options.permits_open("DB.internal", 5432));
+        assert!(options.permits_open("::1", 22));
+        assert!(!options.permits_open("db.internal", 5433));
+        assert!(options.permits_listen("localhost", 8080));
+        assert!(!options.permits_listen("0.0.0.0", 8080));
+        assert!(options.permits_listen("0.0.0.0", 9000));
+
+        let options = KeyOptions::parse("no-port-forwarding,NO-X11-FORWARDING").unwrap();
+        assert!(!options.permits_listen("localhost", 8080));
+        assert!(KeyOptions::parse(r#"command="/bin/true""#).is_err());
+        assert!(KeyOptions::parse(r#"permitopen="db.internal""#).is_err());
+        assert!(KeyOptions::parse("no-pty,").is_err());
+    }
+
+    #[test]
+    fn certificate_extensions_deny_by_default() {
+        let options = KeyOptions::from_extensions([]);
+        assert!(!options.pty());
+        assert!(!options.agent_forwarding());
+        assert!(!options.permits_open("localhost", 80));
+        assert!(!options.permits_listen("localhost", 8080));
+
+        let extensions = [
+            ("permit-pty".to_string(), String::new()),
+            ("permit-port-forwarding".to_string(), String::new()),
+            ("permit-X11-forwarding".to_string(), String::new()),
+        ];
+        let options = KeyOptions::from_extensions(extensions.iter().map(|(k, v)| (k, v)));
+        assert!(options.pty());
+        assert!(!options.agent_forwarding());
+        assert!(options.permits_open("localhost", 80));
+        assert!(options.allows_from(None));
+    }
+}
@@ -0,0 +1,451 @@
+//! Prometheus 指标
+//!
+//! 进程内记录认证、action、任务和文件传输的计数, 连接数、任务队列和 tokio 运行时在导出时读取.
+//! 以 Prometheus 文本格式导出: 配置 `metrics.listen` 时在本地 HTTP 端口提供 `GET /metrics`,
+//! 不能额外开放端口时通过 `metrics` action 读取 (`ssh -p 2222 metrics@<host>`).
+use super::jobs::JobRegistry;
+use super::session::SessionRegistry;
+use super::AppServer;
+use crate::prelude::HorseResult;
+use anyhow::Context;
+use once_cell::sync::Lazy;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+static METRICS: Lazy<Metrics> = Lazy::new(Metrics::default);
+
+/// 单独统计的 action, 其余记为 `other`
+const ACTIONS: [&str; 18] = [
+    "health", "ping", "logs", "metrics", "cargo", "apply", "sync", "just", "git", "cmd",
+    "cmd-sync", "get", "scp", "put", "admin", "job", "ssh", "sftp",
+];
+
+/// 任务耗时的直方图分桶, 单位为秒
+const DURATION_BUCKETS: [f64; 10] = [
+    1.0, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1800.0, 3600.0,
+];
+
+/// HTTP 请求头的长度上限
+const MAX_REQUEST: usize = 8 * 1024;
+
+/// 读取 HTTP 请求头的超时
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// 标签按顺序拼接成 `{key="value",...}`
+type Labels = Vec<(&'static str, String)>;
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; DURATION_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        for (bucket, le) in self.buckets.iter_mut().zip(DURATION_BUCKETS) {
+            if value <= le {
+                *bucket += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+#[derive(Default)]
+struct Metrics {
+    auth: Mutex<BTreeMap<Labels, u64>>,
+    actions: Mutex<BTreeMap<Labels, u64>>,
+    jobs: Mutex<BTreeMap<Labels, u64>>,
+    job_duration: Mutex<BTreeMap<Labels, Histogram>>,
+    transfer: Mutex<BTreeMap<Labels, u64>>,
+}
+
+fn inc(family: &Mutex<BTreeMap<Labels, u64>>, labels: Labels, value: u64) {
+    *family.lock().unwrap().entry(labels).or_default() += value;
+}
+
+/// 认证结果, `method` 为 publickey/cert/invite
+pub(super) fn auth(method: &str, accepted: bool) {
+    let outcome = if accepted { "accepted" } else { "rejected" };
+    inc(
+        &METRICS.auth,
+        vec![
+            ("method", method.to_string()),
+            ("outcome", outcome.to_string()),
+        ],
+        1,
+    );
+}
+
+/// 分发的 action
+pub(super) fn action(action: &str) {
+    let action = if ACTIONS.contains(&action) {
+        action
+    } else {
+        "other"
+    };
+    inc(&METRICS.actions, vec![("action", action.to_string())], 1);
+}
+
+/// 任务结束, `elapsed_ms` 包括排队的时间
+pub(super) fn job_finished(action: &str, status: &str, exit_code: i32, elapsed_ms: u64) {
+    inc(
+        &METRICS.jobs,
+        vec![
+            ("action", action.to_string()),
+            ("status", status.to_string()),
+            ("exit_code", exit_code.to_string()),
+        ],
+        1,
+    );
+    METRICS
+        .job_duration
+        .lock()
+        .unwrap()
+        .entry(vec![("action", action.to_string())])
+        .or_default()
+        .observe(elapsed_ms as f64 / 1000.0);
+}
+
+/// `get`/`scp` 下载和 `put` 上传的字节数
+pub(super) fn transfer(action: &'static str, bytes: u64) {
+    inc(
+        &METRICS.transfer,
+        vec![("action", action.to_string())],
+        bytes,
+    );
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn format_labels(labels: &[(&'static str, String)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let labels = labels
+        .iter()
+        .map(|(key, value)| format!("{key}=\"{}\"", escape(value)))
+        .collect::<Vec<_>>();
+    format!("{{{}}}", labels.join(","))
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+fn write_family<T: std::fmt::Display>(
+    out: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    values: impl IntoIterator<Item = (Labels, T)>,
+) {
+    header(out, name, kind, help);
+    for (labels, value) in values {
+        let _ = writeln!(out, "{name}{} {value}", format_labels(&labels));
+    }
+}
+
+fn counters(family: &Mutex<BTreeMap<Labels, u64>>) -> Vec<(Labels, u64)> {
+    family
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(labels, value)| (labels.clone(), *value))
+        .collect()
+}
+
+fn write_histograms(out: &mut String, name: &str, help: &str) {
+    header(out, name, "histogram", help);
+    for (labels, histogram) in METRICS.job_duration.lock().unwrap().iter() {
+        for (le, count) in DURATION_BUCKETS.iter().zip(histogram.buckets) {
+            let mut labels = labels.clone();
+            labels.push(("le", le.to_string()));
+            let _ = writeln!(out, "{name}_bucket{} {count}", format_labels(&labels));
+        }
+        let mut bucket = labels.clone();
+        bucket.push(("le", "+Inf".to_string()));
+        let labels = format_labels(labels);
+        let _ = writeln!(
+            out,
+            "{name}_bucket{} {}",
+            format_labels(&bucket),
+            histogram.count
+        );
+        let _ = writeln!(out, "{name}_sum{labels} {}", histogram.sum);
+        let _ = writeln!(out, "{name}_count{labels} {}", histogram.count);
+    }
+}
+
+/// Prometheus 文本格式的全部指标
+pub(super) async fn render(sessions: &SessionRegistry, jobs: &JobRegistry) -> String {
+    let mut out = String::new();
+
+    let mut roles = BTreeMap::<String, u64>::new();
+    for session in sessions.list() {
+        let role = session.role.unwrap_or_else(|| "none".to_string());
+        *roles.entry(role).or_default() += 1;
+    }
+    write_family(
+        &mut out,
+        "horsed_sessions",
+        "gauge",
+        "当前连接数, 按登录用户的角色, 未认证的连接为 none",
+        roles
+            .into_iter()
+            .map(|(role, count)| (vec![("role", role)], count)),
+    );
+    write_family(
+        &mut out,
+        "horsed_auth_total",
+        "counter",
+        "认证次数, 按认证方式和结果",
+        counters(&METRICS.auth),
+    );
+    write_family(
+        &mut out,
+        "horsed_actions_total",
+        "counter",
+        "分发的 action 次数",
+        counters(&METRICS.actions),
+    );
+    write_family(
+        &mut out,
+        "horsed_jobs_finished_total",
+        "counter",
+        "结束的任务数, 按 action, 状态和退出码",
+        counters(&METRICS.jobs),
+    );
+    write_histograms(
+        &mut out,
+        "horsed_job_duration_seconds",
+        "任务从创建到结束的时间, 包括排队",
+    );
+    write_family(
+        &mut out,
+        "horsed_transfer_bytes_total",
+        "counter",
+        "文件传输的字节数, get/scp 为下载, put 为上传",
+        counters(&METRICS.transfer),
+    );
+
+    let (running, queued) = jobs.depth().await;
+    write_family(
+        &mut out,
+        "horsed_jobs",
+        "gauge",
+        "运行中和排队中的任务数",
+        [
+            (vec![("status", "running".to_string())], running),
+            (vec![("status", "queued".to_string())], queued),
+        ],
+    );
+    write_family(
+        &mut out,
+        "horsed_draining",
+        "gauge",
+        "服务是否在 drain",
+        [(vec![], u8::from(super::drain::is_draining()))],
+    );
+
+    let runtime = stable::prelude::handle().metrics();
+    write_family(
+        &mut out,
+        "horsed_tokio_workers",
+        "gauge",
+        "tokio 工作线程数",
+        [(vec![], runtime.num_workers())],
+    );
+    write_family(
+        &mut out,
+        "horsed_tokio_alive_tasks",
+        "gauge",
+        "tokio 存活的任务数",
+        [(vec![], runtime.num_alive_tasks())],
+    );
+    write_family(
+        &mut out,
+        "horsed_tokio_global_queue_depth",
+        "gauge",
+        "tokio 全局任务队列长度",
+        [(vec![], runtime.global_queue_depth())],
+    );
+    // tokio_unstable
+    #[cfg(tokio_unstable)]
+    write_family(
+        &mut out,
+        "horsed_tokio_blocking_threads",
+        "gauge",
+        "tokio 阻塞线程数",
+        [(vec![], runtime.num_blocking_threads())],
+    );
+    #[cfg(tokio_unstable)]
+    write_family(
+        &mut out,
+        "horsed_tokio_idle_blocking_threads",
+        "gauge",
+        "tokio 空闲的阻塞线程数",
+        [(vec![], runtime.num_idle_blocking_threads())],
+    );
+
+    out
+}
+
+impl AppServer {
+    /// 输出 Prometheus 文本格式的指标
+    pub async fn metrics(&mut self, _args: Vec<String>) -> HorseResult<()> {
+        let handle = self.handle.take().context("FIXME: NO HANDLE")?;
+        let text = render(&self.sessions, &self.jobs).await;
+        let mut cout = handle.make_writer();
+        cout.write_all(text.as_bytes()).await?;
+        handle.exit_code(0).await
+    }
+}
+
+/// 请求行中的方法和路径
+fn parse_request(head: &[u8]) -> Option<(&str, &str)> {
+    let line = head.split(|b| *b == b'\n').next()?;
+    let line = std::str::from_utf8(line).ok()?.trim_end();
+    let mut parts = line.split(' ');
+    let method = parts.next()?;
+    let target = parts.next()?;
+    if !parts.next()?.starts_with("HTTP/") {
+        return None;
+    }
+    Some((method, target.split('?').next().unwrap_or(target)))
+}
+
+async fn read_head(stream: &mut TcpStream) -> io::Result<Vec<u8>> {
+    let mut head = Vec::new();
+    let mut buf = [0; 1024];
+    while !head.windows(4).any(|it| it == b"\r\n\r\n") && head.len() < MAX_REQUEST {
+        let len = stream.read(&mut buf).await?;
+        if len == 0 {
+            break;
+        }
+        head.extend_from_slice(&buf[..len]);
+    }
+    Ok(head)
+}
+
+async fn respond(
+    mut stream: TcpStream,
+    sessions: &SessionRegistry,
+    jobs: &JobRegistry,
+) -> io::Result<()> {
+    let head = tokio::time::timeout(REQUEST_TIMEOUT, read_head(&mut stream))
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+
+    let (status, body) = match parse_request(&head) {
+        Some(("GET", "/metrics")) => ("200 OK", render(sessions, jobs).await),
+        Some(("GET", _)) => ("404 Not Found", "not found\n".to_string()),
+        Some(_) => ("405 Method Not Allowed", "method not allowed\n".to_string()),
+        None => ("400 Bad Request", "bad request\n".to_string()),
+    };
+    let response = format!(
+        concat!(
+            "HTTP/1.1 {}\r\n",
+            "Content-Type: text/plain; version=0.0.4; charset=utf-8\r\n",
+            "Content-Length: {}\r\n",
+            "Connection: close\r\n\r\n{}",
+        ),
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// 本地 HTTP 端口, 只提供 `GET /metrics`
+pub(super) async fn serve(addr: SocketAddr, sessions: SessionRegistry, jobs: JobRegistry) {
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            tracing::error!("指标服务监听失败: {addr}: {err}");
+            return;
+        }
+    };
+    tracing::info!("指标服务监听: {addr}");
+
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                tracing::warn!("指标服务 accept 失败: {err}");
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let sessions = sessions.clone();
+        let jobs = jobs.clone();
+        tokio::spawn(async move {
+            if let Err(err) = respond(stream, &sessions, &jobs).await {
+                tracing::debug!("指标请求失败: {err}");
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_and_labels_are_parsed_and_escaped() {
+        assert_eq!(
+            parse_request(b"GET /metrics?x=1 HTTP/1.1\r\nHost: a\r\n\r\n"),
+            Some(("GET", "/metrics"))
+        );
+        assert_eq!(parse_request(b"garbage\r\n\r\n"), None);
+
+        let labels = vec![
+            ("action", "a\"b\\c".to_string()),
+            ("le", "+Inf".to_string()),
+        ];
+        assert_eq!(format_labels(&labels), r#"{action="a\"b\\c",le="+Inf"}"#);
+        assert_eq!(format_labels(&[]), "");
+    }
+
+    #[tokio::test]
+    async fn render_includes_recorded_metrics() {
+        auth("publickey", false);
+        action("cargo");
+        action("rm -rf");
+        transfer("put", 42);
+        job_finished("cargo", "failed", 101, 12_000);
+
+        let sessions = SessionRegistry::default();
+        sessions.open(1, None);
+        sessions.open(2, None);
+        sessions.login(2, "alice", "user", "cargo");
+
+        let text = render(&sessions, &JobRegistry::default()).await;
+        assert!(text.contains(r#"horsed_sessions{role="none"} 1"#));
+        assert!(text.contains(r#"horsed_sessions{role="user"} 1"#));
+        assert!(text.contains(r#"horsed_auth_total{method="publickey",outcome="rejected"}"#));
+        assert!(text.contains(r#"horsed_actions_total{action="other"}"#));
+        assert!(text.contains(r#"horsed_transfer_bytes_total{action="put"}"#));
+        assert!(text.contains(
+            r#"horsed_jobs_finished_total{action="cargo",status="failed",exit_code="101"}"#
+        ));
+        assert!(text.contains(r#"horsed_job_duration_seconds_bucket{action="cargo",le="30"}"#));
+        assert!(text.contains(r#"horsed_jobs{status="queued"} 0"#));
+        assert!(text.contains("# TYPE horsed_job_duration_seconds histogram"));
+    }
+}
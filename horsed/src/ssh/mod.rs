@@ -6,6 +6,7 @@ use std::process::{ExitStatus, Stdio};
 use std::str::from_utf8;
 use std::sync::Arc;
 
+use crate::account;
 use crate::db::entity::prelude::{SshPk, User};
 use crate::db::entity::{ssh_pk, user};
 use crate::git::repo::Repo;
@@ -17,7 +18,7 @@ use flate2::write::ZlibEncoder;
 use flate2::Compression;
 #[cfg(not(windows))]
 use pty_process::{Pts, Pty, Size};
-use russh::keys::{Certificate, PublicKey};
+use russh::keys::{Certificate, HashAlg, PrivateKey, PublicKey};
 use russh::{server::*, MethodSet};
 use russh::{Channel, ChannelId, Sig};
 use sea_orm::{
@@ -31,7 +32,7 @@ use stable::{
         v2::{self, *},
         *,
     },
-    task::TaskManager,
+    task::{SpawnTaskHandle, TaskManager},
 };
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpSocket, ToSocketAddrs};
@@ -39,12 +40,44 @@ use tokio::process::Command;
 use tokio::sync::Mutex;
 use tracing::Instrument;
 
+mod acl;
+#[cfg(unix)]
+mod agent;
+mod audit;
+mod ban;
+mod caps;
+mod cert;
+mod ctl;
+pub mod drain;
 mod handle;
 pub mod health;
+mod hostkeys;
+mod invite;
 mod jobs;
+mod key_options;
+mod metrics;
+mod queue;
+mod session;
 pub mod setup;
+mod sftp;
+mod sync;
+mod workspace;
+use acl::{RepoLevel, RepoTarget};
+pub use audit::format_utc;
+use ban::BanList;
+pub use caps::{Capabilities, Capability};
+pub use ctl::{server, ServerHandle};
 use handle::ChannelHandle;
-use jobs::{JobEvent, JobRecord, JobRegistry};
+pub use invite::parse_ttl;
+pub use jobs::JobSummary;
+use jobs::{
+    parse_job_kill_args, parse_job_list_args, JobEvent, JobOwner, JobRecord, JobRegistry,
+    JobSignal, JOB_KILL_USAGE, JOB_LIST_USAGE,
+};
+pub use key_options::KeyOptions;
+use queue::WorkspaceQueue;
+use session::SessionRegistry;
+use sync::SyncPayload;
 use v2::Body;
 
 #[cfg(test)]
@@ -55,6 +88,12 @@ struct SessionUser {
     id: i32,
     name: String,
     role: String,
+    /// 证书登录时的证书标识 `<key_id>#<serial>`
+    cert: Option<String>,
+    /// 公钥登录时公钥的 `authorized_keys` 选项
+    options: KeyOptions,
+    /// 有效的操作权限: 用户权限与公钥权限的交集
+    caps: Capabilities,
 }
 
 impl SessionUser {
@@ -63,6 +102,10 @@ impl SessionUser {
     }
 }
 
+/// 将进程输出同时写入通道与任务记录
+///
+/// 通道写入失败(客户端断开或任务已转入后台)后不再写通道,
+/// 但继续读取进程输出并保存到任务记录, 避免进程因管道阻塞而挂起
 async fn copy_with_job<R, W>(
     reader: &mut R,
     writer: &mut W,
@@ -73,19 +116,71 @@ where
     W: tokio::io::AsyncWrite + Unpin,
 {
     let mut total = 0_u64;
+    let mut connected = true;
     let mut buf = [0_u8; 8192];
     loop {
         let len = reader.read(&mut buf).await?;
         if len == 0 {
             break;
         }
-        writer.write_all(&buf[..len]).await?;
+        if connected {
+            let written = async {
+                writer.write_all(&buf[..len]).await?;
+                writer.flush().await
+            }
+            .await;
+            if let Err(err) = written {
+                tracing::debug!("通道已关闭, 输出仅保存到任务记录: {err}");
+                connected = false;
+            }
+        }
         job.append_output(&buf[..len]).await;
         total = total.saturating_add(len as u64);
     }
     Ok(total)
 }
 
+/// 补丁的统计, 文件数按 `diff --git` 行去重
+#[derive(Default)]
+struct PatchStats {
+    files: std::collections::HashSet<Vec<u8>>,
+    bytes: u64,
+}
+
+impl PatchStats {
+    /// 逐行统计, `line` 可以带行尾的 `\n`
+    fn line(&mut self, line: &[u8]) {
+        self.bytes = self.bytes.saturating_add(line.len() as u64);
+        let line = line.strip_suffix(b"\n").unwrap_or(line);
+        if line.starts_with(b"diff --git ") && !self.files.contains(line) {
+            self.files.insert(line.to_vec());
+        }
+    }
+}
+
+/// 代码同步结果的摘要
+fn code_sync_summary(stats: &PatchStats, untracked: usize) -> String {
+    format!(
+        "code_sync=applied files={} untracked={untracked} patch_bytes={}",
+        stats.files.len(),
+        stats.bytes
+    )
+}
+
+/// 解析工作目录下的文件路径, 绝对路径, 包含 `..` 或者超出工作目录的路径返回 None
+fn work_file(work_path: &Path, file: &str) -> Option<PathBuf> {
+    let file_path = Path::new(file);
+    if file_path.is_absolute()
+        || file_path
+            .components()
+            .any(|c| c == std::path::Component::ParentDir)
+    {
+        return None;
+    }
+    let file_path = work_path.join(file_path).clean();
+    file_path.starts_with(work_path).then_some(file_path)
+}
+
 fn cmd_shell_arg(shell: &str) -> &'static str {
     let shell_name = Path::new(shell)
         .file_name()
@@ -119,27 +214,6 @@ fn parse_exec_command(
     }
 }
 
-#[derive(serde::Serialize)]
-struct AdminUserRow {
-    id: i32,
-    name: String,
-    nick: Option<String>,
-    email: Option<String>,
-    role: String,
-    enabled: bool,
-    key_count: u64,
-}
-
-#[derive(serde::Serialize)]
-struct AdminKeyRow {
-    alg: String,
-    key: String,
-    user_id: i32,
-    user_name: Option<String>,
-    enabled: bool,
-    comment: Option<String>,
-}
-
 pub struct AppServer {
     /// 客户端连接
     id: usize,
@@ -151,6 +225,8 @@ pub struct AppServer {
     clients: Arc<Mutex<HashMap<usize, winptyrs::PTY>>>,
     /// 任务管理器
     tm: TaskManager,
+    /// 服务级任务入口, 后台任务不随连接断开而结束
+    detached: SpawnTaskHandle,
     /// 数据库连接
     db: DatabaseConnection,
     /// 当前 Client 的 ChannelHandle
@@ -159,10 +235,29 @@ pub struct AppServer {
     action: String,
     /// 当前认证通过的账户信息
     user: Option<SessionUser>,
+    /// 客户端地址
+    peer: Option<std::net::SocketAddr>,
+    /// 签名校验通过但未登记的公钥, 用于兑换邀请码
+    pending_key: Option<PublicKey>,
+    /// 有被拒绝的认证请求, 连接结束时仍未登录则计一次认证失败
+    auth_failed: bool,
     /// 当前的环境变量
     env: HashMap<String, String>,
+    /// 客户端请求 agent 转发时创建的 socket
+    #[cfg(unix)]
+    agent: Option<agent::AgentSocket>,
     /// 任务输出缓存与 attach 管理
     jobs: JobRegistry,
+    /// 当前连接中各个通道对应的任务, 用于转发客户端信号
+    channel_jobs: HashMap<ChannelId, Arc<JobRecord>>,
+    /// 工作区任务队列, 所有连接共享
+    queue: WorkspaceQueue,
+    /// 认证失败计数与封禁地址, 所有连接共享
+    bans: BanList,
+    /// 当前的连接列表, 所有连接共享
+    sessions: SessionRegistry,
+    /// 服务端提供的全部主机密钥, 用于 `hostkeys-00@openssh.com` 通告
+    host_keys: Arc<Vec<PrivateKey>>,
 }
 
 impl Clone for AppServer {
@@ -171,28 +266,51 @@ impl Clone for AppServer {
             id: self.id,
             clients: self.clients.clone(),
             tm: TaskManager::default(),
+            detached: self.detached.clone(),
             db: self.db.clone(),
             handle: None,
             action: String::new(),
             user: None,
+            peer: None,
+            pending_key: None,
+            auth_failed: false,
             env: HashMap::new(),
+            #[cfg(unix)]
+            agent: None,
             jobs: self.jobs.clone(),
+            channel_jobs: HashMap::new(),
+            queue: self.queue.clone(),
+            bans: self.bans.clone(),
+            sessions: self.sessions.clone(),
+            host_keys: self.host_keys.clone(),
         }
     }
 }
 
 impl AppServer {
     pub fn new(db: DbConn) -> Self {
+        let tm = TaskManager::default();
         Self {
             id: 0,
             clients: Arc::new(Mutex::new(HashMap::new())),
-            tm: TaskManager::default(),
+            detached: tm.spawn_handle(),
+            tm,
             handle: None,
             db,
             action: String::new(),
             user: None,
+            peer: None,
+            pending_key: None,
+            auth_failed: false,
             env: HashMap::new(),
+            #[cfg(unix)]
+            agent: None,
             jobs: JobRegistry::default(),
+            channel_jobs: HashMap::new(),
+            queue: WorkspaceQueue::default(),
+            bans: BanList::default(),
+            sessions: SessionRegistry::default(),
+            host_keys: Arc::default(),
         }
     }
 
@@ -216,6 +334,23 @@ impl AppServer {
         !self.trace_id().is_empty()
     }
 
+    /// 客户端是否要求任务转入后台运行 (DETACH=true)
+    fn detach_requested(&self) -> bool {
+        self.env
+            .get("DETACH")
+            .map(|s| s.parse::<bool>().unwrap_or(false))
+            .unwrap_or(false)
+    }
+
+    /// 任务的执行入口: 后台任务挂到服务级任务管理器上, 连接断开后继续运行
+    fn job_spawner(&self, detach: bool) -> SpawnTaskHandle {
+        if detach {
+            self.detached.clone()
+        } else {
+            self.tm.spawn_handle()
+        }
+    }
+
     fn user_name(&self) -> &str {
         self.user
             .as_ref()
@@ -223,72 +358,218 @@ impl AppServer {
             .unwrap_or("")
     }
 
-    fn require_admin(&self) -> HorseResult<()> {
-        if self.user.as_ref().is_some_and(SessionUser::is_admin) {
-            return Ok(());
+    /// 任务所有者, 证书登录时附带证书标识
+    fn job_owner(&self) -> JobOwner {
+        JobOwner {
+            name: self.user_name().to_string(),
+            cert: self.user.as_ref().and_then(|user| user.cert.clone()),
         }
+    }
 
-        Err(anyhow!("需要管理员权限").into())
+    /// 记录通道对应的任务, 同时清理已经结束的任务
+    fn track_job(&mut self, channel: ChannelId, job: Arc<JobRecord>) {
+        self.channel_jobs.retain(|_, job| !job.is_finished());
+        self.channel_jobs.insert(channel, job);
     }
 
-    /// 服务端 git 命令处理
-    #[tracing::instrument(skip(self), err)]
-    pub async fn git(&mut self, command: Vec<String>) -> HorseResult<()> {
-        // git clone ssh://git@127.0.0.1:2222/repos/a
-        // git-upload-pack '/repos/a'
-        let mut handle = self.handle.take().context("FIXME: NO HANDLE").unwrap();
+    /// 登录公钥的 `authorized_keys` 选项, 证书登录时来自证书扩展, 邀请码登录时没有限制
+    fn key_options(&self) -> KeyOptions {
+        self.user
+            .as_ref()
+            .map(|user| user.options.clone())
+            .unwrap_or_default()
+    }
+
+    /// 公钥是否已经登记
+    async fn publickey_registered(&self, pk: &PublicKey) -> HorseResult<bool> {
+        #[allow(deprecated)]
+        let data = base64::encode(&pk.to_bytes().context("pk bytes")?);
+        let found = SshPk::find_by_id((pk.algorithm().to_string(), data))
+            .one(&self.db)
+            .await?;
+        Ok(found.is_some())
+    }
 
-        let git = &command.first().context("FIXME: GIT PUSH/CLONE")?;
-        let repo = &command.get(1).context("FIXME: GIT PUSH/CLONE")?;
+    /// 公钥对应的启用用户、公钥选项和有效权限, 公钥未登记时记下公钥用于兑换邀请码
+    async fn publickey_user(
+        &mut self,
+        pk: &PublicKey,
+    ) -> HorseResult<Option<(user::Model, KeyOptions, Capabilities)>> {
+        #[allow(deprecated)]
+        let data = base64::encode(&pk.to_bytes().context("pk bytes")?);
 
-        let mut repo_path = PathBuf::from(repo);
-        repo_path = repo_path
-            .strip_prefix("/")
-            .context("Repo strip_prefix")?
-            .into();
-        // 清理路径
-        repo_path = repo_path.clean();
+        let Some(sa) = SshPk::find_by_id((pk.algorithm().to_string(), data.to_owned()))
+            .one(&self.db)
+            .await?
+        else {
+            tracing::error!("公钥未记录: ({} {})", pk.algorithm().to_string(), data);
+            // 签名已经校验过, 记下公钥, 客户端可以继续用邀请码兑换
+            self.pending_key.get_or_insert_with(|| pk.clone());
+            return Ok(None);
+        };
 
-        // 如果提供的地址包含 .. 等路径，则拒绝请求
-        if let Some(fst) = repo_path.components().next() {
-            if fst == std::path::Component::ParentDir {
-                return Ok(());
+        if !sa.enabled {
+            tracing::warn!("公钥已禁用: ({} {})", pk.algorithm().to_string(), data);
+            return Ok(None);
+        }
+
+        // 选项无法解析时拒绝登录, 不能忽略其中的限制
+        let options = match sa.options.as_deref().map(KeyOptions::parse).transpose() {
+            Ok(options) => options.unwrap_or_default(),
+            Err(err) => {
+                tracing::error!("公钥选项无效: ({} {}): {err}", pk.algorithm(), data);
+                return Ok(None);
             }
+        };
+        if let Err(reason) = options.check_login(self.peer.map(|addr| addr.ip())) {
+            tracing::warn!("公钥选项拒绝登录: ({} {}): {reason}", pk.algorithm(), data);
+            return Ok(None);
+        }
+
+        let Some(user) = sa.find_related(User).one(&self.db).await? else {
+            tracing::error!("公钥未授权: ({} {})", pk.algorithm().to_string(), data);
+            return Ok(None);
+        };
 
-            let parent = fst
-                .as_os_str()
-                .to_str()
-                .context(format!("目录名非法: {:?}", repo_path))
-                .unwrap();
+        if !user.enabled {
+            tracing::warn!("用户已禁用: {}", user.name);
+            return Ok(None);
+        }
 
-            let path = std::env::current_dir()?;
+        let caps = Capabilities::load(user.caps.as_deref())
+            .intersect(Capabilities::load(sa.caps.as_deref()));
+        Ok(Some((user, options, caps)))
+    }
 
-            // 仓库存放在 repos 目录下
-            if parent != "repos" {
-                repo_path = path.join("repos").join(repo_path);
+    /// 登录尝试写入审计日志, `user` 为空表示拒绝
+    async fn audit_login(
+        &mut self,
+        method: &str,
+        action: &str,
+        user: Option<&user::Model>,
+        fingerprint: String,
+    ) {
+        let entry = audit::AuditEntry {
+            kind: "login",
+            user: user.map(|user| user.name.clone()),
+            peer: self.peer.map(|peer| peer.to_string()),
+            action: method.to_string(),
+            outcome: if user.is_some() {
+                "accepted"
             } else {
-                repo_path = path.join(repo_path);
+                "rejected"
             }
+            .to_string(),
+            fingerprint: Some(fingerprint),
+            command: Some(action.to_string()),
+            ..Default::default()
+        };
+        audit::record(&self.db, entry).await;
+        metrics::auth(method, user.is_some());
 
-            repo_path = repo_path.clean();
+        if user.is_some() {
+            self.bans.success(self.peer.map(|peer| peer.ip()));
+        } else {
+            self.auth_failed = true;
+        }
+    }
+
+    /// 当前连接的地址在认证过程中被封禁
+    fn peer_banned(&self) -> bool {
+        self.peer.is_some_and(|peer| self.bans.is_banned(peer.ip()))
+    }
+
+    fn require_admin(&self) -> HorseResult<()> {
+        if self.user.as_ref().is_some_and(SessionUser::is_admin) {
+            return Ok(());
         }
 
-        // 仓库名称统一添加 .git 后缀
-        if repo_path.extension() != Some(OsStr::new("git")) && !repo_path.set_extension("git") {
-            tracing::error!("无效仓库路径: {:?}", repo_path);
+        Err(anyhow!("需要管理员权限").into())
+    }
+
+    /// 统一的权限检查: 用户与公钥的操作权限, 以及公钥选项的限制 (`key_allows`)
+    fn authorize(&self, cap: Capability, key_allows: bool, target: &str) -> Result<(), String> {
+        let caps = self
+            .user
+            .as_ref()
+            .map(|user| user.caps)
+            .unwrap_or_else(Capabilities::none);
+        let reason = if !caps.allows(cap) {
+            format!("没有 {cap} 权限: {target}")
+        } else if !key_allows {
+            format!("公钥选项不允许: {target}")
+        } else {
             return Ok(());
+        };
+        tracing::warn!("HSSH_FORBIDDEN: user={} {reason}", self.user_name());
+        Err(reason)
+    }
+
+    /// 拒绝 pty、转发等请求: 向会话输出 `HSSH_FORBIDDEN` 并写入审计日志
+    async fn forbid(&self, action: &str, reason: String) {
+        if let Some(handle) = &self.handle {
+            let _ = handle.error_with_code("HSSH_FORBIDDEN", &reason).await;
         }
+        let entry = audit::AuditEntry {
+            kind: "action",
+            user: self.user.as_ref().map(|user| user.name.clone()),
+            peer: self.peer.map(|peer| peer.to_string()),
+            action: action.to_string(),
+            outcome: "forbidden".to_string(),
+            command: Some(reason),
+            ..Default::default()
+        };
+        audit::record(&self.db, entry).await;
+    }
+
+    /// 服务端 git 命令处理
+    #[tracing::instrument(skip(self), err)]
+    pub async fn git(&mut self, command: Vec<String>) -> HorseResult<()> {
+        // git clone ssh://git@127.0.0.1:2222/repos/a
+        // git-upload-pack '/repos/a'
+        let git = command.first().context("FIXME: GIT PUSH/CLONE")?.clone();
+        let repo = command.get(1).context("FIXME: GIT PUSH/CLONE")?.clone();
+
+        let need = match git.as_str() {
+            "git-upload-pack" => RepoLevel::Read,
+            "git-receive-pack" => {
+                // 第一次推送的仓库登记到推送者名下
+                if !self.claim_new_repo(&repo).await? {
+                    return Ok(());
+                }
+                RepoLevel::Write
+            }
+            unkonwn => {
+                tracing::error!("不支持的GIT命令: {unkonwn}");
+                let handle = self.handle.take().context("FIXME: NO HANDLE")?;
+                handle
+                    .fail_with_error(
+                        2,
+                        "HSSH_GIT_UNSUPPORTED",
+                        format!("不支持的GIT命令: {unkonwn}"),
+                    )
+                    .await?;
+                return Ok(());
+            }
+        };
+
+        let Some(target) = self.authorize_repo(&repo, need).await? else {
+            return Ok(());
+        };
+        let mut handle = self.handle.take().context("FIXME: NO HANDLE")?;
 
-        tracing::info!("GIT REPO: {}", repo_path.display());
-        let mut repo = Repo::from(repo_path);
+        tracing::info!("GIT REPO: {}", target.repo_path.display());
+        let mut repo = target.repo();
         let task = self.tm.spawn_handle();
 
-        match git.as_str() {
+        match need {
             // git clone
-            "git-upload-pack" => {
-                // TODO: 需要对仓库进行检查
+            RepoLevel::Read => {
                 if !repo.exists() {
                     tracing::warn!("克隆仓库不存在: {:?}", repo.path().display());
+                    handle
+                        .fail_with_error(2, "HSSH_REPO_NOT_FOUND", "代码仓库不存在")
+                        .await?;
                     return Ok(());
                 }
 
@@ -308,7 +589,7 @@ impl AppServer {
                 });
             }
             // git push
-            "git-receive-pack" => {
+            _ => {
                 // 如果仓库目录不存在
                 if !repo.exists() {
                     handle.info("成功创建仓库, 接受第一次推送...").await?;
@@ -330,10 +611,6 @@ impl AppServer {
                     Ok(())
                 });
             }
-            unkonwn => {
-                tracing::error!("不支持的GIT命令: {unkonwn}");
-                return Ok(());
-            }
         }
 
         Ok(())
@@ -361,53 +638,33 @@ impl AppServer {
         };
 
         // 如果命令中包含 REPO 或者 BRANCH 环境变量, 则切换到工作目录执行命令
-        let (cmd_dir, sync_context) = if let (Some(env_repo), Some(env_branch)) =
+        // 在工作目录中执行的命令需要排队, 同一工作区同时只运行一个任务
+        let (cmd_dir, sync_context, in_workspace) = if let (Some(env_repo), Some(env_branch)) =
             (env_repo, env_branch)
         {
-            let mut repo_path = PathBuf::from(env_repo);
-            // 去除开头的 /
-            if let Ok(stripped) = repo_path.strip_prefix("/") {
-                repo_path = stripped.to_path_buf();
-            }
-
-            // 清理路径
-            repo_path = repo_path.clean();
-
-            // 裸仓库名称统一添加 .git 后缀
-            if repo_path.extension() != Some(OsStr::new("git")) && !repo_path.set_extension("git") {
-                tracing::error!("无效仓库路径: {:?}", repo_path);
-                let handle = self.handle.take().context("FIXME: NO HANDLE")?;
-                handle
-                    .fail_with_error(
-                        2,
-                        "HSSH_REPO_PATH_INVALID",
-                        format!("无效仓库路径: {:?}", repo_path),
-                    )
-                    .await?;
+            let Some(target) = self.authorize_repo(&env_repo, RepoLevel::Write).await? else {
                 return Ok(());
-            }
-
-            let repo = Repo::from(std::env::current_dir()?.join("repos").join(&repo_path));
-            let mut work_path = std::env::current_dir()?.join("workspace").join(repo_path);
-            // 构建目录不包含 .git 后缀
-            work_path.set_extension("");
-
+            };
             if sync {
-                if !repo.exists() {
+                if !target.repo().exists() {
                     let handle = self.handle.take().context("FIXME: NO HANDLE")?;
                     handle
                         .fail_with_error(2, "HSSH_REPO_NOT_FOUND", "代码仓库不存在，请先 push 代码")
                         .await?;
                     return Ok(());
                 }
-                if !work_path.exists() {
-                    std::fs::create_dir_all(&work_path).context("创建工作目录失败")?;
-                }
-                (work_path, Some((repo, env_branch, local_commit.clone())))
-            } else if work_path.exists() {
-                (work_path, None)
+                (
+                    target.work_path.clone(),
+                    Some((target, env_branch, local_commit.clone())),
+                    true,
+                )
             } else {
-                (std::env::current_dir()?, None)
+                let branch_path = target.branch_path(&env_branch);
+                if branch_path.exists() {
+                    (branch_path, None, true)
+                } else {
+                    (std::env::current_dir()?, None, false)
+                }
             }
         } else if sync {
             let handle = self.handle.take().context("FIXME: NO HANDLE")?;
@@ -420,14 +677,14 @@ impl AppServer {
                 .await?;
             return Ok(());
         } else {
-            (std::env::current_dir()?, None)
+            (std::env::current_dir()?, None, false)
         };
 
         let mut handle = self
             .handle
             .take()
             .context("FIXME: NO HANDLE".color(Color::Red))?;
-        let owner = self.user_name().to_string();
+        let owner = self.job_owner();
         let job = self
             .jobs
             .create_job(
@@ -436,17 +693,77 @@ impl AppServer {
                 command_line.clone(),
             )
             .await;
+        self.track_job(handle.id, job.clone());
+        handle.audit_job(job.id());
         handle.info(format!("job_id={}", job.id())).await?;
-        let task = self.tm.spawn_handle();
+
+        // 同步执行时按仓库的工作目录模式决定任务目录
+        let sync_context = sync_context.map(|(target, branch, local_commit)| {
+            let workspace = target.job_workspace(&branch, job.id());
+            (target.repo(), workspace, branch, local_commit)
+        });
+        let cmd_dir = match &sync_context {
+            Some((_, workspace, _, _)) => workspace.path.clone(),
+            None => cmd_dir,
+        };
+        let cleanup = sync_context
+            .as_ref()
+            .map(|(repo, workspace, _, _)| (repo.clone(), workspace.clone()));
+
+        let detach = self.detach_requested();
+        let sync_request = self.sync_request();
+        let task = self.job_spawner(detach);
+        let queue = in_workspace.then(|| self.queue.clone());
         let span = tracing::info_span!("spawn", command = %command_line, cmd_dir = ?cmd_dir);
         let mut cmd = Command::new(&shell);
         cmd.envs(&self.env);
+        if let Some(target_dir) = sync_context
+            .as_ref()
+            .and_then(|(_, workspace, _, _)| workspace.target_dir.as_ref())
+        {
+            cmd.env("CARGO_TARGET_DIR", target_dir);
+        }
+        // 独立的进程组, 结束任务时连同子进程一起结束
+        #[cfg(unix)]
+        cmd.process_group(0);
 
         task.spawn(
             async move {
                 let mut final_code = 1_i32;
                 let result: anyhow::Result<()> = async {
-                    if let Some((repo, branch, local_commit)) = sync_context {
+                    // 先收下代码快照, 排队期间客户端不会阻塞在写入上
+                    let payload = if sync_context.is_some() {
+                        Some(SyncPayload::read(&mut handle, sync_request, job.id()).await?)
+                    } else {
+                        None
+                    };
+
+                    // 后台任务在排队前就结束客户端请求, 客户端断开不影响排队和运行
+                    if detach {
+                        handle.detach(job.id()).await?;
+                    }
+
+                    let _workspace = match &queue {
+                        Some(queue) => match queue.acquire(&cmd_dir, &job, &handle).await? {
+                            Some(guard) => Some(guard),
+                            None => {
+                                final_code = 130;
+                                handle
+                                    .fail_with_error(
+                                        130,
+                                        "HSSH_JOB_CANCELLED",
+                                        "任务在排队期间被取消",
+                                    )
+                                    .await?;
+                                return Ok(());
+                            }
+                        },
+                        None => None,
+                    };
+
+                    if let (Some((repo, workspace, branch, local_commit)), Some(payload)) =
+                        (sync_context, payload)
+                    {
                         let remote_commit = repo
                             .rev_parse(&branch)
                             .await
@@ -457,19 +774,10 @@ impl AppServer {
                             ))
                             .await?;
 
-                        repo.checkout(&cmd_dir, Some(&branch))
-                            .await
-                            .context("检出代码失败")?;
-                        let mut patch_input = handle.make_reader();
-                        let mut patch = Vec::new();
-                        patch_input.read_to_end(&mut patch).await?;
-                        drop(patch_input);
-                        repo.apply(&cmd_dir, &patch)
-                            .await
-                            .context("应用代码快照失败")?;
-                        handle
-                            .info(format!("code_sync=applied patch_bytes={}", patch.len()))
+                        let summary = payload
+                            .apply(&repo, &workspace, &branch, sync_request, &handle)
                             .await?;
+                        handle.info(summary).await?;
                     } else {
                         handle
                             .info(format!(
@@ -515,6 +823,8 @@ impl AppServer {
                         }
                     };
 
+                    job.attach_process(cmd.id()).await;
+
                     let mut stdout = cmd.stdout.take().unwrap();
                     let mut stderr = cmd.stderr.take().unwrap();
 
@@ -542,6 +852,10 @@ impl AppServer {
                 }
                 .await;
 
+                if let Some((repo, workspace)) = cleanup {
+                    workspace.cleanup(&repo).await;
+                }
+
                 if let Err(err) = result {
                     tracing::error!("同步远端代码并执行命令失败: {err:#}");
                     job.finish(final_code).await;
@@ -565,33 +879,21 @@ impl AppServer {
     pub async fn get(&mut self, files: Vec<String>) -> HorseResult<()> {
         tracing::info!("GET: {}", files.join(" "));
 
-        let env_repo = self.env.get("REPO").context("REPO 环境变量未设置")?;
-        let _env_branch = self.env.get("BRANCH").context("BRANCH 环境变量未设置")?;
-
-        let mut repo_path = PathBuf::from(env_repo);
-        // 去除开头的 /
-        if let Ok(stripped) = repo_path.strip_prefix("/") {
-            repo_path = stripped.to_path_buf();
-        }
-
-        // 清理路径
-        repo_path = repo_path.clean();
+        let env_repo = self
+            .env
+            .get("REPO")
+            .cloned()
+            .context("REPO 环境变量未设置")?;
+        let env_branch = self
+            .env
+            .get("BRANCH")
+            .cloned()
+            .context("BRANCH 环境变量未设置")?;
 
-        // 裸仓库名称统一添加 .git 后缀
-        if repo_path.extension() != Some(OsStr::new("git")) && !repo_path.set_extension("git") {
-            tracing::error!("无效仓库路径: {:?}", repo_path);
-            let handle = self.handle.take().context("FIXME: NO HANDLE")?;
-            handle
-                .error(format!("无效仓库路径: {:?}", repo_path))
-                .await?;
-            handle.eof().await?;
-            handle.close().await?;
+        let Some(target) = self.authorize_repo(&env_repo, RepoLevel::Read).await? else {
             return Ok(());
-        }
-
-        let mut work_path = std::env::current_dir()?.join("workspace").join(repo_path);
-        // 构建目录不包含 .git 后缀
-        work_path.set_extension("");
+        };
+        let work_path = target.branch_path(&env_branch);
 
         let handle = self
             .handle
@@ -600,23 +902,18 @@ impl AppServer {
         let task = self.tm.spawn_handle();
 
         let file = files.first().context("FIXME: NO FILE")?;
-        let file_path = PathBuf::from(file);
-        if let Some(fst) = file_path.components().next() {
-            if fst == std::path::Component::ParentDir {
-                tracing::warn!("拒绝文件请求, 只能拷贝工作目录文件: {}", file);
-                handle
-                    .error(format!("拒绝文件请求, 路径不合法: {}", file))
-                    .await?;
-                handle.eof().await?;
-                handle.close().await?;
-                return Ok(());
-            }
-        }
+        let Some(file_path) = work_file(&work_path, file) else {
+            tracing::warn!("拒绝文件请求, 只能拷贝工作目录文件: {}", file);
+            handle
+                .error(format!("拒绝文件请求, 路径不合法: {}", file))
+                .await?;
+            handle.eof().await?;
+            handle.close().await?;
+            return Ok(());
+        };
 
         let t1 = task.clone();
         task.spawn(async move {
-            let file_path = work_path.join(file_path).clean();
-
             if !file_path.exists() {
                 handle
                     .error(format!("文件不存在: {}", file_path.display()))
@@ -681,6 +978,7 @@ impl AppServer {
                     }
 
                     cout.write_all(&buf[..len]).await?;
+                    metrics::transfer("get", len as u64);
                 }
 
                 tracing::info!("目录传输完成!");
@@ -727,6 +1025,7 @@ impl AppServer {
                     }
 
                     cout.write_all(&buf[..len]).await?;
+                    metrics::transfer("get", len as u64);
                 }
 
                 tracing::info!("文件传输完成!");
@@ -745,33 +1044,21 @@ impl AppServer {
     pub async fn scp(&mut self, files: Vec<String>) -> HorseResult<()> {
         tracing::info!("GET: {}", files.join(" "));
 
-        let env_repo = self.env.get("REPO").context("REPO 环境变量未设置")?;
-        let _env_branch = self.env.get("BRANCH").context("BRANCH 环境变量未设置")?;
-
-        let mut repo_path = PathBuf::from(env_repo);
-        // 去除开头的 /
-        if let Ok(stripped) = repo_path.strip_prefix("/") {
-            repo_path = stripped.to_path_buf();
-        }
-
-        // 清理路径
-        repo_path = repo_path.clean();
+        let env_repo = self
+            .env
+            .get("REPO")
+            .cloned()
+            .context("REPO 环境变量未设置")?;
+        let env_branch = self
+            .env
+            .get("BRANCH")
+            .cloned()
+            .context("BRANCH 环境变量未设置")?;
 
-        // 裸仓库名称统一添加 .git 后缀
-        if repo_path.extension() != Some(OsStr::new("git")) && !repo_path.set_extension("git") {
-            tracing::error!("无效仓库路径: {:?}", repo_path);
-            let handle = self.handle.take().context("FIXME: NO HANDLE")?;
-            handle
-                .error(format!("无效仓库路径: {:?}", repo_path))
-                .await?;
-            handle.eof().await?;
-            handle.close().await?;
+        let Some(target) = self.authorize_repo(&env_repo, RepoLevel::Read).await? else {
             return Ok(());
-        }
-
-        let mut work_path = std::env::current_dir()?.join("workspace").join(repo_path);
-        // 构建目录不包含 .git 后缀
-        work_path.set_extension("");
+        };
+        let work_path = target.branch_path(&env_branch);
 
         let handle = self
             .handle
@@ -780,25 +1067,22 @@ impl AppServer {
         let task = self.tm.spawn_handle();
 
         let file = files.first().context("FIXME: NO FILE")?;
-        let file_path = PathBuf::from(file);
-        if let Some(fst) = file_path.components().next() {
-            if fst == std::path::Component::ParentDir {
-                tracing::warn!("拒绝文件请求, 只能拷贝工作目录文件: {}", file);
-                handle
-                    .error(format!("拒绝文件请求, 路径不合法: {}", file))
-                    .await?;
-                handle.eof().await?;
-                handle.close().await?;
-                return Ok(());
-            }
-        }
-
-        let file_path = work_path.join(file_path);
+        let Some(file_path) = work_file(&work_path, file) else {
+            tracing::warn!("拒绝文件请求, 只能拷贝工作目录文件: {}", file);
+            handle
+                .fail_with_error(
+                    2,
+                    "HSSH_SCP_PATH_INVALID",
+                    format!("拒绝文件请求, 路径不合法: {}", file),
+                )
+                .await?;
+            return Ok(());
+        };
 
         if !file_path.exists() {
-            handle.error(format!("文件不存在: {}", file)).await?;
-            handle.eof().await?;
-            handle.close().await?;
+            handle
+                .fail_with_error(1, "HSSH_SCP_NOT_FOUND", format!("文件不存在: {}", file))
+                .await?;
             return Ok(());
         }
 
@@ -811,10 +1095,11 @@ impl AppServer {
                 if len == 0 {
                     break;
                 }
+                metrics::transfer("scp", len);
             }
 
             cout.shutdown().await?;
-            handle.eof().await?;
+            handle.exit_code(0).await?;
 
             Ok(())
         });
@@ -829,33 +1114,21 @@ impl AppServer {
         tracing::info!("SSH: {}", commands.join(" "));
         let mut commands = VecDeque::from(commands);
 
-        let env_repo = self.env.get("REPO").context("REPO 环境变量未设置")?;
-        let _env_branch = self.env.get("BRANCH").context("BRANCH 环境变量未设置")?;
-
-        let mut repo_path = PathBuf::from(env_repo);
-        // 去除开头的 /
-        if let Ok(stripped) = repo_path.strip_prefix("/") {
-            repo_path = stripped.to_path_buf();
-        }
-
-        // 清理路径
-        repo_path = repo_path.clean();
+        let env_repo = self
+            .env
+            .get("REPO")
+            .cloned()
+            .context("REPO 环境变量未设置")?;
+        let env_branch = self
+            .env
+            .get("BRANCH")
+            .cloned()
+            .context("BRANCH 环境变量未设置")?;
 
-        // 裸仓库名称统一添加 .git 后缀
-        if repo_path.extension() != Some(OsStr::new("git")) && !repo_path.set_extension("git") {
-            tracing::error!("无效仓库路径: {:?}", repo_path);
-            let handle = self.handle.take().context("FIXME: NO HANDLE")?;
-            handle
-                .error(format!("无效仓库路径: {:?}", repo_path))
-                .await?;
-            handle.eof().await?;
-            handle.close().await?;
+        let Some(target) = self.authorize_repo(&env_repo, RepoLevel::Write).await? else {
             return Ok(());
-        }
-
-        let mut work_path = std::env::current_dir()?.join("workspace").join(repo_path);
-        // 构建目录不包含 .git 后缀
-        work_path.set_extension("");
+        };
+        let work_path = target.branch_path(&env_branch);
 
         #[allow(unused_mut)]
         let mut handle = self
@@ -1062,36 +1335,21 @@ impl AppServer {
     pub async fn put(&mut self, files: Vec<String>) -> HorseResult<()> {
         tracing::info!("PUT: {}", files.join(" "));
 
-        let env_repo = self.env.get("REPO").context("REPO 环境变量未设置")?;
-        let _env_branch = self.env.get("BRANCH").context("BRANCH 环境变量未设置")?;
-
-        let mut repo_path = PathBuf::from(env_repo);
-        // 去除开头的 /
-        if let Ok(stripped) = repo_path.strip_prefix("/") {
-            repo_path = stripped.to_path_buf();
-        }
-
-        // 清理路径
-        repo_path = repo_path.clean();
+        let env_repo = self
+            .env
+            .get("REPO")
+            .cloned()
+            .context("REPO 环境变量未设置")?;
+        let env_branch = self
+            .env
+            .get("BRANCH")
+            .cloned()
+            .context("BRANCH 环境变量未设置")?;
 
-        // 裸仓库名称统一添加 .git 后缀
-        if repo_path.extension() != Some(OsStr::new("git")) && !repo_path.set_extension("git") {
-            tracing::error!("无效仓库路径: {:?}", repo_path);
-            let handle = self.handle.take().context("FIXME: NO HANDLE")?;
-            handle
-                .fail_with_error(
-                    2,
-                    "HSSH_REPO_PATH_INVALID",
-                    format!("无效仓库路径: {:?}", repo_path),
-                )
-                .await?;
+        let Some(target) = self.authorize_repo(&env_repo, RepoLevel::Write).await? else {
             return Ok(());
-        }
-
-        let mut work_path = std::env::current_dir()?.join("workspace").join(repo_path);
-        // 构建目录不包含 .git 后缀
-        work_path.set_extension("");
-        work_path = work_path.clean();
+        };
+        let work_path = target.branch_path(&env_branch);
 
         let remote = files.first().context("FIXME: NO TARGET FILE")?;
         let remote_path = PathBuf::from(remote);
@@ -1145,7 +1403,8 @@ impl AppServer {
                 let mut file = tokio::fs::File::create(&target_path).await?;
                 {
                     let mut cin = handle.make_reader();
-                    tokio::io::copy(&mut cin, &mut file).await?;
+                    let len = tokio::io::copy(&mut cin, &mut file).await?;
+                    metrics::transfer("put", len);
                 }
                 file.flush().await?;
 
@@ -1168,11 +1427,11 @@ impl AppServer {
         Ok(())
     }
 
-    /// 管理员操作（用户、公钥）
+    /// 管理员操作（用户、公钥、仓库权限）
     #[tracing::instrument(skip(self), err)]
     pub async fn admin(&mut self, args: Vec<String>) -> HorseResult<()> {
         tracing::info!("ADMIN: {}", args.join(" "));
-        let handle = self
+        let mut handle = self
             .handle
             .take()
             .context("FIXME: NO HANDLE".color(Color::Red))?;
@@ -1186,285 +1445,176 @@ impl AppServer {
 
         let actor = self.user.clone().context("未获取登录用户")?;
         let db = self.db.clone();
+        let bans = self.bans.clone();
+        let sessions = self.sessions.clone();
+        let current = self.id;
+
+        // keys import 从标准输入读取 authorized_keys 内容
+        const MAX_ADMIN_INPUT: u64 = 1024 * 1024;
+        let mut input = String::new();
+        if args.first().map(String::as_str) == Some("keys")
+            && args.get(1).map(String::as_str) == Some("import")
+        {
+            handle
+                .make_reader()
+                .take(MAX_ADMIN_INPUT)
+                .read_to_string(&mut input)
+                .await
+                .context("读取 authorized_keys")?;
+        }
+
+        // 变更类命令记录变更前后的快照
+        let mutation = audit::is_mutation(&args);
+        let before = if mutation {
+            audit::snapshot(&self.db, &args).await
+        } else {
+            None
+        };
+        let audit_args = args.clone();
+        let audit_actor = actor.name.clone();
 
         let admin_res: anyhow::Result<String> = async move {
             let section = args.first().map(String::as_str).unwrap_or("");
             let command = args.get(1).map(String::as_str).unwrap_or("");
 
             let output = match (section, command) {
-                ("users", "list") => {
-                    let users = User::find().order_by_asc(user::Column::Id).all(&db).await?;
-                    let mut rows = Vec::with_capacity(users.len());
-                    for user in users {
-                        let key_count = user.find_related(SshPk).count(&db).await?;
-                        rows.push(AdminUserRow {
-                            id: user.id,
-                            name: user.name,
-                            nick: user.nick,
-                            email: user.email,
-                            role: user.role,
-                            enabled: user.enabled,
-                            key_count,
-                        });
-                    }
-                    serde_json::to_string_pretty(&rows)?
-                }
+                ("users", "list") => serde_json::to_string_pretty(&account::list_users(&db).await?)?,
                 ("users", "add") => {
                     let name = args.get(2).context("用法: users add <name> [admin|user]")?;
-                    let role = args
-                        .get(3)
-                        .map(String::as_str)
-                        .unwrap_or("user")
-                        .to_ascii_lowercase();
-                    if role != "admin" && role != "user" {
-                        return Err(anyhow!("角色必须是 admin 或 user"));
-                    }
-
-                    let user = user::ActiveModel {
-                        name: Set(name.to_string()),
-                        role: Set(role),
-                        enabled: Set(true),
-                        ..Default::default()
-                    }
-                    .insert(&db)
-                    .await?;
-
+                    let new = account::NewUser {
+                        name: name.to_string(),
+                        nick: None,
+                        email: None,
+                        role: args.get(3).map(String::as_str).unwrap_or("user").to_string(),
+                    };
+                    let user = account::add_user(&db, new).await?;
                     format!("用户已创建: {} (id={})", user.name, user.id)
                 }
                 ("users", "enable") => {
                     let name = args.get(2).context("用法: users enable <name>")?;
-                    let Some(mut target) = User::find()
-                        .filter(user::Column::Name.eq(name.as_str()))
-                        .one(&db)
-                        .await?
-                    else {
-                        return Err(anyhow!("用户不存在: {}", name));
+                    let changes = account::UserChanges {
+                        enabled: Some(true),
+                        ..Default::default()
                     };
-
-                    if !target.enabled {
-                        let mut active: user::ActiveModel = target.clone().into();
-                        active.enabled = Set(true);
-                        target = active.update(&db).await?;
-                    }
-
+                    let target = account::modify_user(&db, name, changes, Some(actor.id)).await?;
                     format!("用户已启用: {}", target.name)
                 }
                 ("users", "disable") => {
                     let name = args.get(2).context("用法: users disable <name>")?;
-                    let Some(mut target) = User::find()
-                        .filter(user::Column::Name.eq(name.as_str()))
-                        .one(&db)
-                        .await?
-                    else {
-                        return Err(anyhow!("用户不存在: {}", name));
+                    let changes = account::UserChanges {
+                        enabled: Some(false),
+                        ..Default::default()
                     };
-
-                    if target.id == actor.id {
-                        return Err(anyhow!("不能禁用当前登录管理员"));
-                    }
-
-                    if target.enabled && target.is_admin() {
-                        let admins = User::find()
-                            .filter(user::Column::Role.eq("admin"))
-                            .filter(user::Column::Enabled.eq(true))
-                            .count(&db)
-                            .await?;
-                        if admins <= 1 {
-                            return Err(anyhow!("不能禁用最后一个启用中的管理员"));
-                        }
-                    }
-
-                    if target.enabled {
-                        let mut active: user::ActiveModel = target.clone().into();
-                        active.enabled = Set(false);
-                        target = active.update(&db).await?;
-                    }
-
+                    let target = account::modify_user(&db, name, changes, Some(actor.id)).await?;
                     format!("用户已禁用: {}", target.name)
                 }
                 ("users", "role") => {
                     let name = args.get(2).context("用法: users role <name> <admin|user>")?;
                     let role = args.get(3).context("用法: users role <name> <admin|user>")?;
-                    let role = role.to_ascii_lowercase();
-                    if role != "admin" && role != "user" {
-                        return Err(anyhow!("角色必须是 admin 或 user"));
-                    }
-
-                    let Some(mut target) = User::find()
-                        .filter(user::Column::Name.eq(name.as_str()))
-                        .one(&db)
-                        .await?
-                    else {
-                        return Err(anyhow!("用户不存在: {}", name));
+                    let changes = account::UserChanges {
+                        role: Some(role.to_string()),
+                        ..Default::default()
                     };
-
-                    if target.role == "admin" && role == "user" && target.enabled {
-                        let admins = User::find()
-                            .filter(user::Column::Role.eq("admin"))
-                            .filter(user::Column::Enabled.eq(true))
-                            .count(&db)
-                            .await?;
-                        if admins <= 1 {
-                            return Err(anyhow!("不能降级最后一个启用中的管理员"));
-                        }
-                    }
-
-                    let mut active: user::ActiveModel = target.clone().into();
-                    active.role = Set(role.clone());
-                    target = active.update(&db).await?;
-
-                    format!("用户角色已更新: {} => {}", target.name, role)
+                    let target = account::modify_user(&db, name, changes, Some(actor.id)).await?;
+                    format!("用户角色已更新: {} => {}", target.name, target.role)
+                }
+                ("users", "caps") => {
+                    let usage = "用法: users caps <name> <caps>";
+                    let name = args.get(2).context(usage)?;
+                    let caps = args.get(3).context(usage)?;
+                    let changes = account::UserChanges {
+                        caps: Some(caps.to_string()),
+                        ..Default::default()
+                    };
+                    let target = account::modify_user(&db, name, changes, Some(actor.id)).await?;
+                    format!(
+                        "用户权限已更新: {} => {}",
+                        target.name,
+                        Capabilities::load(target.caps.as_deref())
+                    )
                 }
                 ("users", "delete") => {
                     let name = args.get(2).context("用法: users delete <name>")?;
-                    let Some(target) = User::find()
-                        .filter(user::Column::Name.eq(name.as_str()))
-                        .one(&db)
-                        .await?
-                    else {
-                        return Err(anyhow!("用户不存在: {}", name));
-                    };
-
-                    if target.id == actor.id {
-                        return Err(anyhow!("不能删除当前登录管理员"));
-                    }
-
-                    if target.enabled && target.is_admin() {
-                        let admins = User::find()
-                            .filter(user::Column::Role.eq("admin"))
-                            .filter(user::Column::Enabled.eq(true))
-                            .count(&db)
-                            .await?;
-                        if admins <= 1 {
-                            return Err(anyhow!("不能删除最后一个启用中的管理员"));
-                        }
-                    }
-
-                    let id = target.id;
-                    let name = target.name.clone();
-                    target.delete(&db).await?;
-                    format!("用户已删除: {} (id={})", name, id)
+                    let target = account::delete_user(&db, name, Some(actor.id)).await?;
+                    format!("用户已删除: {} (id={})", target.name, target.id)
                 }
                 ("keys", "list") => {
-                    let keys = if let Some(name) = args.get(2) {
-                        let Some(owner) = User::find()
-                            .filter(user::Column::Name.eq(name.as_str()))
-                            .one(&db)
-                            .await?
-                        else {
-                            return Err(anyhow!("用户不存在: {}", name));
-                        };
-                        owner.find_related(SshPk).all(&db).await?
-                    } else {
-                        SshPk::find()
-                            .order_by_asc(ssh_pk::Column::UserId)
-                            .order_by_asc(ssh_pk::Column::Alg)
-                            .all(&db)
-                            .await?
-                    };
-
-                    let mut rows = Vec::with_capacity(keys.len());
-                    for key in keys {
-                        let owner = key.find_related(User).one(&db).await?;
-                        rows.push(AdminKeyRow {
-                            alg: key.alg,
-                            key: key.key,
-                            user_id: key.user_id,
-                            user_name: owner.map(|u| u.name),
-                            enabled: key.enabled,
-                            comment: key.comment,
-                        });
-                    }
+                    let rows = account::list_keys(&db, args.get(2).map(String::as_str)).await?;
                     serde_json::to_string_pretty(&rows)?
                 }
                 ("keys", "add") => {
-                    let user_name = args
-                        .get(2)
-                        .context("用法: keys add <user> <alg> <key> [comment]")?;
-                    let alg = args
-                        .get(3)
-                        .context("用法: keys add <user> <alg> <key> [comment]")?;
-                    let key = args
-                        .get(4)
-                        .context("用法: keys add <user> <alg> <key> [comment]")?;
+                    let usage = "用法: keys add <user> <alg> <key> [comment]";
+                    let user_name = args.get(2).context(usage)?;
+                    let alg = args.get(3).context(usage)?;
+                    let key = args.get(4).context(usage)?;
                     let comment = if args.len() > 5 {
                         Some(args[5..].join(" "))
                     } else {
                         None
                     };
 
-                    let Some(owner) = User::find()
-                        .filter(user::Column::Name.eq(user_name.as_str()))
-                        .one(&db)
-                        .await?
-                    else {
-                        return Err(anyhow!("用户不存在: {}", user_name));
-                    };
-
-                    ssh_pk::ActiveModel {
-                        alg: Set(alg.to_string()),
-                        key: Set(key.to_string()),
-                        user_id: Set(owner.id),
-                        enabled: Set(true),
-                        comment: Set(comment),
-                    }
-                    .insert(&db)
-                    .await?;
-
-                    format!("公钥已添加到用户: {}", owner.name)
+                    account::add_key(&db, user_name, alg, key, comment, None).await?;
+                    format!("公钥已添加到用户: {user_name}")
                 }
                 ("keys", "enable") => {
                     let alg = args.get(2).context("用法: keys enable <alg> <key>")?;
                     let key = args.get(3).context("用法: keys enable <alg> <key>")?;
-                    let Some(mut target) = SshPk::find_by_id((alg.to_string(), key.to_string()))
-                        .one(&db)
-                        .await?
-                    else {
-                        return Err(anyhow!("公钥不存在"));
-                    };
-
-                    if !target.enabled {
-                        let mut active: ssh_pk::ActiveModel = target.clone().into();
-                        active.enabled = Set(true);
-                        target = active.update(&db).await?;
-                    }
-
+                    let target = account::set_key_enabled(&db, alg, key, true).await?;
                     format!("公钥已启用: {} {}", target.alg, target.user_id)
                 }
                 ("keys", "disable") => {
                     let alg = args.get(2).context("用法: keys disable <alg> <key>")?;
                     let key = args.get(3).context("用法: keys disable <alg> <key>")?;
-                    let Some(mut target) = SshPk::find_by_id((alg.to_string(), key.to_string()))
-                        .one(&db)
-                        .await?
-                    else {
-                        return Err(anyhow!("公钥不存在"));
-                    };
-
-                    if target.enabled {
-                        let mut active: ssh_pk::ActiveModel = target.clone().into();
-                        active.enabled = Set(false);
-                        target = active.update(&db).await?;
-                    }
-
+                    let target = account::set_key_enabled(&db, alg, key, false).await?;
                     format!("公钥已禁用: {} {}", target.alg, target.user_id)
                 }
+                ("keys", "caps") => {
+                    let usage = "用法: keys caps <alg> <key> <caps>";
+                    let alg = args.get(2).context(usage)?;
+                    let key = args.get(3).context(usage)?;
+                    let caps = args.get(4).context(usage)?;
+                    let target = account::set_key_caps(&db, alg, key, caps).await?;
+                    format!(
+                        "公钥权限已更新: {} => {}",
+                        account::fingerprint(&target.alg, &target.key),
+                        Capabilities::load(target.caps.as_deref())
+                    )
+                }
                 ("keys", "delete") => {
                     let alg = args.get(2).context("用法: keys delete <alg> <key>")?;
                     let key = args.get(3).context("用法: keys delete <alg> <key>")?;
-                    let Some(target) = SshPk::find_by_id((alg.to_string(), key.to_string()))
-                        .one(&db)
-                        .await?
-                    else {
-                        return Err(anyhow!("公钥不存在"));
-                    };
-
-                    target.delete(&db).await?;
+                    account::delete_key(&db, alg, key).await?;
                     "公钥已删除".to_string()
                 }
+                ("keys", "import") => {
+                    let user_name = args
+                        .get(2)
+                        .context("用法: keys import <user> < authorized_keys")?;
+                    let report = account::import_keys(&db, user_name, &input).await?;
+                    let mut output =
+                        format!("已导入 {} 个公钥到用户: {user_name}", report.added.len());
+                    for fingerprint in &report.added {
+                        output.push_str(&format!("\n  + {fingerprint}"));
+                    }
+                    for (line, reason) in &report.skipped {
+                        output.push_str(&format!("\n  跳过第 {line} 行: {reason}"));
+                    }
+                    output
+                }
+                ("keys", "export") => {
+                    let user_name = args.get(2).context("用法: keys export <user>")?;
+                    account::export_keys(&db, user_name).await?
+                }
+                ("repos", _) => acl::admin_repos(&db, &args).await?,
+                ("cas", _) => cert::admin_cas(&db, &args).await?,
+                ("invites", _) => invite::admin_invites(&db, &actor, &args).await?,
+                ("audit", _) => audit::admin_audit(&db, &args).await?,
+                ("bans", _) => ban::admin_bans(&db, &bans, &args).await?,
+                ("drain", _) => drain::admin_drain(&args)?,
+                ("sessions", _) => session::admin_sessions(&sessions, current, &args).await?,
                 _ => {
                     return Err(anyhow!(
-                        "不支持的 admin 命令, 用法: users|keys <list|add|enable|disable|role|delete> ..."
+                        "不支持的 admin 命令, 用法: users|keys <list|add|enable|disable|role|caps|delete|import|export> ... | repos <list|owner|grant|revoke|workspace> ... | cas <list|add|enable|disable|delete> ... | invites <list|create|revoke> ... | audit list ... | bans <list|clear> ... | sessions <list|kill> ... | drain [--timeout 10m]"
                     ));
                 }
             };
@@ -1472,6 +1622,23 @@ impl AppServer {
         }
         .await;
 
+        if mutation {
+            let section = audit_args.first().map(String::as_str).unwrap_or("");
+            let command = audit_args.get(1).map(String::as_str).unwrap_or("");
+            let entry = audit::AuditEntry {
+                kind: "admin",
+                user: Some(audit_actor),
+                peer: self.peer.map(|peer| peer.to_string()),
+                action: format!("{section}.{command}"),
+                outcome: if admin_res.is_ok() { "ok" } else { "failed" }.to_string(),
+                command: Some(audit_args.join(" ")),
+                before,
+                after: audit::snapshot(&self.db, &audit_args).await,
+                ..Default::default()
+            };
+            audit::record(&self.db, entry).await;
+        }
+
         match admin_res {
             Ok(output) => {
                 let mut cout = handle.make_writer();
@@ -1605,8 +1772,53 @@ impl AppServer {
             let action = command.first().map(String::as_str).unwrap_or("list");
             match action {
                 "list" => {
+                    let (mut query, before_job) = match parse_job_list_args(&command[1..]) {
+                        Ok(parsed) => parsed,
+                        Err(err) => {
+                            handle
+                                .fail_with_error(
+                                    2,
+                                    "HSSH_JOB_BAD_REQUEST",
+                                    format!("{err}, 用法: {}", JOB_LIST_USAGE),
+                                )
+                                .await?;
+                            return Ok(());
+                        }
+                    };
+
+                    if !actor.is_admin() && query.owner.as_ref().is_some_and(|o| o != &actor.name) {
+                        handle
+                            .fail_with_error(
+                                3,
+                                "HSSH_JOB_FORBIDDEN",
+                                "只有管理员可以查看他人的任务",
+                            )
+                            .await?;
+                        return Ok(());
+                    }
+
+                    // --before 指定任务 id 时, 以该任务的启动时间翻页
+                    if let Some(id) = before_job {
+                        match jobs
+                            .find_history(&id, &actor.name, actor.is_admin())
+                            .await?
+                        {
+                            Some(job) => query.before_ms = Some(job.started_at_ms),
+                            None => {
+                                handle
+                                    .fail_with_error(
+                                        2,
+                                        "HSSH_JOB_NOT_FOUND",
+                                        format!("未找到任务: {id}"),
+                                    )
+                                    .await?;
+                                return Ok(());
+                            }
+                        }
+                    }
+
                     let mut writer = handle.make_writer();
-                    let rows = jobs.list_visible(&actor.name, actor.is_admin()).await;
+                    let rows = jobs.list(&actor.name, actor.is_admin(), &query).await?;
                     let body = serde_json::to_vec_pretty(&rows)?;
                     writer.write_all(&body).await?;
                     writer.write_all(b"\n").await?;
@@ -1616,6 +1828,59 @@ impl AppServer {
                     handle.close().await?;
                     Ok(())
                 }
+                "kill" => {
+                    let (id, signal) = match parse_job_kill_args(&command[1..]) {
+                        Ok(parsed) => parsed,
+                        Err(err) => {
+                            handle
+                                .fail_with_error(
+                                    2,
+                                    "HSSH_JOB_BAD_REQUEST",
+                                    format!("{err}, 用法: {JOB_KILL_USAGE}"),
+                                )
+                                .await?;
+                            return Ok(());
+                        }
+                    };
+
+                    // 只有任务所有者和管理员可以看到任务, 也只有他们可以结束任务
+                    let Some(job) = jobs.get_visible(&id, &actor.name, actor.is_admin()).await
+                    else {
+                        let (error_code, text) = if jobs
+                            .find_history(&id, &actor.name, actor.is_admin())
+                            .await?
+                            .is_some()
+                        {
+                            ("HSSH_JOB_NOT_RUNNING", format!("任务已经结束: {id}"))
+                        } else {
+                            ("HSSH_JOB_NOT_FOUND", format!("未找到任务: {id}"))
+                        };
+                        handle.fail_with_error(2, error_code, text).await?;
+                        return Ok(());
+                    };
+
+                    let grace = crate::config::config().kill_grace();
+                    if !job.kill(signal, grace).await? {
+                        handle
+                            .fail_with_error(
+                                2,
+                                "HSSH_JOB_NOT_RUNNING",
+                                format!("任务未在运行: {id}"),
+                            )
+                            .await?;
+                        return Ok(());
+                    }
+
+                    handle
+                        .info(format!(
+                            "[JOB] kill job_id={id} signal={} grace={}s",
+                            signal.as_str(),
+                            grace.as_secs()
+                        ))
+                        .await?;
+                    handle.exit_code(0).await?;
+                    Ok(())
+                }
                 "attach" => {
                     let mut id = None;
                     let mut follow = true;
@@ -1692,21 +1957,63 @@ impl AppServer {
 
                     let Some(job) = jobs.get_visible(&id, &actor.name, actor.is_admin()).await
                     else {
-                        handle
-                            .fail_with_error(2, "HSSH_JOB_NOT_FOUND", format!("未找到任务: {id}"))
-                            .await?;
+                        // 已经不在内存中的任务, 从磁盘回放
+                        let Some(history) = jobs
+                            .find_history(&id, &actor.name, actor.is_admin())
+                            .await?
+                        else {
+                            handle
+                                .fail_with_error(
+                                    2,
+                                    "HSSH_JOB_NOT_FOUND",
+                                    format!("未找到任务: {id}"),
+                                )
+                                .await?;
+                            return Ok(());
+                        };
+
+                        let mut writer = handle.make_writer();
+                        match jobs.log_path(&id) {
+                            Some(path) => {
+                                let mut file = tokio::fs::File::open(&path).await?;
+                                tokio::io::copy(&mut file, &mut writer).await?;
+                            }
+                            None => {
+                                writer.write_all(b"[JOB] log_missing=1\n").await?;
+                            }
+                        }
+                        let tail = match history.exit_code {
+                            Some(code) => format!("\n[JOB] exit_code={code}\n"),
+                            None => format!("\n[JOB] status={}\n", history.status),
+                        };
+                        writer.write_all(tail.as_bytes()).await?;
+                        writer.shutdown().await?;
+                        drop(writer);
+                        handle.eof().await?;
+                        handle.close().await?;
                         return Ok(());
                     };
 
                     let mut writer = handle.make_writer();
                     let (snapshot, exit_code, _, dropped_bytes) = job.snapshot().await;
-                    if dropped_bytes > 0 {
-                        writer
-                            .write_all(format!("[JOB] dropped_bytes={dropped_bytes}\n").as_bytes())
-                            .await?;
-                    }
-                    if !snapshot.is_empty() {
-                        writer.write_all(&snapshot).await?;
+                    // 已结束且内存缓存不完整时, 改为回放完整的日志文件
+                    let full_log = jobs
+                        .log_path(&id)
+                        .filter(|_| exit_code.is_some() && dropped_bytes > 0);
+                    if let Some(path) = full_log {
+                        let mut file = tokio::fs::File::open(&path).await?;
+                        tokio::io::copy(&mut file, &mut writer).await?;
+                    } else {
+                        if dropped_bytes > 0 {
+                            writer
+                                .write_all(
+                                    format!("[JOB] dropped_bytes={dropped_bytes}\n").as_bytes(),
+                                )
+                                .await?;
+                        }
+                        if !snapshot.is_empty() {
+                            writer.write_all(&snapshot).await?;
+                        }
                     }
 
                     if follow && exit_code.is_none() {
@@ -1782,68 +2089,23 @@ impl AppServer {
             .context("BRANCH 环境变量未设置")?;
         let justfile = self.env.get("JUSTFILE").cloned();
 
-        let mut repo_path = PathBuf::from(&env_repo);
-        // 去除开头的 /
-        if let Ok(stripped) = repo_path.strip_prefix("/") {
-            repo_path = stripped.to_path_buf();
-        }
-
-        // 清理路径
-        repo_path = repo_path.clean();
-        // 工作路径不包含 .git
-        let repo_work_path = repo_path.clone();
-
-        let mut handle = self.handle.take().context("FIXME: NO HANDLE")?;
-
-        if let Some(fst) = repo_path.components().next() {
-            // 如果提供的地址包含 .. 等路径，则拒绝请求
-            if fst == std::path::Component::ParentDir {
-                tracing::warn!("拒绝仓库请求, 路径不合法: {}", repo_path.display());
-                handle
-                    .error(format!("拒绝仓库请求, 路径不合法: {}", repo_path.display()))
-                    .await?;
-                handle.eof().await?;
-                handle.close().await?;
-                return Ok(());
-            }
-
-            let parent = fst
-                .as_os_str()
-                .to_str()
-                .context(format!("目录名非法: {:?}", repo_path))?;
-
-            let current_dir = std::env::current_dir()?;
-
-            // 仓库存放在 repos 目录下
-            if parent != "repos" {
-                repo_path = current_dir.join("repos").join(repo_path);
-            } else {
-                repo_path = current_dir.join(repo_path);
-            }
-
-            repo_path = repo_path.clean();
-        }
-
-        // 裸仓库名称统一添加 .git 后缀
-        if repo_path.extension() != Some(OsStr::new("git")) && !repo_path.set_extension("git") {
-            tracing::error!("无效仓库路径: {:?}", repo_path);
-            handle
-                .error(format!("无效仓库路径: {:?}", repo_path))
-                .await?;
-            handle.eof().await?;
-            handle.close().await?;
+        let Some(target) = self.authorize_repo(&env_repo, RepoLevel::Write).await? else {
             return Ok(());
-        }
+        };
+        let mut handle = self.handle.take().context("FIXME: NO HANDLE")?;
 
-        let repo = Repo::from(&repo_path);
+        let repo = target.repo();
         tracing::info!("GIT REPO: {}", repo.path().display());
-        let task = self.tm.spawn_handle();
+        let detach = self.detach_requested();
+        let sync_request = self.sync_request();
+        let task = self.job_spawner(detach);
         let command_line = command.join(" ");
-        let owner = self.user_name().to_string();
+        let owner = self.job_owner();
         let job = self
             .jobs
             .create_job(owner, "just", command_line.clone())
             .await;
+        self.track_job(handle.id, job.clone());
 
         // 1. 检出代码用于构建
         // 2. 执行项目的 just 命令, 项目必须包含 justfile 文件
@@ -1856,46 +2118,50 @@ impl AppServer {
             return Ok(());
         }
 
-        let mut work_path = std::env::current_dir()?
-            .join("workspace")
-            .join(repo_work_path);
-        // 构建目录不包含 .git 后缀
-        work_path.set_extension("");
-
-        if !work_path.exists() {
-            tracing::info!("CREATE DIR: {}", work_path.display());
-            std::fs::create_dir_all(&work_path).context("创建工作目录失败")?;
-        }
+        let workspace = target.job_workspace(&env_branch, job.id());
 
         // 执行命令
         handle.info("检出代码到工作目录...").await?;
         handle.info(format!("当前仓库: {}", env_repo)).await?;
         handle.info(format!("检出分支: {}", env_branch)).await?;
+        handle.audit_job(job.id());
         handle.info(format!("job_id={}", job.id())).await?;
 
-        if let Err(err) = repo
-            .checkout(&work_path, Some(&env_branch))
-            .await
-            .context("检出代码失败")
-        {
-            tracing::error!("{:?}", err);
-            handle.error(err.to_string()).await?;
-            handle.eof().await?;
-            handle.close().await?;
-            return Ok(());
-        }
-
         let just_span = tracing::info_span!("just");
         let env = self.env.clone();
+        let queue = self.queue.clone();
         task.spawn(async move {
             let mut final_code = 1_i32;
             let result: anyhow::Result<()> = async {
-                let mut diff_input = handle.make_reader();
-                let mut buf = vec![];
-                diff_input.read_to_end(&mut buf).await?;
+                let payload = SyncPayload::read(&mut handle, sync_request, job.id()).await?;
+                // 后台任务在排队前就结束客户端请求, 客户端断开不影响排队和运行
+                if detach {
+                    handle.detach(job.id()).await?;
+                }
 
-                repo.apply(&work_path, &buf).await.context("git apply")?;
-                drop(diff_input);
+                // 同一工作区的任务依次运行
+                let Some(_workspace) = queue.acquire(&workspace.path, &job, &handle).await? else {
+                    final_code = 130;
+                    handle
+                        .fail_with_error(130, "HSSH_JOB_CANCELLED", "任务在排队期间被取消")
+                        .await?;
+                    return Ok(());
+                };
+
+                let summary = match payload
+                    .apply(&repo, &workspace, &env_branch, sync_request, &handle)
+                    .await
+                {
+                    Ok(summary) => summary,
+                    Err(err) => {
+                        tracing::error!("{:?}", err);
+                        handle.error(err.to_string()).await?;
+                        handle.eof().await?;
+                        handle.close().await?;
+                        return Ok(());
+                    }
+                };
+                handle.info(summary).await?;
 
                 handle
                     .info(format!("just {}...", command.join(" ")).bold().to_string())
@@ -1903,6 +2169,11 @@ impl AppServer {
 
                 let mut cmd = Command::new("just");
                 cmd.envs(&env);
+                if let Some(target_dir) = &workspace.target_dir {
+                    cmd.env("CARGO_TARGET_DIR", target_dir);
+                }
+                #[cfg(unix)]
+                cmd.process_group(0);
 
                 #[cfg(target_os = "windows")]
                 {
@@ -1913,7 +2184,7 @@ impl AppServer {
                     cmd.creation_flags(CREATE_NO_WINDOW);
                 }
 
-                cmd.current_dir(&work_path);
+                cmd.current_dir(&workspace.path);
 
                 // user defined justfile
                 if let Some(justfile) = justfile {
@@ -1944,46 +2215,20 @@ impl AppServer {
                                 127,
                                 "HSSH_JUST_SPAWN_FAILED",
                                 format!("spawn: `just` failed: {err}"),
-                            )
-                            .await?;
-                        return Ok(());
-                    }
-                };
-
-                let mut stdout = cmd.stdout.take().unwrap();
-                let mut stderr = cmd.stderr.take().unwrap();
-
-                let err_job = job.clone();
-                let mut o_output = handle.make_writer();
-                let err_fut = async move {
-                    let mut buf = [0u8; 1024];
-                    while let Ok(len) = stderr.read(&mut buf).await {
-                        if len == 0 {
-                            break;
-                        }
-
-                        o_output.write_all(&buf[..len]).await?;
-                        o_output.flush().await?;
-                        err_job.append_output(&buf[..len]).await;
-                    }
-                    Ok::<_, HorseError>(())
-                };
-
-                let out_job = job.clone();
-                let mut o_output = handle.make_writer();
-                let out_fut = async move {
-                    let mut buf = [0u8; 1024];
-                    while let Ok(len) = stdout.read(&mut buf).await {
-                        if len == 0 {
-                            break;
-                        }
-
-                        o_output.write_all(&buf[..len]).await?;
-                        o_output.flush().await?;
-                        out_job.append_output(&buf[..len]).await;
+                            )
+                            .await?;
+                        return Ok(());
                     }
-                    Ok::<_, HorseError>(())
                 };
+                job.attach_process(cmd.id()).await;
+
+                let mut stdout = cmd.stdout.take().unwrap();
+                let mut stderr = cmd.stderr.take().unwrap();
+
+                let mut o_output = handle.make_writer();
+                let mut e_output = handle.make_writer();
+                let out_fut = copy_with_job(&mut stdout, &mut o_output, job.clone());
+                let err_fut = copy_with_job(&mut stderr, &mut e_output, job.clone());
 
                 futures::future::try_join(out_fut, err_fut).await?;
 
@@ -2000,6 +2245,7 @@ impl AppServer {
             }
             .await;
 
+            workspace.cleanup(&repo).await;
             job.finish(final_code).await;
             result
         });
@@ -2036,23 +2282,27 @@ impl AppServer {
             .context("BRANCH 环境变量未设置")?
             .to_owned();
 
-        let mut repo_path = std::env::current_dir()?.join("repos").join(&env_repo);
-        repo_path.set_extension("git");
-        repo_path = repo_path.clean();
+        let Some(target) = self.authorize_repo(&env_repo, RepoLevel::Write).await? else {
+            return Ok(());
+        };
 
         let mut handle = self.handle.take().context("FIXME: NO HANDLE").unwrap();
-        let task = self.tm.spawn_handle();
-        let repo = Repo::from(repo_path);
+        let detach = self.detach_requested();
+        let sync_request = self.sync_request();
+        let task = self.job_spawner(detach);
+        let repo = target.repo();
         let command_line = command.join(" ");
         let cargo_action = format!(
             "cargo.{}",
             command.first().map(String::as_str).unwrap_or("unknown")
         );
-        let owner = self.user_name().to_string();
+        let owner = self.job_owner();
         let job = self
             .jobs
             .create_job(owner, cargo_action, command_line.clone())
             .await;
+        self.track_job(handle.id, job.clone());
+        handle.audit_job(job.id());
         handle.info(format!("job_id={}", job.id())).await?;
 
         if !repo.exists() {
@@ -2063,14 +2313,7 @@ impl AppServer {
             return Ok(());
         }
 
-        let mut work_path = std::env::current_dir()?.join("workspace").join(&env_repo);
-        // 构建目录不包含 .git 后缀
-        work_path.set_extension("");
-        work_path = work_path.clean();
-
-        if !work_path.exists() {
-            std::fs::create_dir_all(&work_path).context("创建工作目录失败")?;
-        }
+        let workspace = target.job_workspace(&env_branch, job.id());
 
         // let work_repo = Repo::clone(repo.path(), work_path, Some(env_branch))
         //     .await
@@ -2149,28 +2392,43 @@ impl AppServer {
 
         cmd.envs(&self.env);
         cmd.kill_on_drop(true);
-        cmd.current_dir(&work_path);
+        #[cfg(unix)]
+        cmd.process_group(0);
+        cmd.current_dir(&workspace.path);
+        if let Some(target_dir) = &workspace.target_dir {
+            cmd.env("CARGO_TARGET_DIR", target_dir);
+        }
         cmd.stdout(std::process::Stdio::piped());
         cmd.stderr(std::process::Stdio::piped());
 
         let cargo_span = tracing::info_span!("cargo", command = ?cmd);
+        let queue = self.queue.clone();
         task.spawn(async move {
             let mut final_code = 1_i32;
             let result: anyhow::Result<()> = async {
                 let mut o_output = handle.make_writer();
                 let mut e_output = handle.make_writer();
 
-                // git checkout
-                repo.checkout(&work_path, Some(env_branch.as_str()))
-                    .await
-                    .context("git checkout")?;
-                // git apply
-                let mut diff_input = handle.make_reader();
-                let mut buf = vec![];
-                diff_input.read_to_end(&mut buf).await?;
+                let payload = SyncPayload::read(&mut handle, sync_request, job.id()).await?;
+                // 后台任务在排队前就结束客户端请求, 客户端断开不影响排队和运行
+                if detach {
+                    handle.detach(job.id()).await?;
+                }
+
+                // 同一工作区的任务依次运行
+                let Some(_workspace) = queue.acquire(&workspace.path, &job, &handle).await? else {
+                    final_code = 130;
+                    handle
+                        .fail_with_error(130, "HSSH_JOB_CANCELLED", "任务在排队期间被取消")
+                        .await?;
+                    return Ok(());
+                };
 
-                repo.apply(&work_path, &buf).await.context("git apply")?;
-                drop(diff_input);
+                // git checkout + 还原改动
+                let summary = payload
+                    .apply(&repo, &workspace, &env_branch, sync_request, &handle)
+                    .await?;
+                handle.info(summary).await?;
 
                 // Run the command
                 let mut cmd = match cmd.spawn() {
@@ -2187,6 +2445,7 @@ impl AppServer {
                         return Ok(());
                     }
                 };
+                job.attach_process(cmd.id()).await;
                 let mut stdout = cmd.stdout.take().unwrap();
                 let mut stderr = cmd.stderr.take().unwrap();
                 let out_job = job.clone();
@@ -2197,8 +2456,10 @@ impl AppServer {
 
                 futures::future::try_join(o_fut, e_fut).await?;
 
-                e_output.shutdown().await.context("shutdown e_output")?;
-                o_output.shutdown().await.context("shutdown o_output")?;
+                if !handle.is_detached() {
+                    e_output.shutdown().await.context("shutdown e_output")?;
+                    o_output.shutdown().await.context("shutdown o_output")?;
+                }
 
                 let status = cmd.wait().await?;
                 final_code = status.code().unwrap_or(128);
@@ -2207,6 +2468,7 @@ impl AppServer {
             }
             .await;
 
+            workspace.cleanup(&repo).await;
             job.finish(final_code).await;
             result
         });
@@ -2215,16 +2477,24 @@ impl AppServer {
     }
 
     pub async fn apply(&mut self, _command: Vec<String>) -> HorseResult<()> {
-        let env_repo = self.env.get("REPO").context("REPO 环境变量未设置")?;
-        let _env_branch = self.env.get("BRANCH").context("BRANCH 环境变量未设置")?;
+        let env_repo = self
+            .env
+            .get("REPO")
+            .cloned()
+            .context("REPO 环境变量未设置")?;
+        let env_branch = self
+            .env
+            .get("BRANCH")
+            .cloned()
+            .context("BRANCH 环境变量未设置")?;
 
-        let mut repo_path = std::env::current_dir()?.join("repos").join(env_repo);
-        repo_path.set_extension("git");
-        repo_path = repo_path.clean();
+        let Some(target) = self.authorize_repo(&env_repo, RepoLevel::Write).await? else {
+            return Ok(());
+        };
 
         let mut handle = self.handle.take().context("FIXME: NO HANDLE").unwrap();
         let task = self.tm.spawn_handle();
-        let repo = Repo::from(repo_path);
+        let repo = target.repo();
 
         if !repo.exists() {
             tracing::error!("仓库不存在: {}", repo.path().display());
@@ -2232,11 +2502,7 @@ impl AppServer {
             return Ok(());
         }
 
-        let mut work_path = std::env::current_dir()?.join("workspace").join(env_repo);
-        // 构建目录不包含 .git 后缀
-        work_path.set_extension("");
-        work_path = work_path.clean();
-
+        let work_path = target.branch_path(&env_branch);
         if !work_path.exists() {
             std::fs::create_dir_all(&work_path).context("创建工作目录失败")?;
         }
@@ -2258,35 +2524,63 @@ impl AppServer {
         cmd.kill_on_drop(true);
         cmd.current_dir(&work_path);
         cmd.stdin(std::process::Stdio::piped());
+        #[cfg(unix)]
+        cmd.process_group(0);
 
         cmd.arg("apply");
 
+        // 与 cmd/cargo/just 共用工作区队列, 避免和正在运行的任务同时修改工作目录
+        let job = self
+            .jobs
+            .create_job(self.job_owner(), "apply", "git apply")
+            .await;
+        self.track_job(handle.id, job.clone());
+        handle.audit_job(job.id());
+        let queue = self.queue.clone();
+
         task.spawn(async move {
-            // Run the command
-            let mut cmd = cmd.spawn().context("spawn: `git`")?;
+            let mut final_code = 1_i32;
+            let result: anyhow::Result<()> = async {
+                let Some(_workspace) = queue.acquire(&work_path, &job, &handle).await? else {
+                    final_code = 130;
+                    handle
+                        .fail_with_error(130, "HSSH_JOB_CANCELLED", "任务在排队期间被取消")
+                        .await?;
+                    return Ok(());
+                };
 
-            {
-                let mut cin = handle.make_reader();
-                let mut stdin = cmd.stdin.take().unwrap();
+                // Run the command
+                let mut cmd = cmd.spawn().context("spawn: `git`")?;
+                job.attach_process(cmd.id()).await;
 
-                while let Ok(len) = tokio::io::copy(&mut cin, &mut stdin).await {
-                    // eof
-                    if len == 0 {
-                        break;
+                {
+                    let mut cin = handle.make_reader();
+                    let mut stdin = cmd.stdin.take().unwrap();
+
+                    while let Ok(len) = tokio::io::copy(&mut cin, &mut stdin).await {
+                        // eof
+                        if len == 0 {
+                            break;
+                        }
                     }
+
+                    tracing::info!("[git] apply done");
                 }
 
-                tracing::info!("[git] apply done");
-            }
+                let cmd = cmd.wait_with_output().await?;
+                if !cmd.status.success() {
+                    let err = String::from_utf8_lossy(&cmd.stderr);
+                    tracing::error!("git apply err: {err}");
+                }
 
-            let cmd = cmd.wait_with_output().await?;
-            if !cmd.status.success() {
-                let err = String::from_utf8_lossy(&cmd.stderr);
-                tracing::error!("git apply err: {err}");
+                final_code = cmd.status.code().unwrap_or(128);
+                handle.exit(cmd.status).await?;
+                Ok(())
             }
+            .await;
 
-            handle.exit(cmd.status).await?;
-            Ok(())
+            job.finish(final_code).await;
+            result
         });
 
         Ok(())
@@ -2300,7 +2594,9 @@ impl Server for AppServer {
     /// 创建新连接
     fn new_client(&mut self, peer: Option<std::net::SocketAddr>) -> Self {
         tracing::info!("新建连接: {:?}", peer);
-        let this = self.clone();
+        let mut this = self.clone();
+        this.peer = peer;
+        this.sessions.open(this.id, peer);
         self.id += 1;
         this
     }
@@ -2324,10 +2620,20 @@ impl Server for AppServer {
         loop {
             match socket.accept().await {
                 Ok((socket, _)) => {
+                    if let Ok(addr) = socket.peer_addr() {
+                        if self.bans.is_banned(addr.ip()) {
+                            tracing::debug!("拒绝已封禁的地址: {addr}");
+                            continue;
+                        }
+                    }
+
                     let config = config.clone();
                     let handler = self.new_client(socket.peer_addr().ok());
+                    let sessions = self.sessions.clone();
+                    let id = handler.id;
+                    let socket = session::Counted::new(socket, sessions.traffic(id));
 
-                    let span = tracing::info_span!("socket.accept", socket=?socket.peer_addr());
+                    let span = tracing::info_span!("socket.accept", socket=?handler.peer);
                     handle.spawn(
                         async move {
                             tracing::debug!("handle-socket");
@@ -2338,6 +2644,7 @@ impl Server for AppServer {
                                     return Ok(());
                                 }
                             };
+                            sessions.attach(id, session.handle());
 
                             session.await?;
                             Ok(())
@@ -2371,6 +2678,7 @@ impl Handler for AppServer {
         channel: Channel<Msg>,
         session: &mut Session,
     ) -> HorseResult<bool> {
+        self.sessions.channel_open(self.id, channel.id());
         self.handle.replace(ChannelHandle::from(channel, session));
 
         Ok(true)
@@ -2402,6 +2710,20 @@ impl Handler for AppServer {
         pk: &PublicKey,
     ) -> Result<Auth, Self::Error> {
         tracing::info!("PubKey: {:?}", pk.to_openssh());
+        if self.peer_banned() {
+            return Ok(Auth::Reject {
+                proceed_with_methods: None,
+            });
+        }
+
+        // 每个连接只接受第一个未登记的公钥, 签名校验后可以用邀请码绑定;
+        // agent 中其他未登记的公钥直接拒绝, 不再校验签名, 也不写审计日志
+        if self.pending_key.is_some() && !self.publickey_registered(pk).await? {
+            self.auth_failed = true;
+            return Ok(Auth::Reject {
+                proceed_with_methods: Some(MethodSet::PUBLICKEY | MethodSet::KEYBOARD_INTERACTIVE),
+            });
+        }
         Ok(Auth::Accept)
     }
 
@@ -2413,66 +2735,184 @@ impl Handler for AppServer {
     /// time than that.
     #[tracing::instrument(skip(self, pk))]
     async fn auth_publickey(&mut self, action: &str, pk: &PublicKey) -> HorseResult<Auth> {
-        #[allow(deprecated)]
-        let data = base64::encode(&pk.to_bytes().context("pk bytes")?);
+        if self.peer_banned() {
+            return Ok(Auth::Reject {
+                proceed_with_methods: None,
+            });
+        }
 
-        let Some(sa) = SshPk::find_by_id((pk.algorithm().to_string(), data.to_owned()))
-            .one(&self.db)
-            .await?
-        else {
-            tracing::error!("公钥未记录: ({} {})", pk.algorithm().to_string(), data);
+        let found = self.publickey_user(pk).await?;
+        let fingerprint = format!("{} {}", pk.algorithm(), pk.fingerprint(HashAlg::Sha256));
+        let user = found.as_ref().map(|(user, _, _)| user);
+        self.audit_login("publickey", action, user, fingerprint)
+            .await;
+
+        let Some((user, options, caps)) = found else {
+            // 未登记的公钥可以继续用邀请码兑换
+            let methods = if self.pending_key.is_some() {
+                MethodSet::PUBLICKEY | MethodSet::KEYBOARD_INTERACTIVE
+            } else {
+                MethodSet::PUBLICKEY
+            };
             return Ok(Auth::Reject {
-                proceed_with_methods: Some(MethodSet::PUBLICKEY),
+                proceed_with_methods: Some(methods),
             });
         };
 
-        if !sa.enabled {
-            tracing::warn!("公钥已禁用: ({} {})", pk.algorithm().to_string(), data);
+        self.action = action.to_string();
+        self.user.replace(SessionUser {
+            id: user.id,
+            name: user.name.clone(),
+            role: user.role.clone(),
+            cert: None,
+            options,
+            caps,
+        });
+        self.sessions.login(self.id, &user.name, &user.role, action);
+
+        tracing::info!("Login As: {} ({})", user.name, user.role);
+        Ok(Auth::Accept)
+    }
+
+    /// Check authentication using an OpenSSH certificate. This method
+    /// is called after the signature has been verified and key
+    /// ownership has been confirmed.
+    /// Russh guarantees that rejection happens in constant time
+    /// `config.auth_rejection_time`, except if this method takes more
+    /// time than that.
+    #[tracing::instrument(skip(self, certificate))]
+    async fn auth_openssh_certificate(
+        &mut self,
+        action: &str,
+        certificate: &Certificate,
+    ) -> HorseResult<Auth> {
+        if self.peer_banned() {
             return Ok(Auth::Reject {
-                proceed_with_methods: Some(MethodSet::PUBLICKEY),
+                proceed_with_methods: None,
             });
         }
 
-        let Some(user) = sa.find_related(User).one(&self.db).await? else {
-            tracing::error!("公钥未授权: ({} {})", pk.algorithm().to_string(), data);
+        let peer = self.peer.map(|addr| addr.ip());
+        let user = cert::verify(&self.db, certificate, peer).await?;
+        let identity = cert::identity(certificate);
+        let key = PublicKey::from(certificate.public_key().clone());
+        let fingerprint = format!(
+            "{} {} cert={identity}",
+            key.algorithm(),
+            key.fingerprint(HashAlg::Sha256)
+        );
+        self.audit_login("cert", action, user.as_ref(), fingerprint)
+            .await;
+
+        let Some(user) = user else {
             return Ok(Auth::Reject {
                 proceed_with_methods: Some(MethodSet::PUBLICKEY),
             });
         };
 
-        if !user.enabled {
-            tracing::warn!("用户已禁用: {}", user.name);
+        self.action = action.to_string();
+        self.user.replace(SessionUser {
+            id: user.id,
+            name: user.name.clone(),
+            role: user.role.clone(),
+            cert: Some(identity.clone()),
+            options: KeyOptions::from_extensions(certificate.extensions().iter()),
+            caps: Capabilities::load(user.caps.as_deref()),
+        });
+        self.sessions.login(self.id, &user.name, &user.role, action);
+
+        tracing::info!("Login As: {} ({}) cert={}", user.name, user.role, identity);
+        Ok(Auth::Accept)
+    }
+
+    /// 使用邀请码绑定公钥: 先用未登记的公钥完成签名校验, 再通过 keyboard-interactive 输入邀请码
+    #[tracing::instrument(skip(self, response))]
+    async fn auth_keyboard_interactive(
+        &mut self,
+        action: &str,
+        submethods: &str,
+        response: Option<Response<'async_trait>>,
+    ) -> HorseResult<Auth> {
+        if self.peer_banned() {
             return Ok(Auth::Reject {
-                proceed_with_methods: Some(MethodSet::PUBLICKEY),
+                proceed_with_methods: None,
             });
         }
 
+        let Some(pk) = self.pending_key.clone() else {
+            return Ok(Auth::Reject {
+                proceed_with_methods: Some(MethodSet::PUBLICKEY),
+            });
+        };
+
+        let Some(mut response) = response else {
+            return Ok(Auth::Partial {
+                name: "workhorse".into(),
+                instructions: "使用邀请码绑定当前公钥".into(),
+                prompts: vec![("邀请码: ".into(), false)].into(),
+            });
+        };
+
+        let token = response
+            .next()
+            .map(|it| String::from_utf8_lossy(&it).to_string())
+            .unwrap_or_default();
+        let user = invite::redeem(&self.db, &token, &pk, self.peer).await?;
+        let fingerprint = format!("{} {}", pk.algorithm(), pk.fingerprint(HashAlg::Sha256));
+        self.audit_login("invite", action, user.as_ref(), fingerprint)
+            .await;
+
+        let Some(user) = user else {
+            return Ok(Auth::Reject {
+                proceed_with_methods: None,
+            });
+        };
+
+        self.pending_key = None;
         self.action = action.to_string();
         self.user.replace(SessionUser {
             id: user.id,
             name: user.name.clone(),
             role: user.role.clone(),
+            cert: None,
+            options: KeyOptions::default(),
+            caps: Capabilities::load(user.caps.as_deref()),
         });
+        self.sessions.login(self.id, &user.name, &user.role, action);
 
-        tracing::info!("Login As: {} ({})", user.name, user.role);
+        tracing::info!("Login As: {} ({}) invite", user.name, user.role);
         Ok(Auth::Accept)
     }
 
-    /// Check authentication using an OpenSSH certificate. This method
-    /// is called after the signature has been verified and key
-    /// ownership has been confirmed.
-    /// Russh guarantees that rejection happens in constant time
-    /// `config.auth_rejection_time`, except if this method takes more
-    /// time than that.
-    #[tracing::instrument(skip(self, _certificate))]
-    async fn auth_openssh_certificate(
+    /// 登录成功后通告全部主机公钥, 见 [`hostkeys`]
+    async fn auth_succeeded(&mut self, session: &mut Session) -> Result<(), Self::Error> {
+        if !self.host_keys.is_empty() {
+            let data = hostkeys::announcement(&self.host_keys)?;
+            session.global_request(hostkeys::ANNOUNCE, false, data);
+        }
+        Ok(())
+    }
+
+    /// 其他全局请求, 返回 `Some` 时回复成功并附带数据, `None` 时回复失败
+    ///
+    /// 目前只处理 `hostkeys-prove-00@openssh.com`
+    #[tracing::instrument(skip(self, data, session))]
+    async fn global_request(
         &mut self,
-        user: &str,
-        _certificate: &Certificate,
-    ) -> Result<Auth, Self::Error> {
-        Ok(Auth::Reject {
-            proceed_with_methods: None,
-        })
+        name: &str,
+        data: &[u8],
+        session: &mut Session,
+    ) -> Result<Option<Vec<u8>>, Self::Error> {
+        if name != hostkeys::PROVE {
+            return Ok(None);
+        }
+        match hostkeys::prove(&self.host_keys, session.session_id(), data) {
+            Ok(reply) => Ok(Some(reply)),
+            Err(err) => {
+                tracing::warn!("{} 失败: {err}", hostkeys::PROVE);
+                Ok(None)
+            }
+        }
     }
 
     /// The client requests an X11 connection.
@@ -2516,6 +2956,11 @@ impl Handler for AppServer {
                 tracing::info!(key = key.as_str(), stage = "env.set", "stage");
             }
         }
+        // agent socket 由服务端分配, 不能指向其他连接的 socket
+        if key == "SSH_AUTH_SOCK" {
+            tracing::warn!("忽略客户端设置的 SSH_AUTH_SOCK");
+            return Ok(());
+        }
         self.env.insert(key, value.to_string());
         Ok(())
     }
@@ -2593,6 +3038,11 @@ impl Handler for AppServer {
         session: &mut Session,
     ) -> Result<(), Self::Error> {
         tracing::info!("pty request: {}x{}", col_width, row_height);
+        if let Err(reason) = self.authorize(Capability::Shell, self.key_options().pty(), "pty") {
+            self.forbid("pty", reason).await;
+            session.channel_failure(channel)?;
+            return Ok(());
+        }
 
         let (pty, pts) = pty_process::open().context("open pty")?;
         pty.resize(Size::new(row_height as _, col_width as _))
@@ -2600,6 +3050,7 @@ impl Handler for AppServer {
 
         let mut clients = self.clients.lock().await;
         clients.insert(self.id, (pty, pts));
+        self.sessions.pty(self.id);
 
         session.channel_success(channel)?;
         Ok(())
@@ -2622,6 +3073,11 @@ impl Handler for AppServer {
         use std::ffi::OsString;
         use winptyrs::{AgentConfig, MouseMode, PTYArgs, PTYBackend, PTY};
         tracing::info!("pty request: {}x{}", cols, rows);
+        if let Err(reason) = self.authorize(Capability::Shell, self.key_options().pty(), "pty") {
+            self.forbid("pty", reason).await;
+            session.channel_failure(channel)?;
+            return Ok(());
+        }
 
         let pty_args = PTYArgs {
             cols: cols as _,
@@ -2635,6 +3091,7 @@ impl Handler for AppServer {
             Ok(pty) => {
                 let mut clients = self.clients.lock().await;
                 clients.insert(self.id, pty);
+                self.sessions.pty(self.id);
 
                 session.channel_success(channel)?;
             }
@@ -2663,6 +3120,40 @@ impl Handler for AppServer {
             ExecCommand::Args(command) => command.join(" "),
         };
         let started = std::time::Instant::now();
+        if let Some(handle) = &self.handle {
+            handle.audit(audit::ActionAudit::new(
+                self.db.clone(),
+                audit::AuditEntry {
+                    kind: "action",
+                    user: self.user.as_ref().map(|user| user.name.clone()),
+                    peer: self.peer.map(|peer| peer.to_string()),
+                    action: self.action.clone(),
+                    command: Some(command_line.clone()),
+                    repo: self.env.get("REPO").cloned(),
+                    branch: self.env.get("BRANCH").cloned(),
+                    ..Default::default()
+                },
+            ));
+        }
+        if !drain::accepts(self.action.as_str()) {
+            let handle = self.handle.take().context("FIXME: NO HANDLE")?;
+            drain::reject(handle).await?;
+            session.channel_success(channel_id)?;
+            return Ok(());
+        }
+        if let Some(cap) = Capability::for_action(&self.action) {
+            if let Err(reason) = self.authorize(cap, true, &self.action) {
+                let handle = self.handle.take().context("FIXME: NO HANDLE")?;
+                handle.fail_with_error(3, "HSSH_FORBIDDEN", reason).await?;
+                session.channel_success(channel_id)?;
+                return Ok(());
+            }
+        }
+        if !drain::exempt(&self.action) {
+            if let Some(handle) = self.handle.as_mut() {
+                handle.track_inflight();
+            }
+        }
         if self.debug_enabled() {
             tracing::info!(
                 trace_id = %self.trace_id(),
@@ -2673,13 +3164,16 @@ impl Handler for AppServer {
                 "stage"
             );
         }
+        metrics::action(&self.action);
 
         let dispatch_res = match (self.action.as_str(), command) {
             ("health", ExecCommand::Args(command)) => self.health(command).await,
             ("ping", ExecCommand::Args(command)) => self.ping(command).await,
+            ("metrics", ExecCommand::Args(command)) => self.metrics(command).await,
             ("logs", ExecCommand::Args(command)) => self.logs(command).await,
             ("cargo", ExecCommand::Args(command)) => self.cargo(command).await,
             ("apply", ExecCommand::Args(command)) => self.apply(command).await,
+            ("sync", ExecCommand::Args(command)) => self.sync(command).await,
             // just 命令支持 just.xxx 格式, xxx 对应 justfile 中的运行指令
             ("just", ExecCommand::Args(command)) => self.just(command).await,
             // action if action.starts_with("just") => {
@@ -2768,6 +3262,11 @@ impl Handler for AppServer {
 
         tracing::info!("forwarding: {}", address);
         let addr = format!("{}:{}", address, port);
+        let key_allows = self.key_options().permits_listen(&address, port);
+        if let Err(reason) = self.authorize(Capability::Forward, key_allows, &addr) {
+            self.forbid("tcpip-forward", reason).await;
+            return Ok(false);
+        }
 
         let listener = match TcpListener::bind(&addr).await {
             Ok(l) => l,
@@ -2777,6 +3276,8 @@ impl Handler for AppServer {
             }
         };
         tracing::info!("bind success");
+        self.sessions
+            .forward_open(self.id, None, format!("R {addr}"));
         let socket_task = task.clone();
         let handle = session.handle();
 
@@ -2836,6 +3337,8 @@ impl Handler for AppServer {
         session: &mut Session,
     ) -> Result<bool, Self::Error> {
         tracing::info!("cancel");
+        self.sessions
+            .forward_close(self.id, &format!("R {address}:{port}"));
         Ok(true)
     }
 
@@ -2852,6 +3355,15 @@ impl Handler for AppServer {
         originator_port: u32,
         session: &mut Session,
     ) -> Result<bool, Self::Error> {
+        let target = format!("{host_to_connect}:{port_to_connect}");
+        let key_allows = self
+            .key_options()
+            .permits_open(host_to_connect, port_to_connect);
+        if let Err(reason) = self.authorize(Capability::Forward, key_allows, &target) {
+            self.forbid("direct-tcpip", reason).await;
+            return Ok(false);
+        }
+
         let task = self.tm.spawn_handle();
         let host_to_connect = host_to_connect.to_string();
         let tcpip_span = tracing::info_span!("connect");
@@ -2867,6 +3379,10 @@ impl Handler for AppServer {
         else {
             return Ok(false);
         };
+        let channel_id = channel.id().to_string();
+        self.sessions.channel_open(self.id, &channel_id);
+        self.sessions
+            .forward_open(self.id, Some(channel_id), format!("L {target}"));
 
         let task = self.tm.spawn_handle();
 
@@ -2916,12 +3432,51 @@ impl Handler for AppServer {
     /// (such as sftp).
     async fn subsystem_request(
         &mut self,
-        _channel: ChannelId,
+        channel_id: ChannelId,
         name: &str,
-        _session: &mut Session,
+        session: &mut Session,
     ) -> Result<(), Self::Error> {
-        println!("SUBSYSTEM: {name}");
-        // session.channel_success(channel);
+        tracing::info!("SUBSYSTEM: {name}");
+        if name != "sftp" {
+            session.channel_failure(channel_id)?;
+            return Ok(());
+        }
+        let (Some(mut handle), Some(user)) = (self.handle.take(), self.user.clone()) else {
+            session.channel_failure(channel_id)?;
+            return Ok(());
+        };
+        if drain::is_draining() {
+            drain::reject(handle).await?;
+            session.channel_success(channel_id)?;
+            return Ok(());
+        }
+        if !user.caps.allows(Capability::Read) {
+            tracing::warn!("HSSH_FORBIDDEN: user={} 没有 read 权限: sftp", user.name);
+            handle
+                .fail_with_error(3, "HSSH_FORBIDDEN", "没有 read 权限: sftp")
+                .await?;
+            session.channel_success(channel_id)?;
+            return Ok(());
+        }
+        handle.track_inflight();
+        metrics::action("sftp");
+
+        handle.audit(audit::ActionAudit::new(
+            self.db.clone(),
+            audit::AuditEntry {
+                kind: "action",
+                user: Some(user.name.clone()),
+                peer: self.peer.map(|peer| peer.to_string()),
+                action: "sftp".to_string(),
+                branch: self.env.get("BRANCH").cloned(),
+                ..Default::default()
+            },
+        ));
+        let server = sftp::SftpServer::new(self.db.clone(), user, self.env.get("BRANCH").cloned());
+        let task = self.tm.spawn_handle();
+        task.spawn(async move { server.run(handle).await });
+
+        session.channel_success(channel_id)?;
         Ok(())
     }
 
@@ -2929,21 +3484,77 @@ impl Handler for AppServer {
     async fn agent_request(
         &mut self,
         _channel: ChannelId,
-        _session: &mut Session,
+        session: &mut Session,
     ) -> Result<bool, Self::Error> {
-        // session.channel_success(channel);
-        Ok(false)
+        if !crate::config::config().ssh.agent_forwarding {
+            self.forbid(
+                "agent",
+                "服务端未开启 agent 转发 (ssh.agent_forwarding)".to_string(),
+            )
+            .await;
+            return Ok(false);
+        }
+        let key_allows = self.key_options().agent_forwarding();
+        if let Err(reason) = self.authorize(Capability::Forward, key_allows, "agent") {
+            self.forbid("agent", reason).await;
+            return Ok(false);
+        }
+
+        #[cfg(unix)]
+        {
+            if self.agent.is_none() {
+                match agent::AgentSocket::listen(session.handle(), self.id, self.tm.spawn_handle())
+                {
+                    Ok(socket) => {
+                        self.agent.replace(socket);
+                    }
+                    Err(err) => {
+                        tracing::error!("agent 转发失败: {:?}", err);
+                        return Ok(false);
+                    }
+                }
+            }
+            // cmd/cargo/just/ssh 子进程通过环境变量访问 agent
+            if let Some(socket) = &self.agent {
+                self.env.insert(
+                    "SSH_AUTH_SOCK".to_string(),
+                    socket.path().display().to_string(),
+                );
+            }
+            Ok(true)
+        }
+
+        #[cfg(not(unix))]
+        {
+            tracing::warn!("当前平台不支持 agent 转发");
+            Ok(false)
+        }
     }
 
     /// The client is sending a signal (usually to pass to the
     /// currently running process).
     async fn signal(
         &mut self,
-        _channel: ChannelId,
+        channel: ChannelId,
         sig: Sig,
         _session: &mut Session,
     ) -> Result<(), Self::Error> {
         tracing::info!("Client Signal: {:?}", sig);
+
+        let Some(job) = self.channel_jobs.get(&channel).cloned() else {
+            return Ok(());
+        };
+        if job.is_finished() {
+            self.channel_jobs.remove(&channel);
+            return Ok(());
+        }
+        let Some(signal) = JobSignal::from_sig(&sig) else {
+            return Ok(());
+        };
+
+        if let Err(err) = job.kill(signal, crate::config::config().kill_grace()).await {
+            tracing::warn!("转发信号失败: {} {err}", job.id());
+        }
         Ok(())
     }
 
@@ -2954,6 +3565,7 @@ impl Handler for AppServer {
         _session: &mut Session,
     ) -> HorseResult<()> {
         tracing::debug!("Recv Data: {}", data.len());
+        self.sessions.touch(self.id);
         Ok(())
     }
 
@@ -2970,10 +3582,13 @@ impl Handler for AppServer {
     /// Called when the client closes a channel.
     async fn channel_close(
         &mut self,
-        _channel: ChannelId,
+        channel: ChannelId,
         _session: &mut Session,
     ) -> Result<(), Self::Error> {
         tracing::info!("Channel Close");
+        self.sessions.channel_close(self.id, channel);
+        // 通道 id 会被之后的通道复用, 信号不能再转发给这个任务
+        self.channel_jobs.remove(&channel);
         Ok(())
     }
 }
@@ -2982,23 +3597,60 @@ impl Drop for AppServer {
     #[tracing::instrument(skip(self), name = "AppServer::drop", level = "debug")]
     fn drop(&mut self) {
         tracing::debug!("cleanup");
+        self.sessions.close(self.id);
+
+        // 一个连接只计一次认证失败, 不论尝试了多少个公钥
+        if self.auth_failed && self.user.is_none() {
+            let (Some(peer), Ok(rt)) = (self.peer, tokio::runtime::Handle::try_current()) else {
+                return;
+            };
+            let bans = self.bans.clone();
+            let db = self.db.clone();
+            rt.spawn(async move {
+                bans.failure(&db, peer.ip()).await;
+            });
+        }
     }
 }
 
 pub async fn run() -> HorseResult<()> {
-    let key = key_init();
+    let settings = crate::config::config();
+    let host_keys = crate::key::host_keys();
     let config = Config {
-        inactivity_timeout: None,
+        inactivity_timeout: settings.inactivity_timeout(),
         auth_rejection_time: std::time::Duration::from_secs(1),
         auth_rejection_time_initial: Some(std::time::Duration::from_secs(0)),
-        keys: vec![key],
-        keepalive_interval: Some(std::time::Duration::from_secs(5)),
+        // 宽限期内的旧密钥与新密钥一起提供, 登录后通过 hostkeys-00@openssh.com 通告给客户端
+        keys: host_keys.clone(),
+        keepalive_interval: settings.keepalive_interval(),
         ..Default::default()
     };
 
+    tracing::info!("正式服务监听: {}", settings.listen_addr());
     let mut server = AppServer::new(DB.clone());
+    server.host_keys = Arc::new(host_keys);
+    server.jobs = JobRegistry::with_store(DB.clone(), settings.jobs_dir());
+    match server.jobs.recover_interrupted().await {
+        Ok(0) => {}
+        Ok(n) => tracing::warn!("{n} 个任务在上次退出时仍在运行, 已标记为 interrupted"),
+        Err(err) => tracing::error!("恢复任务记录失败: {err}"),
+    }
+    match server.bans.load(&DB).await {
+        Ok(0) => {}
+        Ok(n) => tracing::info!("载入 {n} 个封禁中的地址"),
+        Err(err) => tracing::error!("载入封禁记录失败: {err}"),
+    }
+    ctl::register(server.jobs.clone(), server.sessions.clone());
+    tokio::spawn(server.sessions.clone().reap_idle());
+    if let Some(addr) = settings.metrics_addr() {
+        tokio::spawn(metrics::serve(
+            addr,
+            server.sessions.clone(),
+            server.jobs.clone(),
+        ));
+    }
     server
-        .run(config, ("0.0.0.0", 2222))
+        .run(config, settings.listen_addr())
         .await
         .expect("Failed running server");
     Ok(())
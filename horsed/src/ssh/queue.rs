@@ -0,0 +1,203 @@
+//! 工作区任务队列
+//!
+//! 同一个工作区 (`workspace/<repo>`) 同时只运行一个任务, 其余任务按提交顺序排队,
+//! 避免并发的 `checkout` / `git apply` 互相破坏工作目录.
+//! 全局同时运行的任务数由 `jobs.max_concurrent` 限制.
+use super::handle::ChannelHandle;
+use super::jobs::JobRecord;
+use crate::prelude::*;
+use stable::task::{TaskCondition, TaskPermit};
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+#[derive(Clone)]
+pub struct WorkspaceQueue {
+    inner: Arc<QueueInner>,
+}
+
+struct QueueInner {
+    /// 每个工作区的任务队列, 队首为正在运行的任务
+    queues: Mutex<HashMap<PathBuf, VecDeque<String>>>,
+    /// 队列出队时通知等待中的任务
+    changed: Notify,
+    /// 全局运行名额
+    slots: Arc<TaskCondition>,
+    /// 全局同时运行的任务上限, 0 表示不限制
+    max_concurrent: usize,
+}
+
+/// 任务在工作区队列中的位置, 释放时出队并唤醒后面的任务
+struct Ticket {
+    inner: Arc<QueueInner>,
+    workspace: PathBuf,
+    job_id: String,
+}
+
+/// 持有期间独占工作区并占用一个全局名额
+pub struct WorkspaceGuard {
+    _ticket: Ticket,
+    _permit: Option<TaskPermit>,
+}
+
+impl WorkspaceQueue {
+    pub fn new(max_concurrent: usize) -> Self {
+        Self {
+            inner: Arc::new(QueueInner {
+                queues: Mutex::new(HashMap::new()),
+                changed: Notify::new(),
+                slots: Arc::new(TaskCondition::new()),
+                max_concurrent,
+            }),
+        }
+    }
+
+    fn enqueue(&self, workspace: &Path, job_id: &str) -> Ticket {
+        let mut queues = self.inner.queues.lock().unwrap();
+        queues
+            .entry(workspace.to_path_buf())
+            .or_default()
+            .push_back(job_id.to_string());
+
+        Ticket {
+            inner: self.inner.clone(),
+            workspace: workspace.to_path_buf(),
+            job_id: job_id.to_string(),
+        }
+    }
+
+    /// 当前正在运行的任务数量
+    pub fn running(&self) -> usize {
+        self.inner.slots.count()
+    }
+
+    /// 等待轮到当前任务使用工作区, 期间向客户端报告排队位置
+    ///
+    /// `--detach` 的任务在排队前已经转入后台, 排队位置只记录在任务上 (`job list`).
+    /// 排队期间任务被取消时返回 `None`
+    pub async fn acquire(
+        &self,
+        workspace: &Path,
+        job: &JobRecord,
+        handle: &ChannelHandle,
+    ) -> HorseResult<Option<WorkspaceGuard>> {
+        let ticket = self.enqueue(workspace, job.id());
+        let mut reported = None;
+
+        loop {
+            // 先注册通知再检查位置, 避免错过检查之后的出队
+            let changed = self.inner.changed.notified();
+            futures::pin_mut!(changed);
+            changed.as_mut().enable();
+
+            let position = ticket.position();
+            if position == 0 {
+                break;
+            }
+
+            if reported != Some(position) {
+                reported = Some(position);
+                job.set_queue_position(Some(position)).await;
+                handle
+                    .info(format!(
+                        "等待工作区空闲: queue_position={position} workspace={}",
+                        workspace.display()
+                    ))
+                    .await?;
+            }
+
+            tokio::select! {
+                _ = changed => {}
+                _ = job.cancelled() => return Ok(None),
+            }
+        }
+
+        let permit = match self.inner.max_concurrent {
+            0 => None,
+            upper => match self.inner.slots.try_acquire(upper) {
+                Some(permit) => Some(permit),
+                None => {
+                    job.set_queue_position(Some(0)).await;
+                    handle
+                        .info(format!(
+                            "等待空闲的任务名额: running={} max_concurrent={upper}",
+                            self.running()
+                        ))
+                        .await?;
+                    tokio::select! {
+                        permit = self.inner.slots.acquire(upper) => Some(permit),
+                        _ = job.cancelled() => return Ok(None),
+                    }
+                }
+            },
+        };
+
+        job.set_queue_position(None).await;
+        Ok(Some(WorkspaceGuard {
+            _ticket: ticket,
+            _permit: permit,
+        }))
+    }
+}
+
+impl Default for WorkspaceQueue {
+    fn default() -> Self {
+        Self::new(crate::config::config().jobs.max_concurrent)
+    }
+}
+
+impl Ticket {
+    /// 前面还有几个任务, 0 表示轮到当前任务
+    fn position(&self) -> usize {
+        let queues = self.inner.queues.lock().unwrap();
+        queues
+            .get(&self.workspace)
+            .and_then(|queue| queue.iter().position(|id| id == &self.job_id))
+            .unwrap_or(0)
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        let mut queues = self.inner.queues.lock().unwrap();
+        if let Some(queue) = queues.get_mut(&self.workspace) {
+            queue.retain(|id| id != &self.job_id);
+            if queue.is_empty() {
+                queues.remove(&self.workspace);
+            }
+        }
+        drop(queues);
+        self.inner.changed.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn positions(tickets: &[&Ticket]) -> Vec<usize> {
+        tickets.iter().map(|ticket| ticket.position()).collect()
+    }
+
+    #[test]
+    fn workspace_queue_is_fifo_per_workspace() {
+        let queue = WorkspaceQueue::new(0);
+        let a = queue.enqueue(Path::new("/ws/a"), "job-1");
+        let b = queue.enqueue(Path::new("/ws/a"), "job-2");
+        let c = queue.enqueue(Path::new("/ws/a"), "job-3");
+        let other = queue.enqueue(Path::new("/ws/b"), "job-4");
+
+        assert_eq!(positions(&[&a, &b, &c, &other]), [0, 1, 2, 0]);
+
+        // 排队中的任务离开, 后面的任务前移
+        drop(b);
+        assert_eq!(positions(&[&a, &c]), [0, 1]);
+
+        drop(a);
+        assert_eq!(c.position(), 0);
+        drop(c);
+        drop(other);
+        assert!(queue.inner.queues.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,461 @@
+//! 连接登记
+//!
+//! 每个 SSH 连接在建立时登记, 认证通过后记录用户和 action, 连接断开时移除.
+//! 连接期间记录打开的通道、端口转发和收发的字节数, 管理员通过 `admin sessions` 查看和断开连接.
+//!
+//! 配置了 `ssh.idle_timeout_secs` 时, 按登录用户的角色断开空闲的连接:
+//! 没有打开的通道和转发, 或者交互终端 (pty) 没有输入, 超过时限后断开.
+use super::audit::format_utc;
+use crate::ipc::data::SessionInfo;
+use anyhow::{anyhow, Context};
+use russh::server::Handle;
+use russh::Disconnect;
+use std::collections::BTreeMap;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context as TaskContext, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+const SESSIONS_USAGE: &str = "用法: sessions list [--json] | sessions kill <id>";
+
+/// 检查空闲连接的间隔
+const REAP_INTERVAL: Duration = Duration::from_secs(15);
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// 连接收发的字节数
+#[derive(Default)]
+pub struct Traffic {
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+}
+
+/// 统计收发字节数的连接
+pub struct Counted<S> {
+    inner: S,
+    traffic: Arc<Traffic>,
+}
+
+impl<S> Counted<S> {
+    pub fn new(inner: S, traffic: Arc<Traffic>) -> Self {
+        Self { inner, traffic }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            let read = (buf.filled().len() - filled) as u64;
+            self.traffic.bytes_in.fetch_add(read, Ordering::Relaxed);
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = poll {
+            self.traffic
+                .bytes_out
+                .fetch_add(written as u64, Ordering::Relaxed);
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+struct Entry {
+    info: SessionInfo,
+    traffic: Arc<Traffic>,
+    /// 连接建立后用于断开连接
+    handle: Option<Handle>,
+    channels: Vec<String>,
+    /// 本地转发关联到通道, 通道关闭时移除
+    forwards: Vec<(Option<String>, String)>,
+    pty: bool,
+}
+
+impl Entry {
+    fn info(&self) -> SessionInfo {
+        SessionInfo {
+            channels: self.channels.len(),
+            forwards: self.forwards.iter().map(|(_, it)| it.clone()).collect(),
+            bytes_in: self.traffic.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.traffic.bytes_out.load(Ordering::Relaxed),
+            ..self.info.clone()
+        }
+    }
+
+    fn touch(&mut self) {
+        self.info.last_active_ms = now_ms();
+    }
+
+    /// 没有进行中的请求, 或者只有等待输入的交互终端
+    fn is_idle(&self) -> bool {
+        self.pty || (self.channels.is_empty() && self.forwards.is_empty())
+    }
+}
+
+/// 所有连接共享的连接列表
+#[derive(Clone, Default)]
+pub struct SessionRegistry {
+    sessions: Arc<Mutex<BTreeMap<usize, Entry>>>,
+}
+
+impl SessionRegistry {
+    pub fn open(&self, id: usize, peer: Option<SocketAddr>) {
+        let now = now_ms();
+        self.sessions.lock().unwrap().insert(
+            id,
+            Entry {
+                info: SessionInfo {
+                    id,
+                    peer: peer.map(|peer| peer.to_string()),
+                    user: None,
+                    role: None,
+                    action: None,
+                    connected_at_ms: now,
+                    channels: 0,
+                    forwards: vec![],
+                    bytes_in: 0,
+                    bytes_out: 0,
+                    last_active_ms: now,
+                },
+                traffic: Arc::default(),
+                handle: None,
+                channels: vec![],
+                forwards: vec![],
+                pty: false,
+            },
+        );
+    }
+
+    /// 连接的字节计数, 连接已经移除时返回新的计数
+    pub fn traffic(&self, id: usize) -> Arc<Traffic> {
+        self.with(id, |entry| entry.traffic.clone())
+            .unwrap_or_default()
+    }
+
+    /// 连接建立, 记录用于断开连接的 handle
+    pub fn attach(&self, id: usize, handle: Handle) {
+        self.with(id, |entry| entry.handle = Some(handle));
+    }
+
+    /// 认证通过
+    pub fn login(&self, id: usize, user: &str, role: &str, action: &str) {
+        self.with(id, |entry| {
+            entry.info.user = Some(user.to_string());
+            entry.info.role = Some(role.to_string());
+            entry.info.action = Some(action.to_string());
+            entry.touch();
+        });
+    }
+
+    pub fn channel_open(&self, id: usize, channel: impl ToString) {
+        self.with(id, |entry| {
+            entry.channels.push(channel.to_string());
+            entry.touch();
+        });
+    }
+
+    /// 关闭通道, 同时移除通道上的本地转发
+    pub fn channel_close(&self, id: usize, channel: impl ToString) {
+        let channel = channel.to_string();
+        self.with(id, |entry| {
+            entry.channels.retain(|it| *it != channel);
+            entry
+                .forwards
+                .retain(|(it, _)| it.as_ref() != Some(&channel));
+            if entry.channels.is_empty() {
+                entry.pty = false;
+            }
+            entry.touch();
+        });
+    }
+
+    /// 交互终端
+    pub fn pty(&self, id: usize) {
+        self.with(id, |entry| {
+            entry.pty = true;
+            entry.touch();
+        });
+    }
+
+    /// 客户端输入
+    pub fn touch(&self, id: usize) {
+        self.with(id, Entry::touch);
+    }
+
+    /// 开始端口转发, 本地转发关联到通道
+    pub fn forward_open(&self, id: usize, channel: Option<String>, forward: String) {
+        self.with(id, |entry| {
+            entry.forwards.push((channel, forward));
+            entry.touch();
+        });
+    }
+
+    pub fn forward_close(&self, id: usize, forward: &str) {
+        self.with(id, |entry| {
+            entry.forwards.retain(|(_, it)| it != forward);
+            entry.touch();
+        });
+    }
+
+    pub fn close(&self, id: usize) {
+        self.sessions.lock().unwrap().remove(&id);
+    }
+
+    /// 按连接顺序列出
+    pub fn list(&self) -> Vec<SessionInfo> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .values()
+            .map(Entry::info)
+            .collect()
+    }
+
+    pub fn count(&self) -> usize {
+        self.sessions.lock().unwrap().len()
+    }
+
+    /// 断开连接
+    pub async fn kill(&self, id: usize, reason: &str) -> anyhow::Result<SessionInfo> {
+        let (info, handle) = self
+            .with(id, |entry| (entry.info(), entry.handle.clone()))
+            .with_context(|| format!("连接不存在: {id}"))?;
+        let handle = handle.with_context(|| format!("连接尚未建立: {id}"))?;
+        handle
+            .disconnect(Disconnect::ByApplication, reason.to_string(), String::new())
+            .await
+            .map_err(|err| anyhow!("断开连接失败: {err:?}"))?;
+        Ok(info)
+    }
+
+    /// 空闲时间超过所属角色时限的连接, 未登录的连接不计算
+    fn idle(&self, now: u64, timeout: impl Fn(&str) -> Option<Duration>) -> Vec<usize> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|entry| entry.is_idle())
+            .filter(|entry| {
+                let Some(limit) = entry.info.role.as_deref().and_then(&timeout) else {
+                    return false;
+                };
+                now.saturating_sub(entry.info.last_active_ms) >= limit.as_millis() as u64
+            })
+            .map(|entry| entry.info.id)
+            .collect()
+    }
+
+    /// 定期断开空闲的连接, 时限每次从当前配置读取
+    pub async fn reap_idle(self) {
+        let mut interval = tokio::time::interval(REAP_INTERVAL);
+        loop {
+            interval.tick().await;
+            let config = crate::config::config();
+            for id in self.idle(now_ms(), |role| config.idle_timeout(role)) {
+                match self.kill(id, "空闲超时, 连接已断开").await {
+                    Ok(info) => {
+                        tracing::info!("断开空闲连接: {id} {}", info.user.as_deref().unwrap_or("-"))
+                    }
+                    Err(err) => tracing::warn!("断开空闲连接失败: {err}"),
+                }
+            }
+        }
+    }
+
+    fn with<T>(&self, id: usize, f: impl FnOnce(&mut Entry) -> T) -> Option<T> {
+        self.sessions.lock().unwrap().get_mut(&id).map(f)
+    }
+}
+
+fn format_row(session: &SessionInfo, now: u64) -> String {
+    let forwards = if session.forwards.is_empty() {
+        "-".to_string()
+    } else {
+        session.forwards.join(",")
+    };
+    format!(
+        "{:<6} {:<12} {:<6} {:<8} {:<22} {:>3} {:>12} {:>12} {:>6}s {} {}",
+        session.id,
+        session.user.as_deref().unwrap_or("-"),
+        session.role.as_deref().unwrap_or("-"),
+        session.action.as_deref().unwrap_or("-"),
+        session.peer.as_deref().unwrap_or("-"),
+        session.channels,
+        session.bytes_in,
+        session.bytes_out,
+        now.saturating_sub(session.last_active_ms) / 1000,
+        format_utc(session.connected_at_ms as i64),
+        forwards,
+    )
+}
+
+/// `admin sessions list [--json]`, `admin sessions kill <id>`
+///
+/// `current` 为执行命令的连接, 不能断开自己
+pub(super) async fn admin_sessions(
+    sessions: &SessionRegistry,
+    current: usize,
+    args: &[String],
+) -> anyhow::Result<String> {
+    let command = args.get(1).map(String::as_str).unwrap_or("");
+
+    match (command, &args[2.min(args.len())..]) {
+        ("list", rest) => {
+            let json = match rest {
+                [] => false,
+                [flag] if flag == "--json" => true,
+                _ => return Err(anyhow!(SESSIONS_USAGE)),
+            };
+            let list = sessions.list();
+            if json {
+                return Ok(serde_json::to_string_pretty(&list)?);
+            }
+            let now = now_ms();
+            Ok(list
+                .iter()
+                .map(|session| format_row(session, now))
+                .collect::<Vec<_>>()
+                .join("\n"))
+        }
+        ("kill", [id]) => {
+            let id = id
+                .parse::<usize>()
+                .map_err(|_| anyhow!("无效的连接编号: {id}"))?;
+            if id == current {
+                return Err(anyhow!("不能断开当前连接"));
+            }
+            let info = sessions.kill(id, "连接已被管理员断开").await?;
+            Ok(format!(
+                "已断开连接: {id} {}",
+                info.user.as_deref().unwrap_or("-")
+            ))
+        }
+        _ => Err(anyhow!("不支持的 sessions 命令, {SESSIONS_USAGE}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sessions_follow_connection_lifecycle() {
+        let sessions = SessionRegistry::default();
+        sessions.open(2, Some("10.0.0.2:50000".parse().unwrap()));
+        sessions.open(1, None);
+        sessions.login(2, "alice", "user", "cargo");
+        sessions.login(9, "bob", "user", "cmd");
+
+        let list = sessions.list();
+        assert_eq!(list.iter().map(|it| it.id).collect::<Vec<_>>(), [1, 2]);
+        assert_eq!(list[1].user.as_deref(), Some("alice"));
+        assert_eq!(list[1].role.as_deref(), Some("user"));
+        assert_eq!(list[1].action.as_deref(), Some("cargo"));
+        assert_eq!(list[1].peer.as_deref(), Some("10.0.0.2:50000"));
+
+        sessions.close(2);
+        assert_eq!(sessions.count(), 1);
+    }
+
+    #[test]
+    fn sessions_track_channels_forwards_and_idle_time() {
+        let sessions = SessionRegistry::default();
+        sessions.open(1, None);
+        sessions.open(2, None);
+        sessions.open(3, None);
+        sessions.login(1, "alice", "user", "cargo");
+        sessions.login(2, "bob", "admin", "ssh");
+        sessions.login(3, "carol", "user", "ssh");
+
+        sessions.channel_open(1, 0);
+        sessions.channel_open(1, 1);
+        sessions.forward_open(1, Some("1".to_string()), "L 127.0.0.1:5432".to_string());
+        sessions.forward_open(1, None, "R 0.0.0.0:8080".to_string());
+        sessions
+            .traffic(1)
+            .bytes_in
+            .fetch_add(42, Ordering::Relaxed);
+
+        let info = &sessions.list()[0];
+        assert_eq!(info.channels, 2);
+        assert_eq!(info.forwards, ["L 127.0.0.1:5432", "R 0.0.0.0:8080"]);
+        assert_eq!(info.bytes_in, 42);
+
+        sessions.channel_close(1, 1);
+        sessions.forward_close(1, "R 0.0.0.0:8080");
+        let info = &sessions.list()[0];
+        assert_eq!(info.channels, 1);
+        assert!(info.forwards.is_empty());
+
+        // 交互终端没有输入时也算空闲
+        sessions.channel_open(3, 0);
+        sessions.pty(3);
+
+        let later = now_ms() + 3_600_000;
+        let timeout = |role: &str| (role == "user").then(|| Duration::from_secs(60));
+        assert_eq!(sessions.idle(later, timeout), [3]);
+
+        sessions.channel_close(1, 0);
+        assert_eq!(sessions.idle(later, timeout), [1, 3]);
+        assert!(sessions.idle(now_ms(), timeout).is_empty());
+    }
+
+    #[tokio::test]
+    async fn admin_sessions_lists_and_rejects_bad_arguments() {
+        let sessions = SessionRegistry::default();
+        sessions.open(1, None);
+        sessions.open(2, None);
+        sessions.login(1, "alice", "admin", "admin");
+
+        let args = ["sessions", "list", "--json"].map(String::from);
+        let rows: Vec<serde_json::Value> =
+            serde_json::from_str(&admin_sessions(&sessions, 1, &args).await.unwrap()).unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0]["user"], "alice");
+
+        let args = ["sessions", "list"].map(String::from);
+        let text = admin_sessions(&sessions, 1, &args).await.unwrap();
+        assert_eq!(text.lines().count(), 2);
+
+        let args = ["sessions", "kill", "1"].map(String::from);
+        assert!(admin_sessions(&sessions, 1, &args).await.is_err());
+        let args = ["sessions", "kill", "9"].map(String::from);
+        assert!(admin_sessions(&sessions, 1, &args).await.is_err());
+        // 尚未建立完成的连接没有 handle
+        let args = ["sessions", "kill", "2"].map(String::from);
+        assert!(admin_sessions(&sessions, 1, &args).await.is_err());
+        let args = ["sessions", "list", "--all"].map(String::from);
+        assert!(admin_sessions(&sessions, 1, &args).await.is_err());
+    }
+}
@@ -2,6 +2,7 @@
 ///
 /// THIS CODE IS NOT USED IN THE PRODUCTION ENVIRONMENT. IT IS ONLY USED TO SET UP THE SERVER.
 ///
+use crate::account::{ROLE_ADMIN, ROLE_USER};
 use crate::db::entity::{
     prelude::{SshPk, User},
     ssh_pk, user,
@@ -21,9 +22,6 @@ use stable::task::SpawnEssentialTaskHandle;
 use std::sync::Arc;
 use tokio::net::ToSocketAddrs;
 
-const ROLE_ADMIN: &str = "admin";
-const ROLE_USER: &str = "user";
-
 #[derive(Clone)]
 pub struct SetupServer {
     pub handle: SpawnEssentialTaskHandle,
@@ -160,6 +158,8 @@ impl Handler for SetupServer {
                         user_id: user.id,
                         enabled: Set(true),
                         comment: Set(None),
+                        options: Set(None),
+                        caps: Set(None),
                     };
 
                     if let Err(err) = auth.insert(txn).await {
@@ -206,16 +206,18 @@ impl Handler for SetupServer {
 }
 
 pub async fn run(handle: SpawnEssentialTaskHandle, in_danger: bool) -> HorseResult<()> {
+    let settings = crate::config::config();
     let config = Config {
-        inactivity_timeout: Some(std::time::Duration::from_secs(3600)),
+        inactivity_timeout: settings.setup_inactivity_timeout(),
         auth_rejection_time: std::time::Duration::from_secs(1),
         auth_rejection_time_initial: Some(std::time::Duration::from_secs(0)),
         keys: vec![PrivateKey::random(&mut OsRng, Algorithm::Ed25519).context("random key")?],
-        keepalive_interval: Some(std::time::Duration::from_secs(5)),
+        keepalive_interval: settings.keepalive_interval(),
         ..Default::default()
     };
 
+    tracing::info!("临时服务监听: {}", settings.setup_listen_addr());
     SetupServer::new(handle, DB.clone(), in_danger)
-        .run(config, ("0.0.0.0", 2223))
+        .run(config, settings.setup_listen_addr())
         .await
 }
@@ -0,0 +1,1110 @@
+//! SFTP 子系统 (协议版本 3)
+//!
+//! 根目录 `/` 是虚拟目录, 列出当前用户可以访问的仓库, `/<repo>/...` 对应仓库的工作目录
+//! (设置了 `BRANCH` 时为分支对应的工作目录, 见 [`RepoTarget::branch_path`]).
+//!
+//! 路径先按 `/` 规范化, `..` 不会越过根目录; 解析出的真实路径还会检查符号链接,
+//! 不能离开仓库工作目录. 读取需要仓库的 read 权限, 写入、重命名、删除需要 write 权限,
+//! 仓库列表与权限在会话开始时加载一次, 会话期间的授权变更从下一个会话开始生效.
+//! 不支持符号链接的读取与创建; 指向不存在目标的符号链接一律拒绝,
+//! 写入、创建和修改属性不跟随最后一级的符号链接.
+//!
+//! 旧版本允许登记嵌套的仓库 (如 `team` 与 `team/app`), 外层仓库的工作目录中包含内层仓库的工作目录.
+//! 落在其他仓库的工作目录、分支工作目录 `<repo>@<branch>` 或任务目录 `<workspace>/.jobs`
+//! 中的路径一律拒绝, 不能绕过内层仓库的权限.
+use super::workspace::WorkspaceSettings;
+use super::*;
+use std::io::SeekFrom;
+use tokio::io::AsyncSeekExt;
+
+const SFTP_VERSION: u32 = 3;
+/// 单个数据包上限, 足够容纳客户端常用的 32KiB~256KiB 读写块
+const MAX_PACKET: usize = 1024 * 1024;
+/// 单次 READ 返回的最大字节数
+const MAX_READ: u32 = 256 * 1024;
+/// 单次 READDIR 返回的最大条目数
+const READDIR_BATCH: usize = 100;
+
+const SSH_FXP_INIT: u8 = 1;
+const SSH_FXP_VERSION: u8 = 2;
+const SSH_FXP_OPEN: u8 = 3;
+const SSH_FXP_CLOSE: u8 = 4;
+const SSH_FXP_READ: u8 = 5;
+const SSH_FXP_WRITE: u8 = 6;
+const SSH_FXP_LSTAT: u8 = 7;
+const SSH_FXP_FSTAT: u8 = 8;
+const SSH_FXP_SETSTAT: u8 = 9;
+const SSH_FXP_FSETSTAT: u8 = 10;
+const SSH_FXP_OPENDIR: u8 = 11;
+const SSH_FXP_READDIR: u8 = 12;
+const SSH_FXP_REMOVE: u8 = 13;
+const SSH_FXP_MKDIR: u8 = 14;
+const SSH_FXP_RMDIR: u8 = 15;
+const SSH_FXP_REALPATH: u8 = 16;
+const SSH_FXP_STAT: u8 = 17;
+const SSH_FXP_RENAME: u8 = 18;
+const SSH_FXP_STATUS: u8 = 101;
+const SSH_FXP_HANDLE: u8 = 102;
+const SSH_FXP_DATA: u8 = 103;
+const SSH_FXP_NAME: u8 = 104;
+const SSH_FXP_ATTRS: u8 = 105;
+
+const SSH_FXF_READ: u32 = 0x01;
+const SSH_FXF_WRITE: u32 = 0x02;
+const SSH_FXF_APPEND: u32 = 0x04;
+const SSH_FXF_CREAT: u32 = 0x08;
+const SSH_FXF_TRUNC: u32 = 0x10;
+const SSH_FXF_EXCL: u32 = 0x20;
+
+const SSH_FILEXFER_ATTR_SIZE: u32 = 0x01;
+const SSH_FILEXFER_ATTR_UIDGID: u32 = 0x02;
+const SSH_FILEXFER_ATTR_PERMISSIONS: u32 = 0x04;
+const SSH_FILEXFER_ATTR_ACMODTIME: u32 = 0x08;
+const SSH_FILEXFER_ATTR_EXTENDED: u32 = 0x8000_0000;
+
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+/// SFTP 状态码
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Status {
+    Ok = 0,
+    Eof = 1,
+    NoSuchFile = 2,
+    PermissionDenied = 3,
+    Failure = 4,
+    BadMessage = 5,
+    OpUnsupported = 8,
+}
+
+impl Status {
+    fn message(&self) -> &'static str {
+        match self {
+            Status::Ok => "ok",
+            Status::Eof => "end of file",
+            Status::NoSuchFile => "no such file",
+            Status::PermissionDenied => "permission denied",
+            Status::Failure => "failure",
+            Status::BadMessage => "bad message",
+            Status::OpUnsupported => "operation unsupported",
+        }
+    }
+}
+
+impl From<std::io::Error> for Status {
+    fn from(err: std::io::Error) -> Self {
+        match err.kind() {
+            std::io::ErrorKind::NotFound => Status::NoSuchFile,
+            std::io::ErrorKind::PermissionDenied => Status::PermissionDenied,
+            _ => Status::Failure,
+        }
+    }
+}
+
+impl From<HorseError> for Status {
+    fn from(err: HorseError) -> Self {
+        tracing::error!("sftp: {:?}", err);
+        Status::Failure
+    }
+}
+
+impl From<sea_orm::DbErr> for Status {
+    fn from(err: sea_orm::DbErr) -> Self {
+        tracing::error!("sftp: {:?}", err);
+        Status::Failure
+    }
+}
+
+/// 请求数据包解析
+struct Packet<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Packet<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Status> {
+        if self.buf.len() < len {
+            return Err(Status::BadMessage);
+        }
+        let (head, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, Status> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, Status> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn u64(&mut self) -> Result<u64, Status> {
+        Ok(((self.u32()? as u64) << 32) | self.u32()? as u64)
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], Status> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    fn string(&mut self) -> Result<String, Status> {
+        String::from_utf8(self.bytes()?.to_vec()).map_err(|_| Status::BadMessage)
+    }
+
+    fn attrs(&mut self) -> Result<Attrs, Status> {
+        let flags = self.u32()?;
+        let mut attrs = Attrs::default();
+        if flags & SSH_FILEXFER_ATTR_SIZE != 0 {
+            attrs.size = Some(self.u64()?);
+        }
+        if flags & SSH_FILEXFER_ATTR_UIDGID != 0 {
+            attrs.uid_gid = Some((self.u32()?, self.u32()?));
+        }
+        if flags & SSH_FILEXFER_ATTR_PERMISSIONS != 0 {
+            attrs.permissions = Some(self.u32()?);
+        }
+        if flags & SSH_FILEXFER_ATTR_ACMODTIME != 0 {
+            attrs.times = Some((self.u32()?, self.u32()?));
+        }
+        if flags & SSH_FILEXFER_ATTR_EXTENDED != 0 {
+            for _ in 0..self.u32()? {
+                self.bytes()?;
+                self.bytes()?;
+            }
+        }
+        Ok(attrs)
+    }
+}
+
+/// 响应数据包
+struct Reply(Vec<u8>);
+
+impl Reply {
+    fn new(kind: u8, id: u32) -> Self {
+        let mut reply = Reply(vec![kind]);
+        reply.u32(id);
+        reply
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn bytes(&mut self, value: &[u8]) {
+        self.u32(value.len() as u32);
+        self.0.extend_from_slice(value);
+    }
+
+    fn attrs(&mut self, attrs: &Attrs) {
+        let mut flags = 0;
+        if attrs.size.is_some() {
+            flags |= SSH_FILEXFER_ATTR_SIZE;
+        }
+        if attrs.uid_gid.is_some() {
+            flags |= SSH_FILEXFER_ATTR_UIDGID;
+        }
+        if attrs.permissions.is_some() {
+            flags |= SSH_FILEXFER_ATTR_PERMISSIONS;
+        }
+        if attrs.times.is_some() {
+            flags |= SSH_FILEXFER_ATTR_ACMODTIME;
+        }
+        self.u32(flags);
+        if let Some(size) = attrs.size {
+            self.u64(size);
+        }
+        if let Some((uid, gid)) = attrs.uid_gid {
+            self.u32(uid);
+            self.u32(gid);
+        }
+        if let Some(permissions) = attrs.permissions {
+            self.u32(permissions);
+        }
+        if let Some((atime, mtime)) = attrs.times {
+            self.u32(atime);
+            self.u32(mtime);
+        }
+    }
+
+    fn status(id: u32, status: Status) -> Self {
+        let mut reply = Reply::new(SSH_FXP_STATUS, id);
+        reply.u32(status as u32);
+        reply.bytes(status.message().as_bytes());
+        reply.bytes(b"");
+        reply
+    }
+
+    fn names(id: u32, entries: &[DirEntry]) -> Self {
+        let mut reply = Reply::new(SSH_FXP_NAME, id);
+        reply.u32(entries.len() as u32);
+        for entry in entries {
+            reply.bytes(entry.name.as_bytes());
+            reply.bytes(entry.longname.as_bytes());
+            reply.attrs(&entry.attrs);
+        }
+        reply
+    }
+
+    fn into_frame(self) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(self.0.len() + 4);
+        frame.extend_from_slice(&(self.0.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&self.0);
+        frame
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct Attrs {
+    size: Option<u64>,
+    uid_gid: Option<(u32, u32)>,
+    permissions: Option<u32>,
+    /// (atime, mtime), 秒
+    times: Option<(u32, u32)>,
+}
+
+impl Attrs {
+    /// 虚拟目录 (根目录与仓库命名空间) 只读
+    fn virtual_dir() -> Self {
+        Attrs {
+            permissions: Some(S_IFDIR | 0o555),
+            ..Default::default()
+        }
+    }
+
+    fn from_metadata(md: &std::fs::Metadata) -> Self {
+        let secs = |time: std::io::Result<std::time::SystemTime>| {
+            time.ok()
+                .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+                .map(|d| d.as_secs() as u32)
+                .unwrap_or(0)
+        };
+        let times = Some((secs(md.accessed()), secs(md.modified())));
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            Attrs {
+                size: Some(md.size()),
+                uid_gid: Some((md.uid(), md.gid())),
+                permissions: Some(md.mode()),
+                times,
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            let kind = if md.is_dir() {
+                S_IFDIR | 0o755
+            } else if md.is_symlink() {
+                S_IFLNK | 0o777
+            } else {
+                S_IFREG | 0o644
+            };
+            let mode = if md.permissions().readonly() {
+                kind & !0o222
+            } else {
+                kind
+            };
+            Attrs {
+                size: Some(md.len()),
+                uid_gid: None,
+                permissions: Some(mode),
+                times,
+            }
+        }
+    }
+
+    /// `ls -l` 格式的长名称, 部分客户端直接显示
+    fn longname(&self, name: &str, owner: &str) -> String {
+        let mode = self.permissions.unwrap_or(0);
+        let kind = match mode & 0o170000 {
+            S_IFDIR => 'd',
+            S_IFLNK => 'l',
+            _ => '-',
+        };
+        let mut perms = String::from(kind);
+        for shift in [6, 3, 0] {
+            let bits = (mode >> shift) & 0o7;
+            perms.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+            perms.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+            perms.push(if bits & 0o1 != 0 { 'x' } else { '-' });
+        }
+        let mtime = self.times.map(|(_, mtime)| mtime).unwrap_or(0);
+        let date = super::audit::format_utc(mtime as i64 * 1000);
+        format!(
+            "{perms} 1 {owner} {owner} {:>10} {} {name}",
+            self.size.unwrap_or(0),
+            &date[..16]
+        )
+    }
+}
+
+struct DirEntry {
+    name: String,
+    longname: String,
+    attrs: Attrs,
+}
+
+/// 客户端打开的文件或目录
+enum OpenHandle {
+    File {
+        file: tokio::fs::File,
+        path: PathBuf,
+        writable: bool,
+    },
+    Dir {
+        entries: VecDeque<DirEntry>,
+    },
+}
+
+/// 规范化客户端路径, 相对路径相对于根目录, `..` 不会越过根目录
+///
+/// 返回路径的各个组成部分, 根目录为空
+fn virtual_components(path: &str) -> Vec<String> {
+    let mut parts: Vec<String> = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part.to_string()),
+        }
+    }
+    parts
+}
+
+fn virtual_path(parts: &[String]) -> String {
+    format!("/{}", parts.join("/"))
+}
+
+/// 路径在虚拟目录树中的位置
+#[derive(Debug, PartialEq, Eq)]
+enum Location {
+    /// 根目录或仓库命名空间, 列出下一级名称
+    Virtual(Vec<String>),
+    /// 仓库内的路径
+    Repo { name: String, rest: Vec<String> },
+}
+
+/// 在用户可以访问的仓库中查找路径, 多个仓库匹配时以最长的仓库名为准
+fn locate(repos: &[String], parts: &[String]) -> Option<Location> {
+    let mut best: Option<&String> = None;
+    for repo in repos {
+        let repo_parts = repo.split('/').collect::<Vec<_>>();
+        if repo_parts.len() <= parts.len()
+            && repo_parts.iter().zip(parts).all(|(a, b)| a == b)
+            && best.map_or(true, |best| best.split('/').count() < repo_parts.len())
+        {
+            best = Some(repo);
+        }
+    }
+    if let Some(repo) = best {
+        let depth = repo.split('/').count();
+        return Some(Location::Repo {
+            name: repo.clone(),
+            rest: parts[depth..].to_vec(),
+        });
+    }
+
+    let mut children = repos
+        .iter()
+        .filter_map(|repo| {
+            let repo_parts = repo.split('/').collect::<Vec<_>>();
+            (repo_parts.len() > parts.len() && repo_parts.iter().zip(parts).all(|(a, b)| a == b))
+                .then(|| repo_parts[parts.len()].to_string())
+        })
+        .collect::<Vec<_>>();
+    if children.is_empty() {
+        return None;
+    }
+    children.sort();
+    children.dedup();
+    Some(Location::Virtual(children))
+}
+
+/// 仓库内已解析的真实路径
+struct RealPath {
+    repo: String,
+    root: PathBuf,
+    path: PathBuf,
+}
+
+impl RealPath {
+    fn is_root(&self) -> bool {
+        self.path == self.root
+    }
+}
+
+enum Resolved {
+    Virtual(Vec<String>),
+    Real(RealPath),
+}
+
+/// 检查真实路径 (解析符号链接后) 仍在工作目录内
+///
+/// 路径不存在时检查最近的已存在的上级目录; 存在但无法解析的路径 (指向不存在目标的符号链接)
+/// 创建时会跟随链接写到工作目录之外, 直接拒绝
+fn contained(root: &Path, path: &Path) -> Result<(), Status> {
+    let root = root.canonicalize()?;
+    let mut probe = path;
+    loop {
+        match probe.canonicalize() {
+            Ok(real) if real.starts_with(&root) => return Ok(()),
+            Ok(real) => {
+                tracing::warn!("sftp: 路径超出工作目录: {}", real.display());
+                return Err(Status::PermissionDenied);
+            }
+            Err(_) if probe.symlink_metadata().is_ok() => {
+                tracing::warn!("sftp: 无法解析的符号链接: {}", probe.display());
+                return Err(Status::PermissionDenied);
+            }
+            Err(_) => probe = probe.parent().ok_or(Status::NoSuchFile)?,
+        }
+    }
+}
+
+/// 解析符号链接后的路径, 不存在的部分拼接到最近的已存在的上级目录之后
+fn real_path(path: &Path) -> PathBuf {
+    let mut probe = path;
+    let mut missing = Vec::new();
+    loop {
+        if let Ok(real) = probe.canonicalize() {
+            return missing
+                .iter()
+                .rev()
+                .fold(real, |path, part| path.join(part));
+        }
+        match (probe.parent(), probe.file_name()) {
+            (Some(parent), Some(name)) => {
+                missing.push(name);
+                probe = parent;
+            }
+            _ => return path.to_path_buf(),
+        }
+    }
+}
+
+/// 检查路径不在其他仓库的目录中: 其他仓库的工作目录与分支工作目录 `<repo>@<branch>`,
+/// 以及任务目录 `<workspace>/.jobs`
+///
+/// `others` 为其他已登记仓库的工作目录
+fn outside_others(path: &Path, jobs: &Path, others: &[&Path]) -> Result<(), Status> {
+    let path = real_path(path);
+    let reserved = |other: &Path| {
+        let other = real_path(other);
+        if path.starts_with(&other) {
+            return true;
+        }
+        let (Some(parent), Some(name)) = (other.parent(), other.file_name()) else {
+            return false;
+        };
+        let worktree = format!("{}@", name.to_string_lossy());
+        path.ancestors().any(|dir| {
+            dir.parent() == Some(parent)
+                && dir
+                    .file_name()
+                    .is_some_and(|name| name.to_string_lossy().starts_with(&worktree))
+        })
+    };
+    if reserved(jobs) || others.iter().any(|other| reserved(other)) {
+        tracing::warn!("sftp: 路径属于其他仓库: {}", path.display());
+        return Err(Status::PermissionDenied);
+    }
+    Ok(())
+}
+
+/// 写入、创建和修改属性不跟随符号链接
+fn refuse_symlink(path: &Path) -> Result<(), Status> {
+    match path.symlink_metadata() {
+        Ok(md) if md.file_type().is_symlink() => {
+            tracing::warn!("sftp: 拒绝通过符号链接写入: {}", path.display());
+            Err(Status::PermissionDenied)
+        }
+        _ => Ok(()),
+    }
+}
+
+pub(super) struct SftpServer {
+    db: DatabaseConnection,
+    user: SessionUser,
+    branch: Option<String>,
+    /// 当前用户可以访问的仓库名称, 会话开始时加载
+    repos: Vec<String>,
+    /// 当前用户对仓库的访问级别
+    levels: HashMap<String, RepoLevel>,
+    /// 全部已登记仓库的名称与工作目录
+    registered: Vec<(String, PathBuf)>,
+    /// 任务目录 `<workspace>/.jobs`
+    jobs: PathBuf,
+    handles: HashMap<String, OpenHandle>,
+    next_handle: u64,
+}
+
+impl SftpServer {
+    pub(super) fn new(db: DatabaseConnection, user: SessionUser, branch: Option<String>) -> Self {
+        let workspace = crate::config::config().workspace_dir();
+        let workspace = std::env::current_dir()
+            .map(|dir| dir.join(&workspace))
+            .unwrap_or(workspace);
+        Self {
+            db,
+            user,
+            branch,
+            repos: Vec::new(),
+            levels: HashMap::new(),
+            registered: Vec::new(),
+            jobs: workspace.join(".jobs"),
+            handles: HashMap::new(),
+            next_handle: 0,
+        }
+    }
+
+    /// 加载已登记的仓库与当前用户的访问级别
+    async fn load_repos(&mut self) -> HorseResult<()> {
+        for (name, level) in acl::repo_levels(&self.db, &self.user).await? {
+            let work_path = RepoTarget::new(name.as_str())?.work_path;
+            if let Some(level) = level {
+                self.repos.push(name.clone());
+                self.levels.insert(name.clone(), level);
+            }
+            self.registered.push((name, work_path));
+        }
+        Ok(())
+    }
+
+    /// 处理客户端请求直到通道关闭
+    pub(super) async fn run(mut self, mut handle: ChannelHandle) -> HorseResult<()> {
+        self.load_repos().await?;
+        let mut cout = handle.make_writer();
+        {
+            let mut cin = handle.make_reader();
+            loop {
+                let mut len = [0u8; 4];
+                if cin.read_exact(&mut len).await.is_err() {
+                    break;
+                }
+                let len = u32::from_be_bytes(len) as usize;
+                if len == 0 || len > MAX_PACKET {
+                    tracing::warn!("sftp: 数据包长度无效: {len}");
+                    break;
+                }
+                let mut buf = vec![0u8; len];
+                cin.read_exact(&mut buf).await?;
+
+                let Some(reply) = self.process(&buf).await else {
+                    continue;
+                };
+                cout.write_all(&reply.into_frame()).await?;
+                cout.flush().await?;
+            }
+        }
+
+        tracing::info!("sftp: 会话结束 user={}", self.user.name);
+        handle.exit_code(0).await?;
+        Ok(())
+    }
+
+    async fn process(&mut self, buf: &[u8]) -> Option<Reply> {
+        let mut packet = Packet { buf };
+        let kind = packet.u8().ok()?;
+        if kind == SSH_FXP_INIT {
+            // 客户端版本高于 3 时按版本 3 回复, 不支持扩展
+            let mut reply = Reply(vec![SSH_FXP_VERSION]);
+            reply.u32(SFTP_VERSION);
+            return Some(reply);
+        }
+
+        let id = packet.u32().ok()?;
+        let reply = match self.request(kind, id, &mut packet).await {
+            Ok(reply) => reply,
+            Err(status) => Reply::status(id, status),
+        };
+        Some(reply)
+    }
+
+    async fn request(
+        &mut self,
+        kind: u8,
+        id: u32,
+        packet: &mut Packet<'_>,
+    ) -> Result<Reply, Status> {
+        match kind {
+            SSH_FXP_REALPATH => {
+                let parts = virtual_components(&packet.string()?);
+                let entry = DirEntry {
+                    name: virtual_path(&parts),
+                    longname: String::new(),
+                    attrs: Attrs::default(),
+                };
+                Ok(Reply::names(id, &[entry]))
+            }
+            SSH_FXP_STAT | SSH_FXP_LSTAT => {
+                let attrs = match self.resolve(&packet.string()?, RepoLevel::Read).await? {
+                    Resolved::Virtual(_) => Attrs::virtual_dir(),
+                    Resolved::Real(real) if kind == SSH_FXP_LSTAT => {
+                        Attrs::from_metadata(&tokio::fs::symlink_metadata(&real.path).await?)
+                    }
+                    Resolved::Real(real) => {
+                        Attrs::from_metadata(&tokio::fs::metadata(&real.path).await?)
+                    }
+                };
+                let mut reply = Reply::new(SSH_FXP_ATTRS, id);
+                reply.attrs(&attrs);
+                Ok(reply)
+            }
+            SSH_FXP_FSTAT => {
+                let attrs = match self.handle(&packet.string()?)? {
+                    OpenHandle::File { file, .. } => Attrs::from_metadata(&file.metadata().await?),
+                    OpenHandle::Dir { .. } => return Err(Status::OpUnsupported),
+                };
+                let mut reply = Reply::new(SSH_FXP_ATTRS, id);
+                reply.attrs(&attrs);
+                Ok(reply)
+            }
+            SSH_FXP_OPEN => {
+                let path = packet.string()?;
+                let pflags = packet.u32()?;
+                packet.attrs()?;
+                let writable =
+                    pflags & (SSH_FXF_WRITE | SSH_FXF_APPEND | SSH_FXF_CREAT | SSH_FXF_TRUNC) != 0;
+                let need = if writable {
+                    RepoLevel::Write
+                } else {
+                    RepoLevel::Read
+                };
+                let Resolved::Real(real) = self.resolve(&path, need).await? else {
+                    return Err(Status::PermissionDenied);
+                };
+
+                let mut options = tokio::fs::OpenOptions::new();
+                options
+                    .read(pflags & SSH_FXF_READ != 0)
+                    .write(pflags & SSH_FXF_WRITE != 0)
+                    .append(pflags & SSH_FXF_APPEND != 0)
+                    .truncate(pflags & SSH_FXF_TRUNC != 0);
+                if pflags & SSH_FXF_CREAT != 0 {
+                    if pflags & SSH_FXF_EXCL != 0 {
+                        options.create_new(true);
+                    } else {
+                        options.create(true);
+                    }
+                }
+                if writable {
+                    refuse_symlink(&real.path)?;
+                    #[cfg(unix)]
+                    options.custom_flags(libc::O_NOFOLLOW);
+                }
+                let file = options.open(&real.path).await?;
+                if writable {
+                    tracing::info!("sftp: {} 写入 {}", self.user.name, real.path.display());
+                }
+                Ok(self.open_handle(
+                    id,
+                    OpenHandle::File {
+                        file,
+                        path: real.path,
+                        writable,
+                    },
+                ))
+            }
+            SSH_FXP_OPENDIR => {
+                let entries = match self.resolve(&packet.string()?, RepoLevel::Read).await? {
+                    Resolved::Virtual(children) => children
+                        .into_iter()
+                        .map(|name| {
+                            let attrs = Attrs::virtual_dir();
+                            DirEntry {
+                                longname: attrs.longname(&name, &self.user.name),
+                                name,
+                                attrs,
+                            }
+                        })
+                        .collect(),
+                    Resolved::Real(real) => self.read_dir(&real.path).await?,
+                };
+                Ok(self.open_handle(id, OpenHandle::Dir { entries }))
+            }
+            SSH_FXP_READDIR => {
+                let OpenHandle::Dir { entries } = self.handle(&packet.string()?)? else {
+                    return Err(Status::BadMessage);
+                };
+                if entries.is_empty() {
+                    return Err(Status::Eof);
+                }
+                let count = entries.len().min(READDIR_BATCH);
+                let batch = entries.drain(..count).collect::<Vec<_>>();
+                Ok(Reply::names(id, &batch))
+            }
+            SSH_FXP_CLOSE => {
+                self.handles
+                    .remove(&packet.string()?)
+                    .ok_or(Status::Failure)?;
+                Ok(Reply::status(id, Status::Ok))
+            }
+            SSH_FXP_READ => {
+                let handle = packet.string()?;
+                let offset = packet.u64()?;
+                let len = packet.u32()?.min(MAX_READ) as usize;
+                let OpenHandle::File { file, .. } = self.handle(&handle)? else {
+                    return Err(Status::BadMessage);
+                };
+                file.seek(SeekFrom::Start(offset)).await?;
+                let mut data = vec![0u8; len];
+                let mut filled = 0;
+                while filled < len {
+                    match file.read(&mut data[filled..]).await? {
+                        0 => break,
+                        n => filled += n,
+                    }
+                }
+                if filled == 0 {
+                    return Err(Status::Eof);
+                }
+                let mut reply = Reply::new(SSH_FXP_DATA, id);
+                reply.bytes(&data[..filled]);
+                Ok(reply)
+            }
+            SSH_FXP_WRITE => {
+                let handle = packet.string()?;
+                let offset = packet.u64()?;
+                let data = packet.bytes()?;
+                let OpenHandle::File { file, writable, .. } = self.handle(&handle)? else {
+                    return Err(Status::BadMessage);
+                };
+                if !*writable {
+                    return Err(Status::PermissionDenied);
+                }
+                file.seek(SeekFrom::Start(offset)).await?;
+                file.write_all(data).await?;
+                Ok(Reply::status(id, Status::Ok))
+            }
+            SSH_FXP_SETSTAT => {
+                let path = packet.string()?;
+                let attrs = packet.attrs()?;
+                let Resolved::Real(real) = self.resolve(&path, RepoLevel::Write).await? else {
+                    return Err(Status::PermissionDenied);
+                };
+                refuse_symlink(&real.path)?;
+                if let Some(size) = attrs.size {
+                    let mut options = tokio::fs::OpenOptions::new();
+                    options.write(true);
+                    #[cfg(unix)]
+                    options.custom_flags(libc::O_NOFOLLOW);
+                    let file = options.open(&real.path).await?;
+                    file.set_len(size).await?;
+                }
+                set_permissions(&real.path, &attrs).await?;
+                Ok(Reply::status(id, Status::Ok))
+            }
+            SSH_FXP_FSETSTAT => {
+                let handle = packet.string()?;
+                let attrs = packet.attrs()?;
+                let OpenHandle::File {
+                    file,
+                    path,
+                    writable,
+                } = self.handle(&handle)?
+                else {
+                    return Err(Status::BadMessage);
+                };
+                if !*writable {
+                    return Err(Status::PermissionDenied);
+                }
+                if let Some(size) = attrs.size {
+                    file.set_len(size).await?;
+                }
+                set_permissions(path, &attrs).await?;
+                Ok(Reply::status(id, Status::Ok))
+            }
+            SSH_FXP_REMOVE => {
+                let real = self.resolve_real(&packet.string()?).await?;
+                tokio::fs::remove_file(&real.path).await?;
+                tracing::info!("sftp: {} 删除 {}", self.user.name, real.path.display());
+                Ok(Reply::status(id, Status::Ok))
+            }
+            SSH_FXP_MKDIR => {
+                let path = packet.string()?;
+                packet.attrs()?;
+                let real = self.resolve_real(&path).await?;
+                refuse_symlink(&real.path)?;
+                tokio::fs::create_dir(&real.path).await?;
+                Ok(Reply::status(id, Status::Ok))
+            }
+            SSH_FXP_RMDIR => {
+                let real = self.resolve_real(&packet.string()?).await?;
+                tokio::fs::remove_dir(&real.path).await?;
+                tracing::info!("sftp: {} 删除目录 {}", self.user.name, real.path.display());
+                Ok(Reply::status(id, Status::Ok))
+            }
+            SSH_FXP_RENAME => {
+                let from = self.resolve_real(&packet.string()?).await?;
+                let to = self.resolve_real(&packet.string()?).await?;
+                // 协议版本 3: 目标已存在时失败
+                if tokio::fs::symlink_metadata(&to.path).await.is_ok() {
+                    return Err(Status::Failure);
+                }
+                tokio::fs::rename(&from.path, &to.path).await?;
+                tracing::info!(
+                    "sftp: {} 重命名 {}:{} -> {}:{}",
+                    self.user.name,
+                    from.repo,
+                    from.path.display(),
+                    to.repo,
+                    to.path.display()
+                );
+                Ok(Reply::status(id, Status::Ok))
+            }
+            // READLINK, SYMLINK 与扩展请求
+            _ => Err(Status::OpUnsupported),
+        }
+    }
+
+    fn open_handle(&mut self, id: u32, handle: OpenHandle) -> Reply {
+        self.next_handle += 1;
+        let key = self.next_handle.to_string();
+        self.handles.insert(key.clone(), handle);
+        let mut reply = Reply::new(SSH_FXP_HANDLE, id);
+        reply.bytes(key.as_bytes());
+        reply
+    }
+
+    fn handle(&mut self, key: &str) -> Result<&mut OpenHandle, Status> {
+        self.handles.get_mut(key).ok_or(Status::Failure)
+    }
+
+    async fn read_dir(&self, path: &Path) -> Result<VecDeque<DirEntry>, Status> {
+        let mut entries = VecDeque::new();
+        let mut dir = tokio::fs::read_dir(path).await?;
+        while let Some(entry) = dir.next_entry().await? {
+            let Ok(md) = entry.metadata().await else {
+                continue;
+            };
+            let name = entry.file_name().to_string_lossy().to_string();
+            let attrs = Attrs::from_metadata(&md);
+            entries.push_back(DirEntry {
+                longname: attrs.longname(&name, &self.user.name),
+                name,
+                attrs,
+            });
+        }
+        Ok(entries)
+    }
+
+    /// 解析客户端路径并检查仓库权限
+    async fn resolve(&self, path: &str, need: RepoLevel) -> Result<Resolved, Status> {
+        let parts = virtual_components(path);
+        let (name, rest) = match locate(&self.repos, &parts).ok_or(Status::NoSuchFile)? {
+            Location::Virtual(children) if need == RepoLevel::Read => {
+                return Ok(Resolved::Virtual(children))
+            }
+            Location::Virtual(_) => return Err(Status::PermissionDenied),
+            Location::Repo { name, rest } => (name, rest),
+        };
+
+        if need == RepoLevel::Write && !self.user.caps.allows(Capability::Write) {
+            tracing::warn!("sftp: 没有 write 权限: user={}", self.user.name);
+            return Err(Status::PermissionDenied);
+        }
+        let level = self.levels.get(&name).copied();
+        if !level.is_some_and(|level| level >= need) {
+            tracing::warn!(
+                "sftp: 拒绝仓库访问: user={} repo={} need={}",
+                self.user.name,
+                name,
+                need.as_str()
+            );
+            return Err(Status::PermissionDenied);
+        }
+
+        let mut target = RepoTarget::new(name.as_str())?;
+        target.workspace = WorkspaceSettings::load(&self.db, &target.name).await?;
+        let root = match &self.branch {
+            Some(branch) => target.branch_path(branch),
+            None => target.work_path.clone(),
+        };
+        let path = rest.iter().fold(root.clone(), |path, part| path.join(part));
+        contained(&root, &path)?;
+        let others = self
+            .registered
+            .iter()
+            .filter(|(other, _)| *other != name)
+            .map(|(_, work_path)| work_path.as_path())
+            .collect::<Vec<_>>();
+        outside_others(&path, &self.jobs, &others)?;
+
+        Ok(Resolved::Real(RealPath {
+            repo: name,
+            root,
+            path,
+        }))
+    }
+
+    /// 修改类请求: 需要 write 权限, 且不能作用于仓库工作目录本身
+    async fn resolve_real(&self, path: &str) -> Result<RealPath, Status> {
+        match self.resolve(path, RepoLevel::Write).await? {
+            Resolved::Real(real) if !real.is_root() => Ok(real),
+            _ => Err(Status::PermissionDenied),
+        }
+    }
+}
+
+async fn set_permissions(path: &Path, attrs: &Attrs) -> Result<(), Status> {
+    #[cfg(unix)]
+    if let Some(mode) = attrs.permissions {
+        use std::os::unix::fs::PermissionsExt;
+        tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(mode & 0o7777)).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parts(path: &str) -> Vec<String> {
+        virtual_components(path)
+    }
+
+    #[test]
+    fn virtual_paths_stay_under_root() {
+        assert!(parts("/").is_empty());
+        assert!(parts(".").is_empty());
+        assert_eq!(parts("../../etc/passwd"), ["etc", "passwd"]);
+        assert_eq!(
+            parts("/ns/app/./src/../Cargo.toml"),
+            ["ns", "app", "Cargo.toml"]
+        );
+        assert_eq!(virtual_path(&parts("a//b/")), "/a/b");
+    }
+
+    #[test]
+    fn locate_matches_longest_repo() {
+        let repos = ["ns/app", "ns/app/sub", "tools"].map(String::from);
+
+        assert_eq!(
+            locate(&repos, &[]),
+            Some(Location::Virtual(vec!["ns".into(), "tools".into()]))
+        );
+        assert_eq!(
+            locate(&repos, &parts("/ns")),
+            Some(Location::Virtual(vec!["app".into()]))
+        );
+        assert_eq!(
+            locate(&repos, &parts("/ns/app/src/main.rs")),
+            Some(Location::Repo {
+                name: "ns/app".into(),
+                rest: parts("src/main.rs"),
+            })
+        );
+        assert_eq!(
+            locate(&repos, &parts("/ns/app/sub/README.md")),
+            Some(Location::Repo {
+                name: "ns/app/sub".into(),
+                rest: parts("README.md"),
+            })
+        );
+        assert_eq!(locate(&repos, &parts("/other")), None);
+    }
+
+    #[test]
+    fn attrs_roundtrip() {
+        let attrs = Attrs {
+            size: Some(42),
+            uid_gid: Some((1000, 1000)),
+            permissions: Some(S_IFREG | 0o644),
+            times: Some((1, 2)),
+        };
+        let mut reply = Reply(Vec::new());
+        reply.attrs(&attrs);
+        let mut packet = Packet { buf: &reply.0 };
+        assert_eq!(packet.attrs().unwrap(), attrs);
+        assert!(packet.buf.is_empty());
+
+        assert!(attrs
+            .longname("a.txt", "alice")
+            .starts_with("-rw-r--r-- 1 alice alice"));
+    }
+
+    #[test]
+    fn request_roundtrip_reports_bad_message() {
+        let mut reply = Reply::status(7, Status::NoSuchFile);
+        let frame = std::mem::take(&mut reply.0);
+        let mut packet = Packet { buf: &frame };
+        assert_eq!(packet.u8().unwrap(), SSH_FXP_STATUS);
+        assert_eq!(packet.u32().unwrap(), 7);
+        assert_eq!(packet.u32().unwrap(), Status::NoSuchFile as u32);
+        assert_eq!(packet.string().unwrap(), "no such file");
+
+        let mut short = Packet {
+            buf: &[0, 0, 0, 9, 1],
+        };
+        assert_eq!(short.string(), Err(Status::BadMessage));
+    }
+
+    #[test]
+    fn paths_of_nested_repos_are_rejected() {
+        let dir = std::env::temp_dir().join(format!("horsed-sftp-nested-{}", std::process::id()));
+        let team = dir.join("team");
+        let app = team.join("app");
+        std::fs::create_dir_all(&app).unwrap();
+        std::fs::create_dir_all(team.join("app@main")).unwrap();
+        let jobs = dir.join(".jobs");
+        let others = [app.as_path()];
+
+        assert_eq!(
+            outside_others(&team.join("README.md"), &jobs, &others),
+            Ok(())
+        );
+        assert_eq!(outside_others(&team.join("apps"), &jobs, &others), Ok(()));
+        for path in [
+            app.clone(),
+            app.join("src/main.rs"),
+            team.join("app@main/Cargo.toml"),
+            jobs.join("team/app/job-1"),
+        ] {
+            assert_eq!(
+                outside_others(&path, &jobs, &others),
+                Err(Status::PermissionDenied),
+                "{}",
+                path.display()
+            );
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn dangling_symlink_outside_root_is_rejected() {
+        let dir = std::env::temp_dir().join(format!("horsed-sftp-{}", std::process::id()));
+        let root = dir.join("work");
+        std::fs::create_dir_all(&root).unwrap();
+        let outside = dir.join("outside").join("authorized_keys");
+        std::os::unix::fs::symlink(&outside, root.join("evil")).unwrap();
+        std::os::unix::fs::symlink(root.join("missing"), root.join("inner")).unwrap();
+
+        assert_eq!(
+            contained(&root, &root.join("evil")),
+            Err(Status::PermissionDenied)
+        );
+        assert_eq!(
+            contained(&root, &root.join("inner")),
+            Err(Status::PermissionDenied)
+        );
+        assert_eq!(
+            refuse_symlink(&root.join("evil")),
+            Err(Status::PermissionDenied)
+        );
+        assert_eq!(contained(&root, &root.join("new.txt")), Ok(()));
+        assert_eq!(refuse_symlink(&root.join("new.txt")), Ok(()));
+        assert!(!outside.exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,346 @@
+//! 增量代码同步
+//!
+//! `sync` 请求按客户端的清单回复仓库缺少的 blob, 再把客户端上传的内容写入仓库对象库;
+//! 随后的 `cargo`/`cmd`/`just` 请求只携带清单 (`SYNC_MODE=manifest`),
+//! 从基准提交检出后再用对象库中的 blob 还原变动的文件.
+//!
+//! 旧版客户端仍然发送完整补丁, 按 `git apply` 的方式处理.
+use super::workspace::JobWorkspace;
+use super::*;
+use stable::data::sync::{SyncFrame, SyncManifest};
+use std::collections::HashSet;
+use tokio::io::{AsyncBufReadExt, AsyncRead};
+
+/// 客户端通过环境变量声明的同步方式
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SyncRequest {
+    /// 标准输入为同步清单, 否则为补丁
+    pub manifest: bool,
+    /// 同步的未跟踪文件数量
+    pub untracked: usize,
+    /// `sync` 请求上传的字节数
+    pub uploaded: u64,
+}
+
+/// 客户端发送的工作区改动
+pub enum SyncPayload {
+    Patch(PatchFile),
+    Manifest(SyncManifest),
+}
+
+/// 补丁同步的内容, 边接收边写入任务目录下的 `<job_id>.patch`, 不在内存中缓存; 用完后删除
+pub struct PatchFile {
+    path: PathBuf,
+    stats: PatchStats,
+}
+
+impl PatchFile {
+    async fn receive(reader: impl AsyncRead + Unpin, job_id: &str) -> anyhow::Result<Self> {
+        let dir = crate::config::config().jobs_dir();
+        tokio::fs::create_dir_all(&dir)
+            .await
+            .with_context(|| format!("创建任务目录失败: {}", dir.display()))?;
+
+        let mut patch = Self {
+            path: dir.join(format!("{job_id}.patch")),
+            stats: PatchStats::default(),
+        };
+        let mut file = tokio::fs::File::create(&patch.path)
+            .await
+            .with_context(|| format!("创建补丁文件失败: {}", patch.path.display()))?;
+
+        let mut reader = tokio::io::BufReader::new(reader);
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            if reader.read_until(b'\n', &mut line).await? == 0 {
+                break;
+            }
+            patch.stats.line(&line);
+            file.write_all(&line).await?;
+        }
+        file.flush().await?;
+        Ok(patch)
+    }
+}
+
+impl Drop for PatchFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+impl AppServer {
+    pub(crate) fn sync_request(&self) -> SyncRequest {
+        SyncRequest {
+            manifest: self.env.get("SYNC_MODE").map(String::as_str) == Some("manifest"),
+            untracked: self
+                .env
+                .get("SYNC_UNTRACKED")
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(0),
+            uploaded: self
+                .env
+                .get("SYNC_UPLOADED")
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(0),
+        }
+    }
+
+    /// ### 增量同步
+    ///
+    /// 接收同步清单, 回复缺少的 blob, 再把上传的 blob 写入仓库对象库
+    #[tracing::instrument(skip(self), err)]
+    pub async fn sync(&mut self, _command: Vec<String>) -> HorseResult<()> {
+        let env_repo = self
+            .env
+            .get("REPO")
+            .cloned()
+            .context("REPO 环境变量未设置")?;
+
+        let Some(target) = self.authorize_repo(&env_repo, RepoLevel::Write).await? else {
+            return Ok(());
+        };
+
+        let mut handle = self.handle.take().context("FIXME: NO HANDLE")?;
+        let repo = target.repo();
+        if !repo.exists() {
+            handle
+                .fail_with_error(2, "HSSH_REPO_NOT_FOUND", "代码仓库不存在，请先 push 代码")
+                .await?;
+            return Ok(());
+        }
+
+        let task = self.tm.spawn_handle();
+        task.spawn(async move {
+            match receive_blobs(&repo, &mut handle).await {
+                Ok((blobs, bytes)) => {
+                    tracing::info!(
+                        "[sync] {}: blobs={blobs} bytes={bytes}",
+                        repo.path().display()
+                    );
+                    handle.exit_code(0).await?;
+                }
+                Err(err) => {
+                    tracing::error!("增量同步失败: {err:#}");
+                    handle
+                        .fail_with_error(1, "HSSH_SYNC_FAILED", format!("{err:#}"))
+                        .await?;
+                }
+            }
+            Ok(())
+        });
+
+        Ok(())
+    }
+}
+
+/// 回复缺少的 blob 并接收上传的内容, 返回写入的 blob 数量和字节数
+async fn receive_blobs(repo: &Repo, handle: &mut ChannelHandle) -> anyhow::Result<(usize, u64)> {
+    let mut writer = handle.make_writer();
+    let mut reader = handle.make_reader();
+
+    let SyncFrame::Manifest(manifest) = SyncFrame::read(&mut reader).await? else {
+        anyhow::bail!("预期同步清单");
+    };
+    validate_manifest(&manifest)?;
+    // 基准提交不在服务端时 (例如未推送), 客户端回退为补丁同步
+    if repo
+        .rev_parse(format!("{}^{{commit}}", manifest.base))
+        .await
+        .is_err()
+    {
+        anyhow::bail!("远端仓库缺少基准提交 {}", manifest.base);
+    }
+
+    let missing = repo.missing_objects(&manifest.oids()).await?;
+    SyncFrame::Missing(missing.clone())
+        .write(&mut writer)
+        .await?;
+    writer.flush().await?;
+
+    let mut pending = missing.into_iter().collect::<HashSet<_>>();
+    let (mut blobs, mut bytes) = (0, 0);
+    loop {
+        match SyncFrame::read(&mut reader).await? {
+            SyncFrame::Blob { oid, size } => {
+                if !pending.remove(&oid) {
+                    anyhow::bail!("未请求的 blob: {oid}");
+                }
+                // 内容直接写入对象库, 不在内存中缓存
+                let (written, len) = repo.write_blob((&mut reader).take(size)).await?;
+                if len != size {
+                    anyhow::bail!("blob 内容不完整: {oid} {len}/{size}");
+                }
+                if written != oid {
+                    anyhow::bail!("blob 哈希不一致: {oid} != {written}, 文件可能在同步期间被修改");
+                }
+                blobs += 1;
+                bytes += size;
+            }
+            SyncFrame::Done => break,
+            other => anyhow::bail!("非预期的同步消息: {other:?}"),
+        }
+    }
+
+    if !pending.is_empty() {
+        anyhow::bail!("客户端未上传 {} 个 blob", pending.len());
+    }
+    Ok((blobs, bytes))
+}
+
+/// 哈希用于 git 命令的输入, 只接受 sha1/sha256 十六进制字符串
+fn valid_oid(oid: &str) -> bool {
+    matches!(oid.len(), 40 | 64) && oid.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+fn validate_manifest(manifest: &SyncManifest) -> anyhow::Result<()> {
+    if !valid_oid(&manifest.base) {
+        anyhow::bail!("无效的基准提交: {}", manifest.base);
+    }
+    if let Some(oid) = manifest.oids().into_iter().find(|oid| !valid_oid(oid)) {
+        anyhow::bail!("无效的 blob 哈希: {oid}");
+    }
+    Ok(())
+}
+
+impl SyncPayload {
+    /// 读取客户端发送的清单或者补丁
+    pub async fn read(
+        handle: &mut ChannelHandle,
+        request: SyncRequest,
+        job_id: &str,
+    ) -> HorseResult<Self> {
+        let mut reader = handle.make_reader();
+        if request.manifest {
+            let SyncFrame::Manifest(manifest) = SyncFrame::read(&mut reader).await? else {
+                return Err(anyhow!("预期同步清单").into());
+            };
+            validate_manifest(&manifest)?;
+            Ok(Self::Manifest(manifest))
+        } else {
+            Ok(Self::Patch(PatchFile::receive(reader, job_id).await?))
+        }
+    }
+
+    /// 检出代码并还原客户端的改动, 返回 `code_sync=applied ...` 摘要
+    pub async fn apply(
+        &self,
+        repo: &Repo,
+        workspace: &JobWorkspace,
+        branch: &str,
+        request: SyncRequest,
+        handle: &ChannelHandle,
+    ) -> anyhow::Result<String> {
+        match self {
+            Self::Patch(patch) => {
+                workspace
+                    .checkout(repo, branch)
+                    .await
+                    .context("检出代码失败")?;
+                repo.apply(&workspace.path, &patch.path)
+                    .await
+                    .context("应用代码快照失败")?;
+                Ok(code_sync_summary(&patch.stats, request.untracked))
+            }
+            Self::Manifest(manifest) => {
+                if repo
+                    .rev_parse(format!("{}^{{commit}}", manifest.base))
+                    .await
+                    .is_err()
+                {
+                    anyhow::bail!("远端仓库缺少基准提交 {}, 请先 push 代码", manifest.base);
+                }
+
+                let tip = repo
+                    .rev_parse(branch)
+                    .await
+                    .context("读取远端分支提交失败")?;
+                if tip == manifest.base {
+                    workspace
+                        .checkout(repo, branch)
+                        .await
+                        .context("检出代码失败")?;
+                } else {
+                    handle
+                        .warn(format!(
+                            "远端分支 {branch} 位于 {tip}, 使用本地基准提交 {}",
+                            manifest.base
+                        ))
+                        .await?;
+                    workspace
+                        .checkout_commit(repo, branch, &manifest.base)
+                        .await
+                        .context("检出代码失败")?;
+                }
+
+                repo.materialize(&workspace.path, &manifest.entries)
+                    .await
+                    .context("还原工作区文件失败")?;
+                Ok(manifest_summary(manifest, request))
+            }
+        }
+    }
+}
+
+/// 增量同步的摘要, 列出变动文件总大小以及实际上传/复用的字节数
+fn manifest_summary(manifest: &SyncManifest, request: SyncRequest) -> String {
+    let total = manifest.total_bytes();
+    format!(
+        "code_sync=applied mode=manifest files={} untracked={} total_bytes={total} uploaded_bytes={} reused_bytes={}",
+        manifest.entries.len(),
+        manifest.untracked,
+        request.uploaded,
+        total.saturating_sub(request.uploaded)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use stable::data::sync::{SyncEntry, MODE_FILE};
+
+    #[test]
+    fn manifest_rejects_invalid_oids() {
+        let mut manifest = SyncManifest {
+            base: "a".repeat(40),
+            entries: vec![SyncEntry {
+                path: "src/lib.rs".to_string(),
+                oid: Some("b".repeat(64)),
+                mode: MODE_FILE,
+                size: 100,
+            }],
+            untracked: 0,
+        };
+        assert!(validate_manifest(&manifest).is_ok());
+
+        manifest.entries[0].oid = Some("HEAD\nmaster".to_string());
+        assert!(validate_manifest(&manifest).is_err());
+
+        manifest.base = "master".to_string();
+        assert!(validate_manifest(&manifest).is_err());
+    }
+
+    #[test]
+    fn manifest_summary_reports_savings() {
+        let manifest = SyncManifest {
+            base: "a".repeat(40),
+            entries: vec![SyncEntry {
+                path: "vendor/big.bin".to_string(),
+                oid: Some("b".repeat(40)),
+                mode: MODE_FILE,
+                size: 1000,
+            }],
+            untracked: 1,
+        };
+        let request = SyncRequest {
+            manifest: true,
+            untracked: 1,
+            uploaded: 0,
+        };
+        assert_eq!(
+            manifest_summary(&manifest, request),
+            "code_sync=applied mode=manifest files=1 untracked=1 total_bytes=1000 uploaded_bytes=0 reused_bytes=1000"
+        );
+    }
+}
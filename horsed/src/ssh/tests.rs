@@ -19,6 +19,41 @@ use tokio::net::ToSocketAddrs;
 
 type Result<T> = std::result::Result<T, Error>;
 
+#[test]
+fn code_sync_summary_counts_files_once() {
+    let patch = b"diff --git a/src/lib.rs b/src/lib.rs\n--- a/src/lib.rs\n+++ b/src/lib.rs\n\
+diff --git a/src/lib.rs b/src/lib.rs\n\
+diff --git a/new.txt b/new.txt\nnew file mode 100644\n";
+    let mut stats = PatchStats::default();
+    for line in patch.split_inclusive(|b| *b == b'\n') {
+        stats.line(line);
+    }
+    assert_eq!(
+        code_sync_summary(&stats, 1),
+        format!(
+            "code_sync=applied files=2 untracked=1 patch_bytes={}",
+            patch.len()
+        )
+    );
+    assert_eq!(
+        code_sync_summary(&PatchStats::default(), 0),
+        "code_sync=applied files=0 untracked=0 patch_bytes=0"
+    );
+}
+
+#[rstest]
+#[case("src/main.rs", Some("/work/app/src/main.rs"))]
+#[case("./src/../Cargo.toml", Some("/work/app/Cargo.toml"))]
+#[case("a/../../x", None)]
+#[case("src/../../other/secret", None)]
+#[case("/etc/passwd", None)]
+fn work_file_stays_inside_work_path(#[case] file: &str, #[case] expected: Option<&str>) {
+    assert_eq!(
+        work_file(Path::new("/work/app"), file),
+        expected.map(PathBuf::from)
+    );
+}
+
 #[rstest]
 #[case("bash", "-ic")]
 #[case("/bin/bash", "-ic")]
@@ -189,3 +224,22 @@ async fn test_ssh_server_auth(key: &PrivateKey, db: &DbConn) {
     let ssh = TestClient::connect(key.clone(), "ping", ("127.0.0.1", 1222)).await;
     assert!(ssh.is_ok());
 }
+
+#[tokio::test]
+async fn copy_with_job_keeps_recording_after_channel_closed() {
+    let jobs = JobRegistry::new(16, 1024);
+    let job = jobs.create_job("alice", "cmd", "echo").await;
+
+    // 对端已经关闭, 模拟客户端断开或任务转入后台
+    let (mut writer, reader) = tokio::io::duplex(64);
+    drop(reader);
+
+    let mut input: &[u8] = b"detached output";
+    let total = copy_with_job(&mut input, &mut writer, job.clone())
+        .await
+        .unwrap();
+    assert_eq!(total, 15);
+
+    let (snapshot, ..) = job.snapshot().await;
+    assert_eq!(snapshot, b"detached output");
+}
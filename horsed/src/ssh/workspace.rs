@@ -0,0 +1,261 @@
+//! 任务工作目录
+//!
+//! - `shared`: 所有分支共用 `<workspace>/<repo>`, `target/` 也只有一份
+//! - `branch`: 每个分支一个 `git worktree`: `<workspace>/<repo>@<branch>`, `target/` 各自独立
+//! - `job`: 每个任务一个临时 `git worktree`: `<workspace>/.jobs/<repo>/<job_id>`,
+//!   任务结束后删除, `target/` 共用 `<workspace>/<repo>/target`
+//!
+//! `.jobs` 和 `<repo>@<branch>` 与仓库工作目录位于同一个目录下, 仓库名称不能以 `.` 开头或包含 `@`
+//! (见 [`super::acl::normalize_repo_name`]), 所以任何仓库的工作目录都不会落在这些目录里.
+use super::acl::RepoTarget;
+use crate::config::WorkspaceMode;
+use crate::db::entity::repo;
+use crate::git::repo::Repo;
+use crate::prelude::*;
+use sea_orm::{ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter};
+use std::path::PathBuf;
+
+/// 仓库的工作目录设置, 未单独设置时使用 `horsed.toml` 中的默认值
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WorkspaceSettings {
+    pub mode: WorkspaceMode,
+    pub prune: bool,
+}
+
+impl Default for WorkspaceSettings {
+    fn default() -> Self {
+        let config = crate::config::config();
+        Self {
+            mode: config.workspace.mode,
+            prune: config.workspace.prune,
+        }
+    }
+}
+
+impl WorkspaceSettings {
+    /// 读取仓库单独的设置
+    pub async fn load(db: &DatabaseConnection, name: &str) -> HorseResult<Self> {
+        let mut settings = Self::default();
+        let Some(model) = repo::Entity::find()
+            .filter(repo::Column::Name.eq(name))
+            .one(db)
+            .await?
+        else {
+            return Ok(settings);
+        };
+
+        if let Some(mode) = model.workspace_mode.as_deref() {
+            match WorkspaceMode::parse(mode) {
+                Some(mode) => settings.mode = mode,
+                None => tracing::warn!("仓库 {name} 的工作目录模式无效: {mode}, 使用默认值"),
+            }
+        }
+        if let Some(prune) = model.workspace_prune {
+            settings.prune = prune;
+        }
+        Ok(settings)
+    }
+}
+
+/// 一次任务使用的工作目录
+#[derive(Clone, Debug)]
+pub struct JobWorkspace {
+    pub mode: WorkspaceMode,
+    /// 任务运行目录
+    pub path: PathBuf,
+    /// 共用的 `CARGO_TARGET_DIR`, `None` 时使用运行目录下的 `target/`
+    pub target_dir: Option<PathBuf>,
+    prune: bool,
+}
+
+impl RepoTarget {
+    /// 分支对应的常驻工作目录, `get`/`put` 等直接访问工作目录的请求使用
+    ///
+    /// `job` 模式的临时目录在任务结束后删除, 构建产物位于共用的 `<workspace>/<repo>/target`
+    pub fn branch_path(&self, branch: &str) -> PathBuf {
+        match self.workspace.mode {
+            WorkspaceMode::Shared | WorkspaceMode::Job => self.work_path.clone(),
+            WorkspaceMode::Branch => {
+                let mut name = self.work_path.as_os_str().to_os_string();
+                name.push("@");
+                name.push(branch_dir_name(branch));
+                PathBuf::from(name)
+            }
+        }
+    }
+
+    /// 任务使用的工作目录
+    pub fn job_workspace(&self, branch: &str, job_id: &str) -> JobWorkspace {
+        let (path, target_dir) = match self.workspace.mode {
+            WorkspaceMode::Shared | WorkspaceMode::Branch => (self.branch_path(branch), None),
+            WorkspaceMode::Job => {
+                let root = crate::config::config().workspace_dir();
+                let root = std::env::current_dir()
+                    .map(|dir| dir.join(&root))
+                    .unwrap_or(root);
+                (
+                    root.join(".jobs").join(&self.name).join(job_id),
+                    Some(self.work_path.join("target")),
+                )
+            }
+        };
+
+        JobWorkspace {
+            mode: self.workspace.mode,
+            path,
+            target_dir,
+            prune: self.workspace.prune,
+        }
+    }
+}
+
+impl JobWorkspace {
+    /// 检出分支代码到工作目录
+    pub async fn checkout(&self, repo: &Repo, branch: &str) -> HorseResult<()> {
+        match self.mode {
+            WorkspaceMode::Shared => {
+                if !self.path.exists() {
+                    std::fs::create_dir_all(&self.path)?;
+                }
+                repo.checkout(&self.path, Some(branch)).await?;
+            }
+            WorkspaceMode::Branch | WorkspaceMode::Job => {
+                if let Some(parent) = self.path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                repo.worktree_checkout(&self.path, branch).await?;
+            }
+        }
+
+        if let Some(target_dir) = &self.target_dir {
+            std::fs::create_dir_all(target_dir)?;
+        }
+
+        if self.prune {
+            repo.clean(&self.path).await?;
+        }
+
+        Ok(())
+    }
+
+    /// 检出指定的提交, 共用目录模式下裸仓库的 HEAD 仍然指向分支
+    pub async fn checkout_commit(
+        &self,
+        repo: &Repo,
+        branch: &str,
+        commit: &str,
+    ) -> HorseResult<()> {
+        self.checkout(repo, commit).await?;
+        if self.mode == WorkspaceMode::Shared {
+            repo.set_head(branch).await?;
+        }
+        Ok(())
+    }
+
+    /// 删除 `job` 模式的临时工作目录, 其他模式保留
+    pub async fn cleanup(&self, repo: &Repo) {
+        if self.mode != WorkspaceMode::Job {
+            return;
+        }
+        if let Err(err) = repo.worktree_remove(&self.path).await {
+            tracing::warn!("删除临时工作目录失败: {}: {err}", self.path.display());
+        }
+    }
+}
+
+/// 分支名转换为目录名, `feature/login` => `feature_login+<hash>`
+///
+/// 替换过字符的分支名追加 `+` 和原分支名 sha256 的前 8 位, 避免 `feature/a` 与 `feature_a`
+/// 使用同一个目录; 没有替换的分支名不会包含 `+`, 两者不会重名.
+fn branch_dir_name(branch: &str) -> String {
+    use sha2::{Digest, Sha256};
+
+    let name = branch
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+    if name == branch {
+        return name;
+    }
+
+    let hash = format!("{:x}", Sha256::digest(branch.as_bytes()));
+    format!("{name}+{}", &hash[..8])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(mode: WorkspaceMode) -> RepoTarget {
+        let mut target = RepoTarget::new("team/app").unwrap();
+        target.workspace = WorkspaceSettings { mode, prune: false };
+        target
+    }
+
+    #[test]
+    fn branch_dir_name_is_flat() {
+        assert_eq!(branch_dir_name("feature/login"), "feature_login+df7c7aeb");
+        assert_eq!(branch_dir_name("release-1.2"), "release-1.2");
+        assert_eq!(branch_dir_name("../x"), ".._x+d6b96a97");
+        // 替换字符后同名的分支使用不同的目录
+        assert_eq!(branch_dir_name("feature_a"), "feature_a");
+        assert_ne!(branch_dir_name("feature/a"), branch_dir_name("feature_a"));
+        assert_ne!(
+            branch_dir_name("feature/a"),
+            branch_dir_name("feature_a-951d42dc")
+        );
+    }
+
+    #[test]
+    fn job_workspace_follows_mode() {
+        let shared = target(WorkspaceMode::Shared);
+        let ws = shared.job_workspace("main", "job-1");
+        assert_eq!(ws.path, shared.work_path);
+        assert_eq!(ws.target_dir, None);
+
+        let branch = target(WorkspaceMode::Branch);
+        let ws = branch.job_workspace("feature/a", "job-1");
+        assert!(ws.path.ends_with("app@feature_a+951d42dc"));
+        assert_eq!(ws.path, branch.branch_path("feature/a"));
+        assert_eq!(ws.target_dir, None);
+
+        let job = target(WorkspaceMode::Job);
+        let ws = job.job_workspace("main", "job-1");
+        assert!(ws.path.ends_with(".jobs/team/app/job-1"));
+        assert_eq!(ws.target_dir, Some(job.work_path.join("target")));
+        // 构建产物从共用的 target 目录获取
+        assert_eq!(job.branch_path("main"), job.work_path);
+    }
+
+    #[test]
+    fn worktrees_are_not_repo_names() {
+        use super::super::acl::normalize_repo_name;
+
+        let root = target(WorkspaceMode::Shared).work_path;
+        let root = root.parent().and_then(|p| p.parent()).unwrap().to_owned();
+        let branch = target(WorkspaceMode::Branch);
+        let job = target(WorkspaceMode::Job);
+        for path in [
+            branch.branch_path("main"),
+            branch.branch_path("feature/a"),
+            job.job_workspace("main", "job-1").path,
+        ] {
+            let relative = path.strip_prefix(&root).unwrap();
+            // 包含 worktree 的目录都不能登记为仓库
+            for dir in relative.ancestors().filter(|dir| {
+                dir.components().any(|c| {
+                    let c = c.as_os_str().to_string_lossy();
+                    c.starts_with('.') || c.contains('@')
+                })
+            }) {
+                assert_eq!(normalize_repo_name(dir.to_str().unwrap()), None, "{dir:?}");
+            }
+        }
+    }
+}
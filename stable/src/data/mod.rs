@@ -4,6 +4,7 @@ use std::path::PathBuf;
 use tokio::io::{AsyncRead, AsyncReadExt};
 
 pub use zerocopy::{FromBytes, Immutable, IntoBytes, KnownLayout};
+pub mod sync;
 pub mod v1;
 pub mod v2;
 
@@ -0,0 +1,143 @@
+//! 增量代码同步协议
+//!
+//! 1. 客户端发送 [`SyncFrame::Manifest`]: 相对基准提交有变动的文件及其 blob 哈希
+//! 2. 服务端回复 [`SyncFrame::Missing`]: 仓库中缺少的 blob
+//! 3. 客户端依次发送 [`SyncFrame::Blob`], 每帧后面紧跟 `size` 字节的文件内容, 最后发送 [`SyncFrame::Done`]
+//!
+//! 帧格式: `u32` 大端长度 + bincode 编码的 [`SyncFrame`]
+use super::*;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+/// 单帧的长度上限, blob 内容不计入帧长度
+pub const MAX_FRAME_SIZE: u32 = 64 * 1024 * 1024;
+
+/// 普通文件
+pub const MODE_FILE: u32 = 0o100644;
+/// 可执行文件
+pub const MODE_EXECUTABLE: u32 = 0o100755;
+/// 符号链接, blob 内容为链接目标
+pub const MODE_SYMLINK: u32 = 0o120000;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SyncEntry {
+    /// 相对仓库根目录的路径, 使用 `/` 分隔
+    pub path: String,
+    /// blob 哈希, `None` 表示文件已删除
+    pub oid: Option<String>,
+    pub mode: u32,
+    pub size: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SyncManifest {
+    /// 基准提交, 服务端仓库中必须存在
+    pub base: String,
+    pub entries: Vec<SyncEntry>,
+    /// 其中未跟踪文件的数量
+    pub untracked: usize,
+}
+
+impl SyncManifest {
+    /// 需要的 blob 去重后的列表
+    pub fn oids(&self) -> Vec<&str> {
+        let mut oids = self
+            .entries
+            .iter()
+            .filter_map(|entry| entry.oid.as_deref())
+            .collect::<Vec<_>>();
+        oids.sort_unstable();
+        oids.dedup();
+        oids
+    }
+
+    /// 工作区变动文件的总大小
+    pub fn total_bytes(&self) -> u64 {
+        self.entries.iter().map(|entry| entry.size).sum()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SyncFrame {
+    Manifest(SyncManifest),
+    Missing(Vec<String>),
+    Blob { oid: String, size: u64 },
+    Done,
+}
+
+impl SyncFrame {
+    pub fn encode(&self) -> io::Result<Vec<u8>> {
+        let body = bincode::serialize(self).map_err(io::Error::other)?;
+        let size = u32::try_from(body.len())
+            .ok()
+            .filter(|size| *size <= MAX_FRAME_SIZE)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "sync frame too large"))?;
+
+        let mut frame = Vec::with_capacity(4 + body.len());
+        frame.extend_from_slice(&size.to_be_bytes());
+        frame.extend_from_slice(&body);
+        Ok(frame)
+    }
+
+    pub async fn write<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&self.encode()?).await
+    }
+
+    pub async fn read<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Self> {
+        let mut size = [0u8; 4];
+        reader.read_exact(&mut size).await?;
+        let size = u32::from_be_bytes(size);
+        if size > MAX_FRAME_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "sync frame too large",
+            ));
+        }
+
+        let mut body = vec![0u8; size as usize];
+        reader.read_exact(&mut body).await?;
+        bincode::deserialize(&body).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sync_frame_roundtrip() {
+        let manifest = SyncManifest {
+            base: "a".repeat(40),
+            entries: vec![
+                SyncEntry {
+                    path: "src/lib.rs".to_string(),
+                    oid: Some("b".repeat(40)),
+                    mode: MODE_FILE,
+                    size: 10,
+                },
+                SyncEntry {
+                    path: "src/copy.rs".to_string(),
+                    oid: Some("b".repeat(40)),
+                    mode: MODE_FILE,
+                    size: 10,
+                },
+                SyncEntry {
+                    path: "old.rs".to_string(),
+                    oid: None,
+                    mode: 0,
+                    size: 0,
+                },
+            ],
+            untracked: 1,
+        };
+        assert_eq!(manifest.oids().len(), 1);
+        assert_eq!(manifest.total_bytes(), 20);
+
+        let frame = SyncFrame::Manifest(manifest);
+        let bytes = frame.encode().unwrap();
+        let mut reader = &bytes[..];
+        let decoded = futures::executor::block_on(SyncFrame::read(&mut reader)).unwrap();
+        assert_eq!(decoded, frame);
+        assert!(reader.is_empty());
+    }
+}
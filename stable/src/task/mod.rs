@@ -9,7 +9,9 @@ use once_cell::sync::Lazy;
 use parking_lot::{Condvar, Mutex};
 use std::future::Future;
 use std::pin::Pin;
+use std::sync::Arc;
 use tokio::runtime::Runtime;
+use tokio::sync::Notify;
 
 pub mod executor;
 pub mod manager;
@@ -29,7 +31,19 @@ type JoinFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
 type SomeFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
 
 /// 任务运行限制，比如限制同时运行的任务数量
-struct TaskCondition(Mutex<usize>, Condvar);
+///
+/// 同步场景使用 [`TaskCondition::check`] 阻塞等待, 异步场景使用
+/// [`TaskCondition::acquire`] 等待并占用一个运行名额
+pub struct TaskCondition(Mutex<usize>, Condvar, Notify);
+
+/// 运行名额, 释放时运行数减1
+pub struct TaskPermit(Arc<TaskCondition>);
+
+impl Drop for TaskPermit {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
 
 static RUNTIME: Lazy<Runtime> = Lazy::new(build_multi_thread);
 
@@ -39,7 +53,12 @@ pub fn handle() -> &'static tokio::runtime::Handle {
 
 impl TaskCondition {
     pub fn new() -> Self {
-        TaskCondition(Mutex::new(0), Condvar::new())
+        TaskCondition(Mutex::new(0), Condvar::new(), Notify::new())
+    }
+
+    /// 当前运行数
+    pub fn count(&self) -> usize {
+        *self.0.lock()
     }
 
     /// 运行数加1
@@ -47,6 +66,7 @@ impl TaskCondition {
         let mut count = self.0.lock();
         *count += 1;
         self.1.notify_all();
+        self.2.notify_waiters();
     }
 
     /// 运行数减1
@@ -54,6 +74,33 @@ impl TaskCondition {
         let mut count = self.0.lock();
         *count -= 1;
         self.1.notify_all();
+        self.2.notify_waiters();
+    }
+
+    /// 运行数未达到上限时占用一个名额, 否则返回 `None`
+    pub fn try_acquire(self: &Arc<Self>, upper: usize) -> Option<TaskPermit> {
+        let mut count = self.0.lock();
+        if *count >= upper {
+            return None;
+        }
+        *count += 1;
+        self.1.notify_all();
+        Some(TaskPermit(self.clone()))
+    }
+
+    /// 异步等待运行数低于上限, 然后占用一个名额
+    pub async fn acquire(self: &Arc<Self>, upper: usize) -> TaskPermit {
+        loop {
+            // 先注册通知再检查, 避免检查之后、等待之前的释放被错过
+            let notified = self.2.notified();
+            futures::pin_mut!(notified);
+            notified.as_mut().enable();
+
+            if let Some(permit) = self.try_acquire(upper) {
+                return permit;
+            }
+            notified.await;
+        }
     }
 
     /// 检查运行条件, 不满足则同步等待
@@ -65,6 +112,12 @@ impl TaskCondition {
     }
 }
 
+impl Default for TaskCondition {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 pub fn tracing_unbounded<T>() -> (TracingUnboundedSender<T>, TracingUnboundedReceiver<T>) {
     mpsc::unbounded()
 }
@@ -138,3 +191,33 @@ where
         _ = t3 => {},
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn task_condition_limits_concurrent_permits() {
+        let cd = Arc::new(TaskCondition::new());
+        let first = cd.acquire(1).await;
+        assert_eq!(cd.count(), 1);
+        assert!(cd.try_acquire(1).is_none());
+
+        let waiter = {
+            let cd = cd.clone();
+            tokio::spawn(async move {
+                let _permit = cd.acquire(1).await;
+            })
+        };
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiter.is_finished());
+
+        drop(first);
+        tokio::time::timeout(Duration::from_secs(1), waiter)
+            .await
+            .expect("released permit should wake the waiter")
+            .unwrap();
+        assert_eq!(cd.count(), 0);
+    }
+}